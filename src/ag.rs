@@ -0,0 +1,438 @@
+//! Decoding of the allocation group headers (AGF, AGI and AGFL) that follow
+//! each superblock copy.
+
+use std::fmt::Write;
+use std::io::{Read, Seek};
+
+use nom::{be_u32, be_u64};
+
+use superblock::{self, Superblock, Uuid};
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use fixtures;
+    use superblock;
+    use XfsError;
+
+    #[test]
+    fn it_reads_ag_headers() {
+        let mut image = Cursor::new(fixtures::image(true));
+        let sb = superblock::read(&mut image).unwrap();
+        let ag = super::read(&mut image, &sb, 1).unwrap();
+        assert_eq!(ag.agf.seqno, 1);
+        assert_eq!(ag.agf.free_blocks, 43);
+        assert_eq!(ag.agf.longest, 24);
+        assert_eq!(ag.agf.cnt_root, 2);
+        assert_eq!(ag.agf.rmap_level, 1);
+        assert_eq!(ag.agi.count, 64);
+        assert_eq!(ag.agi.free_count, 3);
+        assert_eq!(ag.agi.free_root, 4);
+        assert_eq!(ag.agi.unlinked_heads(), vec![(5, fixtures::UNLINKED_AGINO)]);
+        assert_eq!(ag.agfl.blocks, vec![7, 8]);
+        assert_eq!(ag.agf.uuid, superblock::Uuid(fixtures::UUID));
+    }
+
+    #[test]
+    fn it_reads_v4_ag_headers() {
+        let mut image = Cursor::new(fixtures::image(false));
+        let sb = superblock::read(&mut image).unwrap();
+        let ag = super::read(&mut image, &sb, 0).unwrap();
+        assert_eq!(ag.agf.free_blocks, 8);
+        assert_eq!(ag.agf.rmap_level, 0);
+        assert_eq!(ag.agi.unlinked_heads(), vec![]);
+        assert_eq!(ag.agfl.blocks, vec![7, 8]);
+    }
+
+    #[test]
+    fn it_summarizes_every_ag() {
+        let mut image = Cursor::new(fixtures::image(true));
+        let sb = superblock::read(&mut image).unwrap();
+        let summary = super::summarize(&mut image, &sb).unwrap();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].free_blocks, 8);
+        assert_eq!(summary[0].unlinked_buckets, 0);
+        assert_eq!(summary[1].unlinked_buckets, 1);
+        assert_eq!(summary[1].free_pct(), 43.0 / 64.0 * 100.0);
+
+        let table = super::format_table(&summary);
+        assert_eq!(table.lines().count(), 3);
+        assert!(table.lines().nth(2).unwrap().starts_with("    1"));
+    }
+
+    #[test]
+    fn it_rejects_a_bad_agf_magic() {
+        let mut raw = fixtures::image(true);
+        raw[512] = 0;
+        let mut image = Cursor::new(raw);
+        let sb = superblock::read(&mut image).unwrap();
+        match super::read(&mut image, &sb, 0) {
+            Err(XfsError::BadMagic) => {}
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// "XAGF"
+pub const AGF_MAGIC: u32 = 0x58414746;
+/// "XAGI"
+pub const AGI_MAGIC: u32 = 0x58414749;
+/// "XAFL"
+pub const AGFL_MAGIC: u32 = 0x5841464c;
+
+/// Byte offsets of the checksums within each header.
+pub const AGF_CRC_OFFSET: usize = 216;
+pub const AGI_CRC_OFFSET: usize = 312;
+pub const AGFL_CRC_OFFSET: usize = 32;
+
+/// Marks an empty unlinked bucket or a missing inode.
+pub const NULL_AGINO: u32 = 0xffffffff;
+
+/// Free space header.
+#[derive(Clone, Debug)]
+pub struct Agf {
+    /// Magic number, "XAGF".
+    pub magic: u32,
+    pub version: u32,
+    /// AG number.
+    pub seqno: u32,
+    /// Size of the AG in blocks.
+    pub length: u32,
+    /// Root block of the by-block-number free space btree.
+    pub bno_root: u32,
+    /// Root block of the by-size free space btree.
+    pub cnt_root: u32,
+    /// Root block of the reverse mapping btree.
+    pub rmap_root: u32,
+    /// Height of the by-block-number free space btree.
+    pub bno_level: u32,
+    /// Height of the by-size free space btree.
+    pub cnt_level: u32,
+    /// Height of the reverse mapping btree.
+    pub rmap_level: u32,
+    /// First active slot of the free list.
+    pub fl_first: u32,
+    /// Last active slot of the free list.
+    pub fl_last: u32,
+    /// Number of blocks on the free list.
+    pub fl_count: u32,
+    /// Number of free blocks, not counting the free list.
+    pub free_blocks: u32,
+    /// Length of the longest free extent.
+    pub longest: u32,
+    /// Blocks held by the free space btrees beyond their roots.
+    pub btree_blocks: u32,
+    /// Filesystem UUID (v5).
+    pub uuid: Uuid,
+    /// Blocks held by the reverse mapping btree (v5).
+    pub rmap_blocks: u32,
+    /// Blocks held by the reference count btree (v5).
+    pub refcount_blocks: u32,
+    /// Root block of the reference count btree (v5).
+    pub refcount_root: u32,
+    /// Height of the reference count btree (v5).
+    pub refcount_level: u32,
+    /// Log sequence number of the last write (v5).
+    pub lsn: u64,
+    /// Header checksum (v5), as stored.
+    pub crc: u32,
+}
+
+/// Inode header.
+#[derive(Clone, Debug)]
+pub struct Agi {
+    /// Magic number, "XAGI".
+    pub magic: u32,
+    pub version: u32,
+    /// AG number.
+    pub seqno: u32,
+    /// Size of the AG in blocks.
+    pub length: u32,
+    /// Number of allocated inodes.
+    pub count: u32,
+    /// Root block of the inode btree.
+    pub root: u32,
+    /// Height of the inode btree.
+    pub level: u32,
+    /// Number of free inodes.
+    pub free_count: u32,
+    /// Most recently allocated inode chunk.
+    pub new_ino: u32,
+    /// Unused.
+    pub dir_ino: u32,
+    /// Heads of the 64 hash buckets of inodes that are unlinked but still
+    /// open, `NULL_AGINO` when empty.
+    pub unlinked: Vec<u32>,
+    /// Filesystem UUID (v5).
+    pub uuid: Uuid,
+    /// Header checksum (v5), as stored.
+    pub crc: u32,
+    /// Log sequence number of the last write (v5).
+    pub lsn: u64,
+    /// Root block of the free inode btree.
+    pub free_root: u32,
+    /// Height of the free inode btree.
+    pub free_level: u32,
+    /// Blocks held by the inode btree, with `inobtcount`.
+    pub inobt_blocks: u32,
+    /// Blocks held by the free inode btree, with `inobtcount`.
+    pub finobt_blocks: u32,
+}
+
+/// The AG free list: blocks set aside to refill the AG btrees.
+#[derive(Clone, Debug)]
+pub struct Agfl {
+    /// Blocks currently on the list, from `fl_first` to `fl_last`.
+    pub blocks: Vec<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AgHeaders {
+    pub agno: u32,
+    pub agf: Agf,
+    pub agi: Agi,
+    pub agfl: Agfl,
+}
+
+/// One row of the per-AG summary table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AgSummary {
+    pub agno: u32,
+    /// Size of the AG in blocks.
+    pub length: u32,
+    /// Free blocks, not counting the free list.
+    pub free_blocks: u32,
+    /// Length of the longest free extent.
+    pub longest_free: u32,
+    /// Blocks on the free list.
+    pub free_list: u32,
+    /// Allocated inodes.
+    pub inodes: u32,
+    /// Free inodes.
+    pub free_inodes: u32,
+    /// Non-empty unlinked buckets. Each holds a chain of at least one inode
+    /// that was unlinked while still open.
+    pub unlinked_buckets: usize,
+}
+
+impl Agi {
+    /// The non-empty unlinked buckets as (bucket, head agino) pairs.
+    pub fn unlinked_heads(&self) -> Vec<(usize, u32)> {
+        self.unlinked.iter()
+            .enumerate()
+            .filter(|&(_, agino)| *agino != NULL_AGINO)
+            .map(|(bucket, agino)| (bucket, *agino))
+            .collect()
+    }
+}
+
+impl AgSummary {
+    /// Free blocks as a percentage of the AG size.
+    pub fn free_pct(&self) -> f64 {
+        if self.length == 0 {
+            return 0.0;
+        }
+        f64::from(self.free_blocks) / f64::from(self.length) * 100.0
+    }
+}
+
+impl<'a> From<&'a AgHeaders> for AgSummary {
+    fn from(ag: &'a AgHeaders) -> AgSummary {
+        AgSummary {
+            agno: ag.agno,
+            length: ag.agf.length,
+            free_blocks: ag.agf.free_blocks,
+            longest_free: ag.agf.longest,
+            free_list: ag.agf.fl_count,
+            inodes: ag.agi.count,
+            free_inodes: ag.agi.free_count,
+            unlinked_buckets: ag.agi.unlinked_heads().len(),
+        }
+    }
+}
+
+/// Reads and decodes the headers of allocation group `agno`. Checksums are
+/// not verified here.
+pub fn read<R: Read + Seek>(dev: &mut R, sb: &Superblock, agno: u32) -> Result<AgHeaders, XfsError> {
+    let sector = sb.sector_size as usize;
+    let headers = superblock::read_at(dev, sb.agb_offset(agno, 0), 4 * sector)?;
+    let agf = parse_agf(&headers[sector..2 * sector])?;
+    let agi = parse_agi(&headers[2 * sector..3 * sector])?;
+    let agfl = parse_agfl(&headers[3 * sector..], sb, &agf)?;
+    Ok(AgHeaders {
+        agno,
+        agf,
+        agi,
+        agfl,
+    })
+}
+
+/// Reads the headers of every allocation group.
+pub fn read_all<R: Read + Seek>(dev: &mut R, sb: &Superblock) -> Result<Vec<AgHeaders>, XfsError> {
+    (0..sb.ag_count).map(|agno| read(dev, sb, agno)).collect()
+}
+
+/// Reads every allocation group and condenses it into a summary row.
+pub fn summarize<R: Read + Seek>(dev: &mut R, sb: &Superblock) -> Result<Vec<AgSummary>, XfsError> {
+    Ok(read_all(dev, sb)?.iter().map(AgSummary::from).collect())
+}
+
+/// Renders summary rows as a fixed-width text table.
+pub fn format_table(rows: &[AgSummary]) -> String {
+    let mut table = String::new();
+    let _ = writeln!(table, "{:>5} {:>10} {:>10} {:>6} {:>10} {:>6} {:>10} {:>10} {:>8}",
+                     "agno", "length", "free", "free%", "longest", "agfl", "inodes", "ifree", "unlinked");
+    for row in rows {
+        let _ = writeln!(table, "{:>5} {:>10} {:>10} {:>6.1} {:>10} {:>6} {:>10} {:>10} {:>8}",
+                         row.agno, row.length, row.free_blocks, row.free_pct(), row.longest_free,
+                         row.free_list, row.inodes, row.free_inodes, row.unlinked_buckets);
+    }
+    table
+}
+
+fn finish<T>(result: nom::IResult<&[u8], T>, magic: fn(&T) -> u32, expected: u32) -> Result<T, XfsError> {
+    match result {
+        nom::IResult::Done(_, ref header) if magic(header) != expected => Err(XfsError::BadMagic),
+        nom::IResult::Done(_, header) => Ok(header),
+        nom::IResult::Error(_) => Err(XfsError::Parse),
+        nom::IResult::Incomplete(_) => Err(XfsError::Incomplete),
+    }
+}
+
+pub fn parse_agf(input: &[u8]) -> Result<Agf, XfsError> {
+    finish(agf(input), |agf| agf.magic, AGF_MAGIC)
+}
+
+pub fn parse_agi(input: &[u8]) -> Result<Agi, XfsError> {
+    finish(agi(input), |agi| agi.magic, AGI_MAGIC)
+}
+
+/// Decodes the AGFL sector, keeping only the active entries described by
+/// the AGF.
+pub fn parse_agfl(input: &[u8], sb: &Superblock, agf: &Agf) -> Result<Agfl, XfsError> {
+    let slots = if sb.has_crc() {
+        match be_u32(input) {
+            nom::IResult::Done(_, AGFL_MAGIC) => {}
+            nom::IResult::Done(_, _) => return Err(XfsError::BadMagic),
+            _ => return Err(XfsError::Incomplete),
+        }
+        &input[36..]
+    } else {
+        input
+    };
+    let size = slots.len() / 4;
+    if size == 0 || agf.fl_count as usize > size {
+        return Err(XfsError::Parse);
+    }
+    let blocks = (0..agf.fl_count as usize)
+        .map(|i| {
+            let slot = (agf.fl_first as usize + i) % size * 4;
+            u32::from_be_bytes([slots[slot], slots[slot + 1], slots[slot + 2], slots[slot + 3]])
+        })
+        .collect();
+    Ok(Agfl { blocks })
+}
+
+named!(agf <Agf>,
+    chain!(
+        magic: be_u32 ~
+        version: be_u32 ~
+        seqno: be_u32 ~
+        length: be_u32 ~
+        bno_root: be_u32 ~
+        cnt_root: be_u32 ~
+        rmap_root: be_u32 ~
+        bno_level: be_u32 ~
+        cnt_level: be_u32 ~
+        rmap_level: be_u32 ~
+        fl_first: be_u32 ~
+        fl_last: be_u32 ~
+        fl_count: be_u32 ~
+        free_blocks: be_u32 ~
+        longest: be_u32 ~
+        btree_blocks: be_u32 ~
+        uuid: take!(16) ~
+        rmap_blocks: be_u32 ~
+        refcount_blocks: be_u32 ~
+        refcount_root: be_u32 ~
+        refcount_level: be_u32 ~
+        take!(112) ~
+        lsn: be_u64 ~
+        crc: take!(4),
+        || {
+            Agf {
+                magic,
+                version,
+                seqno,
+                length,
+                bno_root,
+                cnt_root,
+                rmap_root,
+                bno_level,
+                cnt_level,
+                rmap_level,
+                fl_first,
+                fl_last,
+                fl_count,
+                free_blocks,
+                longest,
+                btree_blocks,
+                uuid: Uuid::from_slice(uuid),
+                rmap_blocks,
+                refcount_blocks,
+                refcount_root,
+                refcount_level,
+                lsn,
+                crc: ::crc::stored_crc(crc, 0),
+            }
+        }
+    )
+);
+
+named!(agi <Agi>,
+    chain!(
+        magic: be_u32 ~
+        version: be_u32 ~
+        seqno: be_u32 ~
+        length: be_u32 ~
+        count: be_u32 ~
+        root: be_u32 ~
+        level: be_u32 ~
+        free_count: be_u32 ~
+        new_ino: be_u32 ~
+        dir_ino: be_u32 ~
+        unlinked: count!(be_u32, 64) ~
+        uuid: take!(16) ~
+        crc: take!(4) ~
+        be_u32 ~
+        lsn: be_u64 ~
+        free_root: be_u32 ~
+        free_level: be_u32 ~
+        inobt_blocks: be_u32 ~
+        finobt_blocks: be_u32,
+        || {
+            Agi {
+                magic,
+                version,
+                seqno,
+                length,
+                count,
+                root,
+                level,
+                free_count,
+                new_ino,
+                dir_ino,
+                unlinked,
+                uuid: Uuid::from_slice(uuid),
+                crc: ::crc::stored_crc(crc, 0),
+                lsn,
+                free_root,
+                free_level,
+                inobt_blocks,
+                finobt_blocks,
+            }
+        }
+    )
+);
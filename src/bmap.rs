@@ -0,0 +1,316 @@
+//! Online extent maps through `XFS_IOC_GETBMAPX`, and a fragmentation
+//! report for a directory tree built on them.
+//!
+//! The fragmentation factor is the one `xfs_db -c frag` prints: the share
+//! of extents beyond the ideal of one per file. Unlike `xfs_db` it works on
+//! a mounted filesystem and reports every top-level subtree separately.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use ioctl;
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::mem;
+    use std::path::Path;
+    use std::process;
+
+    fn raw(offset: i64, block: i64, length: i64, oflags: i32) -> super::RawBmapx {
+        super::RawBmapx { bmv_offset: offset, bmv_block: block, bmv_length: length, bmv_oflags: oflags,
+                          ..Default::default() }
+    }
+
+    #[test]
+    fn it_decodes_mappings() {
+        assert_eq!(mem::size_of::<super::RawBmapx>(), 48);
+        let hole = super::Mapping::from(&raw(0, -1, 8, 0));
+        assert_eq!(hole.block, None);
+        assert!(!hole.delalloc);
+        let delalloc = super::Mapping::from(&raw(8, -2, 8, super::OF_DELALLOC));
+        assert!(delalloc.delalloc);
+        let shared = super::Mapping::from(&raw(16, 1024, 16, super::OF_SHARED | super::OF_PREALLOC));
+        assert_eq!((shared.offset, shared.length, shared.block), (8192, 8192, Some(524288)));
+        assert!(shared.shared && shared.unwritten);
+    }
+
+    #[test]
+    fn it_counts_extents_against_the_ideal() {
+        let mappings = [super::Mapping::from(&raw(0, 100, 8, 0)),
+                        super::Mapping::from(&raw(8, -1, 8, 0)),
+                        super::Mapping::from(&raw(16, 300, 8, 0)),
+                        super::Mapping::from(&raw(24, 308, 8, super::OF_PREALLOC))];
+        let mut frag = super::FragCounts::default();
+        frag.add_file(&mappings);
+        frag.add_file(&[]);
+        // The last two are adjacent on disk and only split by the unwritten
+        // flag, so they count as one extent.
+        assert_eq!((frag.files, frag.actual, frag.ideal), (2, 2, 1));
+        assert_eq!(frag.factor(), 50.0);
+        assert_eq!(super::FragCounts::default().factor(), 0.0);
+    }
+
+    #[test]
+    fn it_walks_a_tree_off_xfs() {
+        let root = env::temp_dir().join(format!("xfs-bmap-{}", process::id()));
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/b/one"), b"1").unwrap();
+        fs::write(root.join("two"), b"2").unwrap();
+        let report = super::fragmentation(&root);
+        fs::remove_dir_all(&root).unwrap();
+
+        // Whatever the filesystem under the temporary directory, every file
+        // is either mapped or counted as an error.
+        let report = report.unwrap();
+        assert_eq!(report.total.files + report.errors, 2);
+        assert!(report.subtrees.keys().all(|k| k == Path::new("a") || k == Path::new("two")));
+    }
+
+    #[test]
+    fn it_stops_when_the_kernel_runs_out() {
+        let mut map = vec![super::RawBmapx::default(); 4];
+        map[0] = super::RawBmapx { bmv_offset: 24, bmv_length: 100, bmv_entries: 3, ..Default::default() };
+        map[1..].copy_from_slice(&[raw(0, 100, 8, 0), raw(8, -1, 8, 0), raw(16, 300, 8, 0)]);
+        let (returned, more) = super::returned(&map);
+        assert_eq!((returned.len(), returned[2].bmv_block, more), (3, 300, true));
+
+        // The last extent, the end of the requested range, no entries or
+        // more entries than fit all end the loop.
+        map[3].bmv_oflags = super::OF_LAST;
+        assert_eq!(super::returned(&map).0.len(), 3);
+        assert!(!super::returned(&map).1);
+        map[3].bmv_oflags = 0;
+        map[0].bmv_length = 0;
+        assert!(!super::returned(&map).1);
+        map[0].bmv_length = 100;
+        for &entries in &[0, 4, -1] {
+            map[0].bmv_entries = entries;
+            assert_eq!((super::returned(&map).0.len(), super::returned(&map).1), (0, false));
+        }
+    }
+}
+
+/// `XFS_IOC_GETBMAPX`, sized by `struct getbmap`.
+const GETBMAPX: u64 = ioctl::iowr(56, 32);
+
+pub const IF_ATTRFORK: i32 = 0x1;
+pub const IF_PREALLOC: i32 = 0x4;
+pub const IF_DELALLOC: i32 = 0x8;
+pub const IF_NO_HOLES: i32 = 0x10;
+
+/// Unwritten (preallocated) extent.
+pub const OF_PREALLOC: i32 = 0x1;
+/// Delayed allocation: reserved in memory, not yet on disk.
+pub const OF_DELALLOC: i32 = 0x2;
+/// The last extent of the file.
+pub const OF_LAST: i32 = 0x4;
+/// Shared with another file through reflink.
+pub const OF_SHARED: i32 = 0x8;
+
+/// Mappings requested per ioctl call.
+const BATCH: usize = 64;
+
+/// `struct getbmapx`. Offsets and lengths are in 512-byte units. The first
+/// element of the array passed in is the request header.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawBmapx {
+    bmv_offset: i64,
+    bmv_block: i64,
+    bmv_length: i64,
+    bmv_count: i32,
+    bmv_entries: i32,
+    bmv_iflags: i32,
+    bmv_oflags: i32,
+    bmv_unused1: i32,
+    bmv_unused2: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fork {
+    Data,
+    Attr,
+}
+
+/// One mapping of a file, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    /// Offset into the file.
+    pub offset: u64,
+    pub length: u64,
+    /// Byte offset on the data device; None for holes and delayed
+    /// allocations.
+    pub block: Option<u64>,
+    pub delalloc: bool,
+    pub unwritten: bool,
+    pub shared: bool,
+}
+
+/// Extent counts of a set of files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FragCounts {
+    /// Files mapped, whether or not they have blocks on disk yet.
+    pub files: u64,
+    /// Extents found.
+    pub actual: u64,
+    /// Extents if every file were contiguous.
+    pub ideal: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Fragmentation {
+    pub total: FragCounts,
+    /// Counts per entry directly below the walked directory.
+    pub subtrees: BTreeMap<PathBuf, FragCounts>,
+    /// Files that could not be opened or mapped.
+    pub errors: u64,
+}
+
+impl From<&RawBmapx> for Mapping {
+    fn from(raw: &RawBmapx) -> Mapping {
+        Mapping {
+            offset: raw.bmv_offset as u64 * 512,
+            length: raw.bmv_length as u64 * 512,
+            block: if raw.bmv_block < 0 { None } else { Some(raw.bmv_block as u64 * 512) },
+            delalloc: raw.bmv_oflags & OF_DELALLOC != 0,
+            unwritten: raw.bmv_oflags & OF_PREALLOC != 0,
+            shared: raw.bmv_oflags & OF_SHARED != 0,
+        }
+    }
+}
+
+impl FragCounts {
+    /// Counts the allocated extents of one file. Mappings that continue
+    /// where the previous one ended on disk are counted as one extent.
+    pub fn add_file(&mut self, mappings: &[Mapping]) {
+        let mut extents = 0;
+        let mut next = None;
+        for mapping in mappings {
+            let block = match mapping.block {
+                Some(block) => block,
+                None => continue,
+            };
+            if next != Some(block) {
+                extents += 1;
+            }
+            next = Some(block + mapping.length);
+        }
+        self.files += 1;
+        self.actual += extents;
+        if extents > 0 {
+            self.ideal += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &FragCounts) {
+        self.files += other.files;
+        self.actual += other.actual;
+        self.ideal += other.ideal;
+    }
+
+    /// Share of extents beyond the ideal, in percent.
+    pub fn factor(&self) -> f64 {
+        if self.actual == 0 {
+            return 0.0;
+        }
+        (self.actual - self.ideal) as f64 / self.actual as f64 * 100.0
+    }
+}
+
+fn map_file(file: &File, fork: Fork) -> Result<Vec<Mapping>, XfsError> {
+    let mut map = vec![RawBmapx::default(); BATCH + 1];
+    map[0].bmv_length = -1;
+    map[0].bmv_count = map.len() as i32;
+    map[0].bmv_iflags = IF_PREALLOC | IF_DELALLOC | if fork == Fork::Attr { IF_ATTRFORK } else { 0 };
+
+    let mut mappings = vec![];
+    loop {
+        unsafe { ioctl::ioctl(file, GETBMAPX, map.as_mut_ptr())? };
+        let (returned, more) = returned(&map);
+        mappings.extend(returned.iter().map(Mapping::from));
+        if !more {
+            break;
+        }
+    }
+    Ok(mappings)
+}
+
+/// The mappings one `GETBMAPX` call left in `map`, and whether another
+/// call could find more.
+fn returned(map: &[RawBmapx]) -> (&[RawBmapx], bool) {
+    let entries = map[0].bmv_entries as usize;
+    if entries == 0 || entries >= map.len() {
+        return (&[], false);
+    }
+    // The kernel moves the header past what it returned.
+    let more = map[entries].bmv_oflags & OF_LAST == 0 && map[0].bmv_length > 0;
+    (&map[1..=entries], more)
+}
+
+/// The mappings of one fork of `path`, holes included, in file offset
+/// order.
+pub fn bmap<P: AsRef<Path>>(path: P, fork: Fork) -> Result<Vec<Mapping>, XfsError> {
+    let file = ioctl::open(path)?;
+    map_file(&file, fork)
+}
+
+/// Walks the regular files below `root`, without crossing into other
+/// filesystems, and counts their data fork extents.
+pub fn fragmentation<P: AsRef<Path>>(root: P) -> Result<Fragmentation, XfsError> {
+    let root = root.as_ref();
+    let dev = fs::metadata(root)?.dev();
+    let mut report = Fragmentation::default();
+    for entry in fs::read_dir(root)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => {
+                report.errors += 1;
+                continue;
+            }
+        };
+        let mut counts = FragCounts::default();
+        walk(&entry.path(), dev, &mut counts, &mut report.errors)?;
+        report.total.merge(&counts);
+        report.subtrees.insert(PathBuf::from(entry.file_name()), counts);
+    }
+    Ok(report)
+}
+
+fn walk(path: &Path, dev: u64, counts: &mut FragCounts, errors: &mut u64) -> Result<(), XfsError> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        // Removed while we were walking.
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(XfsError::Io(err)),
+    };
+    if meta.dev() != dev {
+        return Ok(());
+    }
+    if meta.is_dir() {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(_) => {
+                *errors += 1;
+                return Ok(());
+            }
+        };
+        for entry in entries {
+            match entry {
+                Ok(entry) => walk(&entry.path(), dev, counts, errors)?,
+                Err(_) => *errors += 1,
+            }
+        }
+    } else if meta.is_file() {
+        match File::open(path).map_err(XfsError::from).and_then(|file| map_file(&file, Fork::Data)) {
+            Ok(mappings) => counts.add_file(&mappings),
+            Err(_) => *errors += 1,
+        }
+    }
+    Ok(())
+}
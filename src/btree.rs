@@ -0,0 +1,251 @@
+//! Walking the short-form btrees rooted in the AG headers (free space,
+//! inode, reverse mapping and reference count btrees).
+
+use std::io::{Read, Seek};
+
+use nom::{be_u16, be_u32, be_u64};
+
+use superblock::{self, Superblock, Uuid};
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use fixtures;
+    use superblock;
+
+    #[test]
+    fn it_walks_a_two_level_btree() {
+        let mut image = Cursor::new(fixtures::image(true));
+        let sb = superblock::read(&mut image).unwrap();
+        let mut records = vec![];
+        let blocks = super::walk(&mut image, &sb, 1, 2, 2, super::CNTBT, |rec| {
+            records.push((super::be32(rec, 0), super::be32(rec, 4)));
+        }).unwrap();
+        assert_eq!(blocks, 3);
+        assert_eq!(records, vec![(11, 3), (16, 16), (40, 24)]);
+    }
+
+    #[test]
+    fn it_rejects_the_wrong_btree() {
+        let mut image = Cursor::new(fixtures::image(false));
+        let sb = superblock::read(&mut image).unwrap();
+        assert!(super::walk(&mut image, &sb, 0, 1, 1, super::CNTBT, |_| {}).is_err());
+    }
+}
+
+/// Marks a missing sibling or child block.
+pub const NULL_AGBLOCK: u32 = 0xffffffff;
+
+/// v4 and v5 magic numbers of a btree type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Magic {
+    pub v4: u32,
+    pub v5: u32,
+}
+
+/// Block layout of one kind of short-form btree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Shape {
+    pub magic: Magic,
+    /// Size of a key in node blocks. Overlapping btrees (rmapbt) store a low
+    /// and a high key, counted together here.
+    pub key_len: usize,
+    /// Size of a record in leaf blocks.
+    pub rec_len: usize,
+}
+
+/// Free space by block number, "ABTB" / "AB3B".
+pub const BNOBT: Shape = Shape {
+    magic: Magic { v4: 0x41425442, v5: 0x41423342 },
+    key_len: 8,
+    rec_len: 8,
+};
+/// Free space by size, "ABTC" / "AB3C".
+pub const CNTBT: Shape = Shape {
+    magic: Magic { v4: 0x41425443, v5: 0x41423343 },
+    key_len: 8,
+    rec_len: 8,
+};
+/// Inode chunks, "IABT" / "IAB3".
+pub const INOBT: Shape = Shape {
+    magic: Magic { v4: 0x49414254, v5: 0x49414233 },
+    key_len: 4,
+    rec_len: 16,
+};
+/// Inode chunks with free inodes, "FIBT" / "FIB3".
+pub const FINOBT: Shape = Shape {
+    magic: Magic { v4: 0x46494254, v5: 0x46494233 },
+    key_len: 4,
+    rec_len: 16,
+};
+/// Reverse mappings, "RMB3" (v5 only).
+pub const RMAPBT: Shape = Shape {
+    magic: Magic { v4: 0, v5: 0x524d4233 },
+    key_len: 40,
+    rec_len: 24,
+};
+/// Shared extent reference counts, "R3FC" (v5 only).
+pub const REFCOUNTBT: Shape = Shape {
+    magic: Magic { v4: 0, v5: 0x52334643 },
+    key_len: 4,
+    rec_len: 12,
+};
+
+/// Byte offset of the checksum in a v5 short-form block.
+pub const CRC_OFFSET: usize = 52;
+
+impl Magic {
+    pub fn matches(&self, sb: &Superblock, magic: u32) -> bool {
+        magic == if sb.has_crc() { self.v5 } else { self.v4 }
+    }
+}
+
+/// Header of a short-form btree block.
+#[derive(Clone, Debug)]
+pub struct ShortHeader {
+    pub magic: u32,
+    /// Height above the leaves; zero for a leaf.
+    pub level: u16,
+    /// Number of records (leaves) or keys and pointers (nodes).
+    pub numrecs: u16,
+    pub leftsib: u32,
+    pub rightsib: u32,
+    /// Disk address of this block in 512-byte units (v5).
+    pub blkno: u64,
+    /// Log sequence number of the last write (v5).
+    pub lsn: u64,
+    /// Filesystem UUID (v5).
+    pub uuid: Uuid,
+    /// AG number of the owning AG (v5).
+    pub owner: u32,
+    /// Block checksum (v5), as stored.
+    pub crc: u32,
+}
+
+/// Size of a short-form block header.
+pub fn header_len(sb: &Superblock) -> usize {
+    if sb.has_crc() { 56 } else { 16 }
+}
+
+pub fn parse_header(input: &[u8], sb: &Superblock) -> Result<ShortHeader, XfsError> {
+    let result = if sb.has_crc() { header_v5(input) } else { header_v4(input) };
+    match result {
+        nom::IResult::Done(_, header) => Ok(header),
+        nom::IResult::Error(_) => Err(XfsError::Parse),
+        nom::IResult::Incomplete(_) => Err(XfsError::Incomplete),
+    }
+}
+
+pub(crate) fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+
+/// Walks the btree rooted at `root` in AG `agno`, calling `f` with every
+/// leaf record in key order. Returns the number of blocks visited.
+///
+/// The walk descends the leftmost path to the leaf level and then follows
+/// the right sibling pointers, which is also how `xfs_db` reads these trees.
+pub fn walk<R, F>(dev: &mut R, sb: &Superblock, agno: u32, root: u32, levels: u32, shape: Shape,
+                  mut f: F) -> Result<u32, XfsError>
+    where R: Read + Seek,
+          F: FnMut(&[u8])
+{
+    let block_size = sb.block_size as usize;
+    let hdr = header_len(sb);
+    let limit = sb.ag_length(agno);
+    let mut agbno = root;
+    let mut visited = 0;
+
+    for level in (1..levels).rev() {
+        let block = superblock::read_at(dev, sb.agb_offset(agno, agbno), block_size)?;
+        let header = parse_header(&block, sb)?;
+        if !shape.magic.matches(sb, header.magic) {
+            return Err(XfsError::BadMagic);
+        }
+        if u32::from(header.level) != level || header.numrecs == 0 {
+            return Err(XfsError::Parse);
+        }
+        let maxrecs = (block_size - hdr) / (shape.key_len + 4);
+        agbno = be32(&block, hdr + maxrecs * shape.key_len);
+        visited += 1;
+        if agbno >= limit {
+            return Err(XfsError::Parse);
+        }
+    }
+
+    while agbno != NULL_AGBLOCK {
+        if agbno >= limit || visited > limit {
+            return Err(XfsError::Parse);
+        }
+        let block = superblock::read_at(dev, sb.agb_offset(agno, agbno), block_size)?;
+        let header = parse_header(&block, sb)?;
+        if !shape.magic.matches(sb, header.magic) {
+            return Err(XfsError::BadMagic);
+        }
+        if header.level != 0 || hdr + header.numrecs as usize * shape.rec_len > block_size {
+            return Err(XfsError::Parse);
+        }
+        for i in 0..header.numrecs as usize {
+            let start = hdr + i * shape.rec_len;
+            f(&block[start..start + shape.rec_len]);
+        }
+        visited += 1;
+        agbno = header.rightsib;
+    }
+    Ok(visited)
+}
+
+named!(header_v4 <ShortHeader>,
+    chain!(
+        magic: be_u32 ~
+        level: be_u16 ~
+        numrecs: be_u16 ~
+        leftsib: be_u32 ~
+        rightsib: be_u32,
+        || {
+            ShortHeader {
+                magic,
+                level,
+                numrecs,
+                leftsib,
+                rightsib,
+                blkno: 0,
+                lsn: 0,
+                uuid: Uuid([0; 16]),
+                owner: 0,
+                crc: 0,
+            }
+        }
+    )
+);
+
+named!(header_v5 <ShortHeader>,
+    chain!(
+        magic: be_u32 ~
+        level: be_u16 ~
+        numrecs: be_u16 ~
+        leftsib: be_u32 ~
+        rightsib: be_u32 ~
+        blkno: be_u64 ~
+        lsn: be_u64 ~
+        uuid: take!(16) ~
+        owner: be_u32 ~
+        crc: take!(4),
+        || {
+            ShortHeader {
+                magic,
+                level,
+                numrecs,
+                leftsib,
+                rightsib,
+                blkno,
+                lsn,
+                uuid: Uuid::from_slice(uuid),
+                owner,
+                crc: ::crc::stored_crc(crc, 0),
+            }
+        }
+    )
+);
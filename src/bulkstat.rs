@@ -0,0 +1,639 @@
+//! Inode scanning through `XFS_IOC_BULKSTAT`, falling back to the v1
+//! `XFS_IOC_FSBULKSTAT` on kernels older than 5.3, and capacity reports
+//! built on it.
+//!
+//! Bulkstat reads inodes in inode number order straight from the inode
+//! btrees, which is far cheaper than walking directories and calling
+//! `stat` on every entry.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use geometry;
+use inode::{FileType, Timestamp};
+use ioctl;
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use inode::{FileType, Timestamp};
+
+    fn raw_v5() -> super::RawBulkstat {
+        super::RawBulkstat {
+            bs_ino: 131,
+            bs_size: 10000,
+            bs_blocks: 3,
+            bs_mtime: 1700000000,
+            bs_mtime_nsec: 5,
+            bs_blksize: 4096,
+            bs_extsize_blks: 16,
+            bs_nlink: 1,
+            bs_extents: 2,
+            bs_extents64: 70000,
+            bs_mode: 0o100644,
+            ..Default::default()
+        }
+    }
+
+    fn stat(ino: u64, mode: u16, size: u64, mtime: i64, extents: u64) -> super::Stat {
+        let mut stat = super::Stat::v5(&raw_v5(), false);
+        stat.ino = ino;
+        stat.mode = mode;
+        stat.size = size;
+        stat.mtime = Timestamp { seconds: mtime, nanoseconds: 0 };
+        stat.extents = extents;
+        stat
+    }
+
+    #[test]
+    fn it_decodes_the_ioctl_structs() {
+        assert_eq!(mem::size_of::<super::RawBulkIreq>(), 64);
+        assert_eq!(mem::size_of::<super::RawBulkstat>(), 192);
+        assert_eq!(mem::size_of::<super::RawBstat>(), 136);
+        assert_eq!(mem::size_of::<super::RawBulkreq>(), 32);
+        assert_eq!(super::BULKSTAT, 0x8040587f);
+        assert_eq!(super::FSBULKSTAT, 0xc0205865);
+
+        let stat = super::Stat::v5(&raw_v5(), false);
+        assert_eq!(stat.file_type(), FileType::Regular);
+        assert_eq!(stat.extents, 2);
+        assert_eq!(stat.allocated_bytes(), 12288);
+        assert_eq!(stat.extent_size_hint, 65536);
+        assert_eq!(stat.mtime, Timestamp { seconds: 1700000000, nanoseconds: 5 });
+        assert_eq!(super::Stat::v5(&raw_v5(), true).extents, 70000);
+        // A 4GiB hint overflows 32 bits.
+        let mut raw = raw_v5();
+        raw.bs_cowextsize_blks = 1 << 20;
+        assert_eq!(super::Stat::v5(&raw, false).cow_extent_size_hint, 1 << 32);
+
+        let v1 = super::RawBstat {
+            bs_ino: 132,
+            bs_mode: 0o40755,
+            bs_nlink: 2,
+            bs_blksize: 4096,
+            bs_projid_lo: 1,
+            bs_projid_hi: 2,
+            bs_extsize: 65536,
+            bs_extents: 1,
+            ..Default::default()
+        };
+        let stat = super::Stat::from(&v1);
+        assert_eq!(stat.file_type(), FileType::Directory);
+        assert_eq!(stat.project_id, 0x20001);
+        assert_eq!(stat.extent_size_hint, 65536);
+        assert_eq!(stat.btime, None);
+    }
+
+    #[test]
+    fn it_resumes_from_a_cursor() {
+        let cursor = super::Cursor::ag(2);
+        assert_eq!(cursor.start(19), 2 << 19);
+        let cursor = super::Cursor { ino: 1 << 19 | 70, ag: Some(2) };
+        assert_eq!(cursor.start(19), 2 << 19);
+        let cursor = super::Cursor { ino: 2 << 19 | 70, ag: Some(2) };
+        assert_eq!(cursor.start(19), 2 << 19 | 70);
+        assert_eq!(super::Cursor::default().start(19), 0);
+    }
+
+    #[test]
+    fn it_aggregates_reports() {
+        let day = 86400;
+        let now = 1700000000;
+        let mut first = super::Report::new(now, 2);
+        first.add(&stat(131, 0o100644, 0, now, 0));
+        first.add(&stat(132, 0o100644, 1000, now - 3 * day, 1));
+        first.add(&stat(133, 0o40755, 6, now - 400 * day, 40));
+        let mut second = super::Report::new(now, 2);
+        second.add(&stat(1 << 19 | 64, 0o100644, 1 << 20, now - 3000 * day, 500));
+        second.add(&stat(1 << 19 | 65, 0o100644, 4096, now + day, 3));
+        first.merge(&second);
+
+        assert_eq!((first.inodes, first.files, first.directories), (5, 4, 1));
+        assert_eq!(first.bytes, 1000 + (1 << 20) + 4096);
+        let sizes: Vec<_> = first.sizes.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(sizes, vec![(0, 1), (1024, 1), (4096, 1), (1 << 20, 1)]);
+        let ages: Vec<_> = first.ages.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(ages, vec![(1, 2), (7, 1), (u64::MAX, 1)]);
+        assert_eq!(first.outliers, vec![(500, 1 << 19 | 64), (40, 133)]);
+    }
+}
+
+const BULKSTAT: u64 = ioctl::ior(127, 64);
+const FSBULKSTAT: u64 = ioctl::iowr(101, 32);
+
+/// Only scan the AG in `agno`.
+const BULK_IREQ_AGNO: u32 = 0x1;
+/// Fill in `bs_extents64`; Linux 5.19 and later.
+const BULK_IREQ_NREXT64: u32 = 0x4;
+
+/// Inodes requested per ioctl call.
+const BATCH: usize = 256;
+
+/// Upper bounds, in days since the last modification, of the age classes.
+pub const AGE_DAYS: [u64; 6] = [1, 7, 30, 90, 365, 1095];
+
+/// `struct xfs_bulk_ireq`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawBulkIreq {
+    ino: u64,
+    flags: u32,
+    icount: u32,
+    ocount: u32,
+    agno: u32,
+    reserved: [u64; 5],
+}
+
+/// `struct xfs_bulkstat`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawBulkstat {
+    bs_ino: u64,
+    bs_size: u64,
+    bs_blocks: u64,
+    bs_xflags: u64,
+    bs_atime: i64,
+    bs_mtime: i64,
+    bs_ctime: i64,
+    bs_btime: i64,
+    bs_gen: u32,
+    bs_uid: u32,
+    bs_gid: u32,
+    bs_projectid: u32,
+    bs_atime_nsec: u32,
+    bs_mtime_nsec: u32,
+    bs_ctime_nsec: u32,
+    bs_btime_nsec: u32,
+    bs_blksize: u32,
+    bs_rdev: u32,
+    bs_cowextsize_blks: u32,
+    bs_extsize_blks: u32,
+    bs_nlink: u32,
+    bs_extents: u32,
+    bs_aextents: u32,
+    bs_version: u16,
+    bs_forkoff: u16,
+    bs_sick: u16,
+    bs_checked: u16,
+    bs_mode: u16,
+    bs_pad2: u16,
+    bs_extents64: u64,
+    bs_pad: [u64; 6],
+}
+
+/// `struct xfs_bulkstat_req`: the header followed by the records.
+#[repr(C)]
+struct RawBulkstatReq {
+    hdr: RawBulkIreq,
+    bulkstat: [RawBulkstat; BATCH],
+}
+
+/// `struct xfs_bstime`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawBstime {
+    tv_sec: i64,
+    tv_nsec: i32,
+}
+
+/// `struct xfs_bstat`, the v1 record.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawBstat {
+    bs_ino: u64,
+    bs_mode: u16,
+    bs_nlink16: u16,
+    bs_nlink: u32,
+    bs_uid: u32,
+    bs_gid: u32,
+    bs_rdev: u32,
+    bs_blksize: i32,
+    bs_size: i64,
+    bs_atime: RawBstime,
+    bs_mtime: RawBstime,
+    bs_ctime: RawBstime,
+    bs_blocks: i64,
+    bs_xflags: u32,
+    bs_extsize: i32,
+    bs_extents: i32,
+    bs_gen: u32,
+    bs_projid_lo: u16,
+    bs_forkoff: u16,
+    bs_projid_hi: u16,
+    bs_sick: u16,
+    bs_checked: u16,
+    bs_pad: [u8; 2],
+    bs_cowextsize: u32,
+    bs_dmevmask: u32,
+    bs_dmstate: u16,
+    bs_aextents: u16,
+}
+
+/// `struct xfs_fsop_bulkreq`.
+#[repr(C)]
+struct RawBulkreq {
+    lastip: *mut u64,
+    icount: i32,
+    ubuffer: *mut c_void,
+    ocount: *mut i32,
+}
+
+/// One inode as bulkstat reports it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stat {
+    pub ino: u64,
+    pub mode: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub project_id: u32,
+    pub generation: u32,
+    /// Size in bytes.
+    pub size: u64,
+    /// Filesystem blocks allocated, delayed allocations included.
+    pub blocks: u64,
+    pub block_size: u32,
+    /// Data fork extents.
+    pub extents: u64,
+    /// Attribute fork extents.
+    pub attr_extents: u32,
+    /// `quota::XFLAG_*` bits.
+    pub xflags: u32,
+    /// Extent size hint in bytes.
+    pub extent_size_hint: u64,
+    /// Copy-on-write extent size hint in bytes.
+    pub cow_extent_size_hint: u64,
+    pub atime: Timestamp,
+    pub mtime: Timestamp,
+    pub ctime: Timestamp,
+    /// Creation time; None from the v1 ioctl.
+    pub btime: Option<Timestamp>,
+    pub rdev: u32,
+    /// Metadata known to be damaged (`health::INODE_*` bits).
+    pub sick: u16,
+    /// Metadata that has been checked (`health::INODE_*` bits).
+    pub checked: u16,
+}
+
+/// Where a scan is, so that it can be picked up again later.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Cursor {
+    /// The next inode number to look at.
+    pub ino: u64,
+    /// Restricts the scan to one AG.
+    pub ag: Option<u32>,
+}
+
+/// An iterator over the inodes of a filesystem.
+pub struct Bulkstat {
+    path: PathBuf,
+    file: File,
+    cursor: Cursor,
+    /// `BULK_IREQ_*` flags for the v5 ioctl.
+    flags: u32,
+    v1: bool,
+    /// `Geometry::agino_bits`, looked up when first needed.
+    agino_bits: Option<u32>,
+    pending: VecDeque<Stat>,
+    done: bool,
+}
+
+/// Inode counts, sizes, ages and the most fragmented inodes of a set of
+/// inodes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    /// Seconds since the epoch that ages are measured from.
+    pub now: i64,
+    pub inodes: u64,
+    pub files: u64,
+    pub directories: u64,
+    /// Sum of regular file sizes.
+    pub bytes: u64,
+    /// Sum of allocated space of all inodes.
+    pub allocated_bytes: u64,
+    /// Regular files by size, keyed by the smallest power of two not below
+    /// the size, and zero for empty files.
+    pub sizes: BTreeMap<u64, u64>,
+    /// Regular files by time since the last modification, keyed by the
+    /// first of `AGE_DAYS` they fall under, or `u64::MAX` when older.
+    pub ages: BTreeMap<u64, u64>,
+    /// `(extents, ino)` of the inodes with the most data fork extents, most
+    /// first.
+    pub outliers: Vec<(u64, u64)>,
+    /// How many outliers to keep.
+    pub outlier_limit: usize,
+}
+
+fn timestamp(seconds: i64, nanoseconds: u32) -> Timestamp {
+    Timestamp { seconds, nanoseconds }
+}
+
+impl Stat {
+    fn v5(raw: &RawBulkstat, nrext64: bool) -> Stat {
+        Stat {
+            ino: raw.bs_ino,
+            mode: raw.bs_mode,
+            nlink: raw.bs_nlink,
+            uid: raw.bs_uid,
+            gid: raw.bs_gid,
+            project_id: raw.bs_projectid,
+            generation: raw.bs_gen,
+            size: raw.bs_size,
+            blocks: raw.bs_blocks,
+            block_size: raw.bs_blksize,
+            extents: if nrext64 { raw.bs_extents64 } else { u64::from(raw.bs_extents) },
+            attr_extents: raw.bs_aextents,
+            xflags: raw.bs_xflags as u32,
+            extent_size_hint: u64::from(raw.bs_extsize_blks) * u64::from(raw.bs_blksize),
+            cow_extent_size_hint: u64::from(raw.bs_cowextsize_blks) * u64::from(raw.bs_blksize),
+            atime: timestamp(raw.bs_atime, raw.bs_atime_nsec),
+            mtime: timestamp(raw.bs_mtime, raw.bs_mtime_nsec),
+            ctime: timestamp(raw.bs_ctime, raw.bs_ctime_nsec),
+            btime: Some(timestamp(raw.bs_btime, raw.bs_btime_nsec)),
+            rdev: raw.bs_rdev,
+            sick: raw.bs_sick,
+            checked: raw.bs_checked,
+        }
+    }
+
+    pub fn file_type(&self) -> FileType {
+        FileType::from_mode(self.mode)
+    }
+
+    pub fn allocated_bytes(&self) -> u64 {
+        self.blocks * u64::from(self.block_size)
+    }
+}
+
+impl From<&RawBstat> for Stat {
+    fn from(raw: &RawBstat) -> Stat {
+        let time = |t: &RawBstime| timestamp(t.tv_sec, t.tv_nsec as u32);
+        Stat {
+            ino: raw.bs_ino,
+            mode: raw.bs_mode,
+            nlink: raw.bs_nlink,
+            uid: raw.bs_uid,
+            gid: raw.bs_gid,
+            project_id: u32::from(raw.bs_projid_hi) << 16 | u32::from(raw.bs_projid_lo),
+            generation: raw.bs_gen,
+            size: raw.bs_size as u64,
+            blocks: raw.bs_blocks as u64,
+            block_size: raw.bs_blksize as u32,
+            extents: u64::from(raw.bs_extents as u32),
+            attr_extents: u32::from(raw.bs_aextents),
+            xflags: raw.bs_xflags,
+            extent_size_hint: u64::from(raw.bs_extsize as u32),
+            cow_extent_size_hint: u64::from(raw.bs_cowextsize),
+            atime: time(&raw.bs_atime),
+            mtime: time(&raw.bs_mtime),
+            ctime: time(&raw.bs_ctime),
+            btime: None,
+            rdev: raw.bs_rdev,
+            sick: raw.bs_sick,
+            checked: raw.bs_checked,
+        }
+    }
+}
+
+impl Cursor {
+    /// A cursor at the start of one AG.
+    pub fn ag(agno: u32) -> Cursor {
+        Cursor { ino: 0, ag: Some(agno) }
+    }
+
+    /// The first inode number to look at, given `Geometry::agino_bits`.
+    fn start(&self, agino_bits: u32) -> u64 {
+        match self.ag {
+            Some(agno) => self.ino.max(u64::from(agno) << agino_bits),
+            None => self.ino,
+        }
+    }
+}
+
+impl Bulkstat {
+    /// Scans from `cursor` on, such as one taken from an earlier scan.
+    pub fn resume<P: AsRef<Path>>(path: P, cursor: Cursor) -> Result<Bulkstat, XfsError> {
+        let path = path.as_ref();
+        Ok(Bulkstat {
+            path: path.to_path_buf(),
+            file: ioctl::open(path)?,
+            cursor,
+            flags: BULK_IREQ_NREXT64,
+            v1: false,
+            agino_bits: None,
+            pending: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    /// Where the scan is: just past the last inode returned.
+    pub fn cursor(&self) -> Cursor {
+        self.cursor
+    }
+
+    fn agino_bits(&mut self) -> Result<u32, XfsError> {
+        if let Some(bits) = self.agino_bits {
+            return Ok(bits);
+        }
+        let bits = geometry::geometry(&self.path)?.agino_bits();
+        self.agino_bits = Some(bits);
+        Ok(bits)
+    }
+
+    fn fetch(&mut self) -> Result<(), XfsError> {
+        if !self.v1 {
+            match self.fetch_v5() {
+                Err(XfsError::Io(ref err)) if ioctl::unsupported(err) => {}
+                result => return result,
+            }
+            // Kernels before 5.19 reject the flag; try once more without.
+            if self.flags & BULK_IREQ_NREXT64 != 0 {
+                self.flags &= !BULK_IREQ_NREXT64;
+                return self.fetch();
+            }
+            self.v1 = true;
+        }
+        self.fetch_v1()
+    }
+
+    fn fetch_v5(&mut self) -> Result<(), XfsError> {
+        let mut req = Box::new(RawBulkstatReq {
+            hdr: RawBulkIreq { ino: self.cursor.ino, flags: self.flags, icount: BATCH as u32,
+                               ..Default::default() },
+            bulkstat: [RawBulkstat::default(); BATCH],
+        });
+        if let Some(agno) = self.cursor.ag {
+            req.hdr.flags |= BULK_IREQ_AGNO;
+            req.hdr.agno = agno;
+        }
+        unsafe { ioctl::ioctl(&self.file, BULKSTAT, &mut *req)? };
+        let count = (req.hdr.ocount as usize).min(BATCH);
+        let nrext64 = self.flags & BULK_IREQ_NREXT64 != 0;
+        self.pending.extend(req.bulkstat[..count].iter().map(|raw| Stat::v5(raw, nrext64)));
+        self.done = count == 0;
+        Ok(())
+    }
+
+    fn fetch_v1(&mut self) -> Result<(), XfsError> {
+        let (start, ag_end) = match self.cursor.ag {
+            Some(agno) => {
+                let bits = self.agino_bits()?;
+                (self.cursor.start(bits), Some((u64::from(agno) + 1) << bits))
+            }
+            None => (self.cursor.ino, None),
+        };
+        let mut lastip = start.saturating_sub(1);
+        let mut buffer = vec![RawBstat::default(); BATCH];
+        let mut count = 0i32;
+        let mut req = RawBulkreq {
+            lastip: &mut lastip,
+            icount: BATCH as i32,
+            ubuffer: buffer.as_mut_ptr() as *mut c_void,
+            ocount: &mut count,
+        };
+        unsafe { ioctl::ioctl(&self.file, FSBULKSTAT, &mut req)? };
+        let count = (count.max(0) as usize).min(BATCH);
+        let before = self.pending.len();
+        self.pending.extend(buffer[..count].iter().map(Stat::from)
+                            .take_while(|stat| ag_end.is_none_or(|end| stat.ino < end)));
+        // Stop once the kernel has nothing more or we walked off the AG.
+        self.done = count == 0 || self.pending.len() - before < count;
+        Ok(())
+    }
+}
+
+impl Iterator for Bulkstat {
+    type Item = Result<Stat, XfsError>;
+
+    fn next(&mut self) -> Option<Result<Stat, XfsError>> {
+        if self.pending.is_empty() && !self.done {
+            if let Err(err) = self.fetch() {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+        let stat = self.pending.pop_front()?;
+        self.cursor.ino = stat.ino + 1;
+        Some(Ok(stat))
+    }
+}
+
+/// Scans every inode of the filesystem containing `path`.
+pub fn bulkstat<P: AsRef<Path>>(path: P) -> Result<Bulkstat, XfsError> {
+    Bulkstat::resume(path, Cursor::default())
+}
+
+/// Scans the AGs of the filesystem containing `path` on up to `threads`
+/// threads, folding the inodes of each AG into a value from `init`.
+/// Returns one value per AG, in AG order.
+pub fn scan_ags<P, T, I, F>(path: P, threads: usize, init: I, fold: F) -> Result<Vec<T>, XfsError>
+    where P: AsRef<Path>, T: Send, I: Fn() -> T + Sync, F: Fn(&mut T, &Stat) + Sync
+{
+    let path = path.as_ref();
+    let ag_count = geometry::geometry(path)?.ag_count as usize;
+    let next = AtomicUsize::new(0);
+    let scan = || {
+        let mut done = vec![];
+        loop {
+            let agno = next.fetch_add(1, Ordering::Relaxed);
+            if agno >= ag_count {
+                return done;
+            }
+            let result = Bulkstat::resume(path, Cursor::ag(agno as u32)).and_then(|scan| {
+                let mut value = init();
+                for stat in scan {
+                    fold(&mut value, &stat?);
+                }
+                Ok(value)
+            });
+            done.push((agno, result));
+        }
+    };
+    let mut results: Vec<_> = thread::scope(|s| {
+        let workers: Vec<_> = (0..threads.clamp(1, ag_count.max(1))).map(|_| s.spawn(scan)).collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    });
+    results.sort_by_key(|&(agno, _)| agno);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+impl Report {
+    pub fn new(now: i64, outlier_limit: usize) -> Report {
+        Report {
+            now,
+            inodes: 0,
+            files: 0,
+            directories: 0,
+            bytes: 0,
+            allocated_bytes: 0,
+            sizes: BTreeMap::new(),
+            ages: BTreeMap::new(),
+            outliers: vec![],
+            outlier_limit,
+        }
+    }
+
+    pub fn add(&mut self, stat: &Stat) {
+        self.inodes += 1;
+        self.allocated_bytes += stat.allocated_bytes();
+        match stat.file_type() {
+            FileType::Regular => {
+                self.files += 1;
+                self.bytes += stat.size;
+                let size = match stat.size {
+                    0 => 0,
+                    size => size.checked_next_power_of_two().unwrap_or(u64::MAX),
+                };
+                *self.sizes.entry(size).or_insert(0) += 1;
+                let age = (self.now - stat.mtime.seconds).max(0) as u64;
+                let bound = AGE_DAYS.iter().cloned().find(|days| age < days * 86400).unwrap_or(u64::MAX);
+                *self.ages.entry(bound).or_insert(0) += 1;
+            }
+            FileType::Directory => self.directories += 1,
+            _ => {}
+        }
+        let full = self.outliers.len() >= self.outlier_limit;
+        if stat.extents > 0 && !(full && self.outliers.last() >= Some(&(stat.extents, stat.ino))) {
+            self.add_outliers(&[(stat.extents, stat.ino)]);
+        }
+    }
+
+    fn add_outliers(&mut self, outliers: &[(u64, u64)]) {
+        self.outliers.extend_from_slice(outliers);
+        self.outliers.sort_by(|a, b| b.cmp(a));
+        self.outliers.truncate(self.outlier_limit);
+    }
+
+    pub fn merge(&mut self, other: &Report) {
+        self.inodes += other.inodes;
+        self.files += other.files;
+        self.directories += other.directories;
+        self.bytes += other.bytes;
+        self.allocated_bytes += other.allocated_bytes;
+        for (size, count) in &other.sizes {
+            *self.sizes.entry(*size).or_insert(0) += count;
+        }
+        for (age, count) in &other.ages {
+            *self.ages.entry(*age).or_insert(0) += count;
+        }
+        self.add_outliers(&other.outliers);
+    }
+}
+
+/// Builds a report of the filesystem containing `path`, scanning its AGs in
+/// parallel. Ages are measured from `now`, in seconds since the epoch.
+pub fn report<P: AsRef<Path>>(path: P, threads: usize, now: i64, outlier_limit: usize)
+                              -> Result<Report, XfsError> {
+    let reports = scan_ags(path, threads, || Report::new(now, outlier_limit), Report::add)?;
+    let mut total = Report::new(now, outlier_limit);
+    for report in &reports {
+        total.merge(report);
+    }
+    Ok(total)
+}
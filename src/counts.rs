@@ -0,0 +1,113 @@
+//! Exact free space and inode counts of a mounted filesystem through
+//! `XFS_IOC_FSCOUNTS`, and the reserved block pool through
+//! `XFS_IOC_GET_RESBLKS`.
+//!
+//! Unlike `statvfs`, these are XFS' own counters: free blocks exclude the
+//! reserve pool, and the pool itself is reported separately.
+
+use std::path::Path;
+
+use ioctl;
+use libc;
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    #[test]
+    fn it_decodes_the_ioctl_structs() {
+        assert_eq!(mem::size_of::<super::RawCounts>(), 32);
+        assert_eq!(mem::size_of::<super::RawReserve>(), 16);
+        let raw = super::RawCounts { freedata: 1000, freertx: 0, freeino: 60, allocino: 640 };
+        let reserve = super::RawReserve { resblks: 8192, resblks_avail: 8000 };
+        let counts = super::Counts::new(&raw, Some(&reserve));
+        assert_eq!(counts.free_data_blocks, 1000);
+        assert_eq!(counts.used_inodes(), 580);
+        assert_eq!(counts.reserve, Some(super::Reserve { blocks: 8192, available: 8000 }));
+        assert_eq!(counts.reserve.unwrap().in_use(), 192);
+    }
+}
+
+const FSCOUNTS: u64 = ioctl::ior(113, 32);
+const GET_RESBLKS: u64 = ioctl::ior(115, 16);
+
+/// `struct xfs_fsop_counts`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawCounts {
+    freedata: u64,
+    freertx: u64,
+    freeino: u64,
+    allocino: u64,
+}
+
+/// `struct xfs_fsop_resblks`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawReserve {
+    resblks: u64,
+    resblks_avail: u64,
+}
+
+/// Blocks set aside so that metadata updates can still succeed when the
+/// filesystem is otherwise full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reserve {
+    /// Size of the pool in blocks (`resblks`).
+    pub blocks: u64,
+    /// Blocks still in the pool (`resblks_avail`).
+    pub available: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Counts {
+    /// Free data blocks, excluding the reserve pool (`freedata`).
+    pub free_data_blocks: u64,
+    /// Free realtime extents (`freertx`).
+    pub free_rt_extents: u64,
+    /// Free inodes in allocated inode chunks (`freeino`).
+    pub free_inodes: u64,
+    /// Inodes in allocated inode chunks (`allocino`).
+    pub allocated_inodes: u64,
+    /// The reserve pool. Reading it needs `CAP_SYS_ADMIN`; None without.
+    pub reserve: Option<Reserve>,
+}
+
+impl Reserve {
+    /// Reserved blocks handed out and not yet returned.
+    pub fn in_use(&self) -> u64 {
+        self.blocks.saturating_sub(self.available)
+    }
+}
+
+impl Counts {
+    fn new(raw: &RawCounts, reserve: Option<&RawReserve>) -> Counts {
+        Counts {
+            free_data_blocks: raw.freedata,
+            free_rt_extents: raw.freertx,
+            free_inodes: raw.freeino,
+            allocated_inodes: raw.allocino,
+            reserve: reserve.map(|r| Reserve { blocks: r.resblks, available: r.resblks_avail }),
+        }
+    }
+
+    pub fn used_inodes(&self) -> u64 {
+        self.allocated_inodes.saturating_sub(self.free_inodes)
+    }
+}
+
+/// Reads the counters of the filesystem containing `path`.
+pub fn counts<P: AsRef<Path>>(path: P) -> Result<Counts, XfsError> {
+    let file = ioctl::open(path)?;
+    let mut raw = RawCounts::default();
+    unsafe { ioctl::ioctl(&file, FSCOUNTS, &mut raw)? };
+
+    let mut reserve = RawReserve::default();
+    let reserve = match unsafe { ioctl::ioctl(&file, GET_RESBLKS, &mut reserve) } {
+        Ok(_) => Some(reserve),
+        Err(ref err) if err.raw_os_error() == Some(libc::EPERM) => None,
+        Err(err) => return Err(XfsError::Io(err)),
+    };
+    Ok(Counts::new(&raw, reserve.as_ref()))
+}
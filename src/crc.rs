@@ -0,0 +1,81 @@
+//! CRC32c (Castagnoli) checksums as used by v5 XFS metadata.
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn it_computes_the_check_value() {
+        assert_eq!(super::crc32c(b"123456789"), 0xe3069283);
+    }
+
+    #[test]
+    fn it_verifies_an_embedded_checksum() {
+        let mut block = vec![0x5au8; 64];
+        super::update(&mut block, 8);
+        assert!(super::verify(&block, 8));
+        block[40] ^= 1;
+        assert!(!super::verify(&block, 8));
+    }
+}
+
+const POLY: u32 = 0x82f63b78;
+
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn extend(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc = TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Standard CRC32c of `data`.
+#[cfg(test)]
+pub fn crc32c(data: &[u8]) -> u32 {
+    !extend(!0, data)
+}
+
+/// Checksum of a metadata block, treating the four bytes at `offset` (where
+/// the checksum itself is stored) as zero.
+pub fn block_crc(block: &[u8], offset: usize) -> u32 {
+    let crc = extend(!0, &block[..offset]);
+    let crc = extend(crc, &[0; 4]);
+    !extend(crc, &block[offset + 4..])
+}
+
+/// The checksum stored at `offset`. XFS stores these little-endian, unlike
+/// every other field of its metadata.
+pub fn stored_crc(block: &[u8], offset: usize) -> u32 {
+    u32::from(block[offset]) | u32::from(block[offset + 1]) << 8 |
+        u32::from(block[offset + 2]) << 16 | u32::from(block[offset + 3]) << 24
+}
+
+/// Whether the checksum stored at `offset` matches the block contents.
+pub fn verify(block: &[u8], offset: usize) -> bool {
+    block.len() >= offset + 4 && stored_crc(block, offset) == block_crc(block, offset)
+}
+
+/// Recomputes and stores the checksum at `offset`.
+#[cfg(test)]
+pub fn update(block: &mut [u8], offset: usize) {
+    let crc = block_crc(block, offset);
+    block[offset] = crc as u8;
+    block[offset + 1] = (crc >> 8) as u8;
+    block[offset + 2] = (crc >> 16) as u8;
+    block[offset + 3] = (crc >> 24) as u8;
+}
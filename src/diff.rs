@@ -0,0 +1,193 @@
+//! Field by field differences between two `XfsStat` snapshots, such as
+//! before and after a benchmark.
+
+use std::cmp::Ordering;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use fields::{FieldInfo, FIELDS};
+use XfsError;
+use XfsStat;
+
+#[cfg(test)]
+mod tests {
+    use fixtures;
+
+    fn before() -> ::XfsStat {
+        fixtures::stat(&[("log", "100 800 0 50 10"), ("ig", "1000 900 0 100 0 0 5"), ("rw", "70 30")])
+    }
+
+    fn after() -> ::XfsStat {
+        fixtures::stat(&[("log", "400 3200 2 55 10"), ("ig", "1500 1300 0 200 0 0 5"), ("rw", "70 30")])
+    }
+
+    #[test]
+    fn it_diffs_snapshots() {
+        let diff = super::diff(&before(), &after());
+        assert_eq!(diff.sections.len(), 16);
+        let log = diff.sections.iter().find(|section| section.section == "log_operations").unwrap();
+        let fields: Vec<_> = log.changes.iter().map(|change| change.field).collect();
+        // noiclogs came from zero, so it leads; force_sleep didn't move.
+        assert_eq!(fields, ["noiclogs", "log_writes", "log_blocks", "log_forced"]);
+        assert_eq!(log.unchanged, 1);
+        assert_eq!((log.changes[1].old, log.changes[1].new, log.changes[1].delta()), (100, 400, 300.0));
+        assert_eq!(log.changes[1].relative(), Some(3.0));
+        assert_eq!(log.changes[0].relative(), None);
+
+        let ig = diff.sections.iter().find(|section| section.section == "inode_operations").unwrap();
+        assert_eq!(ig.changes[0].field, "cache_missed");
+        assert_eq!(ig.changes[2].delta(), 400.0);
+        assert!(diff.sections.iter().find(|section| section.section == "read_write_stats").unwrap()
+                    .changes.is_empty());
+
+        // Only gauges shrink; a counter going backwards has wrapped.
+        let active = |value: u32| fixtures::stat(&[("vnodes", &format!("{} 0 0 0 0 0 0 0", value))]);
+        let shrunk = super::diff(&active(200), &active(100));
+        let vnodes = shrunk.sections.iter().find(|section| section.section == "vnode_statistics").unwrap();
+        assert_eq!(vnodes.changes[0].delta(), -100.0);
+        assert_eq!(vnodes.changes[0].relative(), Some(-0.5));
+    }
+
+    #[test]
+    fn it_diffs_a_wrapped_counter_as_growth() {
+        let before = fixtures::stat(&[("log", &format!("0 0 0 0 {}", u32::MAX - 1))]);
+        let after = fixtures::stat(&[("log", "0 0 0 0 3")]);
+        let diff = super::diff(&before, &after);
+        let log = diff.sections.iter().find(|section| section.section == "log_operations").unwrap();
+        assert_eq!((log.changes[0].field, log.changes[0].delta()), ("force_sleep", 5.0));
+        assert!(log.changes[0].relative().unwrap() > 0.0);
+        assert!(super::format_diff(&diff, true).contains(" +5 "));
+    }
+
+    #[test]
+    fn it_formats_a_report() {
+        let diff = super::diff(&before(), &after());
+        let report = super::format_diff(&diff, true);
+        assert_eq!(report, "\
+field                                   old              new            delta   change
+inode_operations
+  cache_missed                          100              200             +100  +100.0%
+  cache_lookups                        1000             1500             +500   +50.0%
+  cache_hits                            900             1300             +400   +44.4%
+log_operations
+  noiclogs                                0                2               +2      new
+  log_writes                            100              400             +300  +300.0%
+  log_blocks                            800             3200            +2400  +300.0%
+  log_forced                             50               55               +5   +10.0%
+");
+        let full = super::format_diff(&diff, false);
+        assert!(full.contains("\nextent_allocation: unchanged\nallocation_btree: unchanged\n"));
+        assert!(full.contains("read_write_stats: unchanged\n"));
+        assert_eq!(full.lines().count(), 1 + 14 + 2 + 7);
+    }
+}
+
+/// A counter that changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub field: &'static str,
+    pub old: u64,
+    pub new: u64,
+    info: &'static FieldInfo,
+}
+
+/// The changes in one section, biggest relative change first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SectionDiff {
+    /// The field name in `XfsStat`, such as `log_operations`.
+    pub section: &'static str,
+    pub changes: Vec<Change>,
+    /// Counters that didn't change.
+    pub unchanged: usize,
+}
+
+/// Every section, in `XfsStat` order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diff {
+    pub sections: Vec<SectionDiff>,
+}
+
+impl Change {
+    /// The change as rules and windows see it, so a wrapped 32-bit
+    /// counter still counts as growth.
+    pub fn delta(&self) -> f64 {
+        self.info.change(self.old, self.new)
+    }
+
+    /// The delta as a fraction of the old value; None when that was zero.
+    pub fn relative(&self) -> Option<f64> {
+        if self.old == 0 {
+            return None;
+        }
+        Some(self.delta() / self.old as f64)
+    }
+
+    /// Orders biggest relative change first, with counters that started
+    /// at zero ahead of all others.
+    fn magnitude_cmp(&self, other: &Change) -> Ordering {
+        match (self.relative(), other.relative()) {
+            (None, None) => other.new.cmp(&self.new),
+            (None, Some(_)) => Ordering::Less,
+            (Some(_), None) => Ordering::Greater,
+            (Some(a), Some(b)) => b.abs().total_cmp(&a.abs()),
+        }
+    }
+}
+
+impl SectionDiff {
+    pub fn is_unchanged(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+/// Compares every counter of two snapshots.
+pub fn diff(old: &XfsStat, new: &XfsStat) -> Diff {
+    let mut sections: Vec<SectionDiff> = vec![];
+    for info in FIELDS {
+        if sections.last().is_none_or(|last| last.section != info.section) {
+            sections.push(SectionDiff { section: info.section, changes: vec![], unchanged: 0 });
+        }
+        let current = sections.last_mut().unwrap();
+        let (before, after) = (info.value(old), info.value(new));
+        if before == after {
+            current.unchanged += 1;
+        } else {
+            current.changes.push(Change { field: info.name, old: before, new: after, info });
+        }
+    }
+    for section in &mut sections {
+        section.changes.sort_by(|a, b| a.magnitude_cmp(b));
+    }
+    Diff { sections }
+}
+
+/// Compares two captured copies of `/proc/fs/xfs/stat`.
+pub fn diff_files<P: AsRef<Path>, Q: AsRef<Path>>(old: P, new: Q) -> Result<Diff, XfsError> {
+    let old = ::parse(&fs::read(old)?)?;
+    let new = ::parse(&fs::read(new)?)?;
+    Ok(diff(&old, &new))
+}
+
+/// Renders a diff with a block per section, optionally leaving out
+/// sections where nothing changed.
+pub fn format_diff(diff: &Diff, hide_unchanged: bool) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<26} {:>16} {:>16} {:>16} {:>8}", "field", "old", "new", "delta", "change");
+    for section in &diff.sections {
+        if section.is_unchanged() {
+            if !hide_unchanged {
+                let _ = writeln!(out, "{}: unchanged", section.section);
+            }
+            continue;
+        }
+        let _ = writeln!(out, "{}", section.section);
+        for change in &section.changes {
+            let relative = change.relative()
+                .map_or("new".to_string(), |relative| format!("{:+.1}%", relative * 100.0));
+            let _ = writeln!(out, "  {:<24} {:>16} {:>16} {:>16} {:>8}",
+                             change.field, change.old, change.new, format!("{:+}", change.delta()), relative);
+        }
+    }
+    out
+}
@@ -0,0 +1,455 @@
+//! Offline decoding of directories in all four formats: short-form
+//! (inline in the inode), single block, leaf and node.
+//!
+//! A directory's data fork is split into three segments: data blocks
+//! holding the entries, from offset zero; the hash index (one leaf block,
+//! or a btree of node and leaf blocks) from 32GiB; and, for node
+//! directories, free space index blocks from 64GiB. Single-block
+//! directories keep entries and hash index together in one block.
+
+use std::borrow::Cow;
+use std::io::{Read, Seek};
+
+use btree::be32;
+use inode::{self, Dinode, Extent, FileType};
+use superblock::Superblock;
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use fixtures;
+    use inode::FileType;
+    use superblock;
+
+    const AG1: u64 = 1 << 6;
+    /// Start of the leaf and free segments in 4k blocks.
+    const LEAF: u64 = 8 << 20;
+    const FREE: u64 = 16 << 20;
+
+    fn names(dir: &super::Directory) -> Vec<String> {
+        dir.entries.iter().map(|e| e.name().into_owned()).collect()
+    }
+
+    fn put_dir(image: &mut [u8], v5: bool, ino: u64, extents: &[[u8; 16]]) {
+        let mut inode = fixtures::dinode(v5, ino, 0o040755);
+        fixtures::put_u32(&mut inode, 76, extents.len() as u32);
+        let fork = if v5 { 176 } else { 100 };
+        for (i, rec) in extents.iter().enumerate() {
+            inode[fork + i * 16..fork + (i + 1) * 16].copy_from_slice(rec);
+        }
+        fixtures::put_inode(image, v5, ino, &inode);
+    }
+
+    #[test]
+    fn it_decodes_a_short_form_directory() {
+        let mut raw = fixtures::image(true);
+        let mut inode = fixtures::dinode(true, 132, 0o040755);
+        inode[5] = 1;
+        let fork = vec![2, 0, 0, 0, 0, 128,
+                        3, 0, 0x30, b'f', b'o', b'o', 1, 0, 0, 0, 0x81,
+                        1, 0, 0x40, b'd', 2, 0, 0, 1, 0x05];
+        fixtures::put_u64(&mut inode, 56, fork.len() as u64);
+        inode[176..176 + fork.len()].copy_from_slice(&fork);
+        fixtures::put_inode(&mut raw, true, 132, &inode);
+
+        let mut image = Cursor::new(raw);
+        let sb = superblock::read(&mut image).unwrap();
+        let dir = super::read(&mut image, &sb, 132).unwrap();
+        assert_eq!(dir.format, super::Format::ShortForm);
+        assert_eq!(dir.parent, Some(128));
+        assert_eq!(names(&dir), vec!["foo", "d"]);
+        assert_eq!(dir.entries[0].ino, 129);
+        assert_eq!(dir.entries[0].file_type, Some(FileType::Regular));
+        assert_eq!(dir.entries[1].ino, 0x105);
+        assert_eq!(dir.entries[1].file_type, Some(FileType::Directory));
+        assert_eq!((dir.blocks, dir.levels), (0, 0));
+    }
+
+    #[test]
+    fn it_decodes_short_form_entries_with_large_inode_numbers() {
+        let fork = [1, 1, 0, 0, 0, 0, 0, 0, 0, 128,
+                    1, 0, 0x30, b'x', 0, 0, 0, 1, 0, 0, 0, 7];
+        let (parent, entries) = super::parse_short_form(&fork, false).unwrap();
+        assert_eq!(parent, 128);
+        assert_eq!(entries[0].ino, (1 << 32) | 7);
+        assert_eq!(entries[0].file_type, None);
+        assert!(super::parse_short_form(&fork[..15], false).is_err());
+    }
+
+    #[test]
+    fn it_decodes_a_block_directory() {
+        let mut raw = fixtures::image(true);
+        put_dir(&mut raw, true, 133, &[fixtures::extent(0, AG1 | 16, 1, false)]);
+        let entries = [(133, ".", 2), (128, "..", 2), (129, "alpha", 1), (130, "beta", 7)];
+        fixtures::put_block(&mut raw, AG1 | 16, &fixtures::dir_data_block(true, true, &entries));
+
+        let mut image = Cursor::new(raw);
+        let sb = superblock::read(&mut image).unwrap();
+        let dir = super::read(&mut image, &sb, 133).unwrap();
+        assert_eq!(dir.format, super::Format::Block);
+        assert_eq!(dir.parent, Some(128));
+        assert_eq!(names(&dir), vec!["alpha", "beta"]);
+        assert_eq!(dir.entries[1].file_type, Some(FileType::Symlink));
+        assert_eq!((dir.data_blocks, dir.leaf_blocks, dir.blocks, dir.levels), (1, 0, 1, 0));
+    }
+
+    #[test]
+    fn it_decodes_a_leaf_directory() {
+        let mut raw = fixtures::image(false);
+        put_dir(&mut raw, false, 134, &[fixtures::extent(0, AG1 | 17, 2, false),
+                                        fixtures::extent(LEAF, AG1 | 19, 1, false)]);
+        let first = [(134, ".", 0), (128, "..", 0), (200, "one", 0)];
+        fixtures::put_block(&mut raw, AG1 | 17, &fixtures::dir_data_block(false, false, &first));
+        let second = [(201, "two", 0), (202, "three", 0)];
+        fixtures::put_block(&mut raw, AG1 | 18, &fixtures::dir_data_block(false, false, &second));
+        fixtures::put_block(&mut raw, AG1 | 19, &fixtures::da_block(false, 0xd2f1, 0, 0));
+
+        let mut image = Cursor::new(raw);
+        let sb = superblock::read(&mut image).unwrap();
+        let dir = super::read(&mut image, &sb, 134).unwrap();
+        assert_eq!(dir.format, super::Format::Leaf);
+        assert_eq!(names(&dir), vec!["one", "two", "three"]);
+        assert_eq!(dir.entries[2].file_type, None);
+        assert_eq!((dir.data_blocks, dir.leaf_blocks, dir.blocks, dir.levels), (2, 1, 3, 1));
+    }
+
+    #[test]
+    fn it_decodes_a_node_directory_in_btree_format() {
+        let mut raw = fixtures::image(true);
+        let mut inode = fixtures::dinode(true, 135, 0o040755);
+        inode[5] = 3;
+        fixtures::put_u32(&mut inode, 76, 3);
+        fixtures::bmbt_root(&mut inode[176..], 1, &[(0, AG1 | 30)]);
+        fixtures::put_inode(&mut raw, true, 135, &inode);
+        let extents = [fixtures::extent(0, AG1 | 20, 3, false),
+                       fixtures::extent(LEAF, AG1 | 23, 3, false),
+                       fixtures::extent(FREE, AG1 | 26, 1, false)];
+        fixtures::put_block(&mut raw, AG1 | 30, &fixtures::bmbt_leaf(true, &extents, u64::MAX));
+
+        let first = [(135, ".", 2), (128, "..", 2), (300, "n0", 1)];
+        fixtures::put_block(&mut raw, AG1 | 20, &fixtures::dir_data_block(true, false, &first));
+        fixtures::put_block(&mut raw, AG1 | 21, &fixtures::dir_data_block(true, false, &[(301, "n1", 1)]));
+        fixtures::put_block(&mut raw, AG1 | 22, &fixtures::dir_data_block(true, false, &[(302, "n2", 3)]));
+        let mut root = fixtures::da_block(true, 0x3ebe, 0, 0);
+        fixtures::put_u16(&mut root, 56, 2);
+        fixtures::put_u16(&mut root, 58, 1);
+        fixtures::put_block(&mut raw, AG1 | 23, &root);
+        fixtures::put_block(&mut raw, AG1 | 24, &fixtures::da_block(true, 0x3dff, 0, 0));
+        fixtures::put_block(&mut raw, AG1 | 25, &fixtures::da_block(true, 0x3dff, 0, 0));
+
+        let mut image = Cursor::new(raw);
+        let sb = superblock::read(&mut image).unwrap();
+        let dir = super::read(&mut image, &sb, 135).unwrap();
+        assert_eq!(dir.format, super::Format::Node);
+        assert_eq!(names(&dir), vec!["n0", "n1", "n2"]);
+        assert_eq!(dir.entries[2].file_type, Some(FileType::CharDevice));
+        assert_eq!((dir.data_blocks, dir.leaf_blocks, dir.free_blocks), (3, 3, 1));
+        assert_eq!((dir.blocks, dir.levels), (7, 2));
+    }
+
+    #[test]
+    fn it_rejects_a_free_region_ending_short_of_the_block() {
+        let sb = superblock::read(&mut Cursor::new(fixtures::image(true))).unwrap();
+        let mut block = fixtures::dir_data_block(true, false, &[(300, "a", 1)]);
+        // The unused region after the entry now stops 8 bytes short of the
+        // end, leaving too little for another entry.
+        let (free, len) = (64 + 16, block.len());
+        fixtures::put_u16(&mut block, free + 2, (len - 8 - free) as u16);
+        assert!(super::parse_data_block(&block, &sb).is_err());
+    }
+
+    #[test]
+    fn it_rejects_an_oversized_directory_block() {
+        let mut raw = fixtures::image(true);
+        put_dir(&mut raw, true, 133, &[fixtures::extent(0, AG1 | 16, 1, false)]);
+        let mut image = Cursor::new(raw);
+        let mut sb = superblock::read(&mut image).unwrap();
+        sb.dir_block_log = 64;
+        assert!(super::read(&mut image, &sb, 133).is_err());
+        sb.dir_block_log = 0;
+        sb.block_log = 64;
+        assert!(super::read(&mut image, &sb, 133).is_err());
+    }
+
+    #[test]
+    fn it_rejects_a_regular_file() {
+        let mut image = Cursor::new(fixtures::image(true));
+        let sb = superblock::read(&mut image).unwrap();
+        assert!(super::read(&mut image, &sb, 130).is_err());
+    }
+}
+
+/// Single-block directory, "XD2B" / "XDB3".
+const BLOCK_MAGIC: (u32, u32) = (0x58443242, 0x58444233);
+/// Data block, "XD2D" / "XDD3".
+const DATA_MAGIC: (u32, u32) = (0x58443244, 0x58444433);
+/// Leaf block of a leaf directory.
+const LEAF1_MAGIC: (u16, u16) = (0xd2f1, 0x3df1);
+/// Leaf block of a node directory.
+const LEAFN_MAGIC: (u16, u16) = (0xd2ff, 0x3dff);
+/// Interior node of the hash btree (shared with attribute forks).
+pub(crate) const DA_NODE_MAGIC: (u16, u16) = (0xfebe, 0x3ebe);
+
+/// Byte offsets of the leaf and free index segments.
+const LEAF_SEGMENT: u64 = 32 << 30;
+const FREE_SEGMENT: u64 = 64 << 30;
+
+/// Tag of an unused region in a data block.
+const FREE_TAG: u16 = 0xffff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Entries stored in the inode itself.
+    ShortForm,
+    /// Entries and hash index in a single directory block.
+    Block,
+    /// Data blocks plus a single leaf block holding the hash index.
+    Leaf,
+    /// Data blocks plus a btree of hash index blocks and free space index
+    /// blocks.
+    Node,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// Name as stored; not necessarily UTF-8.
+    pub name: Vec<u8>,
+    pub ino: u64,
+    /// File type, if the filesystem records it in directory entries.
+    pub file_type: Option<FileType>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Directory {
+    pub ino: u64,
+    pub format: Format,
+    /// Inode of the parent directory.
+    pub parent: Option<u64>,
+    /// Entries in on-disk order, without "." and "..".
+    pub entries: Vec<Entry>,
+    /// Directory blocks in the data segment.
+    pub data_blocks: u64,
+    /// Directory blocks in the hash index segment.
+    pub leaf_blocks: u64,
+    /// Directory blocks in the free index segment.
+    pub free_blocks: u64,
+    /// Filesystem blocks mapped by the data fork.
+    pub blocks: u64,
+    /// Height of the hash index: zero for short-form and block
+    /// directories, one for a single leaf, more for node directories.
+    pub levels: u32,
+}
+
+impl Entry {
+    pub fn name(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.name)
+    }
+}
+
+/// File type from a directory entry's `ftype` byte.
+fn file_type(ftype: u8) -> FileType {
+    match ftype {
+        1 => FileType::Regular,
+        2 => FileType::Directory,
+        3 => FileType::CharDevice,
+        4 => FileType::BlockDevice,
+        5 => FileType::Fifo,
+        6 => FileType::Socket,
+        7 => FileType::Symlink,
+        _ => FileType::Unknown,
+    }
+}
+
+fn be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn be_uint(buf: &[u8]) -> u64 {
+    buf.iter().fold(0, |n, b| n << 8 | u64::from(*b))
+}
+
+/// Picks the v4 or v5 variant of a magic number pair.
+fn pick<T: Copy>(sb: &Superblock, magics: (T, T)) -> T {
+    if sb.has_crc() { magics.1 } else { magics.0 }
+}
+
+/// Decodes a short-form directory: the parent inode and the entries.
+/// Inode numbers are four bytes unless any entry needs eight.
+pub fn parse_short_form(fork: &[u8], ftype: bool) -> Result<(u64, Vec<Entry>), XfsError> {
+    if fork.len() < 2 {
+        return Err(XfsError::Incomplete);
+    }
+    let count = fork[0] as usize;
+    let ino_len = if fork[1] > 0 { 8 } else { 4 };
+    let mut offset = 2 + ino_len;
+    if fork.len() < offset {
+        return Err(XfsError::Incomplete);
+    }
+    let parent = be_uint(&fork[2..offset]);
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        let namelen = *fork.get(offset).ok_or(XfsError::Incomplete)? as usize;
+        let name_start = offset + 3;
+        let ino_start = name_start + namelen + usize::from(ftype);
+        let end = ino_start + ino_len;
+        if end > fork.len() {
+            return Err(XfsError::Incomplete);
+        }
+        entries.push(Entry {
+            name: fork[name_start..name_start + namelen].to_vec(),
+            ino: be_uint(&fork[ino_start..end]),
+            file_type: if ftype { Some(file_type(fork[ino_start - 1])) } else { None },
+        });
+        offset = end;
+    }
+    Ok((parent, entries))
+}
+
+/// Decodes the entries of a data block or of a single-block directory,
+/// "." and ".." included.
+pub fn parse_data_block(block: &[u8], sb: &Superblock) -> Result<Vec<Entry>, XfsError> {
+    let ftype = sb.features().ftype;
+    let magic = be32(block, 0);
+    let mut offset = if sb.has_crc() { 64 } else { 16 };
+    let end = if magic == pick(sb, BLOCK_MAGIC) {
+        let count = be32(block, block.len() - 8) as usize;
+        (block.len() - 8).checked_sub(count * 8).ok_or(XfsError::Parse)?
+    } else if magic == pick(sb, DATA_MAGIC) {
+        block.len()
+    } else {
+        return Err(XfsError::BadMagic);
+    };
+
+    let mut entries = vec![];
+    while offset + 8 <= end {
+        if be16(block, offset) == FREE_TAG {
+            let len = be16(block, offset + 2) as usize;
+            if len == 0 || !len.is_multiple_of(8) {
+                return Err(XfsError::Parse);
+            }
+            offset += len;
+            continue;
+        }
+        if offset + 9 > end {
+            return Err(XfsError::Parse);
+        }
+        let namelen = block[offset + 8] as usize;
+        let name_start = offset + 9;
+        let len = (9 + namelen + usize::from(ftype) + 2).div_ceil(8) * 8;
+        if offset + len > end {
+            return Err(XfsError::Parse);
+        }
+        entries.push(Entry {
+            name: block[name_start..name_start + namelen].to_vec(),
+            ino: inode::be64(block, offset),
+            file_type: if ftype { Some(file_type(block[name_start + namelen])) } else { None },
+        });
+        offset += len;
+    }
+    Ok(entries)
+}
+
+/// Reads directory block `dablk` (in filesystem blocks).
+fn read_dir_block<R>(dev: &mut R, sb: &Superblock, extents: &[Extent], dablk: u64) -> Result<Vec<u8>, XfsError>
+    where R: Read + Seek
+{
+    inode::read_blocks(dev, sb, extents, dablk, dir_blocks(sb)?)
+}
+
+/// Filesystem blocks per directory block.
+fn dir_blocks(sb: &Superblock) -> Result<u64, XfsError> {
+    1u64.checked_shl(u32::from(sb.dir_block_log)).ok_or(XfsError::Parse)
+}
+
+/// Reads and decodes directory `ino`.
+pub fn read<R: Read + Seek>(dev: &mut R, sb: &Superblock, ino: u64) -> Result<Directory, XfsError> {
+    let inode = inode::read(dev, sb, ino)?;
+    read_inode(dev, sb, ino, &inode)
+}
+
+/// Decodes a directory whose inode has already been read.
+pub fn read_inode<R>(dev: &mut R, sb: &Superblock, ino: u64, inode: &Dinode) -> Result<Directory, XfsError>
+    where R: Read + Seek
+{
+    if inode.file_type() != FileType::Directory {
+        return Err(XfsError::Parse);
+    }
+    let mut dir = Directory {
+        ino,
+        format: Format::ShortForm,
+        parent: None,
+        entries: vec![],
+        data_blocks: 0,
+        leaf_blocks: 0,
+        free_blocks: 0,
+        blocks: 0,
+        levels: 0,
+    };
+
+    if inode.format == inode::Format::Local {
+        let fork = inode.data_fork();
+        let size = (inode.size as usize).min(fork.len());
+        let (parent, entries) = parse_short_form(&fork[..size], sb.features().ftype)?;
+        dir.parent = Some(parent);
+        dir.entries = entries;
+        return Ok(dir);
+    }
+
+    let extents = inode::data_extents(dev, sb, inode)?;
+    let block_log = u32::from(sb.block_log);
+    let (leaf_start, free_start) = match (LEAF_SEGMENT.checked_shr(block_log), FREE_SEGMENT.checked_shr(block_log)) {
+        (Some(leaf_start), Some(free_start)) => (leaf_start, free_start),
+        _ => return Err(XfsError::Parse),
+    };
+    let dir_blocks = dir_blocks(sb)?;
+    let mut data = vec![];
+    for extent in &extents {
+        dir.blocks += extent.length;
+        let mut dablk = extent.offset.div_ceil(dir_blocks) * dir_blocks;
+        while dablk + dir_blocks <= extent.offset + extent.length {
+            if dablk < leaf_start {
+                data.push(dablk);
+            } else if dablk < free_start {
+                dir.leaf_blocks += 1;
+            } else {
+                dir.free_blocks += 1;
+            }
+            dablk += dir_blocks;
+        }
+    }
+    dir.data_blocks = data.len() as u64;
+
+    for dablk in data {
+        let block = read_dir_block(dev, sb, &extents, dablk)?;
+        for entry in parse_data_block(&block, sb)? {
+            match &entry.name[..] {
+                b"." => {}
+                b".." => dir.parent = Some(entry.ino),
+                _ => dir.entries.push(entry),
+            }
+        }
+    }
+
+    if dir.leaf_blocks == 0 {
+        dir.format = Format::Block;
+        return Ok(dir);
+    }
+    let root = read_dir_block(dev, sb, &extents, leaf_start)?;
+    let magic = be16(&root, 8);
+    if magic == pick(sb, LEAF1_MAGIC) {
+        dir.format = Format::Leaf;
+        dir.levels = 1;
+    } else if magic == pick(sb, LEAFN_MAGIC) {
+        dir.format = Format::Node;
+        dir.levels = 1;
+    } else if magic == pick(sb, DA_NODE_MAGIC) {
+        let hdr = if sb.has_crc() { 56 } else { 12 };
+        dir.format = Format::Node;
+        dir.levels = u32::from(be16(&root, hdr + 2)) + 1;
+    } else {
+        return Err(XfsError::BadMagic);
+    }
+    Ok(dir)
+}
@@ -0,0 +1,437 @@
+//! Block layer statistics from `/proc/diskstats`, matched with each XFS
+//! mount's own counters under `/sys/fs/xfs/<device>/stats/stats`, to show
+//! write amplification, queue depth and latency per filesystem.
+//!
+//! A filesystem on device-mapper or md is followed down its
+//! `/sys/class/block/*/slaves` links to the devices that finally store it;
+//! bytes are counted there, latency at the top of the stack where XFS
+//! submits its I/O. External log and realtime devices (`logdev=`,
+//! `rtdev=`) are backing devices too. Backing devices shared between
+//! filesystems (two logical volumes on one physical volume, say) count
+//! toward both; a filesystem mounted more than once, as bind mounts are,
+//! is counted once under its first mount point.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use quota::unescape;
+use XfsError;
+use XfsStat;
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::time::{Duration, Instant};
+
+    use fixtures;
+
+    use super::{DiskStat, Filesystem, Sample};
+
+    const DISKSTATS: &str = "\
+   8       0 sda 100 0 2000 50 400 0 16000 800 0 900 850 0 0 0 0
+   8       1 sda1 90 0 1800 45 400 0 16000 800 0 880 845 0 0 0 0
+   8      16 sdb 100 0 2000 50 400 0 16000 800 0 900 850
+   8      17 sdb1 90 0 1800 45 400 0 16000 800 0 880 845
+ 253       0 dm-0 180 0 3600 100 200 0 8000 300 2 800 400 0 0 0 0 0 0
+ bad line
+";
+
+    /// Reads and writes as (requests, sectors, milliseconds).
+    fn disk(name: &str, read: (u64, u64, u64), write: (u64, u64, u64), weighted_ms: u64) -> (String, DiskStat) {
+        let ((reads, sectors_read, read_ms), (writes, sectors_written, write_ms)) = (read, write);
+        (name.to_string(), DiskStat {
+            name: name.to_string(),
+            reads,
+            sectors_read,
+            read_ms,
+            writes,
+            sectors_written,
+            write_ms,
+            weighted_ms,
+            ..Default::default()
+        })
+    }
+
+    fn sample(at: Instant, xfs_write_bytes: u64, disks: Vec<(String, DiskStat)>) -> Sample {
+        let mut xfs = ::std::collections::BTreeMap::new();
+        xfs.insert("dm-0".to_string(), fixtures::stat(&[("xpc", &format!("0 {} 0", xfs_write_bytes))]));
+        Sample { at, xfs, disks: disks.into_iter().collect() }
+    }
+
+    #[test]
+    fn it_parses_diskstats() {
+        let disks = super::parse_diskstats(DISKSTATS);
+        assert_eq!(disks.len(), 5);
+        let dm = &disks[4];
+        assert_eq!((dm.major, dm.minor, dm.name.as_str()), (253, 0, "dm-0"));
+        assert_eq!((dm.reads, dm.sectors_read, dm.read_ms), (180, 3600, 100));
+        assert_eq!((dm.writes, dm.sectors_written, dm.write_ms), (200, 8000, 300));
+        assert_eq!((dm.in_flight, dm.io_ms, dm.weighted_ms), (2, 800, 400));
+        assert_eq!(disks[2].name, "sdb");
+    }
+
+    #[test]
+    fn it_finds_xfs_mounts() {
+        let mounts = "/dev/sda2 / ext4 rw 0 0\n\
+                      /dev/mapper/vg-data /srv/my\\040data xfs rw,noatime 0 0\n\
+                      /dev/sdb1 /srv/rt xfs rw,logdev=/dev/sdc1,rtdev=/dev/sdd 0 0\n\
+                      proc /proc proc rw 0 0\n";
+        let mounts = super::xfs_mounts(mounts);
+        assert_eq!(mounts.len(), 2);
+        assert_eq!((mounts[0].0.as_path(), mounts[0].1.as_path()), (Path::new("/srv/my data"),
+                                                                   Path::new("/dev/mapper/vg-data")));
+        assert!(mounts[0].2.is_empty());
+        assert_eq!(mounts[1].2, [PathBuf::from("/dev/sdc1"), PathBuf::from("/dev/sdd")]);
+    }
+
+    #[test]
+    fn it_counts_each_filesystem_once_with_its_external_devices() {
+        let root = env::temp_dir().join(format!("xfs-diskstats-mounts-{}", process::id()));
+        for dir in &["dm-0/slaves/sda1", "sda1", "sdb1", "sdc1"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        let mounts = "/dev/mapper/vg-data /srv xfs rw,logdev=/dev/sdc1 0 0\n\
+                      /dev/dm-0 /mnt/bind xfs rw,logdev=/dev/sdc1 0 0\n\
+                      /dev/sdb1 /backup xfs rw 0 0\n\
+                      /dev/gone /gone xfs rw 0 0\n";
+        let name = |device: &Path| match device.to_str()? {
+            "/dev/mapper/vg-data" | "/dev/dm-0" => Some("dm-0".to_string()),
+            "/dev/gone" => None,
+            other => Some(other.trim_start_matches("/dev/").to_string()),
+        };
+        let found = super::find_filesystems(mounts, &root, name);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(found, [
+            Filesystem { mount: PathBuf::from("/srv"), device: "dm-0".to_string(),
+                         backing: vec!["sda1".to_string(), "sdc1".to_string()] },
+            Filesystem { mount: PathBuf::from("/backup"), device: "sdb1".to_string(),
+                         backing: vec!["sdb1".to_string()] },
+        ]);
+    }
+
+    #[test]
+    fn it_follows_device_stacks() {
+        let root = env::temp_dir().join(format!("xfs-diskstats-{}", process::id()));
+        for dir in &["dm-0/slaves/md0", "md0/slaves/sda1", "md0/slaves/sdb1", "sda1", "sdb1", "sdc"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        let dm = super::backing_devices(&root, "dm-0");
+        let plain = super::backing_devices(&root, "sdc");
+        let missing = super::backing_devices(&root, "nvme0n1");
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(dm, ["sda1", "sdb1"]);
+        assert_eq!(plain, ["sdc"]);
+        assert_eq!(missing, ["nvme0n1"]);
+    }
+
+    #[test]
+    fn it_correlates_samples() {
+        let fs = Filesystem {
+            mount: PathBuf::from("/srv"),
+            device: "dm-0".to_string(),
+            backing: vec!["sda1".to_string(), "sdb1".to_string()],
+        };
+        let start = Instant::now();
+        let before = sample(start, 1 << 20, vec![
+            disk("dm-0", (0, 0, 0), (0, 0, 0), 0),
+            disk("sda1", (0, 0, 0), (0, 0, 0), 0),
+            disk("sdb1", (0, 0, 0), (0, 0, 0), 0),
+        ]);
+        // 8 MiB written by XFS, mirrored to two disks with 1 MiB of metadata.
+        let after = sample(start + Duration::from_secs(10), 9 << 20, vec![
+            disk("dm-0", (100, 2048, 200), (400, 18432, 2000), 5000),
+            disk("sda1", (50, 1024, 100), (400, 18432, 1000), 2000),
+            disk("sdb1", (50, 1024, 100), (400, 18432, 1000), 2000),
+        ]);
+        let report = super::correlate(&[fs], &before, &after);
+        assert_eq!(report.len(), 1);
+        let fs = &report[0];
+        assert_eq!((fs.xfs_write_bytes, fs.device_write_bytes), (Some(8 << 20), 18 << 20));
+        assert_eq!(fs.write_amplification, Some(2.25));
+        assert_eq!((fs.queue_depth, fs.read_await_ms, fs.write_await_ms), (0.5, Some(2.0), Some(5.0)));
+        assert_eq!(super::format_report(&report), "\
+mount                device     xfs_wr_MiB dev_wr_MiB    amp   aqu r_await w_await
+/srv                 dm-0              8.0       18.0   2.25  0.50    2.00    5.00
+");
+
+        // Without the filesystem's counters there is nothing to divide by.
+        let mut after = after;
+        after.xfs.clear();
+        let report = super::correlate(&[report[0].filesystem.clone()], &before, &after);
+        assert_eq!((report[0].xfs_write_bytes, report[0].write_amplification), (None, None));
+    }
+}
+
+/// One line of `/proc/diskstats`. Sector counts are in 512-byte units
+/// whatever the device's sector size.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiskStat {
+    pub major: u32,
+    pub minor: u32,
+    pub name: String,
+    pub reads: u64,
+    pub reads_merged: u64,
+    pub sectors_read: u64,
+    pub read_ms: u64,
+    pub writes: u64,
+    pub writes_merged: u64,
+    pub sectors_written: u64,
+    pub write_ms: u64,
+    pub in_flight: u64,
+    /// Time with I/O in flight.
+    pub io_ms: u64,
+    /// In-flight time weighted by the number of requests.
+    pub weighted_ms: u64,
+}
+
+/// An XFS mount and the block devices under it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filesystem {
+    pub mount: PathBuf,
+    /// Kernel name of the device mounted, such as `dm-0`.
+    pub device: String,
+    /// Kernel names of the devices at the bottom of its stack.
+    pub backing: Vec<String>,
+}
+
+/// Counters read at one moment, by kernel device name.
+pub struct Sample {
+    pub at: Instant,
+    pub xfs: BTreeMap<String, XfsStat>,
+    pub disks: BTreeMap<String, DiskStat>,
+}
+
+/// One filesystem over an interval between samples.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Correlation {
+    pub filesystem: Filesystem,
+    /// Bytes written through XFS read/write calls, if its counters could
+    /// be read.
+    pub xfs_write_bytes: Option<u64>,
+    /// Bytes written to the backing devices.
+    pub device_write_bytes: u64,
+    /// Device bytes over XFS bytes.
+    pub write_amplification: Option<f64>,
+    /// Average requests in flight on the mounted device.
+    pub queue_depth: f64,
+    pub read_await_ms: Option<f64>,
+    pub write_await_ms: Option<f64>,
+}
+
+/// Takes a sample of every mount.
+#[derive(Clone, Debug)]
+pub struct Sampler {
+    filesystems: Vec<Filesystem>,
+}
+
+/// Parses `/proc/diskstats`, skipping lines it can't read.
+pub fn parse_diskstats(text: &str) -> Vec<DiskStat> {
+    text.lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if fields.len() < 14 {
+                return None;
+            }
+            let mut counters = fields[3..14].iter().map(|field| field.parse::<u64>());
+            let mut next = || counters.next()?.ok();
+            Some(DiskStat {
+                major: fields[0].parse().ok()?,
+                minor: fields[1].parse().ok()?,
+                name: fields[2].to_string(),
+                reads: next()?,
+                reads_merged: next()?,
+                sectors_read: next()?,
+                read_ms: next()?,
+                writes: next()?,
+                writes_merged: next()?,
+                sectors_written: next()?,
+                write_ms: next()?,
+                in_flight: next()?,
+                io_ms: next()?,
+                weighted_ms: next()?,
+            })
+        })
+        .collect()
+}
+
+pub fn read_diskstats() -> Result<Vec<DiskStat>, XfsError> {
+    Ok(parse_diskstats(&fs::read_to_string("/proc/diskstats")?))
+}
+
+/// The counters of the filesystem on device `name`.
+pub fn xfs_stats(name: &str) -> Result<XfsStat, XfsError> {
+    let text = fs::read(Path::new("/sys/fs/xfs").join(name).join("stats/stats"))?;
+    ::parse(&text)
+}
+
+/// Mount points, devices and external log and realtime devices of the XFS
+/// mounts in the contents of `/proc/self/mounts`.
+fn xfs_mounts(mounts: &str) -> Vec<(PathBuf, PathBuf, Vec<PathBuf>)> {
+    mounts.lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let (device, mount, kind) = (fields.next()?, fields.next()?, fields.next()?);
+            if kind != "xfs" {
+                return None;
+            }
+            let external = fields.next().unwrap_or("").split(',')
+                .filter_map(|option| option.strip_prefix("logdev=").or_else(|| option.strip_prefix("rtdev=")))
+                .map(|device| PathBuf::from(unescape(device)))
+                .collect();
+            Some((PathBuf::from(unescape(mount)), PathBuf::from(unescape(device)), external))
+        })
+        .collect()
+}
+
+/// The devices at the bottom of the stack under `name`, following the
+/// `slaves` directories under `sys_block` (normally `/sys/class/block`).
+/// A device with none is its own backing device.
+pub fn backing_devices(sys_block: &Path, name: &str) -> Vec<String> {
+    fn walk(sys_block: &Path, name: &str, depth: u32, found: &mut BTreeSet<String>) {
+        let slaves: Vec<_> = match fs::read_dir(sys_block.join(name).join("slaves")) {
+            Ok(entries) => entries.filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect(),
+            Err(_) => vec![],
+        };
+        if slaves.is_empty() || depth > 16 {
+            found.insert(name.to_string());
+        }
+        for slave in slaves.iter().filter(|_| depth <= 16) {
+            walk(sys_block, slave, depth + 1, found);
+        }
+    }
+    let mut found = BTreeSet::new();
+    walk(sys_block, name, 0, &mut found);
+    found.into_iter().collect()
+}
+
+/// The filesystems in the contents of `/proc/self/mounts`, once per
+/// device, with `name` giving the kernel name of a device path.
+fn find_filesystems<F>(mounts: &str, sys_block: &Path, name: F) -> Vec<Filesystem>
+    where F: Fn(&Path) -> Option<String>
+{
+    let mut filesystems: Vec<Filesystem> = vec![];
+    for (mount, device, external) in xfs_mounts(mounts) {
+        let device = match name(&device) {
+            Some(device) => device,
+            None => continue,
+        };
+        if filesystems.iter().any(|fs| fs.device == device) {
+            continue;
+        }
+        let mut backing: BTreeSet<String> = backing_devices(sys_block, &device).into_iter().collect();
+        for external in external.iter().filter_map(|path| name(path)) {
+            backing.extend(backing_devices(sys_block, &external));
+        }
+        filesystems.push(Filesystem { mount, device, backing: backing.into_iter().collect() });
+    }
+    filesystems
+}
+
+/// The XFS mounts, with their devices resolved through `/dev` symlinks.
+pub fn filesystems() -> Result<Vec<Filesystem>, XfsError> {
+    let mounts = fs::read_to_string("/proc/self/mounts")?;
+    let name = |device: &Path| {
+        let device = fs::canonicalize(device).ok()?;
+        Some(device.file_name()?.to_string_lossy().into_owned())
+    };
+    Ok(find_filesystems(&mounts, Path::new("/sys/class/block"), name))
+}
+
+fn ratio(part: u64, whole: u64) -> Option<f64> {
+    if whole == 0 {
+        return None;
+    }
+    Some(part as f64 / whole as f64)
+}
+
+/// Each filesystem over the interval between two samples.
+pub fn correlate(filesystems: &[Filesystem], before: &Sample, after: &Sample) -> Vec<Correlation> {
+    let ms = after.at.duration_since(before.at).as_secs_f64() * 1000.0;
+    let disk = |name: &str| Some((before.disks.get(name)?, after.disks.get(name)?));
+    filesystems.iter()
+        .map(|fs| {
+            let xfs_write_bytes = match (before.xfs.get(&fs.device), after.xfs.get(&fs.device)) {
+                (Some(b), Some(a)) => {
+                    let (b, a) = (&b.extended_precision_counters, &a.extended_precision_counters);
+                    Some(a.write_bytes.saturating_sub(b.write_bytes))
+                }
+                _ => None,
+            };
+            let device_write_bytes = fs.backing.iter()
+                .filter_map(|name| disk(name))
+                .map(|(b, a)| a.sectors_written.saturating_sub(b.sectors_written) * 512)
+                .sum();
+            let (queue_depth, read_await_ms, write_await_ms) = match disk(&fs.device) {
+                Some((b, a)) => {
+                    let d = |f: fn(&DiskStat) -> u64| f(a).saturating_sub(f(b));
+                    let queue_depth = if ms > 0.0 { d(|s| s.weighted_ms) as f64 / ms } else { 0.0 };
+                    (queue_depth, ratio(d(|s| s.read_ms), d(|s| s.reads)), ratio(d(|s| s.write_ms), d(|s| s.writes)))
+                }
+                None => (0.0, None, None),
+            };
+            Correlation {
+                filesystem: fs.clone(),
+                xfs_write_bytes,
+                device_write_bytes,
+                write_amplification: xfs_write_bytes.and_then(|xfs| ratio(device_write_bytes, xfs)),
+                queue_depth,
+                read_await_ms,
+                write_await_ms,
+            }
+        })
+        .collect()
+}
+
+impl Sampler {
+    /// A sampler for the XFS mounts at the time of the call.
+    pub fn new() -> Result<Sampler, XfsError> {
+        Ok(Sampler { filesystems: filesystems()? })
+    }
+
+    pub fn filesystems(&self) -> &[Filesystem] {
+        &self.filesystems
+    }
+
+    /// Reads the counters of every filesystem and device. A filesystem
+    /// without its own counters is left out of `xfs`.
+    pub fn sample(&self) -> Result<Sample, XfsError> {
+        let at = Instant::now();
+        let disks = read_diskstats()?.into_iter().map(|disk| (disk.name.clone(), disk)).collect();
+        let xfs = self.filesystems.iter()
+            .filter_map(|fs| Some((fs.device.clone(), xfs_stats(&fs.device).ok()?)))
+            .collect();
+        Ok(Sample { at, xfs, disks })
+    }
+
+    pub fn correlate(&self, before: &Sample, after: &Sample) -> Vec<Correlation> {
+        correlate(&self.filesystems, before, after)
+    }
+}
+
+/// Renders correlations as a fixed-width table, with `-` for what couldn't
+/// be measured.
+pub fn format_report(report: &[Correlation]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<20} {:<10} {:>10} {:>10} {:>6} {:>5} {:>7} {:>7}",
+                     "mount", "device", "xfs_wr_MiB", "dev_wr_MiB", "amp", "aqu", "r_await", "w_await");
+    let opt = |value: Option<f64>, precision: usize| {
+        value.map_or("-".to_string(), |value| format!("{:.*}", precision, value))
+    };
+    let mib = |bytes: u64| bytes as f64 / (1 << 20) as f64;
+    for fs in report {
+        let _ = writeln!(out, "{:<20} {:<10} {:>10} {:>10.1} {:>6} {:>5.2} {:>7} {:>7}",
+                         fs.filesystem.mount.display(), fs.filesystem.device,
+                         opt(fs.xfs_write_bytes.map(mib), 1), mib(fs.device_write_bytes),
+                         opt(fs.write_amplification, 2), fs.queue_depth,
+                         opt(fs.read_await_ms, 2), opt(fs.write_await_ms, 2));
+    }
+    out
+}
@@ -0,0 +1,318 @@
+//! A registry describing every `XfsStat` counter, for reading fields by
+//! their dotted path (`log_operations.force_sleep`) or iterating over all
+//! of them without naming each one.
+
+use std::iter;
+use std::slice;
+
+use XfsStat;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use fixtures;
+
+    use super::{Kind, Unit, FIELDS};
+
+    #[test]
+    fn it_describes_every_field() {
+        assert_eq!(FIELDS.len(), 79);
+        let paths: HashSet<_> = FIELDS.iter().map(|info| info.path()).collect();
+        assert_eq!(paths.len(), FIELDS.len());
+        let kernel: HashSet<_> = FIELDS.iter().map(|info| info.kernel_name).collect();
+        assert_eq!(kernel.len(), FIELDS.len());
+        assert!(FIELDS.iter().all(|info| !info.doc.is_empty() && !info.doc.ends_with('.')));
+
+        let sleep = super::field("log_operations.force_sleep").unwrap();
+        assert_eq!((sleep.section, sleep.tag, sleep.name), ("log_operations", "log", "force_sleep"));
+        assert_eq!((sleep.kernel_name, sleep.unit, sleep.kind), ("xs_log_force_sleep", Unit::Count, Kind::Counter));
+        let active = super::field("vnode_statistics.active").unwrap();
+        assert_eq!((active.kernel_name, active.kind), ("vn_active", Kind::Gauge));
+        assert_eq!(super::field("log_operations.log_blocks").unwrap().unit, Unit::BasicBlocks);
+        assert_eq!(super::field("extended_precision_counters.read_bytes").unwrap().unit.name(), "bytes");
+        assert!(super::field("log_operations").is_none());
+        assert!(super::field("log_operations.nope").is_none());
+    }
+
+    #[test]
+    fn it_measures_change_by_kind() {
+        let sleep = super::field("log_operations.force_sleep").unwrap();
+        assert_eq!(sleep.change(10, 15), 5.0);
+        assert_eq!(sleep.change(u64::from(u32::MAX) - 1, 3), 5.0);
+        let active = super::field("vnode_statistics.active").unwrap();
+        assert_eq!(active.change(100, 90), -10.0);
+    }
+
+    #[test]
+    fn it_reads_fields() {
+        let stat = fixtures::stat(&[("log", "1 2 3 4 5"), ("xpc", "6 7 8")]);
+        assert_eq!(stat.get("log_operations.log_forced"), Some(4));
+        assert_eq!(stat.get("extended_precision_counters.write_bytes"), Some(7));
+        assert_eq!(stat.get("debug"), None);
+
+        let fields: Vec<_> = stat.fields().collect();
+        assert_eq!(fields.len(), 79);
+        assert_eq!((fields[0].0.path(), fields[0].1), ("extent_allocation.allocated_extents".to_string(), 0));
+        let nonzero: Vec<_> = fields.iter()
+            .filter(|field| field.1 != 0)
+            .map(|field| (field.0.kernel_name, field.1))
+            .collect();
+        assert_eq!(nonzero, [("xs_log_writes", 1), ("xs_log_blocks", 2), ("xs_log_noiclogs", 3),
+                             ("xs_log_force", 4), ("xs_log_force_sleep", 5), ("xs_xstrat_bytes", 6),
+                             ("xs_write_bytes", 7), ("xs_read_bytes", 8)]);
+    }
+}
+
+/// What a field counts in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Unit {
+    /// Operations or events.
+    Count,
+    Bytes,
+    /// Filesystem blocks.
+    Blocks,
+    /// 512-byte basic blocks.
+    BasicBlocks,
+}
+
+/// Whether a field only ever grows, or reports a current level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// One field of `XfsStat`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FieldInfo {
+    /// The section's field name in `XfsStat`, such as `log_operations`.
+    pub section: &'static str,
+    /// The section's line tag in `/proc/fs/xfs/stat`, such as `log`.
+    pub tag: &'static str,
+    /// The field's name within its section.
+    pub name: &'static str,
+    /// The member of the kernel's `struct xfsstats` it comes from.
+    pub kernel_name: &'static str,
+    pub unit: Unit,
+    pub kind: Kind,
+    pub doc: &'static str,
+}
+
+/// Every field of a snapshot with its value, in `FIELDS` order.
+pub struct Fields<'a> {
+    stat: &'a XfsStat,
+    infos: iter::Cloned<slice::Iter<'static, FieldInfo>>,
+}
+
+macro_rules! registry {
+    ($($section:ident $tag:expr => {
+        $($field:ident $kernel:expr, $unit:ident, $kind:ident, $doc:expr;)*
+    })*) => {
+        /// Every field, in `/proc/fs/xfs/stat` order.
+        pub const FIELDS: &[FieldInfo] = &[$($(FieldInfo {
+            section: stringify!($section),
+            tag: $tag,
+            name: stringify!($field),
+            kernel_name: $kernel,
+            unit: Unit::$unit,
+            kind: Kind::$kind,
+            doc: $doc,
+        },)*)*];
+
+        fn read(stat: &XfsStat, section: &str, name: &str) -> Option<u64> {
+            match (section, name) {
+                $($((stringify!($section), stringify!($field)) => Some(stat.$section.$field as u64),)*)*
+                _ => None,
+            }
+        }
+    }
+}
+
+registry! {
+    extent_allocation "extent_alloc" => {
+        allocated_extents "xs_allocx", Count, Counter, "Extents allocated";
+        allocated_blocks "xs_allocb", Blocks, Counter, "Blocks allocated";
+        freed_extents "xs_freex", Count, Counter, "Extents freed";
+        freed_blocks "xs_freeb", Blocks, Counter, "Blocks freed";
+    }
+    allocation_btree "abt" => {
+        lookups "xs_abt_lookup", Count, Counter, "Free space btree lookups";
+        compares "xs_abt_compare", Count, Counter, "Compares in free space btree lookups";
+        inserts "xs_abt_insrec", Count, Counter, "Records inserted into free space btrees";
+        deletes "xs_abt_delrec", Count, Counter, "Records deleted from free space btrees";
+    }
+    block_mapping "blk_map" => {
+        map_read "xs_blk_mapr", Count, Counter, "Block mappings for reads";
+        map_write "xs_blk_mapw", Count, Counter, "Block mappings for writes";
+        unmap "xs_blk_unmap", Count, Counter, "Block unmap (delete) operations";
+        list_insert "xs_add_exlist", Count, Counter, "Extent list insertions";
+        list_delete "xs_del_exlist", Count, Counter, "Extent list deletions";
+        list_lookup "xs_look_exlist", Count, Counter, "Extent list lookups";
+        list_compare "xs_cmp_exlist", Count, Counter, "Compares in extent list lookups";
+    }
+    block_map_btree "bmbt" => {
+        lookups "xs_bmbt_lookup", Count, Counter, "Block map btree lookups";
+        compares "xs_bmbt_compare", Count, Counter, "Compares in block map btree lookups";
+        inserts "xs_bmbt_insrec", Count, Counter, "Records inserted into block map btrees";
+        deletes "xs_bmbt_delrec", Count, Counter, "Records deleted from block map btrees";
+    }
+    directory_operations "dir" => {
+        lookups "xs_dir_lookup", Count, Counter, "Directory lookups that missed the dentry cache";
+        creates "xs_dir_create", Count, Counter, "Directory entries created";
+        removes "xs_dir_remove", Count, Counter, "Directory entries removed";
+        get_dents "xs_dir_getdents", Count, Counter, "getdents calls on directories";
+    }
+    transactions "trans" => {
+        waited "xs_trans_sync", Count, Counter, "Synchronous transactions, which waited for the log";
+        async "xs_trans_async", Count, Counter, "Asynchronous transactions";
+        empty "xs_trans_empty", Count, Counter, "Transactions that changed nothing";
+    }
+    inode_operations "ig" => {
+        cache_lookups "xs_ig_attempts", Count, Counter, "Inode cache lookups";
+        cache_hits "xs_ig_found", Count, Counter, "Inode cache lookups that found the inode";
+        cache_recycle "xs_ig_frecycle", Count, Counter, "Inode cache hits on inodes being recycled";
+        cache_missed "xs_ig_missed", Count, Counter, "Inode cache lookups that missed";
+        cache_dup "xs_ig_dup", Count, Counter, "Inode cache inserts that raced with another";
+        cache_reclaime "xs_ig_reclaims", Count, Counter, "Inodes reclaimed from the cache";
+        inode_attr_changes "xs_ig_attrchg", Count, Counter, "Inode attribute changes";
+    }
+    log_operations "log" => {
+        log_writes "xs_log_writes", Count, Counter, "Log buffer writes";
+        log_blocks "xs_log_blocks", BasicBlocks, Counter, "Basic blocks written to the log";
+        noiclogs "xs_log_noiclogs", Count, Counter, "Times no in-core log buffer was free";
+        log_forced "xs_log_force", Count, Counter, "Log forces";
+        force_sleep "xs_log_force_sleep", Count, Counter, "Log forces that slept";
+    }
+    tail_pushing_stats "push_ail" => {
+        logspace "xs_try_logspace", Count, Counter, "Log space reservation attempts";
+        sleep_logspace "xs_sleep_logspace", Count, Counter, "Log space reservations that slept";
+        push_ails "xs_push_ail", Count, Counter, "AIL pushes";
+        push_ail_success "xs_push_ail_success", Count, Counter, "AIL items pushed";
+        push_ail_pushbuf "xs_push_ail_pushbuf", Count, Counter, "AIL items whose buffers were pushed";
+        push_ail_pinned "xs_push_ail_pinned", Count, Counter, "AIL items found pinned";
+        push_ail_locked "xs_push_ail_locked", Count, Counter, "AIL items found locked";
+        push_ail_flushing "xs_push_ail_flushing", Count, Counter, "AIL items found already flushing";
+        push_ail_restarts "xs_push_ail_restarts", Count, Counter, "AIL push scans restarted";
+        push_ail_flush "xs_push_ail_flush", Count, Counter, "Log forces issued by AIL pushes";
+    }
+    io_map_write_convert "xstrat" => {
+        quick "xs_xstrat_quick", Count, Counter, "Delayed allocation flushes to contiguous space";
+        split "xs_xstrat_split", Count, Counter, "Delayed allocation flushes split across extents";
+    }
+    read_write_stats "rw" => {
+        write "xs_write_calls", Count, Counter, "write calls";
+        read "xs_read_calls", Count, Counter, "read calls";
+    }
+    attribute_operations "attr" => {
+        get "xs_attr_get", Count, Counter, "Extended attribute gets";
+        set "xs_attr_set", Count, Counter, "Extended attribute sets";
+        remove "xs_attr_remove", Count, Counter, "Extended attribute removes";
+        list "xs_attr_list", Count, Counter, "Extended attribute lists";
+    }
+    inode_clustering "icluster" => {
+        count "xs_iflush_count", Count, Counter, "Inode flushes";
+        flushcnt "xs_icluster_flushcnt", Count, Counter, "Inode cluster flushes";
+        flushinode "xs_icluster_flushinode", Count, Counter, "Inodes flushed along with a cluster";
+    }
+    vnode_statistics "vnodes" => {
+        active "vn_active", Count, Gauge, "Inodes not on free lists";
+        alloc "vn_alloc", Count, Counter, "Inode allocations";
+        get "vn_get", Count, Counter, "Inode gets";
+        hold "vn_hold", Count, Counter, "Inode holds";
+        rele "vn_rele", Count, Counter, "Inode releases";
+        reclaim "vn_reclaim", Count, Counter, "Inode reclaims";
+        remove "vn_remove", Count, Counter, "Inode removals";
+        free "vn_free", Count, Counter, "Inodes freed";
+    }
+    buf_statistics "buf" => {
+        get "xb_get", Count, Counter, "Buffer lookups";
+        create "xb_create", Count, Counter, "Buffers created";
+        get_locked "xb_get_locked", Count, Counter, "Buffers found and locked";
+        get_locked_waited "xb_get_locked_waited", Count, Counter, "Buffers found that had to wait for the lock";
+        busy_locked "xb_busy_locked", Count, Counter, "Trylocks that found the buffer busy";
+        miss_locked "xb_miss_locked", Count, Counter, "Lookups that missed the buffer cache";
+        page_retries "xb_page_retries", Count, Counter, "Page allocation retries";
+        page_found "xb_page_found", Count, Counter, "Pages found in the page cache";
+        get_read "xb_get_read", Count, Counter, "Buffers read from disk";
+    }
+    extended_precision_counters "xpc" => {
+        xstrat_bytes "xs_xstrat_bytes", Bytes, Counter, "Bytes of file data flushed";
+        write_bytes "xs_write_bytes", Bytes, Counter, "Bytes written by write calls";
+        read_bytes "xs_read_bytes", Bytes, Counter, "Bytes read by read calls";
+    }
+}
+
+impl Unit {
+    pub fn name(self) -> &'static str {
+        match self {
+            Unit::Count => "count",
+            Unit::Bytes => "bytes",
+            Unit::Blocks => "blocks",
+            Unit::BasicBlocks => "basic blocks",
+        }
+    }
+}
+
+impl FieldInfo {
+    /// The dotted path, such as `log_operations.force_sleep`.
+    pub fn path(&self) -> String {
+        format!("{}.{}", self.section, self.name)
+    }
+
+    pub fn value(&self, stat: &XfsStat) -> u64 {
+        read(stat, self.section, self.name).unwrap_or(0)
+    }
+
+    /// The change between two readings: a counter's increase, or a gauge's
+    /// signed difference.
+    pub fn change(&self, before: u64, after: u64) -> f64 {
+        match self.kind {
+            Kind::Counter => counter_delta(before, after) as f64,
+            Kind::Gauge => after as f64 - before as f64,
+        }
+    }
+}
+
+/// The change between two readings of a counter, allowing the 32-bit ones
+/// to wrap once.
+fn counter_delta(before: u64, after: u64) -> u64 {
+    if after >= before {
+        after - before
+    } else if before <= u64::from(u32::MAX) {
+        after + (1 << 32) - before
+    } else {
+        0
+    }
+}
+
+/// Looks a field up by its dotted path.
+pub fn field(path: &str) -> Option<&'static FieldInfo> {
+    let (section, name) = path.split_once('.')?;
+    FIELDS.iter().find(|info| info.section == section && info.name == name)
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = (FieldInfo, u64);
+
+    fn next(&mut self) -> Option<(FieldInfo, u64)> {
+        let info = self.infos.next()?;
+        Some((info, info.value(self.stat)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.infos.size_hint()
+    }
+}
+
+impl XfsStat {
+    /// Reads a field by its dotted path.
+    pub fn get(&self, path: &str) -> Option<u64> {
+        field(path).map(|info| info.value(self))
+    }
+
+    /// Every field with its value.
+    pub fn fields(&self) -> Fields<'_> {
+        Fields { stat: self, infos: FIELDS.iter().cloned() }
+    }
+}
@@ -0,0 +1,500 @@
+//! Helpers for crafting on-disk metadata and stat snapshots in tests.
+
+use XfsStat;
+
+pub fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+pub fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+pub fn put_u64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset..offset + 8].copy_from_slice(&value.to_be_bytes());
+}
+
+pub const UUID: [u8; 16] = [0x4a, 0x1f, 0x3c, 0x2e, 0x9b, 0x6d, 0x4e, 0x51,
+                            0x8f, 0x02, 0xa7, 0x33, 0xc4, 0x10, 0xde, 0x7b];
+
+pub const BLOCK_SIZE: u32 = 4096;
+pub const SECTOR_SIZE: u16 = 512;
+pub const INODE_SIZE: u16 = 512;
+pub const AG_BLOCKS: u32 = 64;
+pub const AG_COUNT: u32 = 2;
+/// First block and length of the internal log.
+pub const LOG_START: u32 = 40;
+pub const LOG_BLOCKS: u32 = 16;
+
+/// A 512-byte primary superblock for a tiny two-AG filesystem with an
+/// internal log, v5 (with a valid checksum) or v4.
+pub fn superblock(v5: bool) -> Vec<u8> {
+    let mut sb = vec![0u8; SECTOR_SIZE as usize];
+    put_u32(&mut sb, 0, 0x58465342);
+    put_u32(&mut sb, 4, BLOCK_SIZE);
+    put_u64(&mut sb, 8, u64::from(AG_BLOCKS * AG_COUNT));
+    sb[32..48].copy_from_slice(&UUID);
+    put_u64(&mut sb, 48, u64::from(LOG_START));
+    put_u64(&mut sb, 56, 128);
+    put_u64(&mut sb, 64, 129);
+    put_u64(&mut sb, 72, 130);
+    put_u32(&mut sb, 80, 1);
+    put_u32(&mut sb, 84, AG_BLOCKS);
+    put_u32(&mut sb, 88, AG_COUNT);
+    put_u32(&mut sb, 96, LOG_BLOCKS);
+    put_u16(&mut sb, 100, if v5 { 0xb4a5 } else { 0xb4a4 });
+    put_u16(&mut sb, 102, SECTOR_SIZE);
+    put_u16(&mut sb, 104, INODE_SIZE);
+    put_u16(&mut sb, 106, (BLOCK_SIZE / u32::from(INODE_SIZE)) as u16);
+    sb[108..116].copy_from_slice(b"scratch\0");
+    sb[120] = 12;
+    sb[121] = 9;
+    sb[122] = 9;
+    sb[123] = 3;
+    sb[124] = 6;
+    sb[127] = 25;
+    put_u64(&mut sb, 128, 64);
+    put_u64(&mut sb, 136, 61);
+    put_u64(&mut sb, 144, 100);
+    put_u64(&mut sb, 160, u64::MAX);
+    put_u64(&mut sb, 168, u64::MAX);
+    put_u32(&mut sb, 180, 4);
+    put_u16(&mut sb, 194, SECTOR_SIZE);
+    put_u32(&mut sb, 196, 1);
+    if v5 {
+        put_u32(&mut sb, 200, 0x18a);
+        put_u32(&mut sb, 204, 0x18a);
+        put_u32(&mut sb, 212, 0xf);
+        put_u32(&mut sb, 216, 0x9);
+        put_u64(&mut sb, 232, u64::MAX);
+        put_u64(&mut sb, 240, 0x1_0000_0002);
+        sb[248..264].copy_from_slice(&UUID);
+        ::crc::update(&mut sb, 224);
+    } else {
+        put_u32(&mut sb, 200, 0x8a);
+        put_u32(&mut sb, 204, 0x8a);
+    }
+    sb
+}
+
+/// Free extents (start, length) in each AG of the `image` fixture.
+pub const FREE_EXTENTS: [&[(u32, u32)]; 2] = [&[(12, 1), (14, 2), (24, 5)],
+                                              &[(11, 3), (16, 16), (40, 24)]];
+
+/// First agino of the single inode chunk in each AG. Chunks span eight
+/// blocks, starting at block 16 in AG 0 and block 32 in AG 1.
+pub const CHUNK_AGINO: [u32; 2] = [128, 256];
+
+/// The last three inodes of every chunk are free.
+pub const FREE_INODE_MASK: u64 = 0xe000_0000_0000_0000;
+
+/// Head of the single non-empty unlinked bucket (AG 1, bucket 5).
+pub const UNLINKED_AGINO: u32 = 259;
+
+/// Contents of a short-form btree block. `words` are laid out from the end
+/// of the header: records in leaves, keys in nodes. Node pointers follow
+/// at the position implied by 8-byte keys.
+pub struct Block<'a> {
+    pub magic: u32,
+    pub level: u16,
+    pub numrecs: u16,
+    pub words: &'a [u32],
+    pub ptrs: &'a [u32],
+    pub rightsib: u32,
+}
+
+/// Writes a short-form btree block at `agbno` of AG `agno`.
+pub fn btree_block(image: &mut [u8], v5: bool, agno: u32, agbno: u32, contents: Block) {
+    let hdr = if v5 { 56 } else { 16 };
+    let offset = ((agno * AG_BLOCKS + agbno) * BLOCK_SIZE) as usize;
+    let block = &mut image[offset..offset + BLOCK_SIZE as usize];
+    put_u32(block, 0, contents.magic);
+    put_u16(block, 4, contents.level);
+    put_u16(block, 6, contents.numrecs);
+    put_u32(block, 8, 0xffffffff);
+    put_u32(block, 12, contents.rightsib);
+    for (i, word) in contents.words.iter().enumerate() {
+        put_u32(block, hdr + i * 4, *word);
+    }
+    let maxrecs = (BLOCK_SIZE as usize - hdr) / 12;
+    for (i, ptr) in contents.ptrs.iter().enumerate() {
+        put_u32(block, hdr + maxrecs * 8 + i * 4, *ptr);
+    }
+    if v5 {
+        put_u64(block, 16, offset as u64 / 512);
+        block[32..48].copy_from_slice(&UUID);
+        put_u32(block, 48, agno);
+        ::crc::update(block, 52);
+    }
+}
+
+fn leaf(magic: u32, words: &[u32], numrecs: usize) -> Block<'_> {
+    Block { magic, level: 0, numrecs: numrecs as u16, words, ptrs: &[], rightsib: 0xffffffff }
+}
+
+fn flatten(extents: &[(u32, u32)]) -> Vec<u32> {
+    extents.iter().flat_map(|e| vec![e.0, e.1]).collect()
+}
+
+/// Inode number of `agino` in AG `agno`.
+pub fn ino(agno: u32, agino: u32) -> u64 {
+    u64::from(agno) << 9 | u64::from(agino)
+}
+
+/// Byte offset of inode `ino` in the image.
+pub fn inode_offset(ino: u64) -> usize {
+    let agno = (ino >> 9) as u32;
+    let agino = (ino & 511) as u32;
+    (((agno * AG_BLOCKS + (agino >> 3)) * BLOCK_SIZE) + (agino & 7) * u32::from(INODE_SIZE)) as usize
+}
+
+/// An inode core with the given mode, extents format and no extents. Free
+/// inodes have a mode of zero.
+pub fn dinode(v5: bool, ino: u64, mode: u16) -> Vec<u8> {
+    let mut inode = vec![0u8; INODE_SIZE as usize];
+    put_u16(&mut inode, 0, 0x494e);
+    put_u16(&mut inode, 2, mode);
+    inode[4] = if v5 { 3 } else { 2 };
+    inode[5] = 2;
+    put_u32(&mut inode, 16, if mode == 0 { 0 } else { 1 });
+    put_u32(&mut inode, 96, 0xffffffff);
+    if v5 {
+        put_u64(&mut inode, 152, ino);
+        inode[160..176].copy_from_slice(&UUID);
+    }
+    inode
+}
+
+/// A packed block map record.
+pub fn extent(offset: u64, start_block: u64, length: u64, unwritten: bool) -> [u8; 16] {
+    let l0 = (u64::from(unwritten) << 63) | (offset << 9) | (start_block >> 43);
+    let l1 = (start_block << 21) | length;
+    let mut rec = [0u8; 16];
+    put_u64(&mut rec, 0, l0);
+    put_u64(&mut rec, 8, l1);
+    rec
+}
+
+/// Fills `fork` with a block map btree root of `level` pointing at
+/// `children`, each a (first file offset, filesystem block) pair.
+pub fn bmbt_root(fork: &mut [u8], level: u16, children: &[(u64, u64)]) {
+    let maxrecs = (fork.len() - 4) / 16;
+    put_u16(fork, 0, level);
+    put_u16(fork, 2, children.len() as u16);
+    for (i, &(offset, fsbno)) in children.iter().enumerate() {
+        put_u64(fork, 4 + i * 8, offset);
+        put_u64(fork, 4 + maxrecs * 8 + i * 8, fsbno);
+    }
+}
+
+/// A block map btree leaf holding `extents`.
+pub fn bmbt_leaf(v5: bool, extents: &[[u8; 16]], rightsib: u64) -> Vec<u8> {
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    put_u32(&mut block, 0, if v5 { 0x424d4133 } else { 0x424d4150 });
+    put_u16(&mut block, 6, extents.len() as u16);
+    put_u64(&mut block, 8, u64::MAX);
+    put_u64(&mut block, 16, rightsib);
+    let hdr = if v5 { 72 } else { 24 };
+    for (i, rec) in extents.iter().enumerate() {
+        block[hdr + i * 16..hdr + (i + 1) * 16].copy_from_slice(rec);
+    }
+    block
+}
+
+/// Stores a whole block at filesystem block `fsbno`.
+pub fn put_block(image: &mut [u8], fsbno: u64, block: &[u8]) {
+    let agno = (fsbno >> 6) as u32;
+    let agbno = (fsbno & 63) as u32;
+    let offset = ((agno * AG_BLOCKS + agbno) * BLOCK_SIZE) as usize;
+    image[offset..offset + block.len()].copy_from_slice(block);
+}
+
+/// A directory or attribute block starting with `struct xfs_da_blkinfo`
+/// (`xfs_da3_blkinfo` on v5), whose 16 bit magic sits at byte 8. The rest
+/// of the block is zeroed.
+pub fn da_block(v5: bool, magic: u16, forw: u32, back: u32) -> Vec<u8> {
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    put_u32(&mut block, 0, forw);
+    put_u32(&mut block, 4, back);
+    put_u16(&mut block, 8, magic);
+    if v5 {
+        block[32..48].copy_from_slice(&UUID);
+    }
+    block
+}
+
+/// A directory data block (or, with `block_form`, a single-block
+/// directory) holding `entries` of (inode, name, file type), followed by
+/// the unused rest of the block.
+pub fn dir_data_block(v5: bool, block_form: bool, entries: &[(u64, &str, u8)]) -> Vec<u8> {
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    let magic = match (v5, block_form) {
+        (true, true) => 0x58444233,
+        (true, false) => 0x58444433,
+        (false, true) => 0x58443242,
+        (false, false) => 0x58443244,
+    };
+    put_u32(&mut block, 0, magic);
+    if v5 {
+        block[24..40].copy_from_slice(&UUID);
+    }
+    let mut offset = if v5 { 64 } else { 16 };
+    for &(ino, name, ftype) in entries {
+        let len = (8 + 1 + name.len() + usize::from(v5) + 2).div_ceil(8) * 8;
+        put_u64(&mut block, offset, ino);
+        block[offset + 8] = name.len() as u8;
+        block[offset + 9..offset + 9 + name.len()].copy_from_slice(name.as_bytes());
+        if v5 {
+            block[offset + 9 + name.len()] = ftype;
+        }
+        put_u16(&mut block, offset + len - 2, offset as u16);
+        offset += len;
+    }
+    let end = if block_form {
+        let tail = BLOCK_SIZE as usize - 8;
+        put_u32(&mut block, tail, entries.len() as u32);
+        tail - entries.len() * 8
+    } else {
+        BLOCK_SIZE as usize
+    };
+    put_u16(&mut block, offset, 0xffff);
+    put_u16(&mut block, offset + 2, (end - offset) as u16);
+    put_u16(&mut block, end - 2, offset as u16);
+    block
+}
+
+/// Stores an inode, updating its checksum on v5 filesystems.
+pub fn put_inode(image: &mut [u8], v5: bool, ino: u64, inode: &[u8]) {
+    let offset = inode_offset(ino);
+    let slot = &mut image[offset..offset + INODE_SIZE as usize];
+    slot.copy_from_slice(inode);
+    if v5 {
+        ::crc::update(slot, 100);
+    }
+}
+
+/// A whole filesystem image built around `superblock(v5)`. Every AG has a
+/// secondary superblock followed by AGF, AGI and AGFL sectors, free space
+/// btrees holding `FREE_EXTENTS` (in blocks 1 and 2), an inode btree (block
+/// 3) over one chunk of 64 inodes and, for v5, free inode (4), reverse
+/// mapping (5) and reference count (6) btrees. The by-size btree of AG 1
+/// has two levels: a root in block 2 over leaves in blocks 9 and 10.
+pub fn image(v5: bool) -> Vec<u8> {
+    let ag_bytes = (AG_BLOCKS * BLOCK_SIZE) as usize;
+    let sector = SECTOR_SIZE as usize;
+    let sb = superblock(v5);
+    let mut image = vec![0u8; ag_bytes * AG_COUNT as usize];
+    for agno in 0..AG_COUNT {
+        let base = agno as usize * ag_bytes;
+        let free = FREE_EXTENTS[agno as usize];
+        image[base..base + sector].copy_from_slice(&sb);
+
+        let agf = &mut image[base + sector..base + 2 * sector];
+        put_u32(agf, 0, 0x58414746);
+        put_u32(agf, 4, 1);
+        put_u32(agf, 8, agno);
+        put_u32(agf, 12, AG_BLOCKS);
+        put_u32(agf, 16, 1);
+        put_u32(agf, 20, 2);
+        put_u32(agf, 28, 1);
+        put_u32(agf, 32, agno + 1);
+        put_u32(agf, 44, 1);
+        put_u32(agf, 48, 2);
+        put_u32(agf, 52, free.iter().map(|e| e.1).sum());
+        put_u32(agf, 56, free.iter().map(|e| e.1).max().unwrap());
+        put_u32(agf, 60, 2);
+        if v5 {
+            put_u32(agf, 24, 5);
+            put_u32(agf, 36, 1);
+            agf[64..80].copy_from_slice(&UUID);
+            put_u32(agf, 80, 1);
+            put_u32(agf, 84, 1);
+            put_u32(agf, 88, 6);
+            put_u32(agf, 92, 1);
+            ::crc::update(agf, 216);
+        }
+
+        let agi = &mut image[base + 2 * sector..base + 3 * sector];
+        put_u32(agi, 0, 0x58414749);
+        put_u32(agi, 4, 1);
+        put_u32(agi, 8, agno);
+        put_u32(agi, 12, AG_BLOCKS);
+        put_u32(agi, 16, 64);
+        put_u32(agi, 20, 3);
+        put_u32(agi, 24, 1);
+        put_u32(agi, 28, 3);
+        put_u32(agi, 32, CHUNK_AGINO[agno as usize]);
+        put_u32(agi, 36, 0xffffffff);
+        for bucket in 0..64 {
+            let head = if agno == 1 && bucket == 5 { UNLINKED_AGINO } else { 0xffffffff };
+            put_u32(agi, 40 + bucket * 4, head);
+        }
+        if v5 {
+            agi[296..312].copy_from_slice(&UUID);
+            put_u32(agi, 328, 4);
+            put_u32(agi, 332, 1);
+            put_u32(agi, 336, 1);
+            put_u32(agi, 340, 1);
+            ::crc::update(agi, 312);
+        }
+
+        let agfl = &mut image[base + 3 * sector..base + 4 * sector];
+        let first = if v5 {
+            put_u32(agfl, 0, 0x5841464c);
+            put_u32(agfl, 4, agno);
+            agfl[8..24].copy_from_slice(&UUID);
+            36
+        } else {
+            0
+        };
+        for byte in agfl[first..].iter_mut() {
+            *byte = 0xff;
+        }
+        put_u32(agfl, first, 7);
+        put_u32(agfl, first + 4, 8);
+        if v5 {
+            ::crc::update(agfl, 32);
+        }
+    }
+
+    let (bno, cnt, ino_magic) = if v5 {
+        (0x41423342, 0x41423343, 0x49414233)
+    } else {
+        (0x41425442, 0x41425443, 0x49414254)
+    };
+    for agno in 0..AG_COUNT {
+        let free = FREE_EXTENTS[agno as usize];
+        let mut by_size: Vec<(u32, u32)> = free.to_vec();
+        by_size.sort_by_key(|e| (e.1, e.0));
+        btree_block(&mut image, v5, agno, 1, leaf(bno, &flatten(free), free.len()));
+        if agno == 0 {
+            btree_block(&mut image, v5, agno, 2, leaf(cnt, &flatten(&by_size), 3));
+        } else {
+            let keys = flatten(&[by_size[0], by_size[2]]);
+            btree_block(&mut image, v5, agno, 2,
+                        Block { magic: cnt, level: 1, numrecs: 2, words: &keys, ptrs: &[9, 10], rightsib: !0 });
+            btree_block(&mut image, v5, agno, 9,
+                        Block { rightsib: 10, ..leaf(cnt, &flatten(&by_size[..2]), 2) });
+            btree_block(&mut image, v5, agno, 10, leaf(cnt, &flatten(&by_size[2..]), 1));
+        }
+
+        let chunk = CHUNK_AGINO[agno as usize];
+        let inobt = [chunk, 3, (FREE_INODE_MASK >> 32) as u32, FREE_INODE_MASK as u32];
+        btree_block(&mut image, v5, agno, 3, leaf(ino_magic, &inobt, 1));
+        if v5 {
+            btree_block(&mut image, v5, agno, 4, leaf(0x46494233, &inobt, 1));
+            btree_block(&mut image, v5, agno, 5, leaf(0x524d4233, &[], 0));
+            btree_block(&mut image, v5, agno, 6, leaf(0x52334643, &[], 0));
+        }
+        for i in 0..64 {
+            let ino = ino(agno, chunk + i);
+            let mode = if FREE_INODE_MASK & 1 << i != 0 { 0 } else { 0o100644 };
+            put_inode(&mut image, v5, ino, &dinode(v5, ino, mode));
+        }
+    }
+    image
+}
+
+/// One log operation: transaction ID, client ID, flags and payload.
+pub struct LogOp<'a> {
+    pub tid: u32,
+    pub client: u8,
+    pub flags: u8,
+    pub payload: &'a [u8],
+}
+
+/// A transaction header region, little endian as written on x86.
+pub fn trans_header(tid: u32, trans_type: u32, items: u32) -> Vec<u8> {
+    let mut region = vec![];
+    for word in &[0x5452414e, trans_type, tid, items] {
+        region.extend_from_slice(&u32::to_le_bytes(*word));
+    }
+    region
+}
+
+/// The first region of a log item: its type and number of regions.
+pub fn log_item(item_type: u16, regions: u16) -> Vec<u8> {
+    let mut region = vec![0u8; 16];
+    region[0..2].copy_from_slice(&item_type.to_le_bytes());
+    region[2..4].copy_from_slice(&regions.to_le_bytes());
+    region
+}
+
+/// A v2 log record written at basic block `block` of cycle `cycle`: one
+/// header sector followed by the operations, with the first word of every
+/// data sector stamped with the cycle number.
+pub fn log_record(cycle: u32, block: u32, tail: (u32, u32), ops: &[LogOp]) -> Vec<u8> {
+    let mut data = vec![];
+    for op in ops {
+        let mut header = [0u8; 12];
+        put_u32(&mut header, 0, op.tid);
+        put_u32(&mut header, 4, op.payload.len() as u32);
+        header[8] = op.client;
+        header[9] = op.flags;
+        data.extend_from_slice(&header);
+        data.extend_from_slice(op.payload);
+    }
+    let len = data.len();
+    data.resize(len.div_ceil(512) * 512, 0);
+
+    let mut record = vec![0u8; 512];
+    put_u32(&mut record, 0, 0xfeedbabe);
+    put_u32(&mut record, 4, cycle);
+    put_u32(&mut record, 8, 2);
+    put_u32(&mut record, 12, len as u32);
+    put_u64(&mut record, 16, u64::from(cycle) << 32 | u64::from(block));
+    put_u64(&mut record, 24, u64::from(tail.0) << 32 | u64::from(tail.1));
+    put_u32(&mut record, 40, ops.len() as u32);
+    for (i, sector) in data.chunks_mut(512).enumerate() {
+        record[44 + i * 4..48 + i * 4].copy_from_slice(&sector[0..4]);
+        put_u32(sector, 0, cycle);
+    }
+    put_u32(&mut record, 300, 1);
+    record[304..320].copy_from_slice(&UUID);
+    put_u32(&mut record, 320, 32768);
+    record.extend_from_slice(&data);
+    record
+}
+
+/// Stores a log record at basic block `block` of the internal log,
+/// wrapping around its end.
+pub fn put_log_record(image: &mut [u8], block: u32, record: &[u8]) {
+    let start = (LOG_START * BLOCK_SIZE) as usize;
+    let sectors = (LOG_BLOCKS * BLOCK_SIZE / 512) as usize;
+    for (i, sector) in record.chunks(512).enumerate() {
+        let offset = start + (block as usize + i) % sectors * 512;
+        image[offset..offset + 512].copy_from_slice(sector);
+    }
+}
+
+/// The lines of a zeroed `/proc/fs/xfs/stat`.
+const STAT: [(&str, &str); 17] = [
+    ("extent_alloc", "0 0 0 0"),
+    ("abt", "0 0 0 0"),
+    ("blk_map", "0 0 0 0 0 0 0"),
+    ("bmbt", "0 0 0 0"),
+    ("dir", "0 0 0 0"),
+    ("trans", "0 0 0"),
+    ("ig", "0 0 0 0 0 0 0"),
+    ("log", "0 0 0 0 0"),
+    ("push_ail", "0 0 0 0 0 0 0 0 0 0"),
+    ("xstrat", "0 0"),
+    ("rw", "0 0"),
+    ("attr", "0 0 0 0"),
+    ("icluster", "0 0 0"),
+    ("vnodes", "0 0 0 0 0 0 0 0"),
+    ("buf", "0 0 0 0 0 0 0 0 0"),
+    ("xpc", "0 0 0"),
+    ("debug", "0"),
+];
+
+/// Parses a `/proc/fs/xfs/stat` whose lines are zero except those given,
+/// such as `("log", "0 0 0 0 120")`.
+pub fn stat(lines: &[(&str, &str)]) -> XfsStat {
+    let mut text = String::new();
+    for &(name, values) in STAT.iter() {
+        let values = lines.iter().find(|line| line.0 == name).map_or(values, |line| line.1);
+        text.push_str(&format!("{} {}\n", name, values));
+    }
+    ::parse(text.as_bytes()).unwrap()
+}
@@ -0,0 +1,200 @@
+//! Offline free space fragmentation analysis, equivalent to
+//! `xfs_db -c freesp`.
+//!
+//! `ExtentAllocation` shows how often extents are allocated and freed; this
+//! shows what the free space they are carved from looks like, by walking the
+//! by-size free space btree of every AG.
+
+use std::io::{Read, Seek};
+
+use ag;
+use btree;
+use superblock::Superblock;
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use fixtures;
+    use superblock;
+
+    #[test]
+    fn it_buckets_by_power_of_two() {
+        let mut histogram = super::Histogram::new();
+        for len in &[1, 2, 3, 4, 7, 8, 1000] {
+            histogram.add(*len);
+        }
+        let buckets: Vec<(u32, u32, u64, u64)> = histogram.occupied()
+            .map(|b| (b.from, b.to, b.extents, b.blocks))
+            .collect();
+        assert_eq!(buckets, vec![(1, 1, 1, 1), (2, 3, 2, 5), (4, 7, 2, 11), (8, 15, 1, 8),
+                                 (512, 1023, 1, 1000)]);
+        assert_eq!(histogram.extents, 7);
+        assert_eq!(histogram.blocks, 1025);
+    }
+
+    #[test]
+    fn it_scans_every_ag() {
+        let mut image = Cursor::new(fixtures::image(true));
+        let sb = superblock::read(&mut image).unwrap();
+        let freesp = super::scan(&mut image, &sb).unwrap();
+
+        assert_eq!(freesp.ags.len(), 2);
+        assert_eq!(freesp.ags[0].histogram.extents, 3);
+        assert_eq!(freesp.ags[0].histogram.blocks, 8);
+        assert_eq!(freesp.ags[0].btree_blocks, 1);
+        assert_eq!(freesp.ags[1].histogram.blocks, u64::from(freesp.ags[1].agf_free_blocks));
+        assert_eq!(freesp.ags[1].btree_blocks, 3);
+
+        assert_eq!(freesp.total.extents, 6);
+        assert_eq!(freesp.total.blocks, 51);
+        assert_eq!(freesp.total.average(), 8.5);
+        let largest = freesp.total.occupied().last().unwrap();
+        assert_eq!((largest.from, largest.to, largest.extents), (16, 31, 2));
+        assert_eq!(freesp.total.pct(largest), 40.0 / 51.0 * 100.0);
+    }
+
+    #[test]
+    fn it_scans_a_v4_image() {
+        let mut image = Cursor::new(fixtures::image(false));
+        let sb = superblock::read(&mut image).unwrap();
+        let freesp = super::scan(&mut image, &sb).unwrap();
+        assert_eq!(freesp.total.extents, 6);
+    }
+}
+
+/// Free extents whose length lies in `from..=to`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bucket {
+    pub from: u32,
+    pub to: u32,
+    /// Number of free extents in this bucket.
+    pub extents: u64,
+    /// Total blocks in those extents.
+    pub blocks: u64,
+}
+
+/// Free extent lengths bucketed by powers of two.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    pub buckets: Vec<Bucket>,
+    /// Number of free extents.
+    pub extents: u64,
+    /// Number of free blocks.
+    pub blocks: u64,
+}
+
+/// Free space of one allocation group.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AgFreeSpace {
+    pub agno: u32,
+    pub histogram: Histogram,
+    /// Free blocks according to the AGF, for comparison with the btree.
+    pub agf_free_blocks: u32,
+    /// Blocks of the by-size btree that were read.
+    pub btree_blocks: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FreeSpace {
+    pub ags: Vec<AgFreeSpace>,
+    /// All AGs combined.
+    pub total: Histogram,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        let buckets = (0..32)
+            .map(|i| {
+                Bucket {
+                    from: 1 << i,
+                    to: ((1u64 << (i + 1)) - 1) as u32,
+                    extents: 0,
+                    blocks: 0,
+                }
+            })
+            .collect();
+        Histogram {
+            buckets,
+            extents: 0,
+            blocks: 0,
+        }
+    }
+
+    /// Counts one free extent of `len` blocks.
+    pub fn add(&mut self, len: u32) {
+        if len == 0 {
+            return;
+        }
+        let bucket = &mut self.buckets[31 - len.leading_zeros() as usize];
+        bucket.extents += 1;
+        bucket.blocks += u64::from(len);
+        self.extents += 1;
+        self.blocks += u64::from(len);
+    }
+
+    /// Adds the counts of another histogram into this one.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (bucket, theirs) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            bucket.extents += theirs.extents;
+            bucket.blocks += theirs.blocks;
+        }
+        self.extents += other.extents;
+        self.blocks += other.blocks;
+    }
+
+    /// Buckets holding at least one extent, smallest first. This is what
+    /// `xfs_db` prints.
+    pub fn occupied(&self) -> impl Iterator<Item = &Bucket> {
+        self.buckets.iter().filter(|b| b.extents > 0)
+    }
+
+    /// Average free extent length in blocks.
+    pub fn average(&self) -> f64 {
+        if self.extents == 0 {
+            return 0.0;
+        }
+        self.blocks as f64 / self.extents as f64
+    }
+
+    /// Share of all free blocks held by `bucket`, in percent.
+    pub fn pct(&self, bucket: &Bucket) -> f64 {
+        if self.blocks == 0 {
+            return 0.0;
+        }
+        bucket.blocks as f64 / self.blocks as f64 * 100.0
+    }
+}
+
+/// Walks the by-size free space btree of allocation group `agno`.
+pub fn scan_ag<R: Read + Seek>(dev: &mut R, sb: &Superblock, agno: u32) -> Result<AgFreeSpace, XfsError> {
+    let headers = ag::read(dev, sb, agno)?;
+    let mut histogram = Histogram::new();
+    let btree_blocks = btree::walk(dev, sb, agno, headers.agf.cnt_root, headers.agf.cnt_level,
+                                   btree::CNTBT, |rec| histogram.add(btree::be32(rec, 4)))?;
+    Ok(AgFreeSpace {
+        agno,
+        histogram,
+        agf_free_blocks: headers.agf.free_blocks,
+        btree_blocks,
+    })
+}
+
+/// Walks the free space of every allocation group.
+pub fn scan<R: Read + Seek>(dev: &mut R, sb: &Superblock) -> Result<FreeSpace, XfsError> {
+    let mut total = Histogram::new();
+    let mut ags = Vec::new();
+    for agno in 0..sb.ag_count {
+        let ag = scan_ag(dev, sb, agno)?;
+        total.merge(&ag.histogram);
+        ags.push(ag);
+    }
+    Ok(FreeSpace { ags, total })
+}
@@ -0,0 +1,415 @@
+//! The owner of every physical extent of a mounted filesystem through
+//! `FS_IOC_GETFSMAP`, and a "who owns the disk" report per AG and owner
+//! class.
+//!
+//! Owners come from the reverse mapping btree when the filesystem has one.
+//! Without it the kernel only knows free space and static metadata and
+//! reports everything else as unknown.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Write};
+use std::fs::{self, File};
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use geometry;
+use ioctl;
+use libc;
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use super::{Class, Owner};
+
+    fn raw(device: u32, physical: u64, length: u64, owner: u64, flags: u32) -> super::RawFsmap {
+        super::RawFsmap { fmr_device: device, fmr_flags: flags, fmr_physical: physical, fmr_owner: owner,
+                          fmr_length: length, ..Default::default() }
+    }
+
+    #[test]
+    fn it_decodes_mappings() {
+        assert_eq!(mem::size_of::<super::RawFsmap>(), 64);
+        assert_eq!(mem::size_of::<super::RawFsmapHead>(), 192);
+        assert_eq!(super::GETFSMAP, 0xc0c0583b);
+
+        let log = super::Mapping::from(&raw(0x801, 1 << 20, 4096, super::xfs_owner(2), super::OF_SPECIAL_OWNER));
+        assert_eq!(log.owner, Owner::Log);
+        assert_eq!(log.owner.class(), Class::Log);
+        let free = super::Mapping::from(&raw(0x801, 0, 4096, super::OWN_FREE, super::OF_SPECIAL_OWNER));
+        assert_eq!(free.owner.class(), Class::Free);
+
+        let mut data = raw(0x801, 8192, 4096, 131, super::OF_SHARED | super::OF_PREALLOC);
+        data.fmr_offset = 65536;
+        let data = super::Mapping::from(&data);
+        assert_eq!(data.owner, Owner::File { ino: 131, offset: 65536, attr_fork: false, bmbt: false });
+        assert!(data.shared && data.unwritten);
+        let bmbt = super::Mapping::from(&raw(0x801, 0, 4096, 131, super::OF_EXTENT_MAP));
+        assert_eq!(bmbt.owner.class(), Class::Bmbt);
+        let attr = super::Mapping::from(&raw(0x801, 0, 4096, 131, super::OF_ATTR_FORK));
+        assert_eq!(attr.owner.class(), Class::Xattr);
+    }
+
+    #[test]
+    fn it_aggregates_by_ag_and_class() {
+        let mut report = super::Report::new(0x801, 1 << 20);
+        report.add(&super::Mapping::from(&raw(0x801, 0, 8192, super::xfs_owner(1), super::OF_SPECIAL_OWNER)));
+        report.add(&super::Mapping::from(&raw(0x801, 8192, 4096, super::xfs_owner(3), super::OF_SPECIAL_OWNER)));
+        report.add(&super::Mapping::from(&raw(0x801, 65536, 1 << 19, 131, 0)));
+        report.add(&super::Mapping::from(&raw(0x801, (1 << 20) + 4096, 4096, 132, 0)));
+        report.add(&super::Mapping::from(&raw(0x801, (1 << 20) + 8192, 8192, super::OWN_FREE,
+                                              super::OF_SPECIAL_OWNER)));
+        report.add(&super::Mapping::from(&raw(0x802, 0, 1 << 20, super::xfs_owner(2), super::OF_SPECIAL_OWNER)));
+
+        assert_eq!(report.total[&Class::Data], (1 << 19) + 4096);
+        assert_eq!(report.total[&Class::Log], 1 << 20);
+        assert_eq!(report.ags.len(), 2);
+        assert_eq!(report.ags[0][&Class::Static], 8192);
+        assert_eq!(report.ags[1][&Class::Free], 8192);
+        assert_eq!(report.external[&Class::Log], 1 << 20);
+
+        let expected = " agno       data      xattr       bmbt     inodes      inobt     agmeta   refcount       free      other
+    0        512          0          0          0          0          4          0          0          8
+    1          4          0          0          0          0          0          0          8          0
+  ext          0          0          0          0          0          0          0          0       1024
+";
+        assert_eq!(super::format_report(&report), expected);
+    }
+
+    #[test]
+    fn it_advances_the_low_key() {
+        let mut recs = [raw(0x801, 0, 4096, super::OWN_FREE, super::OF_SPECIAL_OWNER), raw(0x801, 8192, 4096, 131, 0)];
+        recs[1].fmr_offset = 65536;
+        let key = super::next_key(&recs).unwrap();
+        assert_eq!((key.fmr_device, key.fmr_physical, key.fmr_owner, key.fmr_offset), (0x801, 8192, 131, 65536));
+
+        recs[1].fmr_flags = super::OF_LAST;
+        assert!(super::next_key(&recs).is_none());
+        assert!(super::next_key(&[]).is_none());
+    }
+}
+
+const GETFSMAP: u64 = ioctl::iowr(59, 192);
+
+/// Unwritten extent.
+pub const OF_PREALLOC: u32 = 0x1;
+pub const OF_ATTR_FORK: u32 = 0x2;
+/// A block of the owner's extent map (bmbt).
+pub const OF_EXTENT_MAP: u32 = 0x4;
+pub const OF_SHARED: u32 = 0x8;
+/// The owner is one of the special owners rather than an inode.
+pub const OF_SPECIAL_OWNER: u32 = 0x10;
+/// The last mapping of the filesystem.
+pub const OF_LAST: u32 = 0x20;
+
+const OWN_FREE: u64 = 1;
+const OWN_UNKNOWN: u64 = 2;
+const OWN_METADATA: u64 = 3;
+
+/// `FMR_OWNER('X', code)`: the XFS special owners.
+const fn xfs_owner(code: u64) -> u64 {
+    (b'X' as u64) << 32 | code
+}
+
+/// Mappings requested per ioctl call.
+const BATCH: usize = 128;
+
+/// `struct fsmap`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawFsmap {
+    fmr_device: u32,
+    fmr_flags: u32,
+    fmr_physical: u64,
+    fmr_owner: u64,
+    fmr_offset: u64,
+    fmr_length: u64,
+    fmr_reserved: [u64; 3],
+}
+
+/// `struct fsmap_head`, without the records that follow it.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawFsmapHead {
+    fmh_iflags: u32,
+    fmh_oflags: u32,
+    fmh_count: u32,
+    fmh_entries: u32,
+    fmh_reserved: [u64; 6],
+    fmh_keys: [RawFsmap; 2],
+}
+
+#[repr(C)]
+struct RawFsmapReq {
+    head: RawFsmapHead,
+    recs: [RawFsmap; BATCH],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Owner {
+    Free,
+    Unknown,
+    /// Metadata of a filesystem the kernel reports generically.
+    Metadata,
+    /// Superblocks and AG headers.
+    Static,
+    Log,
+    /// Free space btrees, the rmapbt and the AGFL.
+    AgMetadata,
+    Inobt,
+    /// Inode chunks.
+    Inodes,
+    Refcountbt,
+    /// Copy-on-write staging extents.
+    Cow,
+    Defective,
+    File { ino: u64, offset: u64, attr_fork: bool, bmbt: bool },
+    /// A special owner this crate doesn't know.
+    Other(u64),
+}
+
+/// The owner classes the report adds up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Class {
+    Data,
+    Xattr,
+    Bmbt,
+    Inodes,
+    Inobt,
+    AgMetadata,
+    Refcountbt,
+    Free,
+    Static,
+    Log,
+    Other,
+}
+
+/// One physical extent and its owner. Offsets and lengths are in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    /// The device, encoded the way the kernel encodes `dev_t`.
+    pub device: u32,
+    pub physical: u64,
+    pub length: u64,
+    pub owner: Owner,
+    pub unwritten: bool,
+    pub shared: bool,
+}
+
+/// An iterator over the mappings of a physical range.
+pub struct Fsmap {
+    file: File,
+    keys: [RawFsmap; 2],
+    pending: VecDeque<Mapping>,
+    done: bool,
+}
+
+/// Bytes per owner class, for the whole filesystem and per AG of the data
+/// device.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The data device, whose space is split into AGs.
+    pub data_device: u32,
+    pub ag_bytes: u64,
+    pub total: BTreeMap<Class, u64>,
+    /// Indexed by AG number.
+    pub ags: Vec<BTreeMap<Class, u64>>,
+    /// The external log and realtime devices.
+    pub external: BTreeMap<Class, u64>,
+}
+
+impl Owner {
+    pub fn class(&self) -> Class {
+        match *self {
+            Owner::File { bmbt: true, .. } => Class::Bmbt,
+            Owner::File { attr_fork: true, .. } => Class::Xattr,
+            Owner::File { .. } => Class::Data,
+            Owner::Free => Class::Free,
+            Owner::Static => Class::Static,
+            Owner::Log => Class::Log,
+            Owner::AgMetadata => Class::AgMetadata,
+            Owner::Inobt => Class::Inobt,
+            Owner::Inodes => Class::Inodes,
+            Owner::Refcountbt => Class::Refcountbt,
+            _ => Class::Other,
+        }
+    }
+}
+
+impl Class {
+    pub fn name(self) -> &'static str {
+        match self {
+            Class::Data => "data",
+            Class::Xattr => "xattr",
+            Class::Bmbt => "bmbt",
+            Class::Inodes => "inodes",
+            Class::Inobt => "inobt",
+            Class::AgMetadata => "agmeta",
+            Class::Refcountbt => "refcount",
+            Class::Free => "free",
+            Class::Static => "static",
+            Class::Log => "log",
+            Class::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl From<&RawFsmap> for Mapping {
+    fn from(raw: &RawFsmap) -> Mapping {
+        let owner = if raw.fmr_flags & OF_SPECIAL_OWNER != 0 {
+            match raw.fmr_owner {
+                OWN_FREE => Owner::Free,
+                OWN_UNKNOWN => Owner::Unknown,
+                OWN_METADATA => Owner::Metadata,
+                owner if owner == xfs_owner(1) => Owner::Static,
+                owner if owner == xfs_owner(2) => Owner::Log,
+                owner if owner == xfs_owner(3) => Owner::AgMetadata,
+                owner if owner == xfs_owner(4) => Owner::Inobt,
+                owner if owner == xfs_owner(5) => Owner::Inodes,
+                owner if owner == xfs_owner(6) => Owner::Refcountbt,
+                owner if owner == xfs_owner(7) => Owner::Cow,
+                owner if owner == xfs_owner(8) => Owner::Defective,
+                owner => Owner::Other(owner),
+            }
+        } else {
+            Owner::File {
+                ino: raw.fmr_owner,
+                offset: raw.fmr_offset,
+                attr_fork: raw.fmr_flags & OF_ATTR_FORK != 0,
+                bmbt: raw.fmr_flags & OF_EXTENT_MAP != 0,
+            }
+        };
+        Mapping {
+            device: raw.fmr_device,
+            physical: raw.fmr_physical,
+            length: raw.fmr_length,
+            owner,
+            unwritten: raw.fmr_flags & OF_PREALLOC != 0,
+            shared: raw.fmr_flags & OF_SHARED != 0,
+        }
+    }
+}
+
+impl Fsmap {
+    fn fetch(&mut self) -> Result<(), XfsError> {
+        let mut req = Box::new(RawFsmapReq {
+            head: RawFsmapHead { fmh_count: BATCH as u32, fmh_keys: self.keys, ..Default::default() },
+            recs: [RawFsmap::default(); BATCH],
+        });
+        unsafe { ioctl::ioctl(&self.file, GETFSMAP, &mut *req)? };
+        let count = (req.head.fmh_entries as usize).min(BATCH);
+        self.pending.extend(req.recs[..count].iter().map(Mapping::from));
+        match next_key(&req.recs[..count]) {
+            Some(key) => self.keys[0] = key,
+            None => self.done = true,
+        }
+        Ok(())
+    }
+}
+
+/// The low key of the call after one that returned `recs`: the next call
+/// carries on after the last mapping returned. None once the kernel has
+/// nothing more.
+fn next_key(recs: &[RawFsmap]) -> Option<RawFsmap> {
+    recs.last().filter(|last| last.fmr_flags & OF_LAST == 0).cloned()
+}
+
+impl Iterator for Fsmap {
+    type Item = Result<Mapping, XfsError>;
+
+    fn next(&mut self) -> Option<Result<Mapping, XfsError>> {
+        if self.pending.is_empty() && !self.done {
+            if let Err(err) = self.fetch() {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+impl Report {
+    pub fn new(data_device: u32, ag_bytes: u64) -> Report {
+        Report { data_device, ag_bytes, ..Default::default() }
+    }
+
+    pub fn add(&mut self, mapping: &Mapping) {
+        let class = mapping.owner.class();
+        *self.total.entry(class).or_insert(0) += mapping.length;
+        if mapping.device != self.data_device || self.ag_bytes == 0 {
+            *self.external.entry(class).or_insert(0) += mapping.length;
+            return;
+        }
+        // Mappings never cross AG boundaries.
+        let agno = (mapping.physical / self.ag_bytes) as usize;
+        if self.ags.len() <= agno {
+            self.ags.resize(agno + 1, BTreeMap::new());
+        }
+        *self.ags[agno].entry(class).or_insert(0) += mapping.length;
+    }
+}
+
+/// Iterates over the mappings of the filesystem containing `path` whose
+/// physical offsets fall in `range`, device by device: data, log, realtime.
+pub fn fsmap<P: AsRef<Path>>(path: P, range: Range<u64>) -> Result<Fsmap, XfsError> {
+    let mut keys = [RawFsmap::default(); 2];
+    keys[0].fmr_physical = range.start;
+    keys[1] = RawFsmap { fmr_device: !0, fmr_flags: !0, fmr_physical: range.end, fmr_owner: !0,
+                         fmr_offset: !0, fmr_length: 0, fmr_reserved: [0; 3] };
+    Ok(Fsmap { file: ioctl::open(path)?, keys, pending: VecDeque::new(), done: false })
+}
+
+/// The kernel's `new_encode_dev`, which `fmr_device` uses.
+fn encode_dev(dev: u64) -> u32 {
+    let (major, minor) = (libc::major(dev), libc::minor(dev));
+    (minor & 0xff) | major << 8 | (minor & !0xff) << 12
+}
+
+/// Maps the whole filesystem containing `path` and adds up its owners.
+pub fn report<P: AsRef<Path>>(path: P) -> Result<Report, XfsError> {
+    let path = path.as_ref();
+    let geom = geometry::geometry(path)?;
+    let mut report = Report::new(encode_dev(fs::metadata(path)?.dev()), geom.ag_bytes());
+    for mapping in fsmap(path, 0..u64::MAX)? {
+        report.add(&mapping?);
+    }
+    Ok(report)
+}
+
+/// Renders the report as a fixed-width table in KiB, one row per AG and a
+/// last row for the external devices. Static metadata, the log and
+/// anything else fall under "other".
+pub fn format_report(report: &Report) -> String {
+    const COLUMNS: [Class; 8] = [Class::Data, Class::Xattr, Class::Bmbt, Class::Inodes, Class::Inobt,
+                                 Class::AgMetadata, Class::Refcountbt, Class::Free];
+    let mut table = String::new();
+    let _ = write!(table, "{:>5}", "agno");
+    for class in COLUMNS.iter() {
+        let _ = write!(table, " {:>10}", class.name());
+    }
+    let _ = writeln!(table, " {:>10}", "other");
+
+    let mut row = |name: String, classes: &BTreeMap<Class, u64>| {
+        let _ = write!(table, "{:>5}", name);
+        for class in COLUMNS.iter() {
+            let _ = write!(table, " {:>10}", classes.get(class).cloned().unwrap_or(0) / 1024);
+        }
+        let other: u64 = classes.iter().filter(|&(class, _)| !COLUMNS.contains(class)).map(|(_, b)| b).sum();
+        let _ = writeln!(table, " {:>10}", other / 1024);
+    };
+    for (agno, classes) in report.ags.iter().enumerate() {
+        row(agno.to_string(), classes);
+    }
+    if !report.external.is_empty() {
+        row("ext".to_string(), &report.external);
+    }
+    table
+}
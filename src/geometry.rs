@@ -0,0 +1,349 @@
+//! Geometry of a mounted filesystem through `XFS_IOC_FSGEOMETRY`, the same
+//! data `xfs_info` reports.
+
+use std::fmt::Write;
+use std::path::Path;
+
+use ioctl;
+use superblock::Uuid;
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use superblock::Uuid;
+
+    fn geometry() -> super::Geometry {
+        let raw = super::RawGeometry {
+            blocksize: 4096,
+            rtextsize: 1,
+            agblocks: 65536,
+            agcount: 4,
+            logblocks: 16384,
+            sectsize: 512,
+            inodesize: 512,
+            imaxpct: 25,
+            datablocks: 262144,
+            logstart: 131078,
+            sunit: 16,
+            swidth: 64,
+            version: 5,
+            flags: super::FLAG_ATTR | super::FLAG_ATTR2 | super::FLAG_PROJID32 | super::FLAG_DIRV2 |
+                   super::FLAG_LOGV2 | super::FLAG_LAZYSB | super::FLAG_V5SB | super::FLAG_FTYPE |
+                   super::FLAG_FINOBT | super::FLAG_SPINODES | super::FLAG_REFLINK |
+                   super::FLAG_BIGTIME | super::FLAG_INOBTCNT,
+            logsectsize: 512,
+            rtsectsize: 512,
+            dirblocksize: 4096,
+            logsunit: 65536,
+            ..Default::default()
+        };
+        super::Geometry::from(&raw)
+    }
+
+    #[test]
+    fn it_decodes_the_ioctl_struct() {
+        assert_eq!(::std::mem::size_of::<super::RawGeometry>(), 256);
+        let geom = geometry();
+        assert_eq!(geom.ag_count, 4);
+        assert_eq!(geom.uuid, Uuid([0; 16]));
+        assert!(geom.has(super::FLAG_REFLINK));
+        assert!(!geom.has(super::FLAG_RMAPBT));
+        assert!(geom.has_internal_log());
+        assert_eq!(geom.bytes(3), 12288);
+        assert_eq!(geom.ag_bytes(), 256 << 20);
+        assert_eq!(geom.log_bytes(), 64 << 20);
+        assert_eq!(geom.agino_bits(), 19);
+    }
+
+    #[test]
+    fn it_formats_like_xfs_info() {
+        let expected = "\
+meta-data=/mnt/data              isize=512    agcount=4, agsize=65536 blks
+         =                       sectsz=512   attr=2, projid32bit=1
+         =                       crc=1        finobt=1, sparse=1, rmapbt=0
+         =                       reflink=1    bigtime=1 inobtcount=1 nrext64=0
+data     =                       bsize=4096   blocks=262144, imaxpct=25
+         =                       sunit=16     swidth=64 blks
+naming   =version 2              bsize=4096   ascii-ci=0, ftype=1
+log      =internal log           bsize=4096   blocks=16384, version=2
+         =                       sectsz=512   sunit=16 blks, lazy-count=1
+realtime =none                   extsz=4096   blocks=0, rtextents=0
+";
+        assert_eq!(super::format_info(&geometry(), "/mnt/data"), expected);
+    }
+
+    #[test]
+    fn it_decodes_ag_geometry() {
+        assert_eq!(::std::mem::size_of::<super::RawAgGeometry>(), 128);
+        assert_eq!(super::AG_GEOMETRY, 0xc080583d);
+        let raw = super::RawAgGeometry { ag_number: 3, ag_length: 65536, ag_freeblks: 100, ag_icount: 64,
+                                         ag_ifree: 10, ag_sick: 0x40, ag_checked: 0x7ff,
+                                         ..Default::default() };
+        let ag = super::AgGeometry::from(&raw);
+        assert_eq!((ag.agno, ag.length, ag.free_blocks, ag.inodes, ag.free_inodes), (3, 65536, 100, 64, 10));
+        assert_eq!((ag.sick, ag.checked), (0x40, 0x7ff));
+    }
+}
+
+pub const FLAG_ATTR: u32 = 0x1;
+pub const FLAG_NLINK: u32 = 0x2;
+pub const FLAG_QUOTA: u32 = 0x4;
+pub const FLAG_IALIGN: u32 = 0x8;
+pub const FLAG_DALIGN: u32 = 0x10;
+pub const FLAG_SHARED: u32 = 0x20;
+pub const FLAG_EXTFLG: u32 = 0x40;
+pub const FLAG_DIRV2: u32 = 0x80;
+pub const FLAG_LOGV2: u32 = 0x100;
+pub const FLAG_SECTOR: u32 = 0x200;
+pub const FLAG_ATTR2: u32 = 0x400;
+pub const FLAG_PROJID32: u32 = 0x800;
+/// ASCII-only case-insensitive directories.
+pub const FLAG_DIRV2CI: u32 = 0x1000;
+pub const FLAG_LAZYSB: u32 = 0x4000;
+pub const FLAG_V5SB: u32 = 0x8000;
+pub const FLAG_FTYPE: u32 = 0x10000;
+pub const FLAG_FINOBT: u32 = 0x20000;
+pub const FLAG_SPINODES: u32 = 0x40000;
+pub const FLAG_RMAPBT: u32 = 0x80000;
+pub const FLAG_REFLINK: u32 = 0x100000;
+pub const FLAG_BIGTIME: u32 = 0x200000;
+pub const FLAG_INOBTCNT: u32 = 0x400000;
+pub const FLAG_NREXT64: u32 = 0x800000;
+
+const FSGEOMETRY_V4: u64 = ioctl::ior(124, 112);
+const FSGEOMETRY: u64 = ioctl::ior(126, 256);
+const AG_GEOMETRY: u64 = ioctl::iowr(61, 128);
+
+/// `struct xfs_fsop_geom`. The v4 ioctl fills in the first 112 bytes.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawGeometry {
+    blocksize: u32,
+    rtextsize: u32,
+    agblocks: u32,
+    agcount: u32,
+    logblocks: u32,
+    sectsize: u32,
+    inodesize: u32,
+    imaxpct: u32,
+    datablocks: u64,
+    rtblocks: u64,
+    rtextents: u64,
+    logstart: u64,
+    uuid: [u8; 16],
+    sunit: u32,
+    swidth: u32,
+    version: i32,
+    flags: u32,
+    logsectsize: u32,
+    rtsectsize: u32,
+    dirblocksize: u32,
+    logsunit: u32,
+    sick: u32,
+    checked: u32,
+    reserved: [u64; 17],
+}
+
+/// `struct xfs_ag_geometry`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawAgGeometry {
+    ag_number: u32,
+    ag_length: u32,
+    ag_freeblks: u32,
+    ag_icount: u32,
+    ag_ifree: u32,
+    ag_sick: u32,
+    ag_checked: u32,
+    ag_flags: u32,
+    ag_reserved: [u64; 12],
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Geometry {
+    /// Filesystem block size in bytes.
+    pub block_size: u32,
+    /// Realtime extent size in blocks.
+    pub rt_extent_size: u32,
+    /// Blocks per allocation group.
+    pub ag_blocks: u32,
+    pub ag_count: u32,
+    /// Log size in blocks.
+    pub log_blocks: u32,
+    pub sector_size: u32,
+    pub inode_size: u32,
+    /// Maximum percentage of the filesystem used for inodes.
+    pub imax_pct: u32,
+    pub data_blocks: u64,
+    pub rt_blocks: u64,
+    pub rt_extents: u64,
+    /// First block of an internal log; zero for an external log.
+    pub log_start: u64,
+    pub uuid: Uuid,
+    /// Stripe unit in blocks.
+    pub stripe_unit: u32,
+    /// Stripe width in blocks.
+    pub stripe_width: u32,
+    /// Geometry structure version, 4 or 5.
+    pub version: i32,
+    /// `FLAG_*` feature bits.
+    pub flags: u32,
+    pub log_sector_size: u32,
+    pub rt_sector_size: u32,
+    /// Directory block size in bytes.
+    pub dir_block_size: u32,
+    /// Log stripe unit in bytes.
+    pub log_stripe_unit: u32,
+    /// Metadata known to be damaged (`health::FS_*` bits, v5 only).
+    pub sick: u32,
+    /// Metadata that has been checked (`health::FS_*` bits, v5 only).
+    pub checked: u32,
+}
+
+/// Size, usage and health of one allocation group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AgGeometry {
+    pub agno: u32,
+    /// Size in blocks.
+    pub length: u32,
+    pub free_blocks: u32,
+    /// Allocated inodes.
+    pub inodes: u32,
+    pub free_inodes: u32,
+    /// Metadata known to be damaged (`health::AG_*` bits).
+    pub sick: u32,
+    /// Metadata that has been checked (`health::AG_*` bits).
+    pub checked: u32,
+}
+
+impl From<&RawAgGeometry> for AgGeometry {
+    fn from(raw: &RawAgGeometry) -> AgGeometry {
+        AgGeometry {
+            agno: raw.ag_number,
+            length: raw.ag_length,
+            free_blocks: raw.ag_freeblks,
+            inodes: raw.ag_icount,
+            free_inodes: raw.ag_ifree,
+            sick: raw.ag_sick,
+            checked: raw.ag_checked,
+        }
+    }
+}
+
+impl From<&RawGeometry> for Geometry {
+    fn from(raw: &RawGeometry) -> Geometry {
+        Geometry {
+            block_size: raw.blocksize,
+            rt_extent_size: raw.rtextsize,
+            ag_blocks: raw.agblocks,
+            ag_count: raw.agcount,
+            log_blocks: raw.logblocks,
+            sector_size: raw.sectsize,
+            inode_size: raw.inodesize,
+            imax_pct: raw.imaxpct,
+            data_blocks: raw.datablocks,
+            rt_blocks: raw.rtblocks,
+            rt_extents: raw.rtextents,
+            log_start: raw.logstart,
+            uuid: Uuid(raw.uuid),
+            stripe_unit: raw.sunit,
+            stripe_width: raw.swidth,
+            version: raw.version,
+            flags: raw.flags,
+            log_sector_size: raw.logsectsize,
+            rt_sector_size: raw.rtsectsize,
+            dir_block_size: raw.dirblocksize,
+            log_stripe_unit: raw.logsunit,
+            sick: raw.sick,
+            checked: raw.checked,
+        }
+    }
+}
+
+impl Geometry {
+    pub fn has(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn has_internal_log(&self) -> bool {
+        self.log_start > 0
+    }
+
+    /// Converts a count of filesystem blocks, such as
+    /// `ExtentAllocation::allocated_blocks`, to bytes.
+    pub fn bytes(&self, blocks: u64) -> u64 {
+        blocks * u64::from(self.block_size)
+    }
+
+    pub fn ag_bytes(&self) -> u64 {
+        self.bytes(u64::from(self.ag_blocks))
+    }
+
+    pub fn log_bytes(&self) -> u64 {
+        self.bytes(u64::from(self.log_blocks))
+    }
+
+    /// Bits of an inode number below the AG number (`agblklog` plus
+    /// `inopblog`).
+    pub fn agino_bits(&self) -> u32 {
+        let ag_block_log = 32 - self.ag_blocks.saturating_sub(1).leading_zeros();
+        let inodes_per_block = self.block_size / self.inode_size.max(1);
+        ag_block_log + inodes_per_block.trailing_zeros()
+    }
+}
+
+/// Reads the geometry of the filesystem containing `path`, falling back to
+/// the v4 ioctl on kernels older than 5.1.
+pub fn geometry<P: AsRef<Path>>(path: P) -> Result<Geometry, XfsError> {
+    let file = ioctl::open(path)?;
+    let mut raw = RawGeometry::default();
+    if let Err(err) = unsafe { ioctl::ioctl(&file, FSGEOMETRY, &mut raw) } {
+        if !ioctl::unsupported(&err) {
+            return Err(XfsError::Io(err));
+        }
+        raw = RawGeometry::default();
+        unsafe { ioctl::ioctl(&file, FSGEOMETRY_V4, &mut raw)? };
+    }
+    Ok(Geometry::from(&raw))
+}
+
+/// Reads the geometry of AG `agno` of the filesystem containing `path`.
+/// Needs Linux 5.2 or later.
+pub fn ag_geometry<P: AsRef<Path>>(path: P, agno: u32) -> Result<AgGeometry, XfsError> {
+    let file = ioctl::open(path)?;
+    let mut raw = RawAgGeometry { ag_number: agno, ..Default::default() };
+    unsafe { ioctl::ioctl(&file, AG_GEOMETRY, &mut raw)? };
+    Ok(AgGeometry::from(&raw))
+}
+
+/// Formats the geometry the way `xfs_info` prints it, with `name` (the
+/// mount point or device) on the first line.
+pub fn format_info(geom: &Geometry, name: &str) -> String {
+    let flag = |flag| u32::from(geom.has(flag));
+    let attr = if geom.has(FLAG_ATTR2) { 2 } else { flag(FLAG_ATTR) };
+    let log_name = if geom.has_internal_log() { "internal log" } else { "external" };
+    let rt_name = if geom.rt_blocks > 0 { "external" } else { "none" };
+    let mut out = String::new();
+    let _ = writeln!(out, "meta-data={:<22} isize={:<6} agcount={}, agsize={} blks",
+                     name, geom.inode_size, geom.ag_count, geom.ag_blocks);
+    let _ = writeln!(out, "         ={:<22} sectsz={:<5} attr={}, projid32bit={}",
+                     "", geom.sector_size, attr, flag(FLAG_PROJID32));
+    let _ = writeln!(out, "         ={:<22} crc={:<8} finobt={}, sparse={}, rmapbt={}",
+                     "", flag(FLAG_V5SB), flag(FLAG_FINOBT), flag(FLAG_SPINODES), flag(FLAG_RMAPBT));
+    let _ = writeln!(out, "         ={:<22} reflink={:<4} bigtime={} inobtcount={} nrext64={}",
+                     "", flag(FLAG_REFLINK), flag(FLAG_BIGTIME), flag(FLAG_INOBTCNT), flag(FLAG_NREXT64));
+    let _ = writeln!(out, "data     ={:<22} bsize={:<6} blocks={}, imaxpct={}",
+                     "", geom.block_size, geom.data_blocks, geom.imax_pct);
+    let _ = writeln!(out, "         ={:<22} sunit={:<6} swidth={} blks",
+                     "", geom.stripe_unit, geom.stripe_width);
+    let _ = writeln!(out, "naming   =version {:<14} bsize={:<6} ascii-ci={}, ftype={}",
+                     if geom.has(FLAG_DIRV2) { 2 } else { 1 }, geom.dir_block_size,
+                     flag(FLAG_DIRV2CI), flag(FLAG_FTYPE));
+    let _ = writeln!(out, "log      ={:<22} bsize={:<6} blocks={}, version={}",
+                     log_name, geom.block_size, geom.log_blocks, if geom.has(FLAG_LOGV2) { 2 } else { 1 });
+    let _ = writeln!(out, "         ={:<22} sectsz={:<5} sunit={} blks, lazy-count={}",
+                     "", geom.log_sector_size, geom.log_stripe_unit / geom.block_size.max(1), flag(FLAG_LAZYSB));
+    let _ = writeln!(out, "realtime ={:<22} extsz={:<6} blocks={}, rtextents={}",
+                     rt_name, geom.rt_extent_size * geom.block_size, geom.rt_blocks, geom.rt_extents);
+    out
+}
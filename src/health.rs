@@ -0,0 +1,297 @@
+//! Metadata health as the kernel tracks it: structures found damaged
+//! (sick) or verified (checked) by online scrub or during normal
+//! operation, for the filesystem, each AG and individual inodes.
+//!
+//! The masks come from `XFS_IOC_FSGEOMETRY`, `XFS_IOC_AG_GEOMETRY` and
+//! bulkstat. Inodes are only scanned in AGs that report sick inodes.
+
+use std::fmt;
+use std::path::Path;
+
+use bulkstat::{Bulkstat, Cursor, Stat};
+use geometry::{self, AgGeometry, Geometry};
+use ioctl;
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use super::Structure;
+
+    #[test]
+    fn it_decodes_masks() {
+        let status = super::Status::new(super::FS_TABLE, super::FS_COUNTERS | super::FS_PQUOTA,
+                                        super::FS_COUNTERS | super::FS_UQUOTA | super::FS_PQUOTA);
+        assert_eq!(status.sick, vec![Structure::Counters, Structure::ProjectQuota]);
+        assert_eq!(status.checked, vec![Structure::Counters, Structure::UserQuota, Structure::ProjectQuota]);
+        assert!(!status.is_healthy());
+        assert_eq!(Structure::ProjectQuota.to_string(), "project quota");
+
+        let status = super::Status::new(super::AG_TABLE, super::AG_INOBT | 0x8000_0000, super::AG_INOBT);
+        assert_eq!(status.sick, vec![Structure::Inobt]);
+        assert!(super::Status::new(super::INODE_TABLE, 0, !0).is_healthy());
+    }
+
+    #[test]
+    fn it_builds_a_report() {
+        let ags = [super::AgHealth { agno: 0, status: super::Status::new(super::AG_TABLE, 0, 0) },
+                   super::AgHealth { agno: 1, status: super::Status::new(super::AG_TABLE, super::AG_INODES, 0) }];
+        let health = super::Health {
+            fs: super::Status::new(super::FS_TABLE, 0, super::FS_COUNTERS),
+            ags: ags.to_vec(),
+            inodes: vec![super::InodeHealth {
+                ino: 1 << 19 | 70,
+                status: super::Status::new(super::INODE_TABLE, super::INODE_BMBTD | super::INODE_DIR, 0),
+            }],
+        };
+        assert!(!health.is_healthy());
+        assert_eq!(health.sick_ags().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(health.inodes[0].status.sick, vec![Structure::DataFork, Structure::Directory]);
+    }
+}
+
+pub const FS_COUNTERS: u32 = 0x1;
+pub const FS_UQUOTA: u32 = 0x2;
+pub const FS_GQUOTA: u32 = 0x4;
+pub const FS_PQUOTA: u32 = 0x8;
+pub const FS_RT_BITMAP: u32 = 0x10;
+pub const FS_RT_SUMMARY: u32 = 0x20;
+pub const FS_QUOTACHECK: u32 = 0x40;
+pub const FS_NLINKS: u32 = 0x80;
+
+pub const AG_SB: u32 = 0x1;
+pub const AG_AGF: u32 = 0x2;
+pub const AG_AGFL: u32 = 0x4;
+pub const AG_AGI: u32 = 0x8;
+pub const AG_BNOBT: u32 = 0x10;
+pub const AG_CNTBT: u32 = 0x20;
+pub const AG_INOBT: u32 = 0x40;
+pub const AG_FINOBT: u32 = 0x80;
+pub const AG_RMAPBT: u32 = 0x100;
+pub const AG_REFCNTBT: u32 = 0x200;
+/// Some inode in the AG is sick.
+pub const AG_INODES: u32 = 0x400;
+
+pub const INODE_CORE: u32 = 0x1;
+pub const INODE_BMBTD: u32 = 0x2;
+pub const INODE_BMBTA: u32 = 0x4;
+pub const INODE_BMBTC: u32 = 0x8;
+pub const INODE_DIR: u32 = 0x10;
+pub const INODE_XATTR: u32 = 0x20;
+pub const INODE_SYMLINK: u32 = 0x40;
+pub const INODE_PARENT: u32 = 0x80;
+pub const INODE_DIRTREE: u32 = 0x100;
+
+/// A piece of metadata the kernel tracks health for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Structure {
+    Counters,
+    UserQuota,
+    GroupQuota,
+    ProjectQuota,
+    RtBitmap,
+    RtSummary,
+    QuotaCounts,
+    LinkCounts,
+    Superblock,
+    Agf,
+    Agfl,
+    Agi,
+    Bnobt,
+    Cntbt,
+    Inobt,
+    Finobt,
+    Rmapbt,
+    Refcountbt,
+    Inodes,
+    InodeCore,
+    DataFork,
+    AttrFork,
+    CowFork,
+    Directory,
+    Xattr,
+    Symlink,
+    Parent,
+    DirectoryTree,
+}
+
+const FS_TABLE: &[(u32, Structure)] = &[
+    (FS_COUNTERS, Structure::Counters),
+    (FS_UQUOTA, Structure::UserQuota),
+    (FS_GQUOTA, Structure::GroupQuota),
+    (FS_PQUOTA, Structure::ProjectQuota),
+    (FS_RT_BITMAP, Structure::RtBitmap),
+    (FS_RT_SUMMARY, Structure::RtSummary),
+    (FS_QUOTACHECK, Structure::QuotaCounts),
+    (FS_NLINKS, Structure::LinkCounts),
+];
+
+const AG_TABLE: &[(u32, Structure)] = &[
+    (AG_SB, Structure::Superblock),
+    (AG_AGF, Structure::Agf),
+    (AG_AGFL, Structure::Agfl),
+    (AG_AGI, Structure::Agi),
+    (AG_BNOBT, Structure::Bnobt),
+    (AG_CNTBT, Structure::Cntbt),
+    (AG_INOBT, Structure::Inobt),
+    (AG_FINOBT, Structure::Finobt),
+    (AG_RMAPBT, Structure::Rmapbt),
+    (AG_REFCNTBT, Structure::Refcountbt),
+    (AG_INODES, Structure::Inodes),
+];
+
+const INODE_TABLE: &[(u32, Structure)] = &[
+    (INODE_CORE, Structure::InodeCore),
+    (INODE_BMBTD, Structure::DataFork),
+    (INODE_BMBTA, Structure::AttrFork),
+    (INODE_BMBTC, Structure::CowFork),
+    (INODE_DIR, Structure::Directory),
+    (INODE_XATTR, Structure::Xattr),
+    (INODE_SYMLINK, Structure::Symlink),
+    (INODE_PARENT, Structure::Parent),
+    (INODE_DIRTREE, Structure::DirectoryTree),
+];
+
+/// Decoded sick and checked masks. Bits this crate doesn't know are
+/// dropped.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Status {
+    pub sick: Vec<Structure>,
+    pub checked: Vec<Structure>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AgHealth {
+    pub agno: u32,
+    pub status: Status,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InodeHealth {
+    pub ino: u64,
+    pub status: Status,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Health {
+    pub fs: Status,
+    /// Empty on kernels without `XFS_IOC_AG_GEOMETRY`.
+    pub ags: Vec<AgHealth>,
+    /// The sick inodes of AGs with `AG_INODES` set.
+    pub inodes: Vec<InodeHealth>,
+}
+
+impl Structure {
+    /// The name `xfs_spaceman -c health` uses.
+    pub fn name(self) -> &'static str {
+        match self {
+            Structure::Counters => "summary counters",
+            Structure::UserQuota => "user quota",
+            Structure::GroupQuota => "group quota",
+            Structure::ProjectQuota => "project quota",
+            Structure::RtBitmap => "realtime bitmap",
+            Structure::RtSummary => "realtime summary",
+            Structure::QuotaCounts => "quota counts",
+            Structure::LinkCounts => "inode link counts",
+            Structure::Superblock => "superblock",
+            Structure::Agf => "AGF header",
+            Structure::Agfl => "AGFL header",
+            Structure::Agi => "AGI header",
+            Structure::Bnobt => "free space by block btree",
+            Structure::Cntbt => "free space by length btree",
+            Structure::Inobt => "inode btree",
+            Structure::Finobt => "free inode btree",
+            Structure::Rmapbt => "reverse mappings btree",
+            Structure::Refcountbt => "reference count btree",
+            Structure::Inodes => "inodes",
+            Structure::InodeCore => "inode core",
+            Structure::DataFork => "data fork",
+            Structure::AttrFork => "extended attribute fork",
+            Structure::CowFork => "copy on write fork",
+            Structure::Directory => "directory",
+            Structure::Xattr => "extended attributes",
+            Structure::Symlink => "symbolic link target",
+            Structure::Parent => "parent pointers",
+            Structure::DirectoryTree => "directory tree structure",
+        }
+    }
+}
+
+impl fmt::Display for Structure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+fn decode(table: &[(u32, Structure)], mask: u32) -> Vec<Structure> {
+    table.iter().filter(|&&(bit, _)| mask & bit != 0).map(|&(_, structure)| structure).collect()
+}
+
+impl Status {
+    fn new(table: &[(u32, Structure)], sick: u32, checked: u32) -> Status {
+        Status { sick: decode(table, sick), checked: decode(table, checked) }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.sick.is_empty()
+    }
+}
+
+impl From<&Geometry> for Status {
+    fn from(geom: &Geometry) -> Status {
+        Status::new(FS_TABLE, geom.sick, geom.checked)
+    }
+}
+
+impl From<&AgGeometry> for AgHealth {
+    fn from(ag: &AgGeometry) -> AgHealth {
+        AgHealth { agno: ag.agno, status: Status::new(AG_TABLE, ag.sick, ag.checked) }
+    }
+}
+
+impl From<&Stat> for InodeHealth {
+    fn from(stat: &Stat) -> InodeHealth {
+        InodeHealth {
+            ino: stat.ino,
+            status: Status::new(INODE_TABLE, u32::from(stat.sick), u32::from(stat.checked)),
+        }
+    }
+}
+
+impl Health {
+    pub fn is_healthy(&self) -> bool {
+        self.fs.is_healthy() && self.ags.iter().all(|ag| ag.status.is_healthy()) &&
+            self.inodes.iter().all(|inode| inode.status.is_healthy())
+    }
+
+    /// AGs with any sick structure.
+    pub fn sick_ags(&self) -> impl Iterator<Item = u32> + '_ {
+        self.ags.iter().filter(|ag| !ag.status.is_healthy()).map(|ag| ag.agno)
+    }
+}
+
+/// Reads the health of the filesystem containing `path`, its AGs and the
+/// sick inodes in them.
+pub fn health<P: AsRef<Path>>(path: P) -> Result<Health, XfsError> {
+    let path = path.as_ref();
+    let geom = geometry::geometry(path)?;
+    let mut health = Health { fs: Status::from(&geom), ..Default::default() };
+
+    for agno in 0..geom.ag_count {
+        let ag = match geometry::ag_geometry(path, agno) {
+            Ok(ag) => ag,
+            Err(XfsError::Io(ref err)) if ioctl::unsupported(err) => break,
+            Err(err) => return Err(err),
+        };
+        health.ags.push(AgHealth::from(&ag));
+        if ag.sick & AG_INODES == 0 {
+            continue;
+        }
+        for stat in Bulkstat::resume(path, Cursor::ag(agno))? {
+            let stat = stat?;
+            if stat.sick != 0 {
+                health.inodes.push(InodeHealth::from(&stat));
+            }
+        }
+    }
+    Ok(health)
+}
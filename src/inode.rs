@@ -0,0 +1,720 @@
+//! Decoding of on-disk inodes (`struct xfs_dinode`, versions 2 and 3) and
+//! of data forks in extents format.
+
+use std::io::{Read, Seek};
+
+use nom::{be_u8, be_u16, be_u32, be_u64};
+
+use btree::Magic;
+use crc;
+use superblock::{self, Superblock, Uuid};
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use fixtures;
+    use superblock;
+    use XfsError;
+
+    fn regular_file(v5: bool) -> Vec<u8> {
+        let mut raw = fixtures::dinode(v5, 129, 0o100640);
+        fixtures::put_u32(&mut raw, 8, 1000);
+        fixtures::put_u32(&mut raw, 12, 100);
+        fixtures::put_u16(&mut raw, 20, 0x0007);
+        fixtures::put_u16(&mut raw, 22, 0x0001);
+        fixtures::put_u64(&mut raw, 56, 5 * 4096 + 17);
+        fixtures::put_u64(&mut raw, 64, 6);
+        fixtures::put_u32(&mut raw, 76, 3);
+        fixtures::put_u16(&mut raw, 90, 0x0200);
+        let fork = if v5 { 176 } else { 100 };
+        raw[fork..fork + 16].copy_from_slice(&fixtures::extent(0, 24, 2, false));
+        raw[fork + 16..fork + 32].copy_from_slice(&fixtures::extent(2, (1 << 6) | 40, 3, false));
+        raw[fork + 32..fork + 48].copy_from_slice(&fixtures::extent(8, 12, 1, true));
+        raw
+    }
+
+    #[test]
+    fn it_decodes_a_v3_inode() {
+        let mut raw = regular_file(true);
+        // bigtime: 2040-01-01T00:00:00.5Z
+        fixtures::put_u64(&mut raw, 120, 0x8);
+        fixtures::put_u64(&mut raw, 40, (2208988800 + (1 << 31)) * 1_000_000_000 + 500_000_000);
+        let inode = super::parse(&raw).unwrap();
+        assert_eq!(inode.version, 3);
+        assert_eq!(inode.file_type(), super::FileType::Regular);
+        assert_eq!(inode.mode & 0o7777, 0o640);
+        assert_eq!((inode.uid, inode.gid), (1000, 100));
+        assert_eq!(inode.project_id, 0x0001_0007);
+        assert_eq!(inode.size, 20497);
+        assert_eq!(inode.nblocks, 6);
+        assert_eq!(inode.nextents, 3);
+        assert_eq!(inode.format, super::Format::Extents);
+        assert_eq!(inode.flags, super::DIFLAG_PROJINHERIT);
+        assert!(inode.has_bigtime());
+        assert_eq!(inode.mtime, super::Timestamp { seconds: 2208988800, nanoseconds: 500_000_000 });
+        assert_eq!(inode.ino, 129);
+    }
+
+    #[test]
+    fn it_decodes_data_fork_extents() {
+        let inode = super::parse(&regular_file(true)).unwrap();
+        let extents = inode.extents().unwrap();
+        assert_eq!(extents, vec![
+            super::Extent { offset: 0, start_block: 24, length: 2, unwritten: false },
+            super::Extent { offset: 2, start_block: (1 << 6) | 40, length: 3, unwritten: false },
+            super::Extent { offset: 8, start_block: 12, length: 1, unwritten: true },
+        ]);
+    }
+
+    #[test]
+    fn it_decodes_a_v2_inode() {
+        let mut raw = regular_file(false);
+        fixtures::put_u32(&mut raw, 48, 0x8000_0000);
+        fixtures::put_u32(&mut raw, 52, 12);
+        let inode = super::parse(&raw).unwrap();
+        assert_eq!(inode.version, 2);
+        assert!(!inode.has_bigtime());
+        assert_eq!(inode.ctime, super::Timestamp { seconds: -(1 << 31), nanoseconds: 12 });
+        assert_eq!(inode.crtime, None);
+        assert_eq!(inode.extents().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn it_uses_large_extent_counters() {
+        let mut raw = regular_file(true);
+        fixtures::put_u64(&mut raw, 120, 0x10);
+        fixtures::put_u64(&mut raw, 24, 3);
+        fixtures::put_u32(&mut raw, 76, 2);
+        fixtures::put_u16(&mut raw, 80, 0);
+        let inode = super::parse(&raw).unwrap();
+        assert_eq!(inode.nextents, 3);
+        assert_eq!(inode.anextents, 2);
+    }
+
+    #[test]
+    fn it_reads_an_inode_from_an_image() {
+        let mut raw = fixtures::image(true);
+        fixtures::put_inode(&mut raw, true, 129, &regular_file(true));
+        let mut image = Cursor::new(raw);
+        let sb = superblock::read(&mut image).unwrap();
+        let inode = super::read(&mut image, &sb, 129).unwrap();
+        assert_eq!(inode.extents().unwrap()[1].length, 3);
+
+        let free = super::read(&mut image, &sb, fixtures::ino(1, 256 + 63)).unwrap();
+        assert_eq!(free.mode, 0);
+    }
+
+    #[test]
+    fn it_walks_a_btree_format_fork() {
+        for &v5 in &[true, false] {
+            let mut raw = fixtures::image(v5);
+            let mut inode = fixtures::dinode(v5, 131, 0o100600);
+            inode[5] = 3;
+            fixtures::put_u32(&mut inode, 76, 3);
+            let fork = if v5 { 176 } else { 100 };
+            fixtures::bmbt_root(&mut inode[fork..], 1, &[(0, (1 << 6) | 20), (5, (1 << 6) | 21)]);
+            fixtures::put_inode(&mut raw, v5, 131, &inode);
+            let first = [fixtures::extent(0, 40, 1, false), fixtures::extent(2, 42, 3, false)];
+            fixtures::put_block(&mut raw, (1 << 6) | 20, &fixtures::bmbt_leaf(v5, &first, (1 << 6) | 21));
+            let second = [fixtures::extent(5, 50, 2, true)];
+            fixtures::put_block(&mut raw, (1 << 6) | 21, &fixtures::bmbt_leaf(v5, &second, u64::MAX));
+
+            let mut image = Cursor::new(raw);
+            let sb = superblock::read(&mut image).unwrap();
+            let inode = super::read(&mut image, &sb, 131).unwrap();
+            assert_eq!(inode.format, super::Format::Btree);
+            assert!(inode.extents().is_err());
+            let extents = super::data_extents(&mut image, &sb, &inode).unwrap();
+            let offsets: Vec<u64> = extents.iter().map(|e| e.offset).collect();
+            assert_eq!(offsets, vec![0, 2, 5]);
+            assert!(extents[2].unwritten);
+            assert!(super::attr_extents(&mut image, &sb, &inode).unwrap().is_empty());
+            assert!(super::read_blocks(&mut image, &sb, &extents, 4, u64::MAX).is_err());
+
+            // A corrupt extent count doesn't size any allocation.
+            let mut inode = super::read(&mut image, &sb, 131).unwrap();
+            inode.nextents = u64::MAX;
+            assert_eq!(super::data_extents(&mut image, &sb, &inode).unwrap().len(), 3);
+        }
+    }
+
+    #[test]
+    fn it_rejects_a_damaged_inode() {
+        let mut raw = fixtures::image(true);
+        raw[fixtures::inode_offset(130) + 60] = 1;
+        let mut image = Cursor::new(raw);
+        let sb = superblock::read(&mut image).unwrap();
+        match super::read(&mut image, &sb, 130) {
+            Err(XfsError::BadChecksum) => {}
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// "IN"
+pub const MAGIC: u16 = 0x494e;
+
+/// Byte offset of `di_crc` within a v3 inode.
+pub const CRC_OFFSET: usize = 100;
+
+/// Size of the inode core, after which the forks start.
+pub const V2_CORE_SIZE: usize = 100;
+pub const V3_CORE_SIZE: usize = 176;
+
+pub const DIFLAG_REALTIME: u16 = 0x0001;
+pub const DIFLAG_PREALLOC: u16 = 0x0002;
+pub const DIFLAG_NEWRTBM: u16 = 0x0004;
+pub const DIFLAG_IMMUTABLE: u16 = 0x0008;
+pub const DIFLAG_APPEND: u16 = 0x0010;
+pub const DIFLAG_SYNC: u16 = 0x0020;
+pub const DIFLAG_NOATIME: u16 = 0x0040;
+pub const DIFLAG_NODUMP: u16 = 0x0080;
+pub const DIFLAG_RTINHERIT: u16 = 0x0100;
+pub const DIFLAG_PROJINHERIT: u16 = 0x0200;
+pub const DIFLAG_NOSYMLINKS: u16 = 0x0400;
+pub const DIFLAG_EXTSIZE: u16 = 0x0800;
+pub const DIFLAG_EXTSZINHERIT: u16 = 0x1000;
+pub const DIFLAG_NODEFRAG: u16 = 0x2000;
+pub const DIFLAG_FILESTREAM: u16 = 0x4000;
+
+pub const DIFLAG2_DAX: u64 = 0x01;
+pub const DIFLAG2_REFLINK: u64 = 0x02;
+pub const DIFLAG2_COWEXTSIZE: u64 = 0x04;
+pub const DIFLAG2_BIGTIME: u64 = 0x08;
+pub const DIFLAG2_NREXT64: u64 = 0x10;
+
+/// Seconds between the bigtime epoch (the smallest classic timestamp, in
+/// December 1901) and the Unix epoch.
+const BIGTIME_EPOCH_OFFSET: i64 = 1 << 31;
+
+/// Block map btree blocks, "BMAP" / "BMA3".
+pub const BMAP_MAGIC: Magic = Magic { v4: 0x424d4150, v5: 0x424d4133 };
+
+/// Marks a missing long-form sibling pointer.
+pub const NULL_FSBLOCK: u64 = u64::MAX;
+
+/// How a fork stores its contents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Device number, for character and block devices.
+    Device,
+    /// Data held inside the inode: short-form directories and attributes,
+    /// short symlinks.
+    Local,
+    /// An array of extent records inside the inode.
+    Extents,
+    /// The root of a block map btree inside the inode.
+    Btree,
+    Uuid,
+    Unknown(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    /// A free inode, or a mode we don't recognise.
+    Unknown,
+}
+
+/// A point in time as seconds and nanoseconds since the Unix epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub seconds: i64,
+    pub nanoseconds: u32,
+}
+
+/// One mapping of the data or attribute fork.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    /// Offset into the file in filesystem blocks.
+    pub offset: u64,
+    /// First filesystem block (AG number in the high bits).
+    pub start_block: u64,
+    /// Length in filesystem blocks.
+    pub length: u64,
+    /// Allocated but not yet written; reads return zeroes.
+    pub unwritten: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Dinode {
+    /// Magic number, "IN".
+    pub magic: u16,
+    /// File type and permission bits.
+    pub mode: u16,
+    /// Inode version, 2 or 3 (v5 filesystems).
+    pub version: u8,
+    /// Format of the data fork.
+    pub format: Format,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub project_id: u32,
+    /// Flush counter (v2).
+    pub flush_iter: u16,
+    /// Last access.
+    pub atime: Timestamp,
+    /// Last data modification.
+    pub mtime: Timestamp,
+    /// Last status change.
+    pub ctime: Timestamp,
+    /// Creation (v3).
+    pub crtime: Option<Timestamp>,
+    /// File size in bytes.
+    pub size: u64,
+    /// Blocks in use, including the block map btree and attributes.
+    pub nblocks: u64,
+    /// Extent size hint in blocks.
+    pub extsize: u32,
+    /// Number of data fork extents.
+    pub nextents: u64,
+    /// Number of attribute fork extents.
+    pub anextents: u32,
+    /// Offset of the attribute fork in the literal area, in 8-byte units;
+    /// zero if there is no attribute fork.
+    pub forkoff: u8,
+    /// Format of the attribute fork.
+    pub aformat: Format,
+    pub dmevmask: u32,
+    pub dmstate: u16,
+    /// `DIFLAG_*` bits.
+    pub flags: u16,
+    /// Generation number.
+    pub gen: u32,
+    /// Next inode in the unlinked bucket chain.
+    pub next_unlinked: u32,
+    /// Inode checksum (v3), as stored.
+    pub crc: u32,
+    /// Number of attribute changes (v3).
+    pub change_count: u64,
+    /// Log sequence number of the last flush (v3).
+    pub lsn: u64,
+    /// `DIFLAG2_*` bits (v3).
+    pub flags2: u64,
+    /// Copy-on-write extent size hint in blocks (v3).
+    pub cow_extsize: u32,
+    /// Inode number, as stored in v3 inodes.
+    pub ino: u64,
+    /// Filesystem UUID (v3).
+    pub uuid: Uuid,
+    /// The literal area following the core, holding both forks.
+    pub literal: Vec<u8>,
+}
+
+/// Layout of the core as laid out on disk, before the fields whose meaning
+/// depends on the version and feature flags are resolved.
+struct RawCore {
+    magic: u16,
+    mode: u16,
+    version: u8,
+    format: u8,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    projid_lo: u16,
+    projid_hi: u16,
+    big_nextents: u64,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    size: u64,
+    nblocks: u64,
+    extsize: u32,
+    nextents: u32,
+    anextents: u16,
+    forkoff: u8,
+    aformat: u8,
+    dmevmask: u32,
+    dmstate: u16,
+    flags: u16,
+    gen: u32,
+    next_unlinked: u32,
+}
+
+struct RawV3 {
+    crc: u32,
+    change_count: u64,
+    lsn: u64,
+    flags2: u64,
+    cow_extsize: u32,
+    crtime: u64,
+    ino: u64,
+    uuid: Uuid,
+}
+
+impl From<u8> for Format {
+    fn from(format: u8) -> Format {
+        match format {
+            0 => Format::Device,
+            1 => Format::Local,
+            2 => Format::Extents,
+            3 => Format::Btree,
+            4 => Format::Uuid,
+            other => Format::Unknown(other),
+        }
+    }
+}
+
+impl Timestamp {
+    fn decode(raw: u64, bigtime: bool) -> Timestamp {
+        if bigtime {
+            Timestamp {
+                seconds: (raw / 1_000_000_000) as i64 - BIGTIME_EPOCH_OFFSET,
+                nanoseconds: (raw % 1_000_000_000) as u32,
+            }
+        } else {
+            Timestamp {
+                seconds: i64::from((raw >> 32) as u32 as i32),
+                nanoseconds: raw as u32,
+            }
+        }
+    }
+}
+
+impl Extent {
+    /// Decodes a packed 128 bit block map record (`xfs_bmbt_rec`).
+    pub fn parse(rec: &[u8]) -> Extent {
+        let l0 = be64(rec, 0);
+        let l1 = be64(rec, 8);
+        Extent {
+            offset: (l0 >> 9) & ((1 << 54) - 1),
+            start_block: ((l0 & 0x1ff) << 43) | (l1 >> 21),
+            length: l1 & ((1 << 21) - 1),
+            unwritten: l0 >> 63 == 1,
+        }
+    }
+}
+
+impl FileType {
+    /// The file type bits of a `st_mode`-style mode.
+    pub fn from_mode(mode: u16) -> FileType {
+        match mode & 0o170000 {
+            0o100000 => FileType::Regular,
+            0o040000 => FileType::Directory,
+            0o120000 => FileType::Symlink,
+            0o020000 => FileType::CharDevice,
+            0o060000 => FileType::BlockDevice,
+            0o010000 => FileType::Fifo,
+            0o140000 => FileType::Socket,
+            _ => FileType::Unknown,
+        }
+    }
+}
+
+impl Dinode {
+    pub fn file_type(&self) -> FileType {
+        FileType::from_mode(self.mode)
+    }
+
+    pub fn has_bigtime(&self) -> bool {
+        self.flags2 & DIFLAG2_BIGTIME != 0
+    }
+
+    /// The data fork: the literal area up to the attribute fork.
+    pub fn data_fork(&self) -> &[u8] {
+        if self.forkoff == 0 {
+            &self.literal
+        } else {
+            &self.literal[..(self.forkoff as usize * 8).min(self.literal.len())]
+        }
+    }
+
+    /// The attribute fork, if there is one.
+    pub fn attr_fork(&self) -> Option<&[u8]> {
+        if self.forkoff == 0 {
+            None
+        } else {
+            Some(&self.literal[(self.forkoff as usize * 8).min(self.literal.len())..])
+        }
+    }
+
+    /// The data fork mappings of an extents format inode, in file offset
+    /// order.
+    pub fn extents(&self) -> Result<Vec<Extent>, XfsError> {
+        if self.format != Format::Extents {
+            return Err(XfsError::Parse);
+        }
+        fork_extents(self.data_fork(), self.nextents)
+    }
+}
+
+/// Decodes `count` packed extent records from the start of a fork.
+pub fn fork_extents(fork: &[u8], count: u64) -> Result<Vec<Extent>, XfsError> {
+    if count.saturating_mul(16) > fork.len() as u64 {
+        return Err(XfsError::Parse);
+    }
+    Ok(fork.chunks(16).take(count as usize).map(Extent::parse).collect())
+}
+
+/// Decodes the mappings of a fork in extents or btree format. For the
+/// btree format `fork` holds the root (`struct xfs_bmdr_block`) and the
+/// rest of the tree is read from `dev`, leftmost path down and then along
+/// the leaves.
+pub fn fork_mappings<R>(dev: &mut R, sb: &Superblock, format: Format, fork: &[u8], count: u64)
+                        -> Result<Vec<Extent>, XfsError>
+    where R: Read + Seek
+{
+    match format {
+        Format::Extents => fork_extents(fork, count),
+        Format::Btree => {
+            if fork.len() < 4 + 16 {
+                return Err(XfsError::Parse);
+            }
+            let level = u16::from_be_bytes([fork[0], fork[1]]);
+            if level == 0 || fork[2..4] == [0, 0] {
+                return Err(XfsError::Parse);
+            }
+            let maxrecs = (fork.len() - 4) / 16;
+            let mut fsbno = be64(fork, 4 + maxrecs * 8);
+            let block_size = sb.block_size as usize;
+            let hdr = if sb.has_crc() { 72 } else { 24 };
+            let limit = sb.data_blocks;
+            let in_range = |fsbno: u64| fsbno >> sb.ag_blocks_log < u64::from(sb.ag_count);
+
+            for level in (1..level).rev() {
+                if !in_range(fsbno) {
+                    return Err(XfsError::Parse);
+                }
+                let block = superblock::read_at(dev, sb.fsb_offset(fsbno), block_size)?;
+                if !BMAP_MAGIC.matches(sb, ::btree::be32(&block, 0)) {
+                    return Err(XfsError::BadMagic);
+                }
+                if u16::from_be_bytes([block[4], block[5]]) != level {
+                    return Err(XfsError::Parse);
+                }
+                fsbno = be64(&block, hdr + (block_size - hdr) / 16 * 8);
+            }
+
+            // The count is on-disk and unchecked, so let the leaves size this.
+            let mut extents = vec![];
+            let mut visited = 0;
+            while fsbno != NULL_FSBLOCK {
+                visited += 1;
+                if visited > limit || !in_range(fsbno) {
+                    return Err(XfsError::Parse);
+                }
+                let block = superblock::read_at(dev, sb.fsb_offset(fsbno), block_size)?;
+                if !BMAP_MAGIC.matches(sb, ::btree::be32(&block, 0)) {
+                    return Err(XfsError::BadMagic);
+                }
+                let numrecs = u16::from_be_bytes([block[6], block[7]]) as usize;
+                if block[4..6] != [0, 0] || hdr + numrecs * 16 > block_size {
+                    return Err(XfsError::Parse);
+                }
+                extents.extend(block[hdr..hdr + numrecs * 16].chunks(16).map(Extent::parse));
+                fsbno = be64(&block, 16);
+            }
+            Ok(extents)
+        }
+        _ => Err(XfsError::Parse),
+    }
+}
+
+/// The data fork mappings of an extents or btree format inode.
+pub fn data_extents<R: Read + Seek>(dev: &mut R, sb: &Superblock, inode: &Dinode) -> Result<Vec<Extent>, XfsError> {
+    fork_mappings(dev, sb, inode.format, inode.data_fork(), inode.nextents)
+}
+
+/// The attribute fork mappings of an extents or btree format inode.
+pub fn attr_extents<R: Read + Seek>(dev: &mut R, sb: &Superblock, inode: &Dinode) -> Result<Vec<Extent>, XfsError> {
+    match inode.attr_fork() {
+        Some(fork) => fork_mappings(dev, sb, inode.aformat, fork, u64::from(inode.anextents)),
+        None => Ok(vec![]),
+    }
+}
+
+/// Reads `count` filesystem blocks of a fork starting at file offset
+/// `first`, through the fork's mappings. The range may span several
+/// mappings but must not cross a hole.
+pub fn read_blocks<R>(dev: &mut R, sb: &Superblock, extents: &[Extent], first: u64, count: u64)
+                      -> Result<Vec<u8>, XfsError>
+    where R: Read + Seek
+{
+    let block_size = sb.block_size as usize;
+    let end = first.checked_add(count).ok_or(XfsError::Parse)?;
+    let mut blocks = vec![];
+    for offset in first..end {
+        let extent = extents.iter()
+            .find(|e| e.offset <= offset && offset < e.offset + e.length)
+            .ok_or(XfsError::Parse)?;
+        let fsbno = extent.start_block + (offset - extent.offset);
+        blocks.extend(superblock::read_at(dev, sb.fsb_offset(fsbno), block_size)?);
+    }
+    Ok(blocks)
+}
+
+pub(crate) fn be64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Decodes a whole inode: the core and the literal area after it. The
+/// checksum is not verified here; see `read`.
+pub fn parse(input: &[u8]) -> Result<Dinode, XfsError> {
+    let (rest, raw) = match core(input) {
+        nom::IResult::Done(rest, raw) => (rest, raw),
+        nom::IResult::Error(_) => return Err(XfsError::Parse),
+        nom::IResult::Incomplete(_) => return Err(XfsError::Incomplete),
+    };
+    if raw.magic != MAGIC {
+        return Err(XfsError::BadMagic);
+    }
+    let v3 = if raw.version >= 3 {
+        match core_v3(rest) {
+            nom::IResult::Done(_, v3) => Some(v3),
+            nom::IResult::Error(_) => return Err(XfsError::Parse),
+            nom::IResult::Incomplete(_) => return Err(XfsError::Incomplete),
+        }
+    } else {
+        None
+    };
+    let flags2 = v3.as_ref().map_or(0, |v3| v3.flags2);
+    let bigtime = flags2 & DIFLAG2_BIGTIME != 0;
+    let (nextents, anextents) = if flags2 & DIFLAG2_NREXT64 != 0 {
+        (raw.big_nextents, raw.nextents)
+    } else {
+        (u64::from(raw.nextents), u32::from(raw.anextents))
+    };
+    let core_size = if v3.is_some() { V3_CORE_SIZE } else { V2_CORE_SIZE };
+
+    Ok(Dinode {
+        magic: raw.magic,
+        mode: raw.mode,
+        version: raw.version,
+        format: Format::from(raw.format),
+        nlink: raw.nlink,
+        uid: raw.uid,
+        gid: raw.gid,
+        project_id: u32::from(raw.projid_hi) << 16 | u32::from(raw.projid_lo),
+        flush_iter: if v3.is_some() { 0 } else { raw.big_nextents as u16 },
+        atime: Timestamp::decode(raw.atime, bigtime),
+        mtime: Timestamp::decode(raw.mtime, bigtime),
+        ctime: Timestamp::decode(raw.ctime, bigtime),
+        crtime: v3.as_ref().map(|v3| Timestamp::decode(v3.crtime, bigtime)),
+        size: raw.size,
+        nblocks: raw.nblocks,
+        extsize: raw.extsize,
+        nextents,
+        anextents,
+        forkoff: raw.forkoff,
+        aformat: Format::from(raw.aformat),
+        dmevmask: raw.dmevmask,
+        dmstate: raw.dmstate,
+        flags: raw.flags,
+        gen: raw.gen,
+        next_unlinked: raw.next_unlinked,
+        crc: v3.as_ref().map_or(0, |v3| v3.crc),
+        change_count: v3.as_ref().map_or(0, |v3| v3.change_count),
+        lsn: v3.as_ref().map_or(0, |v3| v3.lsn),
+        flags2,
+        cow_extsize: v3.as_ref().map_or(0, |v3| v3.cow_extsize),
+        ino: v3.as_ref().map_or(0, |v3| v3.ino),
+        uuid: v3.as_ref().map_or(Uuid([0; 16]), |v3| v3.uuid),
+        literal: input[core_size.min(input.len())..].to_vec(),
+    })
+}
+
+/// Reads inode `ino` and, on v5 filesystems, verifies its checksum.
+pub fn read<R: Read + Seek>(dev: &mut R, sb: &Superblock, ino: u64) -> Result<Dinode, XfsError> {
+    let raw = superblock::read_at(dev, sb.ino_offset(ino), sb.inode_size as usize)?;
+    let inode = parse(&raw)?;
+    if inode.version >= 3 && !crc::verify(&raw, CRC_OFFSET) {
+        return Err(XfsError::BadChecksum);
+    }
+    Ok(inode)
+}
+
+named!(core <RawCore>,
+    chain!(
+        magic: be_u16 ~
+        mode: be_u16 ~
+        version: be_u8 ~
+        format: be_u8 ~
+        be_u16 ~
+        uid: be_u32 ~
+        gid: be_u32 ~
+        nlink: be_u32 ~
+        projid_lo: be_u16 ~
+        projid_hi: be_u16 ~
+        big_nextents: be_u64 ~
+        atime: be_u64 ~
+        mtime: be_u64 ~
+        ctime: be_u64 ~
+        size: be_u64 ~
+        nblocks: be_u64 ~
+        extsize: be_u32 ~
+        nextents: be_u32 ~
+        anextents: be_u16 ~
+        forkoff: be_u8 ~
+        aformat: be_u8 ~
+        dmevmask: be_u32 ~
+        dmstate: be_u16 ~
+        flags: be_u16 ~
+        gen: be_u32 ~
+        next_unlinked: be_u32,
+        || {
+            RawCore {
+                magic,
+                mode,
+                version,
+                format,
+                nlink,
+                uid,
+                gid,
+                projid_lo,
+                projid_hi,
+                big_nextents,
+                atime,
+                mtime,
+                ctime,
+                size,
+                nblocks,
+                extsize,
+                nextents,
+                anextents,
+                forkoff,
+                aformat,
+                dmevmask,
+                dmstate,
+                flags,
+                gen,
+                next_unlinked,
+            }
+        }
+    )
+);
+
+named!(core_v3 <RawV3>,
+    chain!(
+        crc: take!(4) ~
+        change_count: be_u64 ~
+        lsn: be_u64 ~
+        flags2: be_u64 ~
+        cow_extsize: be_u32 ~
+        take!(12) ~
+        crtime: be_u64 ~
+        ino: be_u64 ~
+        uuid: take!(16),
+        || {
+            RawV3 {
+                crc: crc::stored_crc(crc, 0),
+                change_count,
+                lsn,
+                flags2,
+                cow_extsize,
+                crtime,
+                ino,
+                uuid: Uuid::from_slice(uuid),
+            }
+        }
+    )
+);
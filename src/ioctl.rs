@@ -0,0 +1,73 @@
+//! Request numbers and a checked wrapper for the XFS ioctls.
+//!
+//! Request numbers follow the generic Linux `_IOC` encoding used on x86,
+//! arm and most other architectures.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use libc;
+
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn it_encodes_request_numbers() {
+        assert_eq!(super::ior(126, 256), 0x8100587e);
+        assert_eq!(super::iowr(56, 32), 0xc0205838);
+        assert_eq!(super::iow(32, 28), 0x401c5820);
+    }
+}
+
+const IOC_WRITE: u64 = 1;
+const IOC_READ: u64 = 2;
+
+/// The ioctl type shared by all XFS requests.
+const XFS_TYPE: u64 = b'X' as u64;
+
+const fn ioc(dir: u64, nr: u64, size: usize) -> u64 {
+    dir << 30 | (size as u64) << 16 | XFS_TYPE << 8 | nr
+}
+
+/// `_IOR('X', nr, size)`: the kernel fills in the argument.
+pub const fn ior(nr: u64, size: usize) -> u64 {
+    ioc(IOC_READ, nr, size)
+}
+
+/// `_IOW('X', nr, size)`: the kernel reads the argument.
+pub const fn iow(nr: u64, size: usize) -> u64 {
+    ioc(IOC_WRITE, nr, size)
+}
+
+/// `_IOWR('X', nr, size)`: the argument goes both ways.
+pub const fn iowr(nr: u64, size: usize) -> u64 {
+    ioc(IOC_READ | IOC_WRITE, nr, size)
+}
+
+/// Opens any file or directory on the filesystem to issue ioctls against.
+pub fn open<P: AsRef<Path>>(path: P) -> Result<File, XfsError> {
+    Ok(File::open(path)?)
+}
+
+/// Issues `request` with `arg` against an open file.
+///
+/// # Safety
+///
+/// `arg` must point to memory laid out the way the request expects.
+pub unsafe fn ioctl<T>(file: &File, request: u64, arg: *mut T) -> io::Result<libc::c_int> {
+    let ret = libc::ioctl(file.as_raw_fd(), request as _, arg);
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Whether an ioctl failed because the kernel doesn't know it, so an older
+/// variant may be tried instead.
+pub fn unsupported(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ENOTTY) | Some(libc::EINVAL) | Some(libc::EOPNOTSUPP))
+}
@@ -0,0 +1,374 @@
+//! XFS messages from the kernel log, in the `/dev/kmsg` record format or
+//! as `dmesg` prints them, and a follower that turns new records into
+//! typed events.
+//!
+//! Only the messages XFS prints through `xfs_alert`, `xfs_warn` and
+//! friends are picked up: they all start with `XFS (<device>):` or, before
+//! a mount is set up, `XFS:`.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+
+use libc;
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{Class, Severity};
+
+    #[test]
+    fn it_parses_kmsg_records() {
+        let msg = super::parse_kmsg("2,1234,5678901,-;XFS (sda1): Metadata corruption detected at \
+                                     xfs_dir3_block_read_verify+0x9c/0xb0 [xfs], xfs_dir3_block block 0x1c0 ")
+            .unwrap();
+        assert_eq!((msg.sequence, msg.timestamp_us), (Some(1234), Some(5678901)));
+        assert_eq!(msg.severity, Some(Severity::Critical));
+        assert_eq!(msg.device, Some("sda1".to_string()));
+        assert_eq!(msg.class, Class::MetadataCorruption);
+        assert_eq!(msg.function, Some("xfs_dir3_block_read_verify".to_string()));
+        assert_eq!(msg.block, Some(0x1c0));
+
+        // Facility bits above the level are ignored.
+        let msg = super::parse_kmsg("14,5,6,-;XFS (dm-0): Mounting V5 Filesystem").unwrap();
+        assert_eq!((msg.severity, msg.class), (Some(Severity::Info), Class::Mount));
+        assert!(super::parse_kmsg("6,1,2,-;EXT4-fs (sda2): mounted filesystem").is_none());
+        assert!(super::parse_kmsg("not a record").is_none());
+    }
+
+    #[test]
+    fn it_parses_dmesg_lines() {
+        let msg = super::parse_dmesg("[  123.456789] XFS (sdb): Corruption of in-memory data (0x8) detected at \
+                                      xfs_trans_cancel+0x14c/0x170 [xfs] (fs/xfs/xfs_trans.c:1097).  \
+                                      Shutting down filesystem.").unwrap();
+        assert_eq!(msg.timestamp_us, Some(123456789));
+        assert_eq!(msg.severity, None);
+        assert_eq!(msg.class, Class::InMemoryCorruption);
+        assert_eq!(msg.function, Some("xfs_trans_cancel".to_string()));
+        assert_eq!(msg.file, Some("fs/xfs/xfs_trans.c".to_string()));
+        assert_eq!(msg.line, Some(1097));
+
+        let msg = super::parse_dmesg("XFS (sdb): log I/O error -5").unwrap();
+        assert_eq!((msg.timestamp_us, msg.class), (None, Class::LogIoError));
+
+        let msg = super::parse_dmesg("XFS (sdb): metadata I/O error in \"xfs_trans_read_buf_map\" at daddr 0x2a8 \
+                                      len 8 error 74").unwrap();
+        assert_eq!(msg.class, Class::MetadataIoError);
+        assert_eq!((msg.function, msg.block), (Some("xfs_trans_read_buf_map".to_string()), Some(0x2a8)));
+
+        let msg = super::parse_dmesg("XFS: possible memory allocation deadlock size 65552 in kmem_alloc \
+                                      (mode:0x250)").unwrap();
+        assert_eq!((msg.device, msg.class), (None, Class::AllocationDeadlock));
+        assert_eq!(msg.function, Some("kmem_alloc".to_string()));
+
+        let msg = super::parse_dmesg("XFS (sdc): Internal error XFS_WANT_CORRUPTED_GOTO at line 1423 of file \
+                                      fs/xfs/libxfs/xfs_alloc.c.  Caller xfs_free_ag_extent+0x3e4/0x7a0 [xfs]")
+            .unwrap();
+        assert_eq!(msg.class, Class::InternalError);
+        assert_eq!((msg.line, msg.file), (Some(1423), Some("fs/xfs/libxfs/xfs_alloc.c".to_string())));
+        assert_eq!(msg.function, Some("xfs_free_ag_extent".to_string()));
+
+        let msg = super::parse_dmesg("XFS (sdc): xfs_do_force_shutdown(0x2) called from line 1196 of file \
+                                      fs/xfs/xfs_log.c. Return address = 0xffffffffc0a1b2c3").unwrap();
+        assert_eq!((msg.class, msg.line), (Class::Shutdown, Some(1196)));
+        assert_eq!(super::parse_dmesg("XFS (sdc): Metadata CRC error detected at xfs_agf_read_verify+0x1/0x2 \
+                                       [xfs], xfs_agf block 0x1").unwrap().class, Class::MetadataCrcError);
+        assert!(super::parse_dmesg("[    1.000000] usb 1-1: new device").is_none());
+    }
+
+    #[test]
+    fn it_follows_a_log() {
+        let log = "6,1,100,-;XFS (sda1): Mounting V5 Filesystem\n \
+                   SUBSYSTEM=block\n\
+                   6,2,200,-;EXT4-fs (sda2): mounted\n\
+                   1,3,300,-;XFS (sda1): log I/O error -5\n";
+        let classes: Vec<_> = super::Follower::new(Cursor::new(log)).map(|msg| msg.unwrap().class).collect();
+        assert_eq!(classes, vec![Class::Mount, Class::LogIoError]);
+    }
+}
+
+/// Kernel log levels, most severe first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Severity {
+    Emergency,
+    Alert,
+    Critical,
+    Error,
+    Warning,
+    Notice,
+    Info,
+    Debug,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Class {
+    /// A metadata block failed verification when read or written.
+    MetadataCorruption,
+    /// A metadata block failed its CRC check.
+    MetadataCrcError,
+    /// An in-memory structure was found inconsistent; the filesystem shuts
+    /// down.
+    InMemoryCorruption,
+    /// A consistency check in the code failed (`XFS_WANT_CORRUPTED_*`).
+    InternalError,
+    MetadataIoError,
+    LogIoError,
+    /// A memory allocation has been retrying for a long time.
+    AllocationDeadlock,
+    Shutdown,
+    Mount,
+    Unmount,
+    Recovery,
+    Other,
+}
+
+/// One XFS message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    /// Record sequence number; kmsg only.
+    pub sequence: Option<u64>,
+    /// Microseconds since boot.
+    pub timestamp_us: Option<u64>,
+    /// kmsg only.
+    pub severity: Option<Severity>,
+    /// The device name, such as `sda1` or `dm-3`.
+    pub device: Option<String>,
+    pub class: Class,
+    /// The function that detected the problem.
+    pub function: Option<String>,
+    /// Source file and line, when the message names them.
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    /// Disk address, in 512-byte units, of the block involved.
+    pub block: Option<u64>,
+    /// The message after the `XFS (<device>):` prefix.
+    pub text: String,
+}
+
+/// Reads kernel log records, in either format, and yields the XFS ones.
+pub struct Follower<R> {
+    reader: R,
+    line: String,
+}
+
+impl From<u8> for Severity {
+    fn from(level: u8) -> Severity {
+        match level & 7 {
+            0 => Severity::Emergency,
+            1 => Severity::Alert,
+            2 => Severity::Critical,
+            3 => Severity::Error,
+            4 => Severity::Warning,
+            5 => Severity::Notice,
+            6 => Severity::Info,
+            _ => Severity::Debug,
+        }
+    }
+}
+
+/// The text after `marker`, if present.
+fn after<'a>(text: &'a str, marker: &str) -> Option<&'a str> {
+    text.find(marker).map(|pos| &text[pos + marker.len()..])
+}
+
+/// A symbol as printed by `%pS`, such as `xfs_trans_cancel+0x14c/0x170`,
+/// without the offset.
+fn symbol(text: &str) -> Option<String> {
+    let end = text.find(|c: char| c == '+' || c.is_whitespace()).unwrap_or(text.len());
+    if end == 0 {
+        None
+    } else {
+        Some(text[..end].to_string())
+    }
+}
+
+fn quoted(text: &str) -> Option<String> {
+    let start = text.find('"')? + 1;
+    let len = text[start..].find('"')?;
+    Some(text[start..start + len].to_string())
+}
+
+fn number<T: ::std::str::FromStr>(text: &str) -> Option<T> {
+    let end = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    text[..end].parse().ok()
+}
+
+fn hex(text: &str) -> Option<u64> {
+    let text = text.trim_start_matches("0x");
+    let end = text.find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(text.len());
+    u64::from_str_radix(&text[..end], 16).ok()
+}
+
+/// `at line <line> of file <file>`.
+fn line_of_file(text: &str) -> Option<(u32, String)> {
+    let rest = after(text, "line ")?;
+    let line = number(rest)?;
+    let file = after(rest, "of file ")?;
+    let end = file.find(|c: char| c.is_whitespace()).unwrap_or(file.len());
+    Some((line, file[..end].trim_end_matches('.').to_string()))
+}
+
+/// `(fs/xfs/xfs_trans.c:1097)`.
+fn file_colon_line(text: &str) -> Option<(u32, String)> {
+    let start = text.find("(fs/")? + 1;
+    let end = start + text[start..].find(')')?;
+    let (file, line) = text[start..end].split_at(text[start..end].rfind(':')?);
+    Some((number(&line[1..])?, file.to_string()))
+}
+
+/// Classifies a message and pulls out what it says about where.
+fn classify(text: &str, msg: &mut Message) {
+    let location = if text.starts_with("Metadata corruption detected") {
+        msg.class = Class::MetadataCorruption;
+        None
+    } else if text.starts_with("Metadata CRC error detected") {
+        msg.class = Class::MetadataCrcError;
+        None
+    } else if text.starts_with("Corruption of in-memory data") {
+        msg.class = Class::InMemoryCorruption;
+        file_colon_line(text)
+    } else if text.starts_with("Internal error") {
+        msg.class = Class::InternalError;
+        msg.function = after(text, "Caller ").and_then(symbol);
+        line_of_file(text)
+    } else if text.starts_with("metadata I/O error") {
+        msg.class = Class::MetadataIoError;
+        msg.function = quoted(text);
+        msg.block = after(text, "daddr ").or_else(|| after(text, "block ")).and_then(hex);
+        None
+    } else if text.starts_with("log I/O error") {
+        msg.class = Class::LogIoError;
+        None
+    } else if text.starts_with("possible memory allocation deadlock") {
+        msg.class = Class::AllocationDeadlock;
+        msg.function = after(text, " in ").and_then(symbol);
+        None
+    } else if text.starts_with("xfs_do_force_shutdown") || text.contains("has been shut down") {
+        msg.class = Class::Shutdown;
+        line_of_file(text)
+    } else if text.starts_with("Mounting") || text.starts_with("Ending clean mount") {
+        msg.class = Class::Mount;
+        None
+    } else if text.starts_with("Unmounting") {
+        msg.class = Class::Unmount;
+        None
+    } else if text.starts_with("Starting recovery") || text.starts_with("Ending recovery") {
+        msg.class = Class::Recovery;
+        None
+    } else {
+        None
+    };
+    if let Some((line, file)) = location {
+        msg.line = Some(line);
+        msg.file = Some(file);
+    }
+    if msg.class == Class::MetadataCorruption || msg.class == Class::MetadataCrcError ||
+       msg.class == Class::InMemoryCorruption {
+        msg.function = after(text, "detected at ").and_then(symbol);
+        msg.block = after(text, " block ").and_then(hex);
+    }
+}
+
+/// Parses the message part of a log line if it is an XFS one.
+fn parse_message(text: &str) -> Option<Message> {
+    let (device, text) = if let Some(rest) = text.strip_prefix("XFS (") {
+        let end = rest.find("): ")?;
+        (Some(rest[..end].to_string()), &rest[end + 3..])
+    } else {
+        (None, text.strip_prefix("XFS: ")?)
+    };
+    let text = text.trim_end();
+    let mut msg = Message {
+        sequence: None,
+        timestamp_us: None,
+        severity: None,
+        device,
+        class: Class::Other,
+        function: None,
+        file: None,
+        line: None,
+        block: None,
+        text: text.to_string(),
+    };
+    classify(text, &mut msg);
+    Some(msg)
+}
+
+/// Parses a `/dev/kmsg` record: `<priority>,<sequence>,<usec>,<flags>;`
+/// followed by the message. Continuation lines are ignored.
+pub fn parse_kmsg(record: &str) -> Option<Message> {
+    let line = record.lines().next()?;
+    let semi = line.find(';')?;
+    let mut fields = line[..semi].split(',');
+    let priority: u32 = fields.next()?.parse().ok()?;
+    let sequence = fields.next()?.parse().ok()?;
+    let timestamp = fields.next()?.parse().ok()?;
+    let mut msg = parse_message(&line[semi + 1..])?;
+    msg.sequence = Some(sequence);
+    msg.timestamp_us = Some(timestamp);
+    msg.severity = Some(Severity::from((priority & 7) as u8));
+    Some(msg)
+}
+
+/// Parses a line of `dmesg` output, with or without the `[seconds]`
+/// timestamp.
+pub fn parse_dmesg(line: &str) -> Option<Message> {
+    let (timestamp, text) = match line.strip_prefix('[') {
+        Some(rest) => {
+            let end = rest.find(']')?;
+            let seconds: f64 = rest[..end].trim().parse().ok()?;
+            (Some((seconds * 1e6).round() as u64), rest[end + 1..].trim_start())
+        }
+        None => (None, line),
+    };
+    let mut msg = parse_message(text)?;
+    msg.timestamp_us = timestamp;
+    Some(msg)
+}
+
+impl<R: BufRead> Follower<R> {
+    pub fn new(reader: R) -> Follower<R> {
+        Follower { reader, line: String::new() }
+    }
+}
+
+impl Follower<BufReader<File>> {
+    /// Follows `/dev/kmsg`, starting with the records already in the ring
+    /// buffer if `from_start`, else with new ones only. Reading it may need
+    /// `CAP_SYSLOG`, depending on `kernel.dmesg_restrict`.
+    pub fn open(from_start: bool) -> Result<Follower<BufReader<File>>, XfsError> {
+        let mut file = File::open("/dev/kmsg")?;
+        if !from_start {
+            file.seek(SeekFrom::End(0))?;
+        }
+        Ok(Follower::new(BufReader::new(file)))
+    }
+}
+
+impl<R: BufRead> Iterator for Follower<R> {
+    type Item = Result<Message, XfsError>;
+
+    /// Blocks until the next XFS message when following `/dev/kmsg`.
+    fn next(&mut self) -> Option<Result<Message, XfsError>> {
+        loop {
+            self.line.clear();
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                // Records were overwritten before we read them.
+                Err(ref err) if err.raw_os_error() == Some(libc::EPIPE) => continue,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Some(Err(XfsError::Io(err))),
+            }
+            // Continuation lines of a kmsg record carry key=value pairs.
+            if self.line.starts_with(' ') {
+                continue;
+            }
+            let line = self.line.trim_end();
+            if let Some(msg) = parse_kmsg(line).or_else(|| parse_dmesg(line)) {
+                return Some(Ok(msg));
+            }
+        }
+    }
+}
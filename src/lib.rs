@@ -1,4 +1,5 @@
-#![recursion_limit="100"]
+#![recursion_limit="256"]
+extern crate libc;
 #[macro_use]
 extern crate nom;
 
@@ -11,6 +12,41 @@ use std::str::FromStr;
 
 use self::nom::{le_u8, is_digit, space, newline};
 
+pub mod ag;
+pub mod bmap;
+pub mod btree;
+pub mod bulkstat;
+pub mod counts;
+mod crc;
+pub mod diff;
+pub mod directory;
+pub mod diskstats;
+pub mod fields;
+#[cfg(test)]
+mod fixtures;
+pub mod freesp;
+pub mod fsmap;
+pub mod geometry;
+pub mod health;
+pub mod inode;
+mod ioctl;
+pub mod kmsg;
+pub mod log;
+pub mod quota;
+pub mod rules;
+pub mod scrub;
+pub mod stall;
+pub mod superblock;
+pub mod trace;
+pub mod verify;
+pub mod window;
+pub mod xattr;
+
+pub use counts::counts;
+pub use fsmap::fsmap;
+pub use geometry::geometry;
+pub use health::health;
+
 #[cfg(test)]
 mod tests {
     use nom;
@@ -540,6 +576,10 @@ pub enum XfsError {
     Incomplete,
     /// We encounter an error with the data wer're parsing
     Parse,
+    /// On-disk metadata doesn't carry the magic number we expect
+    BadMagic,
+    /// On-disk metadata fails its CRC32c check
+    BadChecksum,
 }
 
 impl fmt::Display for XfsError {
@@ -550,6 +590,8 @@ impl fmt::Display for XfsError {
             XfsError::Io(ref err) => write!(f, "IO error: {}", err),
             XfsError::Incomplete => write!(f, "Not enough data for XFS parse"),
             XfsError::Parse => write!(f, "Parse error"),
+            XfsError::BadMagic => write!(f, "Bad magic number in XFS metadata"),
+            XfsError::BadChecksum => write!(f, "Checksum mismatch in XFS metadata"),
         }
     }
 }
@@ -562,6 +604,8 @@ impl error::Error for XfsError {
             XfsError::Io(ref err) => err.description(),
             XfsError::Incomplete => "There is not enough data for parsing",
             XfsError::Parse => "There was an error parsing",
+            XfsError::BadMagic => "The metadata has an unexpected magic number",
+            XfsError::BadChecksum => "The metadata failed its checksum",
         }
     }
 
@@ -574,6 +618,8 @@ impl error::Error for XfsError {
             XfsError::Io(ref err) => Some(err),
             XfsError::Incomplete => None,
             XfsError::Parse => None,
+            XfsError::BadMagic => None,
+            XfsError::BadChecksum => None,
         }
     }
 }
@@ -0,0 +1,684 @@
+//! Read-only decoding of the journal, for looking at a dirty log without
+//! replaying it (what `xfs_logprint` does).
+//!
+//! The log is a ring of 512-byte basic blocks. Every write is a record: a
+//! header sector followed by the log operations, with the first word of
+//! every sector replaced by the cycle number (the number of times the ring
+//! has wrapped). The head is the newest record; everything from the tail it
+//! points to up to the head is what recovery would replay.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read, Seek};
+
+use nom::{be_u32, be_u64};
+
+use superblock::{self, Superblock, Uuid};
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use fixtures::{self, LogOp};
+    use superblock;
+
+    const TRANSACTION: u8 = super::CLIENT_TRANSACTION;
+
+    fn op(tid: u32, flags: u8, payload: &[u8]) -> LogOp<'_> {
+        LogOp { tid, client: TRANSACTION, flags, payload }
+    }
+
+    fn checkpoint(tid: u32, items: &[u16]) -> Vec<Vec<u8>> {
+        let mut regions = vec![fixtures::trans_header(tid, 40, items.len() as u32)];
+        for item in items {
+            regions.push(fixtures::log_item(*item, 1));
+        }
+        regions
+    }
+
+    /// A clean log: one committed checkpoint followed by an unmount record.
+    fn clean_image() -> Vec<u8> {
+        let mut image = fixtures::image(true);
+        let regions = checkpoint(3, &[0x123c, 0x123b]);
+        let mut ops = vec![op(3, super::START_TRANS, &[])];
+        ops.extend(regions.iter().map(|r| op(3, 0, r)));
+        ops.push(op(3, super::COMMIT_TRANS, &[]));
+        fixtures::put_log_record(&mut image, 0, &fixtures::log_record(1, 0, (1, 0), &ops));
+
+        let unmount = [0x6e, 0x55, 0, 0, 0, 0, 0, 0];
+        let ops = [LogOp { tid: 4, client: super::CLIENT_LOG, flags: super::UNMOUNT_TRANS, payload: &unmount }];
+        fixtures::put_log_record(&mut image, 2, &fixtures::log_record(1, 2, (1, 2), &ops));
+        image
+    }
+
+    /// A dirty log that has wrapped: an old record of cycle 1 before the
+    /// tail, a record at the end of the ring whose data wraps to the start,
+    /// and the head in cycle 2. Transaction 7 spans both live records and
+    /// commits; transaction 8 never does.
+    fn dirty_image() -> Vec<u8> {
+        let mut image = fixtures::image(true);
+        let stale = checkpoint(1, &[0x123c]);
+        let ops: Vec<LogOp> = stale.iter().map(|r| op(1, 0, r)).collect();
+        fixtures::put_log_record(&mut image, 60, &fixtures::log_record(1, 60, (1, 50), &ops));
+
+        let header = fixtures::trans_header(7, 40, 3);
+        let efi = fixtures::log_item(0x1236, 1);
+        let buf = fixtures::log_item(0x123c, 2);
+        let data = vec![0xa5u8; 1200];
+        let ops = [
+            op(7, super::START_TRANS, &[]),
+            op(7, 0, &header),
+            op(7, 0, &efi),
+            op(7, 0, &buf),
+            op(7, super::CONTINUE_TRANS, &data[..1000]),
+        ];
+        fixtures::put_log_record(&mut image, 125, &fixtures::log_record(1, 125, (1, 125), &ops));
+
+        let rui = fixtures::log_item(0x1240, 1);
+        let header8 = fixtures::trans_header(8, 40, 1);
+        let inode = fixtures::log_item(0x123b, 1);
+        let ops = [
+            op(7, super::WAS_CONT_TRANS, &data[1000..]),
+            op(7, 0, &rui),
+            op(7, super::COMMIT_TRANS, &[]),
+            op(8, super::START_TRANS, &[]),
+            op(8, 0, &header8),
+            op(8, 0, &inode),
+        ];
+        fixtures::put_log_record(&mut image, 1, &fixtures::log_record(2, 1, (1, 125), &ops));
+        image
+    }
+
+    #[test]
+    fn it_locates_the_internal_log() {
+        let sb = superblock::parse(&fixtures::superblock(true)).unwrap();
+        assert_eq!(super::locate(&sb), super::Location::Internal { offset: 40 * 4096, length: 16 * 4096 });
+    }
+
+    #[test]
+    fn it_recognises_a_clean_log() {
+        let mut image = Cursor::new(clean_image());
+        let sb = superblock::read(&mut image).unwrap();
+        let log = super::scan(&mut image, &sb).unwrap();
+        assert_eq!(log.head, Some(super::Lsn { cycle: 1, block: 2 }));
+        assert_eq!(log.tail, log.head);
+        assert!(log.unmounted);
+        assert!(log.is_clean());
+        assert_eq!(log.records.len(), 1);
+        assert!(log.transactions.is_empty());
+    }
+
+    #[test]
+    fn it_decodes_a_dirty_log() {
+        let mut image = Cursor::new(dirty_image());
+        let sb = superblock::read(&mut image).unwrap();
+        let log = super::scan(&mut image, &sb).unwrap();
+        assert!(!log.is_clean());
+        assert_eq!(log.head, Some(super::Lsn { cycle: 2, block: 1 }));
+        assert_eq!(log.head_block, 3);
+        assert_eq!(log.tail, Some(super::Lsn { cycle: 1, block: 125 }));
+        let blocks: Vec<u32> = log.records.iter().map(|r| r.lsn.block).collect();
+        assert_eq!(blocks, vec![125, 1]);
+        assert_eq!(log.records[0].header_blocks(), 1);
+        assert_eq!(log.records[0].data_blocks(), 3);
+
+        assert_eq!(log.transactions.len(), 2);
+        let first = &log.transactions[0];
+        assert_eq!(first.tid, 7);
+        assert_eq!(first.trans_type, Some(super::TransType::Checkpoint));
+        assert!(first.committed);
+        assert_eq!(first.items, vec![super::ItemType::Efi, super::ItemType::Buffer, super::ItemType::Rui]);
+        assert_eq!(first.lsn, super::Lsn { cycle: 1, block: 125 });
+        let second = &log.transactions[1];
+        assert!(!second.committed);
+        assert_eq!(second.items, vec![super::ItemType::Inode]);
+
+        let counts = log.item_counts();
+        assert_eq!(counts.get(&super::ItemType::Buffer), Some(&1));
+        assert_eq!(counts.len(), 4);
+    }
+
+    #[test]
+    fn it_reads_an_external_log() {
+        let image = dirty_image();
+        let start = (fixtures::LOG_START * fixtures::BLOCK_SIZE) as usize;
+        let length = (fixtures::LOG_BLOCKS * fixtures::BLOCK_SIZE) as usize;
+        let mut logdev = Cursor::new(image[start..start + length].to_vec());
+        let sb = superblock::parse(&fixtures::superblock(true)).unwrap();
+        let log = super::scan_device(&mut logdev, &sb).unwrap();
+        assert_eq!(log.transactions.len(), 2);
+    }
+
+    #[test]
+    fn it_reads_the_ring_as_it_goes() {
+        // A 2GiB log claimed on a 64KiB device fails on the first short
+        // read instead of being loaded whole.
+        let mut sb = superblock::parse(&fixtures::superblock(true)).unwrap();
+        sb.log_blocks = 1 << 19;
+        let mut logdev = Cursor::new(vec![0u8; 65536]);
+        assert!(super::scan_device(&mut logdev, &sb).is_err());
+    }
+
+    #[test]
+    fn it_finds_nothing_in_an_empty_log() {
+        let mut image = Cursor::new(fixtures::image(false));
+        let sb = superblock::read(&mut image).unwrap();
+        let log = super::scan(&mut image, &sb).unwrap();
+        assert_eq!(log.head, None);
+        assert!(!log.is_clean());
+    }
+}
+
+/// "FEEDbabe", at the start of every record header.
+pub const HEADER_MAGIC: u32 = 0xfeedbabe;
+/// Magic of the transaction header region, "TRAN".
+pub const TRANS_HEADER_MAGIC: u32 = 0x5452414e;
+
+pub const BBSIZE: usize = 512;
+/// Data sectors whose first word fits in one header sector.
+const CYCLE_WORDS: usize = 64;
+/// Bytes of record data covered by each header sector.
+const HEADER_CYCLE_SIZE: u32 = 32 * 1024;
+/// The largest record the kernel writes (`XLOG_MAX_RECORD_BSIZE`).
+const MAX_RECORD_SIZE: u32 = 256 * 1024;
+/// Sectors read at a time while looking for record headers.
+const SCAN_SECTORS: u32 = 128;
+
+/// Operation written by a transaction.
+pub const CLIENT_TRANSACTION: u8 = 0x69;
+/// Operation written by the log itself (unmount records).
+pub const CLIENT_LOG: u8 = 0xaa;
+
+pub const START_TRANS: u8 = 0x01;
+pub const COMMIT_TRANS: u8 = 0x02;
+pub const CONTINUE_TRANS: u8 = 0x04;
+pub const WAS_CONT_TRANS: u8 = 0x08;
+pub const END_TRANS: u8 = 0x10;
+pub const UNMOUNT_TRANS: u8 = 0x20;
+
+/// Where the log lives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+    /// Inside the data device, at `offset` bytes.
+    Internal { offset: u64, length: u64 },
+    /// On its own device, starting at its first byte.
+    External { length: u64 },
+}
+
+/// Log sequence number: cycle and basic block of a record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Lsn {
+    pub cycle: u32,
+    pub block: u32,
+}
+
+/// Type of a logged transaction. Current kernels only write checkpoints of
+/// the committed item list; older ones logged each operation with its own
+/// type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransType {
+    Checkpoint,
+    Other(u32),
+}
+
+/// Type of a log item.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ItemType {
+    /// Extent free intent.
+    Efi,
+    /// Extent free done.
+    Efd,
+    Iunlink,
+    Inode,
+    Buffer,
+    Dquot,
+    QuotaOff,
+    /// Inode chunk creation.
+    Icreate,
+    /// Reverse mapping update intent.
+    Rui,
+    /// Reverse mapping update done.
+    Rud,
+    /// Reference count update intent.
+    Cui,
+    /// Reference count update done.
+    Cud,
+    /// Block map update intent.
+    Bui,
+    /// Block map update done.
+    Bud,
+    /// Extended attribute intent.
+    Attri,
+    /// Extended attribute done.
+    Attrd,
+    Unknown(u16),
+}
+
+#[derive(Clone, Debug)]
+pub struct RecordHeader {
+    pub magic: u32,
+    pub cycle: u32,
+    /// Record format version, 1 or 2.
+    pub version: u32,
+    /// Bytes of operations following the header.
+    pub len: u32,
+    pub lsn: Lsn,
+    /// Oldest LSN still needed when this record was written.
+    pub tail_lsn: Lsn,
+    /// Record checksum, as stored.
+    pub crc: u32,
+    /// Block of the previous record.
+    pub prev_block: u32,
+    /// Number of log operations in the record.
+    pub num_logops: u32,
+    /// Saved first words of the data sectors.
+    pub cycle_data: Vec<u32>,
+    /// Format of the host that wrote the log.
+    pub fmt: u32,
+    pub fs_uuid: Uuid,
+    /// In-core log buffer size.
+    pub size: u32,
+}
+
+/// One transaction found between the tail and the head.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transaction {
+    pub tid: u32,
+    /// From the transaction header, once seen.
+    pub trans_type: Option<TransType>,
+    /// Record the transaction started in.
+    pub lsn: Lsn,
+    /// Log items in the order they were logged.
+    pub items: Vec<ItemType>,
+    /// Whether the commit record made it to disk. Recovery discards
+    /// transactions without one.
+    pub committed: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Log {
+    pub location: Location,
+    /// Records from the tail to the head.
+    pub records: Vec<RecordHeader>,
+    /// LSN of the newest record.
+    pub head: Option<Lsn>,
+    /// Block following the newest record, where the next write goes.
+    pub head_block: u32,
+    /// Tail LSN recorded in the newest record.
+    pub tail: Option<Lsn>,
+    /// Whether the newest record is an unmount record.
+    pub unmounted: bool,
+    pub transactions: Vec<Transaction>,
+}
+
+/// Per-transaction state while walking the operations.
+struct Pending {
+    header_seen: bool,
+    /// Byte order of the host that logged the transaction.
+    big_endian: bool,
+    /// Regions left in the current item after its format region.
+    regions_left: u16,
+}
+
+impl fmt::Display for Lsn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.cycle, self.block)
+    }
+}
+
+impl From<u64> for Lsn {
+    fn from(lsn: u64) -> Lsn {
+        Lsn {
+            cycle: (lsn >> 32) as u32,
+            block: lsn as u32,
+        }
+    }
+}
+
+impl From<u32> for TransType {
+    fn from(trans_type: u32) -> TransType {
+        match trans_type {
+            40 => TransType::Checkpoint,
+            other => TransType::Other(other),
+        }
+    }
+}
+
+impl From<u16> for ItemType {
+    fn from(item_type: u16) -> ItemType {
+        match item_type {
+            0x1236 => ItemType::Efi,
+            0x1237 => ItemType::Efd,
+            0x1238 => ItemType::Iunlink,
+            0x123b => ItemType::Inode,
+            0x123c => ItemType::Buffer,
+            0x123d => ItemType::Dquot,
+            0x123e => ItemType::QuotaOff,
+            0x123f => ItemType::Icreate,
+            0x1240 => ItemType::Rui,
+            0x1241 => ItemType::Rud,
+            0x1242 => ItemType::Cui,
+            0x1243 => ItemType::Cud,
+            0x1244 => ItemType::Bui,
+            0x1245 => ItemType::Bud,
+            0x1246 => ItemType::Attri,
+            0x1247 => ItemType::Attrd,
+            other => ItemType::Unknown(other),
+        }
+    }
+}
+
+impl fmt::Display for ItemType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ItemType::Efi => "EFI",
+            ItemType::Efd => "EFD",
+            ItemType::Iunlink => "IUNLINK",
+            ItemType::Inode => "INODE",
+            ItemType::Buffer => "BUF",
+            ItemType::Dquot => "DQUOT",
+            ItemType::QuotaOff => "QUOTAOFF",
+            ItemType::Icreate => "ICREATE",
+            ItemType::Rui => "RUI",
+            ItemType::Rud => "RUD",
+            ItemType::Cui => "CUI",
+            ItemType::Cud => "CUD",
+            ItemType::Bui => "BUI",
+            ItemType::Bud => "BUD",
+            ItemType::Attri => "ATTRI",
+            ItemType::Attrd => "ATTRD",
+            ItemType::Unknown(other) => return write!(f, "0x{:x}", other),
+        };
+        f.write_str(name)
+    }
+}
+
+impl RecordHeader {
+    /// Sectors taken by the header: one, plus extension headers for v2
+    /// records larger than 32k.
+    pub fn header_blocks(&self) -> u32 {
+        if self.version == 2 && self.size > HEADER_CYCLE_SIZE {
+            self.size.div_ceil(HEADER_CYCLE_SIZE)
+        } else {
+            1
+        }
+    }
+
+    pub fn data_blocks(&self) -> u32 {
+        self.len.div_ceil(BBSIZE as u32)
+    }
+}
+
+impl Log {
+    /// Clean if the newest record is an unmount record that is also the
+    /// tail: there is nothing to recover.
+    pub fn is_clean(&self) -> bool {
+        self.unmounted && self.head.is_some() && self.head == self.tail
+    }
+
+    /// How many items of each type the transactions logged.
+    pub fn item_counts(&self) -> BTreeMap<ItemType, u64> {
+        let mut counts = BTreeMap::new();
+        for item in self.transactions.iter().flat_map(|t| t.items.iter()) {
+            *counts.entry(*item).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+/// Where the log is according to the superblock.
+pub fn locate(sb: &Superblock) -> Location {
+    let length = u64::from(sb.log_blocks) * u64::from(sb.block_size);
+    if sb.has_internal_log() {
+        Location::Internal {
+            offset: sb.fsb_offset(sb.log_start),
+            length,
+        }
+    } else {
+        Location::External { length }
+    }
+}
+
+pub fn parse_header(input: &[u8]) -> Result<RecordHeader, XfsError> {
+    match record_header(input) {
+        nom::IResult::Done(_, header) => Ok(header),
+        nom::IResult::Error(_) => Err(XfsError::Parse),
+        nom::IResult::Incomplete(_) => Err(XfsError::Incomplete),
+    }
+}
+
+/// Scans the internal log on the data device. Fails for an external log;
+/// use `scan_device` with the log device instead.
+pub fn scan<R: Read + Seek>(dev: &mut R, sb: &Superblock) -> Result<Log, XfsError> {
+    let location = locate(sb);
+    match location {
+        Location::Internal { offset, length } => scan_region(dev, location, offset, length),
+        Location::External { .. } => {
+            Err(XfsError::Io(io::Error::new(io::ErrorKind::NotFound, "the log is on an external device")))
+        }
+    }
+}
+
+/// Scans an external log device.
+pub fn scan_device<R: Read + Seek>(logdev: &mut R, sb: &Superblock) -> Result<Log, XfsError> {
+    let length = u64::from(sb.log_blocks) * u64::from(sb.block_size);
+    scan_region(logdev, Location::External { length }, 0, length)
+}
+
+/// The log's sectors on its device, read as they are needed rather than
+/// all at once.
+struct Ring<'a, R: 'a> {
+    dev: &'a mut R,
+    offset: u64,
+    blocks: u32,
+}
+
+impl<'a, R: Read + Seek> Ring<'a, R> {
+    /// `count` sectors from `start` on, following the ring around its end.
+    fn read(&mut self, start: u32, count: u32) -> Result<Vec<u8>, XfsError> {
+        let start = start % self.blocks;
+        let first = count.min(self.blocks - start);
+        let mut buf = superblock::read_at(self.dev, self.offset + u64::from(start) * BBSIZE as u64,
+                                          first as usize * BBSIZE)?;
+        if first < count {
+            buf.extend(superblock::read_at(self.dev, self.offset, (count - first) as usize * BBSIZE)?);
+        }
+        Ok(buf)
+    }
+}
+
+fn scan_region<R: Read + Seek>(dev: &mut R, location: Location, offset: u64, length: u64)
+                               -> Result<Log, XfsError> {
+    if length / BBSIZE as u64 > u64::from(u32::MAX) {
+        return Err(XfsError::Parse);
+    }
+    let blocks = (length / BBSIZE as u64) as u32;
+    let mut ring = Ring { dev, offset, blocks };
+
+    let mut found = vec![];
+    for first in (0..blocks).step_by(SCAN_SECTORS as usize) {
+        let chunk = ring.read(first, SCAN_SECTORS.min(blocks - first))?;
+        for (block, sector) in (first..).zip(chunk.chunks(BBSIZE)) {
+            if ::btree::be32(sector, 0) != HEADER_MAGIC {
+                continue;
+            }
+            match parse_header(sector) {
+                // A header that isn't where it says it is was overwritten in
+                // a later cycle and only its first sector survived.
+                Ok(header) => if header.lsn.block == block && header.len <= MAX_RECORD_SIZE
+                    && header.header_blocks() + header.data_blocks() <= blocks {
+                    found.push(header)
+                },
+                Err(_) => continue,
+            }
+        }
+    }
+    found.sort_by_key(|header| header.lsn);
+
+    let mut log = Log {
+        location,
+        records: vec![],
+        head: None,
+        head_block: 0,
+        tail: None,
+        unmounted: false,
+        transactions: vec![],
+    };
+    let (head, tail) = match found.last() {
+        Some(head) => (head.lsn, head.tail_lsn),
+        None => return Ok(log),
+    };
+    log.head = Some(head);
+    log.tail = Some(tail);
+    let newest = &found[found.len() - 1];
+    log.head_block = (head.block + newest.header_blocks() + newest.data_blocks()) % blocks;
+
+    let mut pending = BTreeMap::new();
+    for header in found.into_iter().filter(|header| header.lsn >= tail) {
+        let data = record_data(&mut ring, &header)?;
+        log.unmounted = false;
+        walk_ops(&data, &header, &mut log, &mut pending);
+        log.records.push(header);
+    }
+    Ok(log)
+}
+
+/// Reads the operations of a record, restoring the words the cycle number
+/// replaced and following the data around the end of the ring.
+fn record_data<R: Read + Seek>(ring: &mut Ring<R>, header: &RecordHeader) -> Result<Vec<u8>, XfsError> {
+    let headers = ring.read(header.lsn.block, header.header_blocks())?;
+    let mut data = ring.read(header.lsn.block + header.header_blocks(), header.data_blocks())?;
+    for word in 0..header.data_blocks() as usize {
+        let saved = if word < CYCLE_WORDS {
+            Some(header.cycle_data[word])
+        } else if word / CYCLE_WORDS < header.header_blocks() as usize {
+            Some(::btree::be32(&headers, word / CYCLE_WORDS * BBSIZE + 4 + (word % CYCLE_WORDS) * 4))
+        } else {
+            None
+        };
+        if let Some(saved) = saved {
+            data[word * BBSIZE..word * BBSIZE + 4].copy_from_slice(&saved.to_be_bytes());
+        }
+    }
+    data.truncate(header.len as usize);
+    Ok(data)
+}
+
+/// Reads a region-leading u16 or u32 written in host byte order. Nearly all
+/// logs come from little endian machines; the transaction header magic
+/// tells the rest apart.
+fn host_u16(buf: &[u8], offset: usize, big_endian: bool) -> u16 {
+    let bytes = [buf[offset], buf[offset + 1]];
+    if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) }
+}
+
+fn host_u32(buf: &[u8], offset: usize, big_endian: bool) -> u32 {
+    let bytes = [buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]];
+    if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) }
+}
+
+fn walk_ops(data: &[u8], header: &RecordHeader, log: &mut Log, pending: &mut BTreeMap<u32, (usize, Pending)>) {
+    let mut offset = 0;
+    for _ in 0..header.num_logops {
+        if offset + 12 > data.len() {
+            break;
+        }
+        let tid = ::btree::be32(data, offset);
+        let len = ::btree::be32(data, offset + 4) as usize;
+        let client = data[offset + 8];
+        let flags = data[offset + 9];
+        let start = offset + 12;
+        offset = start + len;
+        if offset > data.len() {
+            break;
+        }
+        let payload = &data[start..offset];
+
+        if client == CLIENT_LOG && flags & UNMOUNT_TRANS != 0 {
+            log.unmounted = true;
+            continue;
+        }
+        if client != CLIENT_TRANSACTION {
+            continue;
+        }
+        if flags & START_TRANS != 0 || !pending.contains_key(&tid) {
+            // A transaction whose start is behind the tail is picked up
+            // from its first region we can see.
+            log.transactions.push(Transaction {
+                tid,
+                trans_type: None,
+                lsn: header.lsn,
+                items: vec![],
+                committed: false,
+            });
+            let state = Pending { header_seen: false, big_endian: false, regions_left: 0 };
+            pending.insert(tid, (log.transactions.len() - 1, state));
+            if flags & START_TRANS != 0 {
+                continue;
+            }
+        }
+        let (index, ref mut state) = *pending.get_mut(&tid).unwrap();
+        let transaction = &mut log.transactions[index];
+        if flags & COMMIT_TRANS != 0 {
+            transaction.committed = true;
+            pending.remove(&tid);
+            continue;
+        }
+        if flags & WAS_CONT_TRANS != 0 || payload.len() < 4 {
+            continue;
+        }
+        if !state.header_seen {
+            let big_endian = ::btree::be32(payload, 0) == TRANS_HEADER_MAGIC;
+            if big_endian || host_u32(payload, 0, false) == TRANS_HEADER_MAGIC {
+                state.header_seen = true;
+                state.big_endian = big_endian;
+                if payload.len() >= 8 {
+                    transaction.trans_type = Some(TransType::from(host_u32(payload, 4, big_endian)));
+                }
+                continue;
+            }
+        }
+        if state.regions_left > 0 {
+            state.regions_left -= 1;
+            continue;
+        }
+        transaction.items.push(ItemType::from(host_u16(payload, 0, state.big_endian)));
+        state.regions_left = host_u16(payload, 2, state.big_endian).saturating_sub(1);
+    }
+}
+
+named!(record_header <RecordHeader>,
+    chain!(
+        magic: be_u32 ~
+        cycle: be_u32 ~
+        version: be_u32 ~
+        len: be_u32 ~
+        lsn: be_u64 ~
+        tail_lsn: be_u64 ~
+        crc: take!(4) ~
+        prev_block: be_u32 ~
+        num_logops: be_u32 ~
+        cycle_data: count!(be_u32, CYCLE_WORDS) ~
+        fmt: be_u32 ~
+        fs_uuid: take!(16) ~
+        size: be_u32,
+        || {
+            RecordHeader {
+                magic,
+                cycle,
+                version,
+                len,
+                lsn: Lsn::from(lsn),
+                tail_lsn: Lsn::from(tail_lsn),
+                crc: ::crc::stored_crc(crc, 0),
+                prev_block,
+                num_logops,
+                cycle_data,
+                fmt,
+                fs_uuid: Uuid::from_slice(fs_uuid),
+                size,
+            }
+        }
+    )
+);
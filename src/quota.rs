@@ -0,0 +1,813 @@
+//! Quota usage and limits through the XFS `quotactl` commands, and project
+//! quota setup through the inode attributes behind `FS_IOC_FSGETXATTR` and
+//! `FS_IOC_FSSETXATTR`: the project ID, the extent size hints and the
+//! `XFLAG_*` inode flags.
+
+use std::ffi::CString;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use ioctl;
+use libc;
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn it_decodes_the_ioctl_struct() {
+        assert_eq!(mem::size_of::<super::RawFsxattr>(), 28);
+        assert_eq!(super::FSGETXATTR, 0x801c581f);
+        let raw = super::RawFsxattr {
+            fsx_xflags: super::XFLAG_EXTSIZE | super::XFLAG_PROJINHERIT,
+            fsx_extsize: 1 << 20,
+            fsx_nextents: 3,
+            fsx_projid: 42,
+            ..Default::default()
+        };
+        let attr = super::FileAttr::from(&raw);
+        assert_eq!(attr.project_id, 42);
+        assert_eq!(attr.extent_size_hint, 1 << 20);
+        assert_eq!(attr.extents, 3);
+        assert!(attr.has(super::XFLAG_PROJINHERIT));
+        assert!(!attr.has(super::XFLAG_COWEXTSIZE));
+        assert_eq!(super::RawFsxattr::from(&attr).fsx_xflags, raw.fsx_xflags);
+    }
+
+    #[test]
+    fn it_sets_hint_flags_by_file_type() {
+        let mut attr = super::FileAttr::default();
+        attr.set_extent_size_hint(65536, false);
+        assert_eq!(attr.flags, super::XFLAG_EXTSIZE);
+        attr.set_extent_size_hint(0, false);
+        assert_eq!(attr.flags, 0);
+        attr.set_extent_size_hint(65536, true);
+        attr.set_cow_extent_size_hint(1 << 20);
+        assert_eq!(attr.flags, super::XFLAG_EXTSZINHERIT | super::XFLAG_COWEXTSIZE);
+    }
+
+    fn disk_quota() -> super::RawDiskQuota {
+        super::RawDiskQuota {
+            d_version: super::DQUOT_VERSION,
+            d_flags: 1,
+            d_id: 1000,
+            d_blk_softlimit: 2048,
+            d_blk_hardlimit: 4096,
+            d_bcount: 3000,
+            d_icount: 12,
+            d_btimer: 0x2345,
+            d_btimer_hi: 1,
+            d_bwarns: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_decodes_quotactl_structs() {
+        assert_eq!(mem::size_of::<super::RawDiskQuota>(), 112);
+        assert_eq!(mem::size_of::<super::RawStatv>(), 160);
+        assert_eq!(super::qcmd(super::XGETQUOTA, super::Type::User), 0x580300);
+        assert_eq!(super::qcmd(super::XGETNEXTQUOTA, super::Type::Project), 0x580902);
+
+        let quota = super::Quota::from(&disk_quota());
+        assert_eq!((quota.kind, quota.id), (super::Type::User, 1000));
+        assert_eq!(quota.blocks.used, 3000 * 512);
+        assert_eq!(quota.blocks.grace_expires, 0x1_0000_2345);
+        assert!(quota.blocks.over_soft() && !quota.blocks.over_hard());
+        assert!(!quota.inodes.over_soft());
+
+        let statv = super::RawStatv {
+            qs_flags: super::QUOTA_UDQ_ACCT | super::QUOTA_UDQ_ENFD | super::QUOTA_PDQ_ACCT,
+            qs_uquota: super::RawFileStatv { qfs_ino: 131, qfs_nblks: 1, qfs_nextents: 1, qfs_pad: 0 },
+            qs_gquota: super::RawFileStatv { qfs_ino: !0, ..Default::default() },
+            qs_pquota: super::RawFileStatv { qfs_ino: 133, ..Default::default() },
+            qs_btimelimit: 604800,
+            ..Default::default()
+        };
+        let state = super::State::from(&statv);
+        assert!(state.enforced(super::Type::User));
+        assert!(state.accounted(super::Type::Project) && !state.enforced(super::Type::Project));
+        assert_eq!(state.user.map(|f| f.ino), Some(131));
+        assert_eq!(state.group, None);
+        assert_eq!(state.block_grace, 604800);
+    }
+
+    #[test]
+    fn it_sets_only_the_given_limits() {
+        let limits = super::Limits { block_hard: Some(1 << 20), inode_soft: Some(100), ..Default::default() };
+        let raw = limits.raw(super::Type::Group, 7);
+        assert_eq!(raw.d_fieldmask, super::DQ_BHARD | super::DQ_ISOFT);
+        assert_eq!((raw.d_flags, raw.d_id), (4, 7));
+        assert_eq!((raw.d_blk_hardlimit, raw.d_ino_softlimit, raw.d_blk_softlimit), (2048, 100, 0));
+    }
+
+    #[test]
+    fn it_finds_the_device_of_a_mount() {
+        let mounts = "\
+/dev/sda1 / ext4 rw,relatime 0 0
+/dev/sdb /srv/data xfs rw,prjquota 0 0
+/dev/sdc /srv/data\\040two xfs rw 0 0
+";
+        let find = |path: &str| super::find_mount(mounts, Path::new(path));
+        assert_eq!(find("/srv/data/tenant"), Some((PathBuf::from("/srv/data"), "/dev/sdb".to_string())));
+        assert_eq!(find("/srv/data two/x").map(|m| m.1), Some("/dev/sdc".to_string()));
+        assert_eq!(find("/srv/database").map(|m| m.1), Some("/dev/sda1".to_string()));
+    }
+
+    #[test]
+    fn it_formats_like_xfs_quota_report() {
+        let mut other = super::Quota::from(&disk_quota());
+        other.id = 1001;
+        other.blocks = super::Usage { used: 1 << 20, ..Default::default() };
+        let expected = "\
+User quota on /srv/data (/dev/sdb)
+                               Blocks
+User ID          Used       Soft       Hard    Warn/Grace
+---------- --------------------------------------------------
+#1000            1500       1024       2048     02 [2 days]
+#1001            1024          0          0     00 [--------]
+";
+        let quotas = [super::Quota::from(&disk_quota()), other];
+        let now = 0x1_0000_2345 - 2 * 86400 - 100;
+        assert_eq!(super::format_report(super::Type::User, "/srv/data", "/dev/sdb", &quotas, now), expected);
+    }
+}
+
+pub const XFLAG_REALTIME: u32 = 0x1;
+pub const XFLAG_PREALLOC: u32 = 0x2;
+pub const XFLAG_IMMUTABLE: u32 = 0x8;
+pub const XFLAG_APPEND: u32 = 0x10;
+pub const XFLAG_SYNC: u32 = 0x20;
+pub const XFLAG_NOATIME: u32 = 0x40;
+pub const XFLAG_NODUMP: u32 = 0x80;
+/// New files in the directory are created on the realtime device.
+pub const XFLAG_RTINHERIT: u32 = 0x100;
+/// New entries in the directory inherit its project ID.
+pub const XFLAG_PROJINHERIT: u32 = 0x200;
+pub const XFLAG_NOSYMLINKS: u32 = 0x400;
+/// The file has an extent size hint.
+pub const XFLAG_EXTSIZE: u32 = 0x800;
+/// New files in the directory inherit its extent size hint.
+pub const XFLAG_EXTSZINHERIT: u32 = 0x1000;
+pub const XFLAG_NODEFRAG: u32 = 0x2000;
+pub const XFLAG_FILESTREAM: u32 = 0x4000;
+pub const XFLAG_DAX: u32 = 0x8000;
+/// The file or directory has a copy-on-write extent size hint.
+pub const XFLAG_COWEXTSIZE: u32 = 0x10000;
+/// The inode has extended attributes. Read only.
+pub const XFLAG_HASATTR: u32 = 0x80000000;
+
+const FSGETXATTR: u64 = ioctl::ior(31, 28);
+const FSSETXATTR: u64 = ioctl::iow(32, 28);
+
+/// `struct fsxattr`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawFsxattr {
+    fsx_xflags: u32,
+    fsx_extsize: u32,
+    fsx_nextents: u32,
+    fsx_projid: u32,
+    fsx_cowextsize: u32,
+    fsx_pad: [u8; 8],
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileAttr {
+    /// `XFLAG_*` bits (`fsx_xflags`).
+    pub flags: u32,
+    /// Extent size hint in bytes (`fsx_extsize`).
+    pub extent_size_hint: u32,
+    /// Data fork extents. Ignored when setting (`fsx_nextents`).
+    pub extents: u32,
+    pub project_id: u32,
+    /// Copy-on-write extent size hint in bytes (`fsx_cowextsize`).
+    pub cow_extent_size_hint: u32,
+}
+
+/// What `set_project` changed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProjectSetup {
+    pub directories: u64,
+    pub files: u64,
+    /// Symlinks, devices, fifos and sockets, which can't carry a project ID
+    /// through this interface.
+    pub skipped: u64,
+}
+
+impl From<&RawFsxattr> for FileAttr {
+    fn from(raw: &RawFsxattr) -> FileAttr {
+        FileAttr {
+            flags: raw.fsx_xflags,
+            extent_size_hint: raw.fsx_extsize,
+            extents: raw.fsx_nextents,
+            project_id: raw.fsx_projid,
+            cow_extent_size_hint: raw.fsx_cowextsize,
+        }
+    }
+}
+
+impl From<&FileAttr> for RawFsxattr {
+    fn from(attr: &FileAttr) -> RawFsxattr {
+        RawFsxattr {
+            fsx_xflags: attr.flags,
+            fsx_extsize: attr.extent_size_hint,
+            fsx_nextents: attr.extents,
+            fsx_projid: attr.project_id,
+            fsx_cowextsize: attr.cow_extent_size_hint,
+            fsx_pad: [0; 8],
+        }
+    }
+}
+
+impl FileAttr {
+    pub fn has(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    fn set_flag(&mut self, flag: u32, on: bool) {
+        if on {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+
+    /// Sets the extent size hint along with the flag the kernel expects for
+    /// it: `XFLAG_EXTSZINHERIT` on directories, `XFLAG_EXTSIZE` on files.
+    /// Zero clears the hint.
+    pub fn set_extent_size_hint(&mut self, bytes: u32, directory: bool) {
+        let flag = if directory { XFLAG_EXTSZINHERIT } else { XFLAG_EXTSIZE };
+        self.extent_size_hint = bytes;
+        self.set_flag(flag, bytes > 0);
+    }
+
+    /// Sets the copy-on-write extent size hint. Zero clears it.
+    pub fn set_cow_extent_size_hint(&mut self, bytes: u32) {
+        self.cow_extent_size_hint = bytes;
+        self.set_flag(XFLAG_COWEXTSIZE, bytes > 0);
+    }
+}
+
+fn get_file_attr(file: &File) -> Result<FileAttr, XfsError> {
+    let mut raw = RawFsxattr::default();
+    unsafe { ioctl::ioctl(file, FSGETXATTR, &mut raw)? };
+    Ok(FileAttr::from(&raw))
+}
+
+fn set_file_attr(file: &File, attr: &FileAttr) -> Result<(), XfsError> {
+    let mut raw = RawFsxattr::from(attr);
+    unsafe { ioctl::ioctl(file, FSSETXATTR, &mut raw)? };
+    Ok(())
+}
+
+/// Reads the attributes of `path`.
+pub fn get_attr<P: AsRef<Path>>(path: P) -> Result<FileAttr, XfsError> {
+    get_file_attr(&ioctl::open(path)?)
+}
+
+/// Writes the flags, hints and project ID of `path`. Changing them needs
+/// ownership of the file, and changing the project ID needs
+/// `CAP_FOWNER` or `CAP_SYS_ADMIN` as well.
+pub fn set_attr<P: AsRef<Path>>(path: P, attr: &FileAttr) -> Result<(), XfsError> {
+    set_file_attr(&ioctl::open(path)?, attr)
+}
+
+/// Reads the attributes of `path`, lets `change` edit them and writes them
+/// back.
+pub fn modify_attr<P, F>(path: P, change: F) -> Result<FileAttr, XfsError>
+    where P: AsRef<Path>, F: FnOnce(&mut FileAttr)
+{
+    let file = ioctl::open(path)?;
+    let mut attr = get_file_attr(&file)?;
+    change(&mut attr);
+    set_file_attr(&file, &attr)?;
+    Ok(attr)
+}
+
+/// Assigns project `id` to `root` and everything below it, and marks the
+/// directories so that new entries inherit it, like
+/// `xfs_quota -x -c 'project -s'`. Doesn't cross into other filesystems.
+pub fn set_project<P: AsRef<Path>>(root: P, id: u32) -> Result<ProjectSetup, XfsError> {
+    let root = root.as_ref();
+    let dev = fs::symlink_metadata(root)?.dev();
+    let mut setup = ProjectSetup::default();
+    walk(root, dev, &mut |path, directory| {
+        let directory = match directory {
+            Some(directory) => directory,
+            None => {
+                setup.skipped += 1;
+                return Ok(());
+            }
+        };
+        modify_attr(path, |attr| {
+            attr.project_id = id;
+            attr.set_flag(XFLAG_PROJINHERIT, directory);
+        })?;
+        if directory {
+            setup.directories += 1;
+        } else {
+            setup.files += 1;
+        }
+        Ok(())
+    })?;
+    Ok(setup)
+}
+
+/// Lists the files and directories below `root` that aren't set up for
+/// project `id`, like `xfs_quota -x -c 'project -c'`: a different project
+/// ID, or a directory without `XFLAG_PROJINHERIT`.
+pub fn check_project<P: AsRef<Path>>(root: P, id: u32) -> Result<Vec<PathBuf>, XfsError> {
+    let root = root.as_ref();
+    let dev = fs::symlink_metadata(root)?.dev();
+    let mut wrong = vec![];
+    walk(root, dev, &mut |path, directory| {
+        if let Some(directory) = directory {
+            let attr = get_attr(path)?;
+            if attr.project_id != id || directory && !attr.has(XFLAG_PROJINHERIT) {
+                wrong.push(path.to_path_buf());
+            }
+        }
+        Ok(())
+    })?;
+    Ok(wrong)
+}
+
+/// Calls `visit` for `path` and everything below it on device `dev`, with
+/// whether each is a directory, or None for anything that is neither a
+/// directory nor a regular file.
+fn walk<F>(path: &Path, dev: u64, visit: &mut F) -> Result<(), XfsError>
+    where F: FnMut(&Path, Option<bool>) -> Result<(), XfsError>
+{
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        // Removed while we were walking.
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(XfsError::Io(err)),
+    };
+    if meta.dev() != dev {
+        return Ok(());
+    }
+    if meta.is_dir() {
+        visit(path, Some(true))?;
+        for entry in fs::read_dir(path)? {
+            walk(&entry?.path(), dev, visit)?;
+        }
+        Ok(())
+    } else if meta.is_file() {
+        visit(path, Some(false))
+    } else {
+        visit(path, None)
+    }
+}
+
+/// `Q_XGETQUOTA` and friends: `XQM_CMD(n)`, before `QCMD` adds the type.
+const XGETQUOTA: i32 = 0x5803;
+const XSETQLIM: i32 = 0x5804;
+const XGETQSTATV: i32 = 0x5808;
+const XGETNEXTQUOTA: i32 = 0x5809;
+
+const DQUOT_VERSION: i8 = 1;
+const QSTATV_VERSION: i8 = 1;
+
+pub const DQ_ISOFT: u16 = 0x1;
+pub const DQ_IHARD: u16 = 0x2;
+pub const DQ_BSOFT: u16 = 0x4;
+pub const DQ_BHARD: u16 = 0x8;
+pub const DQ_RTBSOFT: u16 = 0x10;
+pub const DQ_RTBHARD: u16 = 0x20;
+
+pub const QUOTA_UDQ_ACCT: u16 = 0x1;
+pub const QUOTA_UDQ_ENFD: u16 = 0x2;
+pub const QUOTA_GDQ_ACCT: u16 = 0x4;
+pub const QUOTA_GDQ_ENFD: u16 = 0x8;
+pub const QUOTA_PDQ_ACCT: u16 = 0x10;
+pub const QUOTA_PDQ_ENFD: u16 = 0x20;
+
+/// `struct fs_disk_quota`. Block counts are in 512-byte units.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawDiskQuota {
+    d_version: i8,
+    d_flags: i8,
+    d_fieldmask: u16,
+    d_id: u32,
+    d_blk_hardlimit: u64,
+    d_blk_softlimit: u64,
+    d_ino_hardlimit: u64,
+    d_ino_softlimit: u64,
+    d_bcount: u64,
+    d_icount: u64,
+    d_itimer: i32,
+    d_btimer: i32,
+    d_iwarns: u16,
+    d_bwarns: u16,
+    d_itimer_hi: i8,
+    d_btimer_hi: i8,
+    d_rtbtimer_hi: i8,
+    d_padding2: i8,
+    d_rtb_hardlimit: u64,
+    d_rtb_softlimit: u64,
+    d_rtbcount: u64,
+    d_rtbtimer: i32,
+    d_rtbwarns: u16,
+    d_padding3: i16,
+    d_padding4: [u8; 8],
+}
+
+/// `struct fs_qfilestatv`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawFileStatv {
+    qfs_ino: u64,
+    qfs_nblks: u64,
+    qfs_nextents: u32,
+    qfs_pad: u32,
+}
+
+/// `struct fs_quota_statv`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawStatv {
+    qs_version: i8,
+    qs_pad1: u8,
+    qs_flags: u16,
+    qs_incoredqs: u32,
+    qs_uquota: RawFileStatv,
+    qs_gquota: RawFileStatv,
+    qs_pquota: RawFileStatv,
+    qs_btimelimit: i32,
+    qs_itimelimit: i32,
+    qs_rtbtimelimit: i32,
+    qs_bwarnlimit: u16,
+    qs_iwarnlimit: u16,
+    qs_rtbwarnlimit: u16,
+    qs_pad3: u16,
+    qs_pad4: u32,
+    qs_pad2: [u64; 7],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Type {
+    User = 0,
+    Group = 1,
+    Project = 2,
+}
+
+/// Usage of one resource against its limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub used: u64,
+    /// Zero when unlimited.
+    pub soft: u64,
+    /// Zero when unlimited.
+    pub hard: u64,
+    /// Warnings issued for going over the soft limit.
+    pub warnings: u16,
+    /// When the grace period over the soft limit ends, in seconds since the
+    /// epoch; zero when not running.
+    pub grace_expires: i64,
+}
+
+/// Usage and limits of one user, group or project. Blocks are in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub kind: Type,
+    pub id: u32,
+    pub blocks: Usage,
+    pub inodes: Usage,
+    pub rt_blocks: Usage,
+}
+
+/// A quota inode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuotaFile {
+    pub ino: u64,
+    pub blocks: u64,
+    pub extents: u32,
+}
+
+/// Quota state of a mount, from `Q_XGETQSTATV`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct State {
+    /// `QUOTA_*` accounting and enforcement bits.
+    pub flags: u16,
+    /// Quota records cached in memory.
+    pub incore_dquots: u32,
+    pub user: Option<QuotaFile>,
+    pub group: Option<QuotaFile>,
+    pub project: Option<QuotaFile>,
+    /// Default grace periods in seconds.
+    pub block_grace: i32,
+    pub inode_grace: i32,
+    pub rt_block_grace: i32,
+    /// Default warning limits.
+    pub block_warn_limit: u16,
+    pub inode_warn_limit: u16,
+    pub rt_block_warn_limit: u16,
+}
+
+/// Limits to set. None leaves a limit as it is, Some(0) removes it. Blocks
+/// are in bytes and rounded down to 512-byte units.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub block_soft: Option<u64>,
+    pub block_hard: Option<u64>,
+    pub inode_soft: Option<u64>,
+    pub inode_hard: Option<u64>,
+    pub rt_block_soft: Option<u64>,
+    pub rt_block_hard: Option<u64>,
+}
+
+impl Type {
+    /// The type bit of `fs_disk_quota.d_flags`.
+    fn disk_flag(self) -> i8 {
+        match self {
+            Type::User => 1,
+            Type::Project => 2,
+            Type::Group => 4,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Type::User => "User",
+            Type::Group => "Group",
+            Type::Project => "Project",
+        }
+    }
+}
+
+impl Usage {
+    pub fn over_soft(&self) -> bool {
+        self.soft > 0 && self.used > self.soft
+    }
+
+    pub fn over_hard(&self) -> bool {
+        self.hard > 0 && self.used > self.hard
+    }
+}
+
+/// Joins a 32-bit timer with the high bits bigtime filesystems add.
+fn timer(low: i32, high: i8) -> i64 {
+    i64::from(low as u32) | i64::from(high as u8) << 32
+}
+
+impl From<&RawDiskQuota> for Quota {
+    fn from(raw: &RawDiskQuota) -> Quota {
+        let kind = match raw.d_flags {
+            2 => Type::Project,
+            4 => Type::Group,
+            _ => Type::User,
+        };
+        Quota {
+            kind,
+            id: raw.d_id,
+            blocks: Usage {
+                used: raw.d_bcount * 512,
+                soft: raw.d_blk_softlimit * 512,
+                hard: raw.d_blk_hardlimit * 512,
+                warnings: raw.d_bwarns,
+                grace_expires: timer(raw.d_btimer, raw.d_btimer_hi),
+            },
+            inodes: Usage {
+                used: raw.d_icount,
+                soft: raw.d_ino_softlimit,
+                hard: raw.d_ino_hardlimit,
+                warnings: raw.d_iwarns,
+                grace_expires: timer(raw.d_itimer, raw.d_itimer_hi),
+            },
+            rt_blocks: Usage {
+                used: raw.d_rtbcount * 512,
+                soft: raw.d_rtb_softlimit * 512,
+                hard: raw.d_rtb_hardlimit * 512,
+                warnings: raw.d_rtbwarns,
+                grace_expires: timer(raw.d_rtbtimer, raw.d_rtbtimer_hi),
+            },
+        }
+    }
+}
+
+fn quota_file(raw: &RawFileStatv) -> Option<QuotaFile> {
+    if raw.qfs_ino == 0 || raw.qfs_ino == !0 {
+        return None;
+    }
+    Some(QuotaFile { ino: raw.qfs_ino, blocks: raw.qfs_nblks, extents: raw.qfs_nextents })
+}
+
+impl From<&RawStatv> for State {
+    fn from(raw: &RawStatv) -> State {
+        State {
+            flags: raw.qs_flags,
+            incore_dquots: raw.qs_incoredqs,
+            user: quota_file(&raw.qs_uquota),
+            group: quota_file(&raw.qs_gquota),
+            project: quota_file(&raw.qs_pquota),
+            block_grace: raw.qs_btimelimit,
+            inode_grace: raw.qs_itimelimit,
+            rt_block_grace: raw.qs_rtbtimelimit,
+            block_warn_limit: raw.qs_bwarnlimit,
+            inode_warn_limit: raw.qs_iwarnlimit,
+            rt_block_warn_limit: raw.qs_rtbwarnlimit,
+        }
+    }
+}
+
+impl State {
+    pub fn accounted(&self, kind: Type) -> bool {
+        let flag = match kind {
+            Type::User => QUOTA_UDQ_ACCT,
+            Type::Group => QUOTA_GDQ_ACCT,
+            Type::Project => QUOTA_PDQ_ACCT,
+        };
+        self.flags & flag != 0
+    }
+
+    pub fn enforced(&self, kind: Type) -> bool {
+        let flag = match kind {
+            Type::User => QUOTA_UDQ_ENFD,
+            Type::Group => QUOTA_GDQ_ENFD,
+            Type::Project => QUOTA_PDQ_ENFD,
+        };
+        self.flags & flag != 0
+    }
+}
+
+impl Limits {
+    fn raw(&self, kind: Type, id: u32) -> RawDiskQuota {
+        let mut mask = 0;
+        let mut set = |limit: Option<u64>, bit: u16| {
+            mask |= if limit.is_some() { bit } else { 0 };
+            limit.unwrap_or(0)
+        };
+        let blocks = |bytes: Option<u64>| bytes.map(|b| b / 512);
+        let mut raw = RawDiskQuota {
+            d_version: DQUOT_VERSION,
+            d_flags: kind.disk_flag(),
+            d_id: id,
+            d_blk_softlimit: set(blocks(self.block_soft), DQ_BSOFT),
+            d_blk_hardlimit: set(blocks(self.block_hard), DQ_BHARD),
+            d_ino_softlimit: set(self.inode_soft, DQ_ISOFT),
+            d_ino_hardlimit: set(self.inode_hard, DQ_IHARD),
+            d_rtb_softlimit: set(blocks(self.rt_block_soft), DQ_RTBSOFT),
+            d_rtb_hardlimit: set(blocks(self.rt_block_hard), DQ_RTBHARD),
+            ..Default::default()
+        };
+        raw.d_fieldmask = mask;
+        raw
+    }
+}
+
+/// `QCMD(cmd, type)`.
+fn qcmd(cmd: i32, kind: Type) -> i32 {
+    cmd << 8 | kind as i32
+}
+
+/// Undoes the octal escapes of `/proc/self/mounts`.
+pub(crate) fn unescape(field: &str) -> String {
+    let mut out = String::new();
+    let mut rest = field;
+    while let Some(pos) = rest.find('\\') {
+        out.push_str(&rest[..pos]);
+        match rest.get(pos + 1..pos + 4).and_then(|oct| u8::from_str_radix(oct, 8).ok()) {
+            Some(c) => {
+                out.push(c as char);
+                rest = &rest[pos + 4..];
+            }
+            None => {
+                out.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The mount point and device of the mount containing `path`, from the
+/// contents of `/proc/self/mounts`. Later mounts shadow earlier ones.
+fn find_mount(mounts: &str, path: &Path) -> Option<(PathBuf, String)> {
+    let mut found: Option<(PathBuf, String)> = None;
+    for line in mounts.lines() {
+        let mut fields = line.split(' ');
+        let (device, mount) = match (fields.next(), fields.next()) {
+            (Some(device), Some(mount)) => (unescape(device), PathBuf::from(unescape(mount))),
+            _ => continue,
+        };
+        if !path.starts_with(&mount) {
+            continue;
+        }
+        let deeper = match found {
+            Some((ref best, _)) => mount.components().count() >= best.components().count(),
+            None => true,
+        };
+        if deeper {
+            found = Some((mount, device));
+        }
+    }
+    found
+}
+
+/// The device `quotactl` needs for the mount containing `path`.
+fn device(path: &Path) -> Result<CString, XfsError> {
+    let path = fs::canonicalize(path)?;
+    let mounts = fs::read_to_string("/proc/self/mounts")?;
+    let (_, device) = find_mount(&mounts, &path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no mount found"))?;
+    CString::new(device).map_err(|err| XfsError::Io(err.into()))
+}
+
+fn quotactl<T>(device: &CString, cmd: i32, kind: Type, id: u32, arg: &mut T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::quotactl(qcmd(cmd, kind), device.as_ptr(), id as libc::c_int, arg as *mut T as *mut libc::c_char)
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Reads the quota state of the filesystem containing `path`.
+pub fn state<P: AsRef<Path>>(path: P) -> Result<State, XfsError> {
+    let device = device(path.as_ref())?;
+    let mut raw = RawStatv { qs_version: QSTATV_VERSION, ..Default::default() };
+    quotactl(&device, XGETQSTATV, Type::User, 0, &mut raw)?;
+    Ok(State::from(&raw))
+}
+
+/// Reads the usage and limits of one user, group or project.
+pub fn get<P: AsRef<Path>>(path: P, kind: Type, id: u32) -> Result<Quota, XfsError> {
+    let device = device(path.as_ref())?;
+    let mut raw = RawDiskQuota::default();
+    quotactl(&device, XGETQUOTA, kind, id, &mut raw)?;
+    Ok(Quota::from(&raw))
+}
+
+/// Lists every user, group or project with a quota record, in ID order.
+/// Needs Linux 4.6 or later for `Q_XGETNEXTQUOTA`.
+pub fn report<P: AsRef<Path>>(path: P, kind: Type) -> Result<Vec<Quota>, XfsError> {
+    let device = device(path.as_ref())?;
+    let mut quotas = vec![];
+    let mut id = 0;
+    loop {
+        let mut raw = RawDiskQuota::default();
+        match quotactl(&device, XGETNEXTQUOTA, kind, id, &mut raw) {
+            Ok(()) => {}
+            Err(ref err) if err.raw_os_error() == Some(libc::ENOENT) => break,
+            Err(err) => return Err(XfsError::Io(err)),
+        }
+        quotas.push(Quota::from(&raw));
+        id = match raw.d_id.checked_add(1) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(quotas)
+}
+
+/// Changes the limits of one user, group or project. Needs
+/// `CAP_SYS_ADMIN`.
+pub fn set_limits<P: AsRef<Path>>(path: P, kind: Type, id: u32, limits: &Limits) -> Result<(), XfsError> {
+    let device = device(path.as_ref())?;
+    let mut raw = limits.raw(kind, id);
+    quotactl(&device, XSETQLIM, kind, id, &mut raw)?;
+    Ok(())
+}
+
+/// Formats the time left on a grace period the way `xfs_quota` does.
+fn grace(expires: i64, now: i64) -> String {
+    if expires == 0 {
+        return "[--------]".to_string();
+    }
+    let left = (expires - now).max(0);
+    let days = left / 86400;
+    match days {
+        0 => format!("[{:02}:{:02}:{:02}]", left / 3600, left / 60 % 60, left % 60),
+        1 => "[1 day]".to_string(),
+        _ => format!("[{} days]", days),
+    }
+}
+
+/// Formats block usage the way `xfs_quota -x -c 'report -n'` prints it,
+/// in KiB, with grace periods counted from `now` (seconds since the epoch).
+pub fn format_report(kind: Type, mount: &str, device: &str, quotas: &[Quota], now: i64) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{} quota on {} ({})", kind.name(), mount, device);
+    let _ = writeln!(out, "{:>37}", "Blocks");
+    let _ = writeln!(out, "{:<10} {:>10} {:>10} {:>10}    Warn/Grace",
+                     format!("{} ID", kind.name()), "Used", "Soft", "Hard");
+    let _ = writeln!(out, "---------- {}", "-".repeat(50));
+    for quota in quotas {
+        let _ = writeln!(out, "{:<10} {:>10} {:>10} {:>10}     {:02} {}",
+                         format!("#{}", quota.id), quota.blocks.used / 1024, quota.blocks.soft / 1024,
+                         quota.blocks.hard / 1024, quota.blocks.warnings,
+                         grace(quota.blocks.grace_expires, now));
+    }
+    out
+}
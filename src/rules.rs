@@ -0,0 +1,505 @@
+//! Threshold rules over `/proc/fs/xfs/stat` deltas, with alert states that
+//! move from pending to firing to resolved under hysteresis.
+//!
+//! Rules are loaded from a TOML file of `[[rule]]` tables:
+//!
+//! ```toml
+//! [[rule]]
+//! name = "log_force_sleeps"
+//! when = "rate(log_operations.force_sleep) > 50"
+//! for = 3        # intervals the condition must hold before firing
+//! clear = 40     # must fall to here to resolve (defaults to the threshold)
+//! clear_for = 2  # intervals it must stay cleared (defaults to 1)
+//!
+//! [[rule]]
+//! name = "inode_cache_hits"
+//! when = "ratio(inode_operations.cache_hits, inode_operations.cache_lookups) < 0.8"
+//! ```
+//!
+//! The file must be TOML; other formats such as YAML aren't read. Only
+//! the subset of TOML rules need is understood: `[[rule]]` tables,
+//! comments, basic and literal strings, numbers and booleans. Any other
+//! key, or anything after a value but a comment, is an error.
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use fields;
+use XfsError;
+use XfsStat;
+
+#[cfg(test)]
+mod tests {
+    use fixtures;
+
+    use super::{Engine, Metric, Op, State};
+
+    const RULES: &str = r#"
+# Log force sleeps.
+[[rule]]
+name = "log_force_sleeps"
+when = "rate(log_operations.force_sleep) > 50"
+for = 3
+clear = 40
+clear_for = 2
+
+[[rule]]
+name = "inode_cache_hits"   # a derived ratio
+when = "ratio(inode_operations.cache_hits, inode_operations.cache_lookups) < 0.8"
+"#;
+
+    fn sample(force_sleep: u32, lookups: u32, hits: u32) -> ::XfsStat {
+        fixtures::stat(&[("log", &format!("0 0 0 0 {}", force_sleep)),
+                         ("ig", &format!("{} {} 0 0 0 0 0", lookups, hits))])
+    }
+
+    #[test]
+    fn it_parses_rules() {
+        let rules = super::parse_rules(RULES).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].name, "log_force_sleeps");
+        assert_eq!(rules[0].metric, Metric::Rate("log_operations.force_sleep".to_string()));
+        assert_eq!((rules[0].op, rules[0].threshold, rules[0].clear), (Op::Gt, 50.0, 40.0));
+        assert_eq!((rules[0].for_intervals, rules[0].clear_intervals), (3, 2));
+        assert_eq!(rules[1].metric, Metric::Ratio("inode_operations.cache_hits".to_string(),
+                                                  "inode_operations.cache_lookups".to_string()));
+        assert_eq!((rules[1].clear, rules[1].for_intervals, rules[1].clear_intervals), (0.8, 1, 1));
+
+        assert!(super::parse_rules("[[rule]]\nname = \"x\"\nwhen = \"rate(log_operations.nope) > 1\"").is_err());
+        assert!(super::parse_rules("[[rule]]\nname = \"x\"\nwhen = \"log_operations.log_writes >\"").is_err());
+        assert!(super::parse_rules("[[rule]]\nname = \"x\"").is_err());
+        assert!(super::parse_rules("[[rule]]\nname = \"x\"\nwhen = \"delta(transactions.async) >= 1\"\nfor = 2.5").is_err());
+    }
+
+    #[test]
+    fn it_moves_through_pending_firing_and_resolved() {
+        let mut engine = Engine::new(super::parse_rules(RULES).unwrap());
+        // Force sleeps per second over 1s intervals, with the cache healthy.
+        let mut sleeps = 0;
+        let mut previous = sample(0, 0, 0);
+        let mut states = vec![];
+        let mut changes = vec![];
+        for (i, &rate) in [60, 60, 60, 45, 30, 60, 30, 30, 30].iter().enumerate() {
+            sleeps += rate;
+            let current = sample(sleeps, 100 * (i as u32 + 1), 90 * (i as u32 + 1));
+            for transition in engine.evaluate(&previous, &current, 1.0) {
+                changes.push(format!("{}", transition));
+            }
+            states.push(engine.state("log_force_sleeps").unwrap());
+            previous = current;
+        }
+        // Above 40 keeps it firing; one interval below it isn't enough.
+        assert_eq!(states, [State::Pending, State::Pending, State::Firing, State::Firing, State::Firing,
+                            State::Firing, State::Firing, State::Inactive, State::Inactive]);
+        assert_eq!(changes, ["log_force_sleeps: inactive -> pending (60)",
+                             "log_force_sleeps: pending -> firing (60)",
+                             "log_force_sleeps: firing -> inactive (30), resolved"]);
+
+        // A ratio fires at once, and an interval with no lookups leaves it be.
+        let t0 = sample(0, 1000, 900);
+        let t1 = sample(0, 1100, 950);
+        let t2 = sample(0, 1100, 950);
+        let t3 = sample(0, 1200, 1050);
+        let transitions = engine.evaluate(&t0, &t1, 1.0);
+        assert_eq!(transitions.len(), 1);
+        assert_eq!((transitions[0].to, transitions[0].value), (State::Firing, 0.5));
+        assert!(engine.evaluate(&t1, &t2, 1.0).is_empty());
+        assert!(engine.evaluate(&t2, &t3, 1.0)[0].resolved());
+    }
+
+    #[test]
+    fn it_handles_counter_wrap() {
+        let metric = Metric::Delta("log_operations.force_sleep".to_string());
+        assert_eq!(metric.value(&sample(u32::MAX - 1, 0, 0), &sample(3, 0, 0), 1.0), Some(5.0));
+    }
+
+    #[test]
+    fn it_lets_gauges_fall() {
+        let active = |value: u32| fixtures::stat(&[("vnodes", &format!("{} 0 0 0 0 0 0 0", value))]);
+        let metric = Metric::Rate("vnode_statistics.active".to_string());
+        assert_eq!(metric.value(&active(100), &active(90), 2.0), Some(-5.0));
+        let mut engine = Engine::new(vec![super::Rule::new("churn", "rate(vnode_statistics.active) > 5").unwrap()]);
+        assert!(engine.evaluate(&active(100), &active(90), 1.0).is_empty());
+        assert_eq!(engine.evaluate(&active(90), &active(100), 1.0)[0].value, 10.0);
+    }
+
+    #[test]
+    fn it_reads_toml_strings_strictly() {
+        let rules = super::parse_rules(r#"
+[[rule]]
+name = "a \"quoted\" \\ \u00e9 # name" # comment
+when = 'rate(log_operations.force_sleep) > 1'
+"#).unwrap();
+        assert_eq!(rules[0].name, "a \"quoted\" \\ \u{e9} # name");
+        assert_eq!(rules[0].metric, Metric::Rate("log_operations.force_sleep".to_string()));
+
+        let when = "when = \"value(log_operations.log_writes) > 1\"";
+        for bad in &["name = \"x\" y", "name = \"x\\q\"", "name = \"x", "name = 'x", "nmae = \"x\"",
+                     "threshold = 5", "for = 1 2", "name = \"x\"\nname = \"y\""] {
+            assert!(super::parse_rules(&format!("[[rule]]\n{}\n{}", when, bad)).is_err(), "{}", bad);
+        }
+        // YAML is not TOML.
+        assert!(super::parse_rules("- name: x\n  when: value(log_operations.log_writes) > 1").is_err());
+    }
+}
+
+/// A comparison in a rule's condition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+/// What a rule measures each interval. Counters are named by their
+/// dotted `XfsStat` path, such as `log_operations.force_sleep`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Metric {
+    /// The counter's current value.
+    Value(String),
+    /// Its change over the interval; negative when a gauge falls.
+    Delta(String),
+    /// Its change per second.
+    Rate(String),
+    /// The change in one counter over the change in another; no value
+    /// when the second didn't move.
+    Ratio(String, String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    pub metric: Metric,
+    pub op: Op,
+    pub threshold: f64,
+    /// The value must get back past this for the alert to clear.
+    pub clear: f64,
+    /// Consecutive intervals the condition must hold to fire.
+    pub for_intervals: u32,
+    /// Consecutive intervals past `clear` to resolve.
+    pub clear_intervals: u32,
+}
+
+/// Where a rule's alert stands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Inactive,
+    /// The condition holds but not yet for long enough.
+    Pending,
+    Firing,
+}
+
+/// A change of alert state, with the value that caused it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transition {
+    pub rule: String,
+    pub from: State,
+    pub to: State,
+    pub value: f64,
+}
+
+/// Evaluates rules over successive pairs of snapshots.
+#[derive(Clone, Debug)]
+pub struct Engine {
+    rules: Vec<Rule>,
+    /// State of each rule, and how many intervals in a row it has been
+    /// heading for the next one.
+    states: Vec<(State, u32)>,
+}
+
+/// The change in a field between two snapshots, as its kind has it.
+fn delta(previous: &XfsStat, current: &XfsStat, path: &str) -> Option<f64> {
+    Some(fields::field(path)?.change(previous.get(path)?, current.get(path)?))
+}
+
+impl Op {
+    fn holds(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Op::Gt => value > threshold,
+            Op::Ge => value >= threshold,
+            Op::Lt => value < threshold,
+            Op::Le => value <= threshold,
+        }
+    }
+}
+
+impl Metric {
+    fn paths(&self) -> Vec<&str> {
+        match *self {
+            Metric::Value(ref path) | Metric::Delta(ref path) | Metric::Rate(ref path) => vec![path],
+            Metric::Ratio(ref numerator, ref denominator) => vec![numerator, denominator],
+        }
+    }
+
+    /// The metric over an interval of `seconds` between two snapshots.
+    pub fn value(&self, previous: &XfsStat, current: &XfsStat, seconds: f64) -> Option<f64> {
+        match *self {
+            Metric::Value(ref path) => current.get(path).map(|value| value as f64),
+            Metric::Delta(ref path) => delta(previous, current, path),
+            Metric::Rate(ref path) => {
+                if seconds <= 0.0 {
+                    return None;
+                }
+                delta(previous, current, path).map(|delta| delta / seconds)
+            }
+            Metric::Ratio(ref numerator, ref denominator) => {
+                match delta(previous, current, denominator)? {
+                    0.0 => None,
+                    below => Some(delta(previous, current, numerator)? / below),
+                }
+            }
+        }
+    }
+}
+
+impl Rule {
+    /// A rule from a condition such as `rate(log_operations.force_sleep) > 50`,
+    /// firing at once and clearing at the threshold.
+    pub fn new(name: &str, when: &str) -> Result<Rule, XfsError> {
+        let (rest, threshold) = when.trim().rsplit_once(' ').ok_or(XfsError::Parse)?;
+        let (metric, op) = rest.trim_end().rsplit_once(' ').ok_or(XfsError::Parse)?;
+        let metric = parse_metric(metric.trim()).ok_or(XfsError::Parse)?;
+        if metric.paths().iter().any(|path| fields::field(path).is_none()) {
+            return Err(XfsError::Parse);
+        }
+        let op = match op {
+            ">" => Op::Gt,
+            ">=" => Op::Ge,
+            "<" => Op::Lt,
+            "<=" => Op::Le,
+            _ => return Err(XfsError::Parse),
+        };
+        let threshold = threshold.parse().map_err(|_| XfsError::Parse)?;
+        Ok(Rule {
+            name: name.to_string(),
+            metric,
+            op,
+            threshold,
+            clear: threshold,
+            for_intervals: 1,
+            clear_intervals: 1,
+        })
+    }
+}
+
+/// `path`, `delta(path)`, `rate(path)` or `ratio(path, path)`.
+fn parse_metric(text: &str) -> Option<Metric> {
+    let (function, args) = match text.find('(') {
+        Some(open) => (&text[..open], text[open + 1..].strip_suffix(')')?),
+        None => ("value", text),
+    };
+    let metric = match function {
+        "value" => Metric::Value(args.to_string()),
+        "delta" => Metric::Delta(args.to_string()),
+        "rate" => Metric::Rate(args.to_string()),
+        "ratio" => {
+            let (numerator, denominator) = args.split_once(',')?;
+            Metric::Ratio(numerator.trim().to_string(), denominator.trim().to_string())
+        }
+        _ => return None,
+    };
+    Some(metric)
+}
+
+/// A TOML value in a rule table.
+enum Value {
+    Str(String),
+    Num(f64),
+}
+
+/// The keys a rule table may have.
+const KEYS: [&str; 5] = ["name", "when", "for", "clear", "clear_for"];
+
+/// A basic string after its opening quote, with its escapes replaced, and
+/// what follows the closing quote.
+fn basic_string(text: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &text[i + 1..])),
+            '\\' => {
+                let escaped = match chars.next()?.1 {
+                    'b' => '\u{8}',
+                    't' => '\t',
+                    'n' => '\n',
+                    'f' => '\u{c}',
+                    'r' => '\r',
+                    '"' => '"',
+                    '\\' => '\\',
+                    digits @ ('u' | 'U') => {
+                        let len = if digits == 'u' { 4 } else { 8 };
+                        let hex: String = (0..len).map(|_| chars.next().map(|(_, c)| c)).collect::<Option<_>>()?;
+                        char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                    }
+                    _ => return None,
+                };
+                value.push(escaped);
+            }
+            _ => value.push(c),
+        }
+    }
+    None
+}
+
+/// Splits `key = value`. Only a comment may follow the value.
+fn key_value(line: &str) -> Option<(&str, Value)> {
+    let (key, value) = line.split_once('=')?;
+    let value = value.trim_start();
+    let (value, rest) = if let Some(quoted) = value.strip_prefix('"') {
+        let (value, rest) = basic_string(quoted)?;
+        (Value::Str(value), rest)
+    } else if let Some(quoted) = value.strip_prefix('\'') {
+        let (value, rest) = quoted.split_once('\'')?;
+        (Value::Str(value.to_string()), rest)
+    } else {
+        let end = value.find('#').unwrap_or(value.len());
+        let value = match value[..end].trim() {
+            "true" => Value::Num(1.0),
+            "false" => Value::Num(0.0),
+            number => Value::Num(number.replace('_', "").parse().ok()?),
+        };
+        (value, "")
+    };
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return None;
+    }
+    Some((key.trim(), value))
+}
+
+fn rule_from(table: &[(&str, Value)]) -> Result<Rule, XfsError> {
+    for (i, entry) in table.iter().enumerate() {
+        if !KEYS.contains(&entry.0) || table[..i].iter().any(|other| other.0 == entry.0) {
+            return Err(XfsError::Parse);
+        }
+    }
+    let string = |key| match table.iter().find(|entry| entry.0 == key) {
+        Some(&(_, Value::Str(ref value))) => Ok(Some(value.as_str())),
+        Some(_) => Err(XfsError::Parse),
+        None => Ok(None),
+    };
+    let number = |key| match table.iter().find(|entry| entry.0 == key) {
+        Some(&(_, Value::Num(value))) => Ok(Some(value)),
+        Some(_) => Err(XfsError::Parse),
+        None => Ok(None),
+    };
+    let intervals = |key| match number(key)? {
+        Some(count) if count < 0.0 || count.fract() != 0.0 => Err(XfsError::Parse),
+        Some(count) => Ok(Some(count as u32)),
+        None => Ok(None),
+    };
+    let name = string("name")?.ok_or(XfsError::Parse)?;
+    let mut rule = Rule::new(name, string("when")?.ok_or(XfsError::Parse)?)?;
+    rule.clear = number("clear")?.unwrap_or(rule.threshold);
+    rule.for_intervals = intervals("for")?.unwrap_or(1);
+    rule.clear_intervals = intervals("clear_for")?.unwrap_or(1);
+    Ok(rule)
+}
+
+/// Parses `[[rule]]` tables.
+pub fn parse_rules(text: &str) -> Result<Vec<Rule>, XfsError> {
+    let mut tables: Vec<Vec<(&str, Value)>> = vec![];
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.split('#').next().map(str::trim) == Some("[[rule]]") {
+            tables.push(vec![]);
+            continue;
+        }
+        let entry = key_value(line).ok_or(XfsError::Parse)?;
+        tables.last_mut().ok_or(XfsError::Parse)?.push(entry);
+    }
+    tables.iter().map(|table| rule_from(table)).collect()
+}
+
+/// Reads a rules file.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Rule>, XfsError> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    parse_rules(&text)
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Inactive => "inactive",
+            State::Pending => "pending",
+            State::Firing => "firing",
+        }
+    }
+}
+
+impl Transition {
+    /// Whether a firing alert has cleared.
+    pub fn resolved(&self) -> bool {
+        self.from == State::Firing && self.to == State::Inactive
+    }
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} -> {} ({})", self.rule, self.from.name(), self.to.name(), self.value)?;
+        if self.resolved() {
+            write!(f, ", resolved")?;
+        }
+        Ok(())
+    }
+}
+
+impl Engine {
+    pub fn new(rules: Vec<Rule>) -> Engine {
+        let states = vec![(State::Inactive, 0); rules.len()];
+        Engine { rules, states }
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn state(&self, name: &str) -> Option<State> {
+        let index = self.rules.iter().position(|rule| rule.name == name)?;
+        Some(self.states[index].0)
+    }
+
+    /// Evaluates every rule over an interval of `seconds` between two
+    /// snapshots. A rule whose metric has no value this interval keeps its
+    /// state.
+    pub fn evaluate(&mut self, previous: &XfsStat, current: &XfsStat, seconds: f64) -> Vec<Transition> {
+        let mut transitions = vec![];
+        for (rule, entry) in self.rules.iter().zip(self.states.iter_mut()) {
+            let value = match rule.metric.value(previous, current, seconds) {
+                Some(value) => value,
+                None => continue,
+            };
+            let (state, streak) = *entry;
+            let next = match state {
+                State::Inactive | State::Pending if rule.op.holds(value, rule.threshold) => {
+                    if streak + 1 >= rule.for_intervals {
+                        (State::Firing, 0)
+                    } else {
+                        (State::Pending, streak + 1)
+                    }
+                }
+                State::Inactive | State::Pending => (State::Inactive, 0),
+                State::Firing if !rule.op.holds(value, rule.clear) => {
+                    if streak + 1 >= rule.clear_intervals {
+                        (State::Inactive, 0)
+                    } else {
+                        (State::Firing, streak + 1)
+                    }
+                }
+                State::Firing => (State::Firing, 0),
+            };
+            if next.0 != state {
+                transitions.push(Transition { rule: rule.name.clone(), from: state, to: next.0, value });
+            }
+            *entry = next;
+        }
+        transitions
+    }
+}
@@ -0,0 +1,445 @@
+//! Online metadata checks through `XFS_IOC_SCRUB_METADATA`, the ioctl
+//! `xfs_scrub` drives, with a rate limited scheduler so that checks can run
+//! on our own timetable.
+//!
+//! Checks never repair anything here; results update the kernel's health
+//! masks that `health` reports.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bulkstat::{Bulkstat, Cursor};
+use geometry;
+use inode::FileType;
+use ioctl;
+use libc;
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::time::{Duration, Instant};
+
+    use inode::FileType;
+
+    use super::{Scope, Target, Type};
+
+    #[test]
+    fn it_encodes_the_ioctl_struct() {
+        assert_eq!(mem::size_of::<super::RawScrubMetadata>(), 64);
+        assert_eq!(super::SCRUB_METADATA, 0xc040583c);
+        let raw = super::RawScrubMetadata::new(Type::Directory, Target::Inode { ino: 131, generation: 7 });
+        assert_eq!((raw.sm_type, raw.sm_ino, raw.sm_gen, raw.sm_agno), (15, 131, 7, 0));
+        let raw = super::RawScrubMetadata::new(Type::Refcountbt, Target::Ag(3));
+        assert_eq!((raw.sm_type, raw.sm_agno), (10, 3));
+    }
+
+    #[test]
+    fn it_lists_types_by_scope() {
+        let ag: Vec<_> = Type::all().iter().filter(|t| t.scope() == Scope::Ag).collect();
+        assert_eq!(ag.len(), 10);
+        assert_eq!(Type::Counters.scope(), Scope::Fs);
+        assert_eq!(Type::Parent.scope(), Scope::Inode);
+        assert_eq!(Type::Finobt.to_string(), "finobt");
+        assert!(Type::Directory.applies_to(FileType::Directory));
+        assert!(!Type::Directory.applies_to(FileType::Regular));
+        assert!(Type::DataFork.applies_to(FileType::Regular));
+    }
+
+    #[test]
+    fn it_decodes_outcomes() {
+        let outcome = super::Outcome::new(Type::Inobt, Target::Ag(1), super::OFLAG_PREEN | super::OFLAG_XCORRUPT);
+        assert!(outcome.preen && outcome.xcorrupt);
+        assert!(!outcome.corrupt && !outcome.incomplete);
+        assert!(!outcome.is_clean());
+        assert!(super::Outcome::new(Type::Inobt, Target::Ag(1), 0).is_clean());
+    }
+
+    #[test]
+    fn it_spaces_out_calls() {
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        assert_eq!(super::delay(None, now, second), Duration::from_secs(0));
+        assert_eq!(super::delay(Some(now), now + Duration::from_millis(300), second), Duration::from_millis(700));
+        assert_eq!(super::delay(Some(now), now + second * 2, second), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn it_picks_checks_by_scope_and_file_type() {
+        let checks = |scope, file_type| super::applicable(Type::all(), scope, file_type).collect::<Vec<_>>();
+        let fs = checks(Scope::Fs, None);
+        assert_eq!(fs.len(), 9);
+        assert!(!fs.contains(&Type::Probe) && fs.contains(&Type::Counters));
+        assert_eq!(checks(Scope::Ag, None)[..3], [Type::Superblock, Type::Agf, Type::Agfl]);
+        assert!(checks(Scope::Inode, None).is_empty());
+
+        let inode = [Type::Inode, Type::DataFork, Type::AttrFork, Type::CowFork, Type::Xattr, Type::Parent];
+        assert_eq!(checks(Scope::Inode, Some(FileType::Regular)), inode);
+        assert_eq!(checks(Scope::Inode, Some(FileType::Directory)),
+                   [&inode[..4], &[Type::Directory], &inode[4..], &[Type::DirectoryTree]].concat());
+        assert!(checks(Scope::Inode, Some(FileType::Symlink)).contains(&Type::Symlink));
+        // Only the kinds asked for.
+        let kinds = [Type::Bnobt, Type::Symlink, Type::Counters];
+        assert_eq!(super::applicable(&kinds, Scope::Ag, None).collect::<Vec<_>>(), [Type::Bnobt]);
+        assert_eq!(super::applicable(&kinds, Scope::Inode, Some(FileType::Regular)).count(), 0);
+    }
+}
+
+const SCRUB_METADATA: u64 = ioctl::iowr(60, 64);
+
+/// The metadata was found damaged.
+pub const OFLAG_CORRUPT: u32 = 0x2;
+/// The metadata is correct but could be optimised.
+pub const OFLAG_PREEN: u32 = 0x4;
+/// A cross-reference with other metadata could not be made.
+pub const OFLAG_XFAIL: u32 = 0x8;
+/// The metadata disagrees with other metadata.
+pub const OFLAG_XCORRUPT: u32 = 0x10;
+/// Not everything could be checked.
+pub const OFLAG_INCOMPLETE: u32 = 0x20;
+/// Something looked suspicious but not wrong.
+pub const OFLAG_WARNING: u32 = 0x40;
+
+/// `struct xfs_scrub_metadata`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RawScrubMetadata {
+    sm_type: u32,
+    sm_flags: u32,
+    sm_ino: u64,
+    sm_gen: u32,
+    sm_agno: u32,
+    sm_reserved: [u64; 5],
+}
+
+/// What a check covers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Fs,
+    Ag,
+    Inode,
+}
+
+/// A check (`XFS_SCRUB_TYPE_*`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Type {
+    /// Only tests that the kernel supports scrubbing.
+    Probe,
+    Superblock,
+    Agf,
+    Agfl,
+    Agi,
+    Bnobt,
+    Cntbt,
+    Inobt,
+    Finobt,
+    Rmapbt,
+    Refcountbt,
+    Inode,
+    DataFork,
+    AttrFork,
+    CowFork,
+    Directory,
+    Xattr,
+    Symlink,
+    Parent,
+    RtBitmap,
+    RtSummary,
+    UserQuota,
+    GroupQuota,
+    ProjectQuota,
+    /// Summary counters.
+    Counters,
+    QuotaCheck,
+    LinkCounts,
+    /// Marks the filesystem healthy if nothing else is sick.
+    Healthy,
+    DirectoryTree,
+}
+
+const TYPES: [Type; 29] = [
+    Type::Probe, Type::Superblock, Type::Agf, Type::Agfl, Type::Agi, Type::Bnobt, Type::Cntbt, Type::Inobt,
+    Type::Finobt, Type::Rmapbt, Type::Refcountbt, Type::Inode, Type::DataFork, Type::AttrFork, Type::CowFork,
+    Type::Directory, Type::Xattr, Type::Symlink, Type::Parent, Type::RtBitmap, Type::RtSummary, Type::UserQuota,
+    Type::GroupQuota, Type::ProjectQuota, Type::Counters, Type::QuotaCheck, Type::LinkCounts, Type::Healthy,
+    Type::DirectoryTree,
+];
+
+/// What a check ran against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Target {
+    Fs,
+    Ag(u32),
+    Inode { ino: u64, generation: u32 },
+}
+
+/// The result of one check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Outcome {
+    pub kind: Type,
+    pub target: Target,
+    pub corrupt: bool,
+    pub xcorrupt: bool,
+    pub xfail: bool,
+    pub preen: bool,
+    pub incomplete: bool,
+    pub warning: bool,
+}
+
+/// The results of a pass over many targets. Clean results are only
+/// counted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub checked: u64,
+    /// Checks the kernel or the filesystem doesn't support, such as
+    /// `Rmapbt` without the rmapbt feature.
+    pub skipped: u64,
+    pub findings: Vec<Outcome>,
+}
+
+/// Runs checks against one filesystem, at most one per `interval`.
+pub struct Scrubber {
+    path: PathBuf,
+    file: File,
+    ag_count: u32,
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl Type {
+    /// Every check, in the kernel's order.
+    pub fn all() -> &'static [Type] {
+        &TYPES
+    }
+
+    fn code(self) -> u32 {
+        self as u32
+    }
+
+    pub fn scope(self) -> Scope {
+        match self {
+            Type::Superblock | Type::Agf | Type::Agfl | Type::Agi | Type::Bnobt | Type::Cntbt | Type::Inobt |
+            Type::Finobt | Type::Rmapbt | Type::Refcountbt => Scope::Ag,
+            Type::Inode | Type::DataFork | Type::AttrFork | Type::CowFork | Type::Directory | Type::Xattr |
+            Type::Symlink | Type::Parent | Type::DirectoryTree => Scope::Inode,
+            _ => Scope::Fs,
+        }
+    }
+
+    /// Whether an inode check makes sense for a file of type `file_type`.
+    pub fn applies_to(self, file_type: FileType) -> bool {
+        match self {
+            Type::Directory | Type::DirectoryTree => file_type == FileType::Directory,
+            Type::Symlink => file_type == FileType::Symlink,
+            _ => self.scope() == Scope::Inode,
+        }
+    }
+
+    /// The name `xfs_io -c scrub` takes.
+    pub fn name(self) -> &'static str {
+        match self {
+            Type::Probe => "probe",
+            Type::Superblock => "sb",
+            Type::Agf => "agf",
+            Type::Agfl => "agfl",
+            Type::Agi => "agi",
+            Type::Bnobt => "bnobt",
+            Type::Cntbt => "cntbt",
+            Type::Inobt => "inobt",
+            Type::Finobt => "finobt",
+            Type::Rmapbt => "rmapbt",
+            Type::Refcountbt => "refcountbt",
+            Type::Inode => "inode",
+            Type::DataFork => "bmapbtd",
+            Type::AttrFork => "bmapbta",
+            Type::CowFork => "bmapbtc",
+            Type::Directory => "directory",
+            Type::Xattr => "xattr",
+            Type::Symlink => "symlink",
+            Type::Parent => "parent",
+            Type::RtBitmap => "rtbitmap",
+            Type::RtSummary => "rtsummary",
+            Type::UserQuota => "usrquota",
+            Type::GroupQuota => "grpquota",
+            Type::ProjectQuota => "prjquota",
+            Type::Counters => "fscounters",
+            Type::QuotaCheck => "quotacheck",
+            Type::LinkCounts => "nlinks",
+            Type::Healthy => "healthy",
+            Type::DirectoryTree => "dirtree",
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl RawScrubMetadata {
+    fn new(kind: Type, target: Target) -> RawScrubMetadata {
+        let mut raw = RawScrubMetadata { sm_type: kind.code(), ..Default::default() };
+        match target {
+            Target::Fs => {}
+            Target::Ag(agno) => raw.sm_agno = agno,
+            Target::Inode { ino, generation } => {
+                raw.sm_ino = ino;
+                raw.sm_gen = generation;
+            }
+        }
+        raw
+    }
+}
+
+impl Outcome {
+    fn new(kind: Type, target: Target, flags: u32) -> Outcome {
+        Outcome {
+            kind,
+            target,
+            corrupt: flags & OFLAG_CORRUPT != 0,
+            xcorrupt: flags & OFLAG_XCORRUPT != 0,
+            xfail: flags & OFLAG_XFAIL != 0,
+            preen: flags & OFLAG_PREEN != 0,
+            incomplete: flags & OFLAG_INCOMPLETE != 0,
+            warning: flags & OFLAG_WARNING != 0,
+        }
+    }
+
+    /// Nothing found, not even something to preen.
+    pub fn is_clean(&self) -> bool {
+        !(self.corrupt || self.xcorrupt || self.xfail || self.preen || self.incomplete || self.warning)
+    }
+}
+
+impl Report {
+    fn add(&mut self, outcome: Option<Outcome>) {
+        match outcome {
+            Some(outcome) => {
+                self.checked += 1;
+                if !outcome.is_clean() {
+                    self.findings.push(outcome);
+                }
+            }
+            None => self.skipped += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: Report) {
+        self.checked += other.checked;
+        self.skipped += other.skipped;
+        self.findings.extend(other.findings);
+    }
+}
+
+/// How long to wait before the next call, `interval` after the `last`.
+fn delay(last: Option<Instant>, now: Instant, interval: Duration) -> Duration {
+    match last {
+        Some(last) => interval.checked_sub(now.duration_since(last)).unwrap_or_default(),
+        None => Duration::default(),
+    }
+}
+
+/// The checks among `kinds` to run against a target in `scope`; for an
+/// inode, one of type `file_type`.
+fn applicable<'a>(kinds: &'a [Type], scope: Scope, file_type: Option<FileType>) -> impl Iterator<Item = Type> + 'a {
+    kinds.iter().cloned().filter(move |&kind| match scope {
+        Scope::Fs => kind.scope() == Scope::Fs && kind != Type::Probe,
+        Scope::Ag => kind.scope() == Scope::Ag,
+        Scope::Inode => file_type.is_some_and(|file_type| kind.applies_to(file_type)),
+    })
+}
+
+/// Whether a check failed only because the kernel or the filesystem
+/// doesn't have what it covers.
+fn absent(err: &io::Error) -> bool {
+    ioctl::unsupported(err) || err.raw_os_error() == Some(libc::ENOENT)
+}
+
+impl Scrubber {
+    /// Opens the filesystem containing `path`. Scrubbing needs
+    /// `CAP_SYS_ADMIN`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Scrubber, XfsError> {
+        let path = path.as_ref();
+        let ag_count = geometry::geometry(path)?.ag_count;
+        Ok(Scrubber {
+            path: path.to_path_buf(),
+            file: ioctl::open(path)?,
+            ag_count,
+            interval: Duration::default(),
+            last: None,
+        })
+    }
+
+    /// Spaces checks at least `interval` apart.
+    pub fn rate_limit(mut self, interval: Duration) -> Scrubber {
+        self.interval = interval;
+        self
+    }
+
+    /// Whether the kernel supports online scrub at all.
+    pub fn supported(&mut self) -> Result<bool, XfsError> {
+        Ok(self.scrub(Type::Probe, Target::Fs)?.is_some())
+    }
+
+    /// Runs one check. None when the kernel or the filesystem doesn't
+    /// support it.
+    pub fn scrub(&mut self, kind: Type, target: Target) -> Result<Option<Outcome>, XfsError> {
+        thread::sleep(delay(self.last, Instant::now(), self.interval));
+        let mut raw = RawScrubMetadata::new(kind, target);
+        let result = unsafe { ioctl::ioctl(&self.file, SCRUB_METADATA, &mut raw) };
+        self.last = Some(Instant::now());
+        match result {
+            Ok(_) => Ok(Some(Outcome::new(kind, target, raw.sm_flags))),
+            Err(ref err) if absent(err) => Ok(None),
+            Err(err) => Err(XfsError::Io(err)),
+        }
+    }
+
+    /// Runs the filesystem-wide checks among `kinds`.
+    pub fn scrub_fs(&mut self, kinds: &[Type]) -> Result<Report, XfsError> {
+        let mut report = Report::default();
+        for kind in applicable(kinds, Scope::Fs, None) {
+            report.add(self.scrub(kind, Target::Fs)?);
+        }
+        Ok(report)
+    }
+
+    /// Runs the per-AG checks among `kinds` against every AG.
+    pub fn scrub_ags(&mut self, kinds: &[Type]) -> Result<Report, XfsError> {
+        let mut report = Report::default();
+        for agno in 0..self.ag_count {
+            for kind in applicable(kinds, Scope::Ag, None) {
+                report.add(self.scrub(kind, Target::Ag(agno))?);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Runs the per-inode checks among `kinds` against every inode from
+    /// `cursor` on, as far as they apply to the file type.
+    pub fn scrub_inodes(&mut self, kinds: &[Type], cursor: Cursor) -> Result<Report, XfsError> {
+        let mut report = Report::default();
+        for stat in Bulkstat::resume(&self.path, cursor)? {
+            let stat = stat?;
+            let target = Target::Inode { ino: stat.ino, generation: stat.generation };
+            for kind in applicable(kinds, Scope::Inode, Some(stat.file_type())) {
+                report.add(self.scrub(kind, target)?);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Runs every check: AG metadata first, then inodes, then the
+    /// filesystem-wide summaries that depend on both, as `xfs_scrub` does.
+    pub fn scrub_all(&mut self) -> Result<Report, XfsError> {
+        let mut report = self.scrub_ags(Type::all())?;
+        report.merge(self.scrub_inodes(Type::all(), Cursor::default())?);
+        report.merge(self.scrub_fs(Type::all())?);
+        Ok(report)
+    }
+}
@@ -0,0 +1,254 @@
+//! Classifies log space and AIL tail pushing activity between two
+//! `/proc/fs/xfs/stat` snapshots into a likely cause of log stalls.
+//!
+//! Transactions reserve log space up front and sleep when there isn't
+//! enough; space comes back as the AIL (active item list) writes back
+//! metadata and the log tail moves. The `push_ail` counters say what the
+//! pusher found on each item it visited, which is what tells the causes
+//! apart.
+
+use std::fmt;
+
+use XfsStat;
+
+#[cfg(test)]
+mod tests {
+    use fixtures;
+
+    use super::{Condition, Pressure, Thresholds};
+
+    /// logspace, sleep_logspace, push_ails, success, pushbuf, pinned,
+    /// locked, flushing, restarts, flush; then log force sleeps.
+    fn sample(push_ail: [u32; 10], force_sleep: u32) -> ::XfsStat {
+        let push_ail: Vec<_> = push_ail.iter().map(|count| count.to_string()).collect();
+        fixtures::stat(&[("push_ail", &push_ail.join(" ")),
+                         ("log", &format!("0 0 0 0 {}", force_sleep))])
+    }
+
+    fn classify(push_ail: [u32; 10], force_sleep: u32) -> super::Diagnosis {
+        let before = sample([1000, 10, 50, 1000, 100, 10, 10, 10, 0, 5], 100);
+        super::diagnose(&before, &sample(push_ail, force_sleep), 10.0, &Thresholds::default())
+    }
+
+    #[test]
+    fn it_measures_an_interval() {
+        let before = sample([1000, 10, 50, 1000, 100, 10, 10, 10, 0, 5], 100);
+        let after = sample([3000, 30, 60, 1800, 100, 110, 10, 10, 2, 25], 150);
+        let pressure = Pressure::between(&before, &after, 10.0);
+        assert_eq!((pressure.reservations, pressure.sleeps, pressure.pushes), (2000, 20, 10));
+        assert_eq!((pressure.pushed, pressure.pinned, pressure.restarts, pressure.log_forces), (800, 100, 2, 20));
+        assert_eq!((pressure.visited(), pressure.force_sleeps), (900, 50));
+        assert_eq!(pressure.sleep_fraction(), 0.01);
+    }
+
+    #[test]
+    fn it_classifies_intervals() {
+        assert_eq!(classify([1000, 10, 50, 1000, 100, 10, 10, 10, 0, 5], 100).condition, Condition::Idle);
+        assert_eq!(classify([3000, 10, 60, 1800, 100, 10, 10, 10, 0, 5], 100).condition, Condition::Healthy);
+
+        let starved = classify([3000, 210, 60, 1800, 100, 10, 10, 10, 0, 5], 100);
+        assert_eq!(starved.condition, Condition::LogSpaceStarved);
+        assert_eq!(starved.explanation, "10.0% of 2000 log reservations slept waiting for space (20.0/s) \
+                                         while the AIL wrote back 800 of 800 items; the log is too small \
+                                         for the transaction rate, or metadata writeback can't keep up");
+
+        let pinned = classify([3000, 210, 60, 1200, 100, 810, 10, 10, 0, 45], 400);
+        assert_eq!(pinned.condition, Condition::AilPinned);
+        assert_eq!(pinned.explanation, "80% of the 1000 AIL items visited were pinned, waiting for a log \
+                                        force to commit them (40 forces from the AIL, 300 force sleeps); \
+                                        the tail can't move until log writes complete, so check the log \
+                                        device's write latency. 200 log reservations slept for space");
+
+        let locked = classify([3000, 10, 60, 1100, 100, 10, 810, 10, 30, 5], 100);
+        assert_eq!(locked.condition, Condition::AilLocked);
+        assert!(locked.explanation.ends_with("restarted its scan 30 times"));
+        assert_eq!(classify([3000, 10, 60, 1100, 100, 10, 10, 810, 0, 5], 100).condition,
+                   Condition::AilFlushing);
+        // Too few items to blame the AIL.
+        assert_eq!(classify([3000, 10, 60, 1000, 100, 20, 10, 10, 0, 5], 100).condition, Condition::Healthy);
+        assert_eq!(format!("{}", Condition::AilPinned), "AIL pinned by pending log force");
+    }
+}
+
+/// What held up the log over an interval.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    /// No log reservations and no tail pushing.
+    Idle,
+    Healthy,
+    /// Transactions slept for log space while the AIL made progress.
+    LogSpaceStarved,
+    /// Most items the pusher visited were pinned in memory until a log
+    /// force commits them.
+    AilPinned,
+    /// Most items were locked by other threads.
+    AilLocked,
+    /// Most items were already being written back.
+    AilFlushing,
+}
+
+/// Counter deltas over an interval.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pressure {
+    pub seconds: f64,
+    /// Attempts to reserve log space.
+    pub reservations: u64,
+    /// Reservations that slept waiting for space.
+    pub sleeps: u64,
+    /// Runs of the AIL pusher.
+    pub pushes: u64,
+    /// Items the pusher wrote back, or whose buffers it queued.
+    pub pushed: u64,
+    pub pinned: u64,
+    pub locked: u64,
+    pub flushing: u64,
+    pub restarts: u64,
+    /// Log forces the pusher issued to unpin items.
+    pub log_forces: u64,
+    /// Log forces that had to sleep.
+    pub force_sleeps: u64,
+}
+
+/// Where one condition takes over from another.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Thresholds {
+    /// Fraction of reservations sleeping that counts as starved.
+    pub sleep_fraction: f64,
+    /// Fraction of visited AIL items pinned, locked or flushing that
+    /// counts as the AIL being stuck on them.
+    pub stuck_fraction: f64,
+    /// Fewer items visited than this says nothing about the AIL.
+    pub min_items: u64,
+}
+
+/// The condition an interval was in, and why, in words.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnosis {
+    pub condition: Condition,
+    pub explanation: String,
+    pub pressure: Pressure,
+}
+
+impl Condition {
+    pub fn name(self) -> &'static str {
+        match self {
+            Condition::Idle => "idle",
+            Condition::Healthy => "healthy",
+            Condition::LogSpaceStarved => "log space starved",
+            Condition::AilPinned => "AIL pinned by pending log force",
+            Condition::AilLocked => "AIL stuck on locked items",
+            Condition::AilFlushing => "AIL waiting on metadata writeback",
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Default for Thresholds {
+    fn default() -> Thresholds {
+        Thresholds { sleep_fraction: 0.01, stuck_fraction: 0.5, min_items: 100 }
+    }
+}
+
+impl Pressure {
+    pub fn between(previous: &XfsStat, current: &XfsStat, seconds: f64) -> Pressure {
+        let delta = |before: u32, after: u32| u64::from(after.wrapping_sub(before));
+        let (before, after) = (&previous.tail_pushing_stats, &current.tail_pushing_stats);
+        Pressure {
+            seconds,
+            reservations: delta(before.logspace, after.logspace),
+            sleeps: delta(before.sleep_logspace, after.sleep_logspace),
+            pushes: delta(before.push_ails, after.push_ails),
+            pushed: delta(before.push_ail_success, after.push_ail_success)
+                + delta(before.push_ail_pushbuf, after.push_ail_pushbuf),
+            pinned: delta(before.push_ail_pinned, after.push_ail_pinned),
+            locked: delta(before.push_ail_locked, after.push_ail_locked),
+            flushing: delta(before.push_ail_flushing, after.push_ail_flushing),
+            restarts: delta(before.push_ail_restarts, after.push_ail_restarts),
+            log_forces: delta(before.push_ail_flush, after.push_ail_flush),
+            force_sleeps: delta(previous.log_operations.force_sleep, current.log_operations.force_sleep),
+        }
+    }
+
+    /// Items the pusher looked at.
+    pub fn visited(&self) -> u64 {
+        self.pushed + self.pinned + self.locked + self.flushing
+    }
+
+    pub fn sleep_fraction(&self) -> f64 {
+        fraction(self.sleeps, self.reservations)
+    }
+
+    fn per_second(&self, count: u64) -> f64 {
+        if self.seconds <= 0.0 {
+            return 0.0;
+        }
+        count as f64 / self.seconds
+    }
+}
+
+fn fraction(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        return 0.0;
+    }
+    part as f64 / whole as f64
+}
+
+/// Classifies the pressure over an interval. A stuck AIL is reported
+/// ahead of starvation, since it says why the log ran out of space.
+pub fn classify(pressure: &Pressure, thresholds: &Thresholds) -> Diagnosis {
+    let p = pressure;
+    let visited = p.visited();
+    let sleeps = if p.sleeps > 0 {
+        format!(". {} log reservations slept for space", p.sleeps)
+    } else {
+        String::new()
+    };
+    let stuck = [(Condition::AilPinned, p.pinned), (Condition::AilLocked, p.locked),
+                 (Condition::AilFlushing, p.flushing)]
+        .iter()
+        .cloned()
+        .filter(|_| visited >= thresholds.min_items)
+        .filter(|&(_, count)| fraction(count, visited) >= thresholds.stuck_fraction)
+        .max_by_key(|&(_, count)| count);
+
+    let (condition, explanation) = if let Some((condition, count)) = stuck {
+        let share = format!("{:.0}% of the {} AIL items visited were", fraction(count, visited) * 100.0, visited);
+        let explanation = match condition {
+            Condition::AilPinned => format!(
+                "{} pinned, waiting for a log force to commit them ({} forces from the AIL, {} force sleeps); \
+                 the tail can't move until log writes complete, so check the log device's write latency{}",
+                share, p.log_forces, p.force_sleeps, sleeps),
+            Condition::AilLocked => format!(
+                "{} locked by other threads; the tail waits on inode and buffer lock holders, such as a long \
+                 running transaction or contention on the same inodes{}. The pusher restarted its scan {} times",
+                share, sleeps, p.restarts),
+            _ => format!(
+                "{} already being flushed; the tail waits on metadata writeback, so check the data device's \
+                 write latency{}. The pusher restarted its scan {} times",
+                share, sleeps, p.restarts),
+        };
+        (condition, explanation)
+    } else if p.reservations == 0 && p.pushes == 0 {
+        (Condition::Idle, "no log reservations or tail pushing".to_string())
+    } else if p.sleeps > 0 && p.sleep_fraction() >= thresholds.sleep_fraction {
+        (Condition::LogSpaceStarved, format!(
+            "{:.1}% of {} log reservations slept waiting for space ({:.1}/s) while the AIL wrote back {} of {} \
+             items; the log is too small for the transaction rate, or metadata writeback can't keep up",
+            p.sleep_fraction() * 100.0, p.reservations, p.per_second(p.sleeps), p.pushed, visited))
+    } else {
+        (Condition::Healthy, format!(
+            "{:.0} log reservations/s with {:.1}% sleeping for space; the AIL wrote back {} of {} items",
+            p.per_second(p.reservations), p.sleep_fraction() * 100.0, p.pushed, visited))
+    };
+    Diagnosis { condition, explanation, pressure: *pressure }
+}
+
+/// Classifies the interval of `seconds` between two snapshots.
+pub fn diagnose(previous: &XfsStat, current: &XfsStat, seconds: f64, thresholds: &Thresholds) -> Diagnosis {
+    classify(&Pressure::between(previous, current, seconds), thresholds)
+}
@@ -0,0 +1,671 @@
+//! Decoding of the primary XFS superblock (`struct xfs_dsb`) from an
+//! unmounted device or a filesystem image.
+
+use std::cmp;
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
+
+use nom::{be_u8, be_u16, be_u32, be_u64};
+
+use crc;
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use fixtures;
+    use XfsError;
+
+    #[test]
+    fn it_parses_a_v5_superblock() {
+        let sb = super::parse(&fixtures::superblock(true)).unwrap();
+        assert_eq!(sb.block_size, 4096);
+        assert_eq!(sb.data_blocks, 128);
+        assert_eq!(sb.ag_count, 2);
+        assert_eq!(sb.ag_blocks, 64);
+        assert_eq!(sb.label(), "scratch");
+        assert_eq!(sb.version(), 5);
+        assert_eq!(sb.log_start, 40);
+        assert_eq!(sb.log_blocks, 16);
+        assert_eq!(sb.uuid.to_string(), "4a1f3c2e-9b6d-4e51-8f02-a733c410de7b");
+        assert!(sb.has_internal_log());
+    }
+
+    #[test]
+    fn it_translates_block_addresses() {
+        let sb = super::parse(&fixtures::superblock(true)).unwrap();
+        assert_eq!(sb.ag_length(1), 64);
+        assert_eq!(sb.agb_offset(1, 2), 66 * 4096);
+        assert_eq!(sb.fsb_offset((1 << 6) | 2), 66 * 4096);
+        assert_eq!(sb.fsb_offset(40), 40 * 4096);
+        assert_eq!(sb.ino_offset(fixtures::ino(1, 259)), fixtures::inode_offset(fixtures::ino(1, 259)) as u64);
+    }
+
+    #[test]
+    fn it_decodes_feature_flags() {
+        let features = super::parse(&fixtures::superblock(true)).unwrap().features();
+        assert!(features.crc);
+        assert!(features.finobt);
+        assert!(features.rmapbt);
+        assert!(features.reflink);
+        assert!(features.inobtcount);
+        assert!(features.bigtime);
+        assert!(features.ftype);
+        assert!(!features.sparse_inodes);
+
+        let features = super::parse(&fixtures::superblock(false)).unwrap().features();
+        assert!(!features.crc);
+        assert!(!features.finobt);
+        assert!(!features.bigtime);
+        assert!(features.attr2);
+        assert!(features.logv2);
+    }
+
+    #[test]
+    fn it_reads_a_superblock_from_an_image() {
+        let mut image = fixtures::superblock(true);
+        image.resize(8192, 0);
+        let sb = super::read(&mut Cursor::new(image)).unwrap();
+        assert_eq!(sb.inode_size, 512);
+        assert_eq!(sb.lsn, 0x1_0000_0002);
+    }
+
+    #[test]
+    fn it_rejects_a_bad_checksum() {
+        let mut image = fixtures::superblock(true);
+        image[300] = 0xff;
+        match super::read(&mut Cursor::new(image)) {
+            Err(XfsError::BadChecksum) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn it_does_not_checksum_v4_superblocks() {
+        let mut image = fixtures::superblock(false);
+        image[300] = 0xff;
+        assert_eq!(super::read(&mut Cursor::new(image)).unwrap().version(), 4);
+    }
+
+    #[test]
+    fn it_rejects_a_bad_magic() {
+        let mut image = fixtures::superblock(true);
+        image[0] = b'Y';
+        match super::parse(&image) {
+            Err(XfsError::BadMagic) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    /// Asserts that the v5 superblock is rejected once `edit` has been
+    /// applied to it.
+    fn assert_rejected<F: Fn(&mut [u8])>(edit: F) {
+        let mut image = fixtures::superblock(true);
+        edit(&mut image);
+        match super::parse(&image) {
+            Err(XfsError::Parse) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn it_rejects_a_bad_block_size() {
+        assert_rejected(|sb| fixtures::put_u32(sb, 4, 4000));
+        assert_rejected(|sb| sb[120] = 13);
+        assert_rejected(|sb| {
+            fixtures::put_u32(sb, 4, 1 << 17);
+            sb[120] = 17;
+        });
+    }
+
+    #[test]
+    fn it_rejects_a_bad_sector_size() {
+        assert_rejected(|sb| fixtures::put_u16(sb, 102, 0));
+        assert_rejected(|sb| sb[121] = 10);
+    }
+
+    #[test]
+    fn it_rejects_a_bad_inode_size() {
+        assert_rejected(|sb| fixtures::put_u16(sb, 104, 500));
+        assert_rejected(|sb| sb[122] = 12);
+    }
+
+    #[test]
+    fn it_rejects_a_bad_inodes_per_block() {
+        assert_rejected(|sb| fixtures::put_u16(sb, 106, 16));
+        assert_rejected(|sb| sb[123] = 4);
+    }
+
+    #[test]
+    fn it_rejects_a_bad_dirblklog() {
+        assert_rejected(|sb| sb[192] = 5);
+        assert_rejected(|sb| sb[192] = 255);
+    }
+
+    #[test]
+    fn it_rejects_an_agcount_not_covering_the_data_device() {
+        assert_rejected(|sb| fixtures::put_u32(sb, 88, 0));
+        assert_rejected(|sb| fixtures::put_u32(sb, 88, 0xffff_ffff));
+        assert_rejected(|sb| fixtures::put_u64(sb, 8, 129));
+    }
+
+    #[test]
+    fn it_rejects_a_log_outside_the_data_device() {
+        assert_rejected(|sb| fixtures::put_u32(sb, 96, 0xffff_ffff));
+        assert_rejected(|sb| fixtures::put_u32(sb, 96, 0));
+        assert_rejected(|sb| fixtures::put_u32(sb, 96, 89));
+        assert_rejected(|sb| fixtures::put_u64(sb, 48, u64::MAX));
+    }
+
+    #[test]
+    fn it_rejects_a_bad_agblklog() {
+        for &agblklog in &[64u8, 32, 7, 5] {
+            let mut image = fixtures::superblock(true);
+            image[124] = agblklog;
+            match super::parse(&image) {
+                Err(XfsError::Parse) => {}
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+/// "XFSB"
+pub const MAGIC: u32 = 0x58465342;
+
+/// Byte offset of `sb_crc` within the superblock.
+pub const CRC_OFFSET: usize = 224;
+
+const VERSION_NUMBITS: u16 = 0x000f;
+const VERSION_ATTRBIT: u16 = 0x0010;
+const VERSION_QUOTABIT: u16 = 0x0040;
+const VERSION_LOGV2BIT: u16 = 0x0400;
+const VERSION_MOREBITSBIT: u16 = 0x8000;
+
+const FEATURES2_LAZYSBCOUNT: u32 = 0x0002;
+const FEATURES2_ATTR2: u32 = 0x0008;
+const FEATURES2_PROJID32: u32 = 0x0080;
+const FEATURES2_FTYPE: u32 = 0x0200;
+
+const RO_COMPAT_FINOBT: u32 = 0x0001;
+const RO_COMPAT_RMAPBT: u32 = 0x0002;
+const RO_COMPAT_REFLINK: u32 = 0x0004;
+const RO_COMPAT_INOBTCNT: u32 = 0x0008;
+
+const INCOMPAT_FTYPE: u32 = 0x0001;
+const INCOMPAT_SPINODES: u32 = 0x0002;
+const INCOMPAT_META_UUID: u32 = 0x0004;
+const INCOMPAT_BIGTIME: u32 = 0x0008;
+const INCOMPAT_NEEDSREPAIR: u32 = 0x0010;
+const INCOMPAT_NREXT64: u32 = 0x0020;
+
+/// A filesystem UUID, displayed in the usual 8-4-4-4-12 form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Uuid(pub [u8; 16]);
+
+impl Uuid {
+    pub(crate) fn from_slice(bytes: &[u8]) -> Uuid {
+        let mut uuid = [0; 16];
+        uuid.copy_from_slice(&bytes[..16]);
+        Uuid(uuid)
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Superblock {
+    /// Magic number, "XFSB".
+    pub magic: u32,
+    /// Size of a filesystem block in bytes.
+    pub block_size: u32,
+    /// Number of blocks in the data device.
+    pub data_blocks: u64,
+    /// Number of blocks in the realtime device.
+    pub rt_blocks: u64,
+    /// Number of extents in the realtime device.
+    pub rt_extents: u64,
+    /// Filesystem UUID.
+    pub uuid: Uuid,
+    /// First block of the log if it is internal, zero for an external log.
+    pub log_start: u64,
+    /// Root directory inode number.
+    pub root_ino: u64,
+    /// Realtime bitmap inode number.
+    pub rt_bitmap_ino: u64,
+    /// Realtime summary inode number.
+    pub rt_summary_ino: u64,
+    /// Size of a realtime extent in blocks.
+    pub rt_extent_size: u32,
+    /// Size of each allocation group in blocks. The last AG may be shorter.
+    pub ag_blocks: u32,
+    /// Number of allocation groups.
+    pub ag_count: u32,
+    /// Number of blocks in the realtime bitmap.
+    pub rt_bitmap_blocks: u32,
+    /// Number of blocks in the log.
+    pub log_blocks: u32,
+    /// Version number and feature bits; see `version()` and `features()`.
+    pub version_num: u16,
+    /// Size of a sector in bytes.
+    pub sector_size: u16,
+    /// Size of an inode in bytes.
+    pub inode_size: u16,
+    /// Number of inodes in a filesystem block.
+    pub inodes_per_block: u16,
+    /// Filesystem label, NUL padded; see `label()`.
+    pub fname: [u8; 12],
+    /// log2 of `block_size`.
+    pub block_log: u8,
+    /// log2 of `sector_size`.
+    pub sector_log: u8,
+    /// log2 of `inode_size`.
+    pub inode_log: u8,
+    /// log2 of `inodes_per_block`.
+    pub inodes_per_block_log: u8,
+    /// log2 of `ag_blocks`, rounded up.
+    pub ag_blocks_log: u8,
+    /// log2 of `rt_extents`.
+    pub rt_extents_log: u8,
+    /// Set while mkfs is still writing the filesystem.
+    pub in_progress: u8,
+    /// Maximum percentage of the filesystem that may hold inodes.
+    pub imax_pct: u8,
+    /// Number of allocated inodes.
+    pub inode_count: u64,
+    /// Number of free inodes.
+    pub free_inodes: u64,
+    /// Number of free data blocks.
+    pub free_data_blocks: u64,
+    /// Number of free realtime extents.
+    pub free_rt_extents: u64,
+    /// User quota inode number.
+    pub user_quota_ino: u64,
+    /// Group quota inode number, also used for project quotas before v5.
+    pub group_quota_ino: u64,
+    /// Quota accounting and enforcement flags.
+    pub quota_flags: u16,
+    /// Miscellaneous flags.
+    pub flags: u8,
+    /// Unused, was the shared version number.
+    pub shared_vn: u8,
+    /// Inode chunk alignment in blocks.
+    pub inode_alignment: u32,
+    /// Stripe unit in blocks.
+    pub stripe_unit: u32,
+    /// Stripe width in blocks.
+    pub stripe_width: u32,
+    /// log2 of the directory block size in filesystem blocks.
+    pub dir_block_log: u8,
+    /// log2 of `log_sector_size`.
+    pub log_sector_log: u8,
+    /// Sector size of the log device in bytes.
+    pub log_sector_size: u16,
+    /// Log stripe unit in bytes.
+    pub log_stripe_unit: u32,
+    /// Additional feature bits.
+    pub features2: u32,
+    /// Copy of `features2` kept for old kernels that wrote it at the wrong
+    /// offset.
+    pub bad_features2: u32,
+    /// Compatible feature bits (v5).
+    pub features_compat: u32,
+    /// Read-only compatible feature bits (v5).
+    pub features_ro_compat: u32,
+    /// Incompatible feature bits (v5).
+    pub features_incompat: u32,
+    /// Incompatible log feature bits (v5).
+    pub features_log_incompat: u32,
+    /// Superblock checksum (v5), as stored.
+    pub crc: u32,
+    /// Sparse inode chunk alignment in blocks (v5).
+    pub sparse_inode_align: u32,
+    /// Project quota inode number (v5).
+    pub project_quota_ino: u64,
+    /// Log sequence number of the last superblock update (v5).
+    pub lsn: u64,
+    /// UUID stamped into metadata if it differs from `uuid` (v5).
+    pub meta_uuid: Uuid,
+}
+
+/// Feature flags decoded from the version number and feature words.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Features {
+    /// Metadata checksums and self describing metadata (v5 superblock).
+    pub crc: bool,
+    /// Free inode btree.
+    pub finobt: bool,
+    /// Reverse mapping btree.
+    pub rmapbt: bool,
+    /// Shared data extents.
+    pub reflink: bool,
+    /// Inode btree block counters in the AGI.
+    pub inobtcount: bool,
+    /// Timestamps beyond 2038.
+    pub bigtime: bool,
+    /// File types stored in directory entries.
+    pub ftype: bool,
+    /// Sparse inode chunks.
+    pub sparse_inodes: bool,
+    /// Metadata UUID differs from the filesystem UUID.
+    pub meta_uuid: bool,
+    /// Filesystem must be repaired before mounting.
+    pub needs_repair: bool,
+    /// Large extent counters.
+    pub nrext64: bool,
+    /// Lazy superblock counters.
+    pub lazy_sb_counters: bool,
+    /// Extended attributes.
+    pub attr: bool,
+    /// Version 2 attribute fork layout.
+    pub attr2: bool,
+    /// 32 bit project IDs.
+    pub projid32: bool,
+    /// Version 2 log format.
+    pub logv2: bool,
+    /// Quotas.
+    pub quota: bool,
+}
+
+impl Superblock {
+    /// The superblock version, 4 or 5.
+    pub fn version(&self) -> u16 {
+        self.version_num & VERSION_NUMBITS
+    }
+
+    /// Whether this is a v5 filesystem with checksummed metadata.
+    pub fn has_crc(&self) -> bool {
+        self.version() == 5
+    }
+
+    /// Whether the log lives inside the data device.
+    pub fn has_internal_log(&self) -> bool {
+        self.log_start != 0
+    }
+
+    /// The filesystem label.
+    pub fn label(&self) -> String {
+        let len = self.fname.iter().position(|b| *b == 0).unwrap_or(self.fname.len());
+        String::from_utf8_lossy(&self.fname[..len]).into_owned()
+    }
+
+    /// Whether `ag_blocks_log` is log2 of `ag_blocks` rounded up, and the
+    /// inode number shift fits in 64 bits.
+    fn shifts_valid(&self) -> bool {
+        let log = u32::from(self.ag_blocks_log);
+        log < 32
+            && u64::from(self.ag_blocks) <= 1 << log
+            && (log == 0 || u64::from(self.ag_blocks) > 1 << (log - 1))
+            && log + u32::from(self.inodes_per_block_log) < 64
+    }
+
+    /// Whether the sizes agree with their logs and lie within the limits
+    /// the kernel mounts, the AGs cover the data device, and an internal
+    /// log fits inside it.
+    fn geometry_valid(&self) -> bool {
+        let block_log = u32::from(self.block_log);
+        let sector_log = u32::from(self.sector_log);
+        let inode_log = u32::from(self.inode_log);
+        let sizes = (9..=16).contains(&block_log)
+            && self.block_size == 1 << block_log
+            && (9..=15).contains(&sector_log)
+            && u32::from(self.sector_size) == 1 << sector_log
+            && sector_log <= block_log
+            && (8..=11).contains(&inode_log)
+            && u32::from(self.inode_size) == 1 << inode_log
+            && inode_log <= block_log
+            && u32::from(self.inodes_per_block_log) == block_log - inode_log
+            && u32::from(self.inodes_per_block) == 1 << (block_log - inode_log)
+            // Directory blocks are at most 64KiB.
+            && block_log + u32::from(self.dir_block_log) <= 16;
+        let (ag_blocks, ag_count) = (u64::from(self.ag_blocks), u64::from(self.ag_count));
+        let ags = ag_count > 0
+            && self.data_blocks <= ag_count * ag_blocks
+            && self.data_blocks > (ag_count - 1) * ag_blocks;
+        let log_bytes = u64::from(self.log_blocks) * u64::from(self.block_size);
+        let log = self.log_blocks > 0
+            && log_bytes <= 1 << 31
+            && (!self.has_internal_log()
+                || self.log_start.checked_add(u64::from(self.log_blocks)).is_some_and(|end| end <= self.data_blocks));
+        sizes && ags && log
+    }
+
+    /// Number of blocks in allocation group `agno`; the last AG may be
+    /// shorter than `ag_blocks`.
+    pub fn ag_length(&self, agno: u32) -> u32 {
+        let start = u64::from(agno) * u64::from(self.ag_blocks);
+        cmp::min(u64::from(self.ag_blocks), self.data_blocks.saturating_sub(start)) as u32
+    }
+
+    /// Byte offset of block `agbno` within allocation group `agno`.
+    pub fn agb_offset(&self, agno: u32, agbno: u32) -> u64 {
+        (u64::from(agno) * u64::from(self.ag_blocks) + u64::from(agbno)) * u64::from(self.block_size)
+    }
+
+    /// Byte offset of a filesystem block number, which carries the AG number
+    /// in its high bits.
+    pub fn fsb_offset(&self, fsbno: u64) -> u64 {
+        let agno = fsbno >> self.ag_blocks_log;
+        let agbno = fsbno & ((1 << self.ag_blocks_log) - 1);
+        self.agb_offset(agno as u32, agbno as u32)
+    }
+
+    /// Byte offset of inode `ino`, which carries the AG number above the
+    /// AG block and the inode's slot within that block.
+    pub fn ino_offset(&self, ino: u64) -> u64 {
+        let per_block_log = u32::from(self.inodes_per_block_log);
+        let agino_log = u32::from(self.ag_blocks_log) + per_block_log;
+        let agno = (ino >> agino_log) as u32;
+        let agbno = ((ino >> per_block_log) & ((1 << self.ag_blocks_log) - 1)) as u32;
+        let slot = ino & ((1 << per_block_log) - 1);
+        self.agb_offset(agno, agbno) + slot * u64::from(self.inode_size)
+    }
+
+    pub fn features(&self) -> Features {
+        let v5 = self.has_crc();
+        let more = self.version_num & VERSION_MOREBITSBIT != 0;
+        let features2 = if more { self.features2 } else { 0 };
+        let (ro_compat, incompat) = if v5 {
+            (self.features_ro_compat, self.features_incompat)
+        } else {
+            (0, 0)
+        };
+        Features {
+            crc: v5,
+            finobt: ro_compat & RO_COMPAT_FINOBT != 0,
+            rmapbt: ro_compat & RO_COMPAT_RMAPBT != 0,
+            reflink: ro_compat & RO_COMPAT_REFLINK != 0,
+            inobtcount: ro_compat & RO_COMPAT_INOBTCNT != 0,
+            bigtime: incompat & INCOMPAT_BIGTIME != 0,
+            ftype: incompat & INCOMPAT_FTYPE != 0 || features2 & FEATURES2_FTYPE != 0,
+            sparse_inodes: incompat & INCOMPAT_SPINODES != 0,
+            meta_uuid: incompat & INCOMPAT_META_UUID != 0,
+            needs_repair: incompat & INCOMPAT_NEEDSREPAIR != 0,
+            nrext64: incompat & INCOMPAT_NREXT64 != 0,
+            lazy_sb_counters: v5 || features2 & FEATURES2_LAZYSBCOUNT != 0,
+            attr: v5 || self.version_num & VERSION_ATTRBIT != 0,
+            attr2: v5 || features2 & FEATURES2_ATTR2 != 0,
+            projid32: v5 || features2 & FEATURES2_PROJID32 != 0,
+            logv2: v5 || self.version_num & VERSION_LOGV2BIT != 0,
+            quota: self.version_num & VERSION_QUOTABIT != 0,
+        }
+    }
+}
+
+/// Decodes a superblock from the start of `input`, which must hold at least
+/// one 512-byte sector. Checksums are not verified here; see `read`.
+pub fn parse(input: &[u8]) -> Result<Superblock, XfsError> {
+    match dsb(input) {
+        nom::IResult::Done(_, ref sb) if sb.magic != MAGIC => Err(XfsError::BadMagic),
+        // Block and inode numbers are split with these shifts, so they must
+        // fit and match the geometry they describe. Every reader sizes its
+        // buffers and walks from the rest.
+        nom::IResult::Done(_, ref sb) if !sb.shifts_valid() || !sb.geometry_valid() => Err(XfsError::Parse),
+        nom::IResult::Done(_, sb) => Ok(sb),
+        nom::IResult::Error(_) => Err(XfsError::Parse),
+        nom::IResult::Incomplete(_) => Err(XfsError::Incomplete),
+    }
+}
+
+/// Reads the primary superblock from the start of a device or image and,
+/// for v5 filesystems, verifies its checksum.
+pub fn read<R: Read + Seek>(dev: &mut R) -> Result<Superblock, XfsError> {
+    let mut sector = vec![0u8; 512];
+    dev.seek(SeekFrom::Start(0))?;
+    dev.read_exact(&mut sector)?;
+    let sb = parse(&sector)?;
+    if sb.has_crc() {
+        if (sb.sector_size as usize) > sector.len() {
+            sector.resize(sb.sector_size as usize, 0);
+            dev.read_exact(&mut sector[512..])?;
+        }
+        if !crc::verify(&sector[..sb.sector_size as usize], CRC_OFFSET) {
+            return Err(XfsError::BadChecksum);
+        }
+    }
+    Ok(sb)
+}
+
+/// Reads `len` bytes at byte offset `offset`.
+pub(crate) fn read_at<R: Read + Seek>(dev: &mut R, offset: u64, len: usize) -> Result<Vec<u8>, XfsError> {
+    let mut buf = vec![0u8; len];
+    dev.seek(SeekFrom::Start(offset))?;
+    dev.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn fname(bytes: &[u8]) -> [u8; 12] {
+    let mut fname = [0; 12];
+    fname.copy_from_slice(bytes);
+    fname
+}
+
+named!(dsb <Superblock>,
+    chain!(
+        magic: be_u32 ~
+        block_size: be_u32 ~
+        data_blocks: be_u64 ~
+        rt_blocks: be_u64 ~
+        rt_extents: be_u64 ~
+        uuid: take!(16) ~
+        log_start: be_u64 ~
+        root_ino: be_u64 ~
+        rt_bitmap_ino: be_u64 ~
+        rt_summary_ino: be_u64 ~
+        rt_extent_size: be_u32 ~
+        ag_blocks: be_u32 ~
+        ag_count: be_u32 ~
+        rt_bitmap_blocks: be_u32 ~
+        log_blocks: be_u32 ~
+        version_num: be_u16 ~
+        sector_size: be_u16 ~
+        inode_size: be_u16 ~
+        inodes_per_block: be_u16 ~
+        name: take!(12) ~
+        block_log: be_u8 ~
+        sector_log: be_u8 ~
+        inode_log: be_u8 ~
+        inodes_per_block_log: be_u8 ~
+        ag_blocks_log: be_u8 ~
+        rt_extents_log: be_u8 ~
+        in_progress: be_u8 ~
+        imax_pct: be_u8 ~
+        inode_count: be_u64 ~
+        free_inodes: be_u64 ~
+        free_data_blocks: be_u64 ~
+        free_rt_extents: be_u64 ~
+        user_quota_ino: be_u64 ~
+        group_quota_ino: be_u64 ~
+        quota_flags: be_u16 ~
+        flags: be_u8 ~
+        shared_vn: be_u8 ~
+        inode_alignment: be_u32 ~
+        stripe_unit: be_u32 ~
+        stripe_width: be_u32 ~
+        dir_block_log: be_u8 ~
+        log_sector_log: be_u8 ~
+        log_sector_size: be_u16 ~
+        log_stripe_unit: be_u32 ~
+        features2: be_u32 ~
+        bad_features2: be_u32 ~
+        features_compat: be_u32 ~
+        features_ro_compat: be_u32 ~
+        features_incompat: be_u32 ~
+        features_log_incompat: be_u32 ~
+        checksum: take!(4) ~
+        sparse_inode_align: be_u32 ~
+        project_quota_ino: be_u64 ~
+        lsn: be_u64 ~
+        meta_uuid: take!(16),
+        || {
+            Superblock {
+                magic,
+                block_size,
+                data_blocks,
+                rt_blocks,
+                rt_extents,
+                uuid: Uuid::from_slice(uuid),
+                log_start,
+                root_ino,
+                rt_bitmap_ino,
+                rt_summary_ino,
+                rt_extent_size,
+                ag_blocks,
+                ag_count,
+                rt_bitmap_blocks,
+                log_blocks,
+                version_num,
+                sector_size,
+                inode_size,
+                inodes_per_block,
+                fname: fname(name),
+                block_log,
+                sector_log,
+                inode_log,
+                inodes_per_block_log,
+                ag_blocks_log,
+                rt_extents_log,
+                in_progress,
+                imax_pct,
+                inode_count,
+                free_inodes,
+                free_data_blocks,
+                free_rt_extents,
+                user_quota_ino,
+                group_quota_ino,
+                quota_flags,
+                flags,
+                shared_vn,
+                inode_alignment,
+                stripe_unit,
+                stripe_width,
+                dir_block_log,
+                log_sector_log,
+                log_sector_size,
+                log_stripe_unit,
+                features2,
+                bad_features2,
+                features_compat,
+                features_ro_compat,
+                features_incompat,
+                features_log_incompat,
+                crc: crc::stored_crc(checksum, 0),
+                sparse_inode_align,
+                project_quota_ino,
+                lsn,
+                meta_uuid: Uuid::from_slice(meta_uuid),
+            }
+        }
+    )
+);
@@ -0,0 +1,494 @@
+//! XFS tracepoints as ftrace prints them to `trace_pipe`, and an
+//! aggregator of event frequencies and lock latencies keyed by the
+//! `XfsStat` section each event feeds.
+//!
+//! Enable the events with `echo 1 > /sys/kernel/tracing/events/xfs/enable`
+//! or per event; the parser takes any line and types the common ones.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use log::Lsn;
+
+#[cfg(test)]
+mod tests {
+    use log::Lsn;
+
+    use super::{Event, Section};
+
+    const TRACE: &str = "\
+# tracer: nop
+#
+#           TASK-PID     CPU#  |||||  TIMESTAMP  FUNCTION
+#              | |         |   |||||     |         |
+     kworker/2:1-1187    [002] ..... 10.000000: xfs_log_force: dev 8:1 lsn 0x100000040 caller xfs_log_worker+0x3a/0x70 [xfs]
+        xfsaild/sda1-431 [001] ..... 10.100000: xfs_ail_push: dev 8:1 lip 00000000c2b1e8a4 lsn 1/64 type XFS_LI_INODE flags IN_AIL
+              dd-2001    [000] ..... 10.200000: xfs_buf_lock: dev 8:1 daddr 0x2a8 bbcount 0x8 hold 2 pincount 0 lock 0 flags DONE|KMEM caller xfs_buf_find_lock+0x5f/0x120 [xfs]
+              dd-2001    [000] ..... 10.200500: xfs_buf_lock_done: dev 8:1 daddr 0x2a8 bbcount 0x8 hold 2 pincount 0 lock 0 flags DONE|KMEM caller xfs_buf_find_lock+0x5f/0x120 [xfs]
+              dd-2001    [000] ..... 10.300000: xfs_ilock: dev 8:1 ino 0x83 flags ILOCK_EXCL caller xfs_vn_update_time+0x7e/0x1c0 [xfs]
+              dd-2001    [000] ..... 10.300040: xfs_iunlock: dev 8:1 ino 0x83 flags ILOCK_EXCL caller xfs_vn_update_time+0xf1/0x1c0 [xfs]
+              dd-2001    [000] ..... 10.400000: xfs_alloc_near_first: dev 8:1 agno 0x1 agbno 0x40 minlen 1 maxlen 16 mod 0 prod 1 minleft 1 total 0 alignment 1 minalignslop 0 len 16 wasdel 1 wasfromfl 0 resv 0 datatype 0x5 firstblock 0xffffffffffffffff
+              dd-2001    [000] ..... 11.000000: xfs_iomap_found: dev 8:1 ino 0x83 disize 0x0 pos 0x10000 bytecount 0x1000 fork data startoff 0x10 startblock 0x2000 fsbcount 0x1
+";
+
+    #[test]
+    fn it_parses_trace_lines() {
+        let records: Vec<_> = TRACE.lines().filter_map(super::parse_line).collect();
+        assert_eq!(records.len(), 8);
+        let force = &records[0];
+        assert_eq!((force.task.as_str(), force.pid, force.cpu), ("kworker/2:1", 1187, 2));
+        assert_eq!(force.timestamp_us, 10000000);
+        assert_eq!(force.dev, Some((8, 1)));
+        assert_eq!(force.event, Event::LogForce { lsn: Lsn { cycle: 1, block: 64 },
+                                                  caller: "xfs_log_worker".to_string() });
+        assert_eq!(force.field("caller"), Some("xfs_log_worker+0x3a/0x70 [xfs]"));
+        assert_eq!(records[1].task, "xfsaild/sda1");
+        assert_eq!(records[1].event, Event::Ail { lsn: Lsn { cycle: 1, block: 64 },
+                                                  item_type: "XFS_LI_INODE".to_string() });
+        assert_eq!(records[3].event, Event::BufLock { daddr: 0x2a8, done: true });
+        assert_eq!(records[5].event, Event::Ilock { ino: 0x83, flags: "ILOCK_EXCL".to_string(), unlock: true });
+        assert_eq!(records[6].event, Event::Alloc { agno: 1, agbno: 0x40, len: 16 });
+        assert_eq!(records[7].event, Event::Iomap { ino: 0x83, offset: 0x10000, count: 0x1000 });
+        assert_eq!(super::section("xfs_iomap_found"), Section::BlockMapping);
+        assert!(super::parse_line("garbage").is_none());
+    }
+
+    #[test]
+    fn it_aggregates_frequencies_and_latencies() {
+        let mut agg = super::Aggregator::default();
+        for record in TRACE.lines().filter_map(super::parse_line) {
+            agg.add(&record);
+        }
+        let buf = &agg.events[&(Section::BufStatistics, "xfs_buf_lock".to_string())];
+        assert_eq!(buf.count, 1);
+        assert_eq!((buf.latency.count, buf.latency.total_us, buf.latency.max_us), (1, 500, 500));
+        let ilock = &agg.events[&(Section::InodeOperations, "xfs_ilock".to_string())];
+        assert_eq!(ilock.latency.mean_us(), 40.0);
+        assert_eq!(agg.span_us(), 1000000);
+
+        assert_eq!(super::format_table(&agg), "\
+section                  event                         count      per_s    timed   mean_us    max_us
+extent_allocation        xfs_alloc_near_first              1        1.0        0       0.0         0
+block_mapping            xfs_iomap_found                   1        1.0        0       0.0         0
+inode_operations         xfs_ilock                         1        1.0        1      40.0        40
+inode_operations         xfs_iunlock                       1        1.0        0       0.0         0
+log_operations           xfs_log_force                     1        1.0        0       0.0         0
+tail_pushing_stats       xfs_ail_push                      1        1.0        0       0.0         0
+buf_statistics           xfs_buf_lock                      1        1.0        1     500.0       500
+buf_statistics           xfs_buf_lock_done                 1        1.0        0       0.0         0
+");
+    }
+
+    fn ilock(us: u64, name: &str, ino: u64, flags: &str) -> super::Record {
+        let line = format!("dd-2001 [000] ..... {}.{:06}: {}: dev 8:1 ino {:#x} flags {} caller x+0x1/0x2 [xfs]",
+                           us / 1_000_000, us % 1_000_000, name, ino, flags);
+        super::parse_line(&line).unwrap()
+    }
+
+    #[test]
+    fn it_times_nested_locks_separately() {
+        let mut agg = super::Aggregator::default();
+        agg.add(&ilock(0, "xfs_ilock", 0x83, "IOLOCK_EXCL"));
+        agg.add(&ilock(10, "xfs_ilock", 0x83, "ILOCK_EXCL"));
+        agg.add(&ilock(30, "xfs_iunlock", 0x83, "ILOCK_EXCL"));
+        agg.add(&ilock(100, "xfs_iunlock", 0x83, "IOLOCK_EXCL"));
+        // Taken and released together.
+        agg.add(&ilock(200, "xfs_ilock", 0x84, "IOLOCK_SHARED|ILOCK_SHARED"));
+        agg.add(&ilock(260, "xfs_iunlock", 0x84, "IOLOCK_SHARED|ILOCK_SHARED"));
+        let latency = agg.events[&(Section::InodeOperations, "xfs_ilock".to_string())].latency;
+        assert_eq!((latency.count, latency.total_us, latency.max_us), (4, 20 + 100 + 60 + 60, 100));
+        assert!(agg.pending.is_empty());
+    }
+
+    #[test]
+    fn it_bounds_unmatched_starts() {
+        let mut agg = super::Aggregator::default();
+        agg.add(&ilock(0, "xfs_ilock", 1, "ILOCK_EXCL"));
+        for ino in 2..=super::MAX_PENDING as u64 {
+            agg.add(&ilock(5_000_000, "xfs_ilock_nowait", ino, "ILOCK_SHARED"));
+        }
+        assert_eq!(agg.pending.len(), super::MAX_PENDING);
+        // The first has waited too long by now. Once none has, the oldest
+        // quarter goes.
+        agg.add(&ilock(12_000_000, "xfs_ilock", 0x10000, "ILOCK_EXCL"));
+        assert_eq!(agg.pending.len(), super::MAX_PENDING);
+        assert!(!agg.pending.keys().any(|key| key.2 == 1));
+        agg.add(&ilock(12_000_000, "xfs_ilock", 0x10001, "ILOCK_EXCL"));
+        assert_eq!(agg.pending.len(), super::MAX_PENDING * 3 / 4 + 1);
+        assert!(agg.pending.keys().any(|key| key.2 == 0x10000));
+        agg.add(&ilock(12_000_010, "xfs_iunlock", 0x10000, "ILOCK_EXCL"));
+        assert_eq!(agg.events[&(Section::InodeOperations, "xfs_ilock".to_string())].latency.max_us, 10);
+    }
+}
+
+/// The `XfsStat` sections, for keying tracepoints by what they count.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Section {
+    ExtentAllocation,
+    AllocationBTree,
+    BlockMapping,
+    BlockMapBTree,
+    DirectoryOperations,
+    Transactions,
+    InodeOperations,
+    LogOperations,
+    TailPushingStats,
+    IoMapWriteConvert,
+    ReadWriteStats,
+    AttributeOperations,
+    InodeClustering,
+    VnodeStatistics,
+    BufStatistics,
+    ExtendedPrecisionCounters,
+    /// Events no section counts.
+    Other,
+}
+
+/// The typed part of the common events.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    LogForce { lsn: Lsn, caller: String },
+    /// A log item seen by the AIL pusher (`xfs_ail_push`, `xfs_ail_pinned`,
+    /// `xfs_ail_locked`, `xfs_ail_flushing`).
+    Ail { lsn: Lsn, item_type: String },
+    /// Waiting for (`xfs_buf_lock`) or having got (`xfs_buf_lock_done`) a
+    /// buffer lock.
+    BufLock { daddr: u64, done: bool },
+    /// `xfs_ilock` and its variants, or `xfs_iunlock`.
+    Ilock { ino: u64, flags: String, unlock: bool },
+    /// One of the `xfs_alloc_*` allocation attempts.
+    Alloc { agno: u32, agbno: u32, len: u32 },
+    /// One of the `xfs_iomap_*` events; offset and count in bytes.
+    Iomap { ino: u64, offset: u64, count: u64 },
+    Other,
+}
+
+/// One trace line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub task: String,
+    pub pid: u32,
+    pub cpu: u32,
+    /// Microseconds on the trace clock.
+    pub timestamp_us: u64,
+    /// The event name, such as `xfs_ilock`.
+    pub name: String,
+    /// Major and minor number of the filesystem's device.
+    pub dev: Option<(u32, u32)>,
+    pub event: Event,
+    /// Every `key value` pair as printed.
+    pub fields: Vec<(String, String)>,
+}
+
+/// Latency between a start event and the end event that completes it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Latency {
+    pub count: u64,
+    pub total_us: u64,
+    pub max_us: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EventStats {
+    pub count: u64,
+    /// Kept under the start event. For `xfs_buf_lock` this is the time
+    /// spent waiting for the lock; for `xfs_ilock` it is the time the lock
+    /// was held, as the kernel traces no ilock acquisition.
+    pub latency: Latency,
+}
+
+/// Event counts and latencies over a stream of records.
+#[derive(Clone, Debug, Default)]
+pub struct Aggregator {
+    pub events: BTreeMap<(Section, String), EventStats>,
+    first_us: Option<u64>,
+    last_us: Option<u64>,
+    /// Start events waiting for their end, by pid, end event, object and
+    /// lock flag.
+    pending: HashMap<(u32, &'static str, u64, String), (String, u64)>,
+}
+
+/// Start and end events measured as a latency, with the field naming the
+/// object both refer to and, for locks taken in several modes at once, the
+/// field listing the modes.
+const PAIRS: [(&str, &str, &str, Option<&str>); 3] = [
+    ("xfs_buf_lock", "xfs_buf_lock_done", "daddr", None),
+    ("xfs_ilock", "xfs_iunlock", "ino", Some("flags")),
+    ("xfs_ilock_nowait", "xfs_iunlock", "ino", Some("flags")),
+];
+
+/// Start events kept waiting for their end. Failed `xfs_ilock_nowait`
+/// calls, `xfs_ilock_demote` and events the kernel dropped leave starts
+/// that never end.
+const MAX_PENDING: usize = 4096;
+/// How long a start event may wait before it is given up on once
+/// `MAX_PENDING` is reached.
+const MAX_PENDING_US: u64 = 10_000_000;
+
+impl Section {
+    /// The field name in `XfsStat`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Section::ExtentAllocation => "extent_allocation",
+            Section::AllocationBTree => "allocation_btree",
+            Section::BlockMapping => "block_mapping",
+            Section::BlockMapBTree => "block_map_btree",
+            Section::DirectoryOperations => "directory_operations",
+            Section::Transactions => "transactions",
+            Section::InodeOperations => "inode_operations",
+            Section::LogOperations => "log_operations",
+            Section::TailPushingStats => "tail_pushing_stats",
+            Section::IoMapWriteConvert => "io_map_write_convert",
+            Section::ReadWriteStats => "read_write_stats",
+            Section::AttributeOperations => "attribute_operations",
+            Section::InodeClustering => "inode_clustering",
+            Section::VnodeStatistics => "vnode_statistics",
+            Section::BufStatistics => "buf_statistics",
+            Section::ExtendedPrecisionCounters => "extended_precision_counters",
+            Section::Other => "other",
+        }
+    }
+}
+
+/// The section whose counters an event most closely tracks.
+pub fn section(name: &str) -> Section {
+    let prefixes = [
+        ("xfs_alloc_", Section::ExtentAllocation),
+        ("xfs_free_extent", Section::ExtentAllocation),
+        ("xfs_bmap_", Section::BlockMapping),
+        ("xfs_iomap_", Section::BlockMapping),
+        ("xfs_dir2_", Section::DirectoryOperations),
+        ("xfs_trans_", Section::Transactions),
+        ("xfs_ilock", Section::InodeOperations),
+        ("xfs_iunlock", Section::InodeOperations),
+        ("xfs_iget", Section::InodeOperations),
+        ("xfs_log_", Section::LogOperations),
+        ("xfs_ail_", Section::TailPushingStats),
+        ("xfs_file_", Section::ReadWriteStats),
+        ("xfs_attr_", Section::AttributeOperations),
+        ("xfs_iflush", Section::InodeClustering),
+        ("xfs_buf_", Section::BufStatistics),
+    ];
+    prefixes.iter().find(|&&(prefix, _)| name.starts_with(prefix)).map_or(Section::Other, |&(_, s)| s)
+}
+
+/// Parses a decimal or `0x` hexadecimal number.
+fn number(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// An `lsn` printed as `cycle/block` or as one hexadecimal number.
+fn lsn(value: &str) -> Option<Lsn> {
+    match value.split_once('/') {
+        Some((cycle, block)) => Some(Lsn { cycle: cycle.parse().ok()?, block: block.parse().ok()? }),
+        None => number(value).map(Lsn::from),
+    }
+}
+
+/// Splits `key value key value ...`, keeping the `[module]` that follows a
+/// symbol with its value.
+fn fields(text: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = vec![];
+    let mut tokens = text.split_whitespace();
+    while let Some(key) = tokens.next() {
+        if key.starts_with('[') {
+            if let Some(last) = fields.last_mut() {
+                last.1.push(' ');
+                last.1.push_str(key);
+            }
+            continue;
+        }
+        let value = tokens.next().unwrap_or("");
+        fields.push((key.to_string(), value.to_string()));
+    }
+    fields
+}
+
+impl Record {
+    pub fn field(&self, key: &str) -> Option<&str> {
+        self.fields.iter().find(|field| field.0 == key).map(|field| field.1.as_str())
+    }
+
+    fn number(&self, key: &str) -> Option<u64> {
+        self.field(key).and_then(number)
+    }
+
+    fn typed(&self) -> Option<Event> {
+        let name = self.name.as_str();
+        let event = match name {
+            "xfs_log_force" => {
+                let caller = self.field("caller")?;
+                let end = caller.find(['+', ' ']).unwrap_or(caller.len());
+                Event::LogForce { lsn: lsn(self.field("lsn")?)?, caller: caller[..end].to_string() }
+            }
+            "xfs_buf_lock" | "xfs_buf_lock_done" => {
+                Event::BufLock { daddr: self.number("daddr")?, done: name == "xfs_buf_lock_done" }
+            }
+            "xfs_ilock" | "xfs_ilock_nowait" | "xfs_ilock_demote" | "xfs_iunlock" => Event::Ilock {
+                ino: self.number("ino")?,
+                flags: self.field("flags")?.to_string(),
+                unlock: name == "xfs_iunlock",
+            },
+            _ if name.starts_with("xfs_ail_") => Event::Ail {
+                lsn: lsn(self.field("lsn")?)?,
+                item_type: self.field("type")?.to_string(),
+            },
+            _ if name.starts_with("xfs_alloc_") => Event::Alloc {
+                agno: self.number("agno")? as u32,
+                agbno: self.number("agbno")? as u32,
+                len: self.number("len")? as u32,
+            },
+            _ if name.starts_with("xfs_iomap_") => Event::Iomap {
+                ino: self.number("ino")?,
+                offset: self.number("pos").or_else(|| self.number("offset"))?,
+                count: self.number("bytecount").or_else(|| self.number("count"))?,
+            },
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+/// Parses one line of `trace_pipe` (or `trace`) output. Comments and lines
+/// that aren't events give None.
+pub fn parse_line(line: &str) -> Option<Record> {
+    if line.trim_start().starts_with('#') {
+        return None;
+    }
+    // TASK-PID [CPU] FLAGS TIMESTAMP: EVENT: FIELDS, where the task name
+    // may itself contain dashes and spaces, and a (TGID) may follow.
+    let open = line.find(" [")?;
+    let close = open + line[open..].find(']')?;
+    let mut task_pid = line[..open].trim();
+    if task_pid.ends_with(')') {
+        task_pid = task_pid[..task_pid.rfind('(')?].trim_end();
+    }
+    let (task, pid) = task_pid.rsplit_once('-')?;
+    let cpu = line[open + 2..close].trim().parse().ok()?;
+
+    let rest = &line[close + 1..];
+    let stamp_end = rest.find(": ")?;
+    let timestamp = rest[..stamp_end].split_whitespace().last()?;
+    let (seconds, micros) = timestamp.split_once('.')?;
+    let timestamp_us = seconds.parse::<u64>().ok()? * 1_000_000 + micros.get(..6)?.parse::<u64>().ok()?;
+
+    let rest = &rest[stamp_end + 2..];
+    let (name, text) = rest.split_once(": ").unwrap_or((rest.trim_end_matches(':'), ""));
+    let fields = fields(text);
+    let dev = fields.iter().find(|field| field.0 == "dev").and_then(|field| {
+        let (major, minor) = field.1.split_once(':')?;
+        Some((major.parse().ok()?, minor.parse().ok()?))
+    });
+    let mut record = Record {
+        task: task.to_string(),
+        pid: pid.parse().ok()?,
+        cpu,
+        timestamp_us,
+        name: name.to_string(),
+        dev,
+        event: Event::Other,
+        fields,
+    };
+    record.event = record.typed().unwrap_or(Event::Other);
+    Some(record)
+}
+
+impl Latency {
+    fn add(&mut self, us: u64) {
+        self.count += 1;
+        self.total_us += us;
+        self.max_us = self.max_us.max(us);
+    }
+
+    pub fn mean_us(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.total_us as f64 / self.count as f64
+    }
+}
+
+impl Aggregator {
+    pub fn add(&mut self, record: &Record) {
+        self.first_us = Some(self.first_us.map_or(record.timestamp_us, |first| first.min(record.timestamp_us)));
+        self.last_us = Some(self.last_us.map_or(record.timestamp_us, |last| last.max(record.timestamp_us)));
+        self.events.entry((section(&record.name), record.name.clone())).or_default().count += 1;
+
+        for &(start, end, object, flags) in PAIRS.iter() {
+            let object = match record.number(object) {
+                Some(object) => object,
+                None => continue,
+            };
+            // IOLOCK_EXCL|ILOCK_EXCL takes two locks, released together or
+            // one at a time.
+            let flags: Vec<&str> = match flags {
+                Some(flags) => record.field(flags).map_or(vec![], |flags| flags.split('|').collect()),
+                None => vec![""],
+            };
+            for flag in flags {
+                let key = (record.pid, end, object, flag.to_string());
+                if record.name == start {
+                    self.prune(record.timestamp_us);
+                    self.pending.insert(key, (record.name.clone(), record.timestamp_us));
+                } else if record.name == end {
+                    if let Some((start, at)) = self.pending.remove(&key) {
+                        let stats = self.events.entry((section(&start), start)).or_default();
+                        stats.latency.add(record.timestamp_us.saturating_sub(at));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Makes room for another start event at `now_us`: first by dropping
+    /// those older than `MAX_PENDING_US`, then, if none were, the oldest
+    /// quarter.
+    fn prune(&mut self, now_us: u64) {
+        if self.pending.len() < MAX_PENDING {
+            return;
+        }
+        self.pending.retain(|_, &mut (_, at)| now_us.saturating_sub(at) <= MAX_PENDING_US);
+        if self.pending.len() < MAX_PENDING {
+            return;
+        }
+        let mut oldest: Vec<_> = self.pending.iter().map(|(key, &(_, at))| (at, key.clone())).collect();
+        oldest.sort_unstable();
+        for (_, key) in oldest.into_iter().take(MAX_PENDING / 4) {
+            self.pending.remove(&key);
+        }
+    }
+
+    /// Time between the first and last record.
+    pub fn span_us(&self) -> u64 {
+        match (self.first_us, self.last_us) {
+            (Some(first), Some(last)) => last - first,
+            _ => 0,
+        }
+    }
+
+    /// Events per second over the span of the stream.
+    pub fn rate(&self, stats: &EventStats) -> f64 {
+        match self.span_us() {
+            0 => 0.0,
+            span => stats.count as f64 * 1e6 / span as f64,
+        }
+    }
+}
+
+/// Renders the aggregated events as a fixed-width table, by section.
+/// `timed` counts the start events matched with their end, which the mean
+/// and max cover.
+pub fn format_table(agg: &Aggregator) -> String {
+    let mut table = String::new();
+    let _ = writeln!(table, "{:<24} {:<24} {:>10} {:>10} {:>8} {:>9} {:>9}",
+                     "section", "event", "count", "per_s", "timed", "mean_us", "max_us");
+    for (&(section, ref name), stats) in &agg.events {
+        let _ = writeln!(table, "{:<24} {:<24} {:>10} {:>10.1} {:>8} {:>9.1} {:>9}",
+                         section.name(), name, stats.count, agg.rate(stats), stats.latency.count,
+                         stats.latency.mean_us(), stats.latency.max_us);
+    }
+    table
+}
@@ -0,0 +1,449 @@
+//! Read-only checksum and magic number verification of the metadata that
+//! can be reached from the superblock, for triage before running the real
+//! repair tools.
+//!
+//! Covers every superblock copy, the AG headers, all blocks of the AG
+//! btrees, the inode chunks listed in the inode btrees and the blocks of
+//! extent-format directories. Checksums are only checked on v5 filesystems.
+
+use std::fmt;
+use std::io::{Read, Seek};
+
+use ag;
+use btree::{self, Shape};
+use crc;
+use inode::{self, Extent, FileType, Format};
+use superblock::{self, Superblock};
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use fixtures;
+
+    #[test]
+    fn it_passes_a_clean_image() {
+        let report = super::verify(&mut Cursor::new(fixtures::image(true))).unwrap();
+        assert!(report.is_clean(), "{:?}", report.findings);
+        // 8 header sectors, 14 btree blocks, 128 inodes
+        assert_eq!(report.checked, 150);
+    }
+
+    #[test]
+    fn it_checks_magic_numbers_on_v4() {
+        let mut raw = fixtures::image(false);
+        let offset = (fixtures::AG_BLOCKS + 3) as usize * 4096;
+        raw[offset] = b'X';
+        let report = super::verify(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(report.findings.len(), 1);
+        assert_eq!(report.findings[0].metadata, super::Metadata::Inobt);
+        assert_eq!((report.findings[0].agno, report.findings[0].agbno), (1, 3));
+        match report.findings[0].problem {
+            super::Problem::BadMagic { found } => assert_eq!(found >> 24, u32::from(b'X')),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn it_reports_checksum_mismatches() {
+        let mut raw = fixtures::image(true);
+        // agf_longest in AG 1, and a byte of a leaf of its by-size btree
+        raw[fixtures::AG_BLOCKS as usize * 4096 + 512 + 59] ^= 1;
+        raw[(fixtures::AG_BLOCKS + 10) as usize * 4096 + 100] ^= 1;
+        // di_size of an inode in AG 0
+        let inode = fixtures::inode_offset(fixtures::ino(0, 130));
+        raw[inode + 60] = 1;
+
+        let report = super::verify(&mut Cursor::new(raw)).unwrap();
+        let found: Vec<(super::Metadata, u32, u32, Option<u64>)> = report.findings.iter()
+            .map(|f| (f.metadata, f.agno, f.agbno, f.ino))
+            .collect();
+        assert_eq!(found, vec![(super::Metadata::Inode, 0, 16, Some(130)),
+                               (super::Metadata::Agf, 1, 0, None),
+                               (super::Metadata::Cntbt, 1, 10, None)]);
+        assert!(report.findings[1].to_string()
+            .starts_with("AG 1 block 0: agf checksum mismatch (stored 0x"));
+        assert_eq!(report.findings[0].to_string().split(':').next(), Some("AG 0 block 16"));
+    }
+
+    #[test]
+    fn it_reports_a_chunk_past_the_last_agino() {
+        let mut raw = fixtures::image(false);
+        // The first record of the inobt leaf in AG 1.
+        let offset = (fixtures::AG_BLOCKS + 3) as usize * 4096 + 16;
+        fixtures::put_u32(&mut raw, offset, 0xffff_fff0);
+        let report = super::verify(&mut Cursor::new(raw)).unwrap();
+        let last = report.findings.last().unwrap();
+        assert_eq!((last.metadata, last.agno, last.problem.clone()),
+                   (super::Metadata::Inobt, 1, super::Problem::OutOfRange));
+    }
+
+    #[test]
+    fn it_reports_a_damaged_primary_superblock() {
+        let mut raw = fixtures::image(true);
+        raw[130] ^= 1;
+        let report = super::verify(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(report.findings.len(), 1);
+        assert_eq!(report.findings[0].metadata, super::Metadata::Superblock);
+        assert_eq!(report.findings[0].agno, 0);
+    }
+}
+
+/// The kind of metadata a finding is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metadata {
+    Superblock,
+    Agf,
+    Agi,
+    Agfl,
+    Bnobt,
+    Cntbt,
+    Inobt,
+    Finobt,
+    Rmapbt,
+    Refcountbt,
+    Inode,
+    Directory,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The block doesn't start with the magic number for its type.
+    BadMagic { found: u32 },
+    /// The stored checksum doesn't match the contents.
+    BadChecksum { stored: u32, computed: u32 },
+    /// A pointer leads outside its allocation group.
+    OutOfRange,
+    /// The block couldn't be read.
+    Unreadable,
+}
+
+/// One damaged piece of metadata.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub metadata: Metadata,
+    pub agno: u32,
+    /// Block within the AG, in filesystem blocks.
+    pub agbno: u32,
+    /// Inode number, for inodes and directory blocks.
+    pub ino: Option<u64>,
+    pub problem: Problem,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Number of blocks and inodes checked.
+    pub checked: u64,
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Metadata::Superblock => "superblock",
+            Metadata::Agf => "agf",
+            Metadata::Agi => "agi",
+            Metadata::Agfl => "agfl",
+            Metadata::Bnobt => "bnobt",
+            Metadata::Cntbt => "cntbt",
+            Metadata::Inobt => "inobt",
+            Metadata::Finobt => "finobt",
+            Metadata::Rmapbt => "rmapbt",
+            Metadata::Refcountbt => "refcountbt",
+            Metadata::Inode => "inode",
+            Metadata::Directory => "directory",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AG {} block {}: {}", self.agno, self.agbno, self.metadata)?;
+        if let Some(ino) = self.ino {
+            write!(f, " {}", ino)?;
+        }
+        match self.problem {
+            Problem::BadMagic { found } => write!(f, " bad magic {:#x}", found),
+            Problem::BadChecksum { stored, computed } => {
+                write!(f, " checksum mismatch (stored {:#010x}, computed {:#010x})", stored, computed)
+            }
+            Problem::OutOfRange => write!(f, " points outside its AG"),
+            Problem::Unreadable => write!(f, " could not be read"),
+        }
+    }
+}
+
+/// Directory block magics checksummed at offset 4 (data, block and free
+/// blocks, v5 then v4).
+const DIR_BLOCK_MAGICS: [u32; 6] = [0x58444233, 0x58444433, 0x58444633,
+                                    0x58443242, 0x58443244, 0x58443246];
+/// Directory leaf and node magics at offset 8, checksummed at offset 12 (v5
+/// then v4).
+const DIR_DA_MAGICS: [u16; 6] = [0x3df1, 0x3dff, 0x3ebe, 0xd2f1, 0xd2ff, 0xfebe];
+
+fn be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+struct Verifier<'a, R: 'a> {
+    dev: &'a mut R,
+    sb: Superblock,
+    report: Report,
+}
+
+/// Verifies everything reachable from the primary superblock. Damage is
+/// collected into the report; an error is only returned if the primary
+/// superblock can't be read or isn't an XFS superblock at all.
+pub fn verify<R: Read + Seek>(dev: &mut R) -> Result<Report, XfsError> {
+    let sector = superblock::read_at(dev, 0, 512)?;
+    let sb = superblock::parse(&sector)?;
+    let mut verifier = Verifier {
+        dev,
+        sb,
+        report: Report::default(),
+    };
+    for agno in 0..verifier.sb.ag_count {
+        verifier.check_ag(agno);
+    }
+    Ok(verifier.report)
+}
+
+impl<'a, R: Read + Seek> Verifier<'a, R> {
+    fn finding(&mut self, metadata: Metadata, agno: u32, agbno: u32, ino: Option<u64>, problem: Problem) {
+        self.report.findings.push(Finding {
+            metadata,
+            agno,
+            agbno,
+            ino,
+            problem,
+        });
+    }
+
+    fn read(&mut self, offset: u64, len: usize) -> Option<Vec<u8>> {
+        superblock::read_at(self.dev, offset, len).ok()
+    }
+
+    /// Checks the checksum at `crc_offset`, if this is a v5 filesystem.
+    fn check_crc(&mut self, buf: &[u8], crc_offset: usize, metadata: Metadata, agno: u32, agbno: u32,
+                 ino: Option<u64>) -> bool {
+        if !self.sb.has_crc() || crc::verify(buf, crc_offset) {
+            return true;
+        }
+        let problem = Problem::BadChecksum {
+            stored: crc::stored_crc(buf, crc_offset),
+            computed: crc::block_crc(buf, crc_offset),
+        };
+        self.finding(metadata, agno, agbno, ino, problem);
+        false
+    }
+
+    /// Checks a 32 bit magic number and the checksum of a header sector.
+    /// Returns whether the magic matched, in which case the header is worth
+    /// following even if its checksum is off.
+    fn check_sector(&mut self, buf: &[u8], magic: u32, crc_offset: usize, metadata: Metadata,
+                    agno: u32) -> bool {
+        self.report.checked += 1;
+        let found = match buf.get(..4) {
+            Some(word) => btree::be32(word, 0),
+            None => {
+                self.finding(metadata, agno, 0, None, Problem::Unreadable);
+                return false;
+            }
+        };
+        if found != magic {
+            self.finding(metadata, agno, 0, None, Problem::BadMagic { found });
+            return false;
+        }
+        self.check_crc(buf, crc_offset, metadata, agno, 0, None);
+        true
+    }
+
+    fn check_ag(&mut self, agno: u32) {
+        let sector = self.sb.sector_size as usize;
+        let headers = match self.read(self.sb.agb_offset(agno, 0), 4 * sector) {
+            Some(headers) => headers,
+            None => {
+                self.report.checked += 1;
+                self.finding(Metadata::Superblock, agno, 0, None, Problem::Unreadable);
+                return;
+            }
+        };
+        self.check_sector(&headers[..sector], superblock::MAGIC, superblock::CRC_OFFSET,
+                          Metadata::Superblock, agno);
+        let agf_ok = self.check_sector(&headers[sector..2 * sector], ag::AGF_MAGIC, ag::AGF_CRC_OFFSET,
+                                       Metadata::Agf, agno);
+        let agi_ok = self.check_sector(&headers[2 * sector..3 * sector], ag::AGI_MAGIC,
+                                       ag::AGI_CRC_OFFSET, Metadata::Agi, agno);
+        if self.sb.has_crc() {
+            self.check_sector(&headers[3 * sector..], ag::AGFL_MAGIC, ag::AGFL_CRC_OFFSET,
+                              Metadata::Agfl, agno);
+        }
+
+        let features = self.sb.features();
+        if let (true, Ok(agf)) = (agf_ok, ag::parse_agf(&headers[sector..2 * sector])) {
+            self.check_btree(agno, agf.bno_root, btree::BNOBT, Metadata::Bnobt, None);
+            self.check_btree(agno, agf.cnt_root, btree::CNTBT, Metadata::Cntbt, None);
+            if features.rmapbt {
+                self.check_btree(agno, agf.rmap_root, btree::RMAPBT, Metadata::Rmapbt, None);
+            }
+            if features.reflink {
+                self.check_btree(agno, agf.refcount_root, btree::REFCOUNTBT,
+                                 Metadata::Refcountbt, None);
+            }
+        }
+        if let (true, Ok(agi)) = (agi_ok, ag::parse_agi(&headers[2 * sector..3 * sector])) {
+            let mut chunks = vec![];
+            self.check_btree(agno, agi.root, btree::INOBT, Metadata::Inobt, Some(&mut chunks));
+            if features.finobt {
+                self.check_btree(agno, agi.free_root, btree::FINOBT, Metadata::Finobt, None);
+            }
+            for (startino, holemask) in chunks {
+                self.check_chunk(agno, startino, holemask);
+            }
+        }
+    }
+
+    /// Checks every block of a btree, collecting inode chunk records
+    /// (start agino, sparse hole mask) from the leaves into `chunks`.
+    fn check_btree(&mut self, agno: u32, root: u32, shape: Shape, metadata: Metadata,
+                   mut chunks: Option<&mut Vec<(u32, u16)>>) {
+        let block_size = self.sb.block_size as usize;
+        let hdr = btree::header_len(&self.sb);
+        let limit = self.sb.ag_length(agno);
+        let mut pending = vec![root];
+        let mut seen = 0;
+        while let Some(agbno) = pending.pop() {
+            seen += 1;
+            if agbno >= limit || seen > limit {
+                self.finding(metadata, agno, agbno, None, Problem::OutOfRange);
+                continue;
+            }
+            self.report.checked += 1;
+            let block = match self.read(self.sb.agb_offset(agno, agbno), block_size) {
+                Some(block) => block,
+                None => {
+                    self.finding(metadata, agno, agbno, None, Problem::Unreadable);
+                    continue;
+                }
+            };
+            let header = match btree::parse_header(&block, &self.sb) {
+                Ok(header) => header,
+                Err(_) => continue,
+            };
+            if !shape.magic.matches(&self.sb, header.magic) {
+                self.finding(metadata, agno, agbno, None, Problem::BadMagic { found: header.magic });
+                continue;
+            }
+            if !self.check_crc(&block, btree::CRC_OFFSET, metadata, agno, agbno, None) {
+                continue;
+            }
+            let numrecs = header.numrecs as usize;
+            if header.level > 0 {
+                let maxrecs = (block_size - hdr) / (shape.key_len + 4);
+                let ptrs = hdr + maxrecs * shape.key_len;
+                for i in (0..numrecs.min(maxrecs)).rev() {
+                    pending.push(btree::be32(&block, ptrs + i * 4));
+                }
+            } else if let Some(ref mut chunks) = chunks {
+                let sparse = self.sb.features().sparse_inodes;
+                for i in 0..numrecs.min((block_size - hdr) / shape.rec_len) {
+                    let rec = hdr + i * shape.rec_len;
+                    let holemask = if sparse { be16(&block, rec + 4) } else { 0 };
+                    chunks.push((btree::be32(&block, rec), holemask));
+                }
+            }
+        }
+    }
+
+    /// Checks the 64 inodes of a chunk, skipping sparse holes.
+    fn check_chunk(&mut self, agno: u32, startino: u32, holemask: u16) {
+        let inode_size = self.sb.inode_size as usize;
+        let per_block_log = self.sb.inodes_per_block_log;
+        let ag_shift = u32::from(self.sb.ag_blocks_log) + u32::from(per_block_log);
+        for i in 0..64 {
+            if holemask & (1 << (i / 4)) != 0 {
+                continue;
+            }
+            // A damaged record can start a chunk too near the top of the
+            // agino space to hold 64 inodes.
+            let agino = match startino.checked_add(i) {
+                Some(agino) => agino,
+                None => {
+                    self.finding(Metadata::Inobt, agno, startino >> per_block_log, None, Problem::OutOfRange);
+                    return;
+                }
+            };
+            let agbno = agino >> per_block_log;
+            let ino = u64::from(agno) << ag_shift | u64::from(agino);
+            self.report.checked += 1;
+            let inode = match self.read(self.sb.ino_offset(ino), inode_size) {
+                Some(inode) => inode,
+                None => {
+                    self.finding(Metadata::Inode, agno, agbno, Some(ino), Problem::Unreadable);
+                    continue;
+                }
+            };
+            let found = be16(&inode, 0);
+            if found != inode::MAGIC {
+                self.finding(Metadata::Inode, agno, agbno, Some(ino), Problem::BadMagic { found: u32::from(found) });
+                continue;
+            }
+            if !self.check_crc(&inode, inode::CRC_OFFSET, Metadata::Inode, agno, agbno, Some(ino)) {
+                continue;
+            }
+            let extents = match inode::parse(&inode) {
+                Ok(ref dinode) if dinode.file_type() == FileType::Directory &&
+                                  dinode.format == Format::Extents => dinode.extents(),
+                _ => continue,
+            };
+            if let Ok(extents) = extents {
+                self.check_directory(ino, &extents);
+            }
+        }
+    }
+
+    /// Checks the blocks of an extent-format directory.
+    fn check_directory(&mut self, ino: u64, extents: &[Extent]) {
+        let dir_blocks = match 1u64.checked_shl(u32::from(self.sb.dir_block_log)) {
+            Some(dir_blocks) if dir_blocks * u64::from(self.sb.block_size) <= 1 << 16 => dir_blocks,
+            _ => return,
+        };
+        let dir_bytes = self.sb.block_size as usize * dir_blocks as usize;
+        let ag_mask = (1u64 << self.sb.ag_blocks_log) - 1;
+        for extent in extents {
+            let (startoff, startblock, blockcount) = (extent.offset, extent.start_block, extent.length);
+            let mut off = startoff.div_ceil(dir_blocks) * dir_blocks;
+            while off + dir_blocks <= startoff + blockcount {
+                let fsbno = startblock + (off - startoff);
+                let (agno, agbno) = ((fsbno >> self.sb.ag_blocks_log) as u32, (fsbno & ag_mask) as u32);
+                off += dir_blocks;
+                self.report.checked += 1;
+                let block = match self.read(self.sb.fsb_offset(fsbno), dir_bytes) {
+                    Some(block) => block,
+                    None => {
+                        self.finding(Metadata::Directory, agno, agbno, Some(ino), Problem::Unreadable);
+                        continue;
+                    }
+                };
+                let crc_offset = if DIR_BLOCK_MAGICS.contains(&btree::be32(&block, 0)) {
+                    4
+                } else if DIR_DA_MAGICS.contains(&be16(&block, 8)) {
+                    12
+                } else {
+                    let found = btree::be32(&block, 0);
+                    self.finding(Metadata::Directory, agno, agbno, Some(ino), Problem::BadMagic { found });
+                    continue;
+                };
+                self.check_crc(&block, crc_offset, Metadata::Directory, agno, agbno, Some(ino));
+            }
+        }
+    }
+}
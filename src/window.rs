@@ -0,0 +1,218 @@
+//! A ring buffer of per-interval counter rates, summarised over the last
+//! N samples or T seconds: min, max, mean, standard deviation and p50,
+//! p95 and p99.
+//!
+//! Fields are named by their dotted `XfsStat` path. Gauges are kept as
+//! their value at the end of each interval rather than as a rate.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use fields::{self, Kind, FIELDS};
+use XfsStat;
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use fixtures;
+
+    use super::Window;
+
+    fn sample(force_sleep: u32, get_locked_waited: u32) -> ::XfsStat {
+        fixtures::stat(&[("log", &format!("0 0 0 0 {}", force_sleep)),
+                         ("buf", &format!("0 0 0 {} 0 0 0 0 0", get_locked_waited))])
+    }
+
+    #[test]
+    fn it_summarises_rates() {
+        let start = Instant::now();
+        let mut window = Window::new(100);
+        let mut waited = 0;
+        // Waits at 1/s, 2/s, ... 120/s over 1s intervals; the first 20
+        // fall out of the buffer.
+        for i in 0..121 {
+            waited += i;
+            window.push(&sample(0, waited), start + Duration::from_secs(u64::from(i)));
+        }
+        assert_eq!(window.len(), 100);
+        let rates = window.rates("buf_statistics.get_locked_waited").unwrap();
+        assert_eq!((rates[0], rates[99]), (21.0, 120.0));
+
+        let summary = window.summary("buf_statistics.get_locked_waited").unwrap();
+        assert_eq!((summary.samples, summary.min, summary.max, summary.mean), (100, 21.0, 120.0, 70.5));
+        assert!((summary.stddev - 28.866).abs() < 0.001);
+        assert_eq!((summary.p50, summary.p95, summary.p99), (70.0, 115.0, 119.0));
+        assert_eq!(window.summary("log_operations.force_sleep").unwrap().max, 0.0);
+
+        // The last ten seconds are the last ten intervals.
+        let recent = window.summary_over("buf_statistics.get_locked_waited", Duration::from_secs(10)).unwrap();
+        assert_eq!((recent.samples, recent.min, recent.max), (10, 111.0, 120.0));
+        assert_eq!(format!("{}", recent),
+                   "n=10 min=111.00 mean=115.50 max=120.00 stddev=2.87 p50=115.00 p95=120.00 p99=120.00");
+
+        assert!(window.summary("buf_statistics.nope").is_none());
+    }
+
+    #[test]
+    fn it_ages_out_samples() {
+        let start = Instant::now();
+        let mut window = Window::new(1000).max_age(Duration::from_secs(30));
+        assert!(window.summary("log_operations.force_sleep").is_none());
+        window.push(&sample(u32::MAX - 9, 0), start);
+        for i in 1..=60u32 {
+            // Every interval is 10s; the first wraps the 32-bit counter.
+            window.push(&sample(i * 100 - 10, 0), start + Duration::from_secs(u64::from(i) * 10));
+        }
+        assert_eq!(window.len(), 4);
+        let summary = window.summary("log_operations.force_sleep").unwrap();
+        assert_eq!((summary.min, summary.max), (10.0, 10.0));
+
+        let mut window = Window::new(10);
+        window.push(&sample(u32::MAX - 9, 0), start);
+        window.push(&sample(90, 0), start + Duration::from_secs(10));
+        assert_eq!(window.rates("log_operations.force_sleep").unwrap(), [10.0]);
+        // A sample no later than the last only moves the baseline.
+        window.push(&sample(500, 0), start + Duration::from_secs(10));
+        assert_eq!(window.len(), 1);
+    }
+}
+
+/// One field's rates over a window.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub samples: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub stddev: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+/// The rate of every field over one interval, in `FIELDS` order.
+#[derive(Clone, Debug)]
+struct Interval {
+    end: Instant,
+    rates: Vec<f64>,
+}
+
+/// Holds the rates of the last `capacity` intervals, and optionally no
+/// more than `max_age` of them.
+#[derive(Clone, Debug)]
+pub struct Window {
+    capacity: usize,
+    max_age: Option<Duration>,
+    /// The last sample, which the next interval starts from.
+    last: Option<(Instant, Vec<u64>)>,
+    intervals: VecDeque<Interval>,
+}
+
+/// The value at or below which a fraction `q` of the sorted values fall,
+/// by nearest rank.
+fn percentile(sorted: &[f64], q: f64) -> f64 {
+    let rank = (q * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn summarize(rates: &[f64]) -> Option<Summary> {
+    if rates.is_empty() {
+        return None;
+    }
+    let mut sorted = rates.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let n = rates.len() as f64;
+    let mean = rates.iter().sum::<f64>() / n;
+    let variance = rates.iter().map(|rate| (rate - mean) * (rate - mean)).sum::<f64>() / n;
+    Some(Summary {
+        samples: rates.len(),
+        min: sorted[0],
+        max: sorted[sorted.len() - 1],
+        mean,
+        stddev: variance.sqrt(),
+        p50: percentile(&sorted, 0.50),
+        p95: percentile(&sorted, 0.95),
+        p99: percentile(&sorted, 0.99),
+    })
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "n={} min={:.2} mean={:.2} max={:.2} stddev={:.2} p50={:.2} p95={:.2} p99={:.2}",
+               self.samples, self.min, self.mean, self.max, self.stddev, self.p50, self.p95, self.p99)
+    }
+}
+
+impl Window {
+    pub fn new(capacity: usize) -> Window {
+        Window { capacity, max_age: None, last: None, intervals: VecDeque::with_capacity(capacity) }
+    }
+
+    /// Also drops intervals that ended more than `age` before the newest.
+    pub fn max_age(mut self, age: Duration) -> Window {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// Intervals held.
+    pub fn len(&self) -> usize {
+        self.intervals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+
+    /// Adds a sample taken at `at`, recording the interval since the last.
+    pub fn push(&mut self, stat: &XfsStat, at: Instant) {
+        let values: Vec<u64> = FIELDS.iter().map(|info| info.value(stat)).collect();
+        if let Some((last_at, ref last)) = self.last {
+            let seconds = at.saturating_duration_since(last_at).as_secs_f64();
+            if seconds > 0.0 {
+                let rates = FIELDS.iter()
+                    .zip(last.iter().zip(&values))
+                    .map(|(info, (&before, &after))| match info.kind {
+                        Kind::Counter => info.change(before, after) / seconds,
+                        Kind::Gauge => after as f64,
+                    })
+                    .collect();
+                self.intervals.push_back(Interval { end: at, rates });
+            }
+        }
+        self.last = Some((at, values));
+
+        while self.intervals.len() > self.capacity {
+            self.intervals.pop_front();
+        }
+        if let (Some(age), Some(newest)) = (self.max_age, self.intervals.back().map(|i| i.end)) {
+            while self.intervals.front().is_some_and(|oldest| newest.duration_since(oldest.end) > age) {
+                self.intervals.pop_front();
+            }
+        }
+    }
+
+    /// The per-second rates of a counter, or the values of a gauge,
+    /// oldest first.
+    pub fn rates(&self, path: &str) -> Option<Vec<f64>> {
+        let info = fields::field(path)?;
+        let index = FIELDS.iter().position(|other| other == info)?;
+        Some(self.intervals.iter().map(|interval| interval.rates[index]).collect())
+    }
+
+    /// A counter's rates over every interval held; None for an unknown
+    /// counter or an empty window.
+    pub fn summary(&self, path: &str) -> Option<Summary> {
+        summarize(&self.rates(path)?)
+    }
+
+    /// A counter's rates over the intervals that ended within `span` of
+    /// the newest, counting the newest.
+    pub fn summary_over(&self, path: &str, span: Duration) -> Option<Summary> {
+        let newest = self.intervals.back()?.end;
+        let rates = self.rates(path)?;
+        let recent = self.intervals.iter().filter(|interval| newest.duration_since(interval.end) < span).count();
+        summarize(&rates[rates.len() - recent..])
+    }
+}
@@ -0,0 +1,504 @@
+//! Offline decoding of the extended attribute fork in short-form, leaf and
+//! node form.
+//!
+//! Small attribute sets live in the inode. Once they outgrow it they move
+//! to a leaf block in the attribute fork, and then to a btree of leaves
+//! indexed by name hash. Names always stay in the leaves; values that don't
+//! fit next to their name are stored in separate "remote" blocks.
+
+use std::borrow::Cow;
+use std::io::{Read, Seek};
+
+use btree::be32;
+use directory::DA_NODE_MAGIC;
+use inode::{self, Dinode, Extent};
+use superblock::Superblock;
+use XfsError;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use fixtures;
+    use superblock;
+
+    const AG1: u64 = 1 << 6;
+
+    enum Value<'a> {
+        Local(&'a [u8]),
+        Remote(u32, u32),
+    }
+
+    /// An attribute leaf block, names packed downwards from its end.
+    fn leaf(v5: bool, attrs: &[(u8, &str, Value)], forw: u32) -> Vec<u8> {
+        let mut block = fixtures::da_block(v5, if v5 { 0x3bee } else { 0xfbee }, forw, 0);
+        let hdr = if v5 { 80 } else { 32 };
+        let info = if v5 { 56 } else { 12 };
+        fixtures::put_u16(&mut block, info, attrs.len() as u16);
+        let mut end = block.len();
+        for (i, &(flags, name, ref value)) in attrs.iter().enumerate() {
+            let entry = hdr + i * 8;
+            let mut nameval = vec![];
+            match *value {
+                Value::Local(value) => {
+                    nameval.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                    nameval.push(name.len() as u8);
+                    nameval.extend_from_slice(name.as_bytes());
+                    nameval.extend_from_slice(value);
+                    block[entry + 6] = flags | super::LOCAL;
+                }
+                Value::Remote(blk, len) => {
+                    nameval.extend_from_slice(&blk.to_be_bytes());
+                    nameval.extend_from_slice(&len.to_be_bytes());
+                    nameval.push(name.len() as u8);
+                    nameval.extend_from_slice(name.as_bytes());
+                    block[entry + 6] = flags;
+                }
+            }
+            end -= nameval.len().div_ceil(4) * 4;
+            block[end..end + nameval.len()].copy_from_slice(&nameval);
+            fixtures::put_u16(&mut block, entry + 4, end as u16);
+        }
+        block
+    }
+
+    fn put_attr_inode(image: &mut [u8], v5: bool, ino: u64, aformat: u8, extents: &[[u8; 16]]) {
+        let mut inode = fixtures::dinode(v5, ino, 0o100644);
+        let fork = if v5 { 176 } else { 100 } + 120;
+        inode[82] = 15;
+        inode[83] = aformat;
+        fixtures::put_u16(&mut inode, 80, extents.len() as u16);
+        for (i, rec) in extents.iter().enumerate() {
+            inode[fork + i * 16..fork + (i + 1) * 16].copy_from_slice(rec);
+        }
+        fixtures::put_inode(image, v5, ino, &inode);
+    }
+
+    fn read(raw: Vec<u8>, ino: u64) -> super::Attributes {
+        let mut image = Cursor::new(raw);
+        let sb = superblock::read(&mut image).unwrap();
+        super::read(&mut image, &sb, ino).unwrap()
+    }
+
+    #[test]
+    fn it_decodes_short_form_attributes() {
+        let mut raw = fixtures::image(true);
+        let mut inode = fixtures::dinode(true, 136, 0o100644);
+        inode[82] = 15;
+        inode[83] = 1;
+        let fork = [0, 26, 3, 0,
+                    5, 4, 0, b'c', b'o', b'l', b'o', b'r', b'b', b'l', b'u', b'e',
+                    1, 1, 0x02, b't', b'1',
+                    2, 0, 0x08, b'p', b'p'];
+        inode[296..296 + fork.len()].copy_from_slice(&fork);
+        fixtures::put_inode(&mut raw, true, 136, &inode);
+
+        let attrs = read(raw, 136);
+        assert_eq!(attrs.format, Some(super::Format::ShortForm));
+        let found: Vec<(super::Namespace, String, &[u8])> = attrs.attrs.iter()
+            .map(|a| (a.namespace, a.name().into_owned(), &a.value[..]))
+            .collect();
+        assert_eq!(found, vec![(super::Namespace::User, "color".to_string(), &b"blue"[..]),
+                               (super::Namespace::Trusted, "t".to_string(), &b"1"[..]),
+                               (super::Namespace::Parent, "pp".to_string(), &b""[..])]);
+        assert_eq!(attrs.stats.short_form, super::Usage { attrs: 3, value_bytes: 5 });
+        assert_eq!((attrs.blocks, attrs.levels), (0, 0));
+    }
+
+    #[test]
+    fn it_decodes_a_leaf_with_a_remote_value() {
+        let mut raw = fixtures::image(false);
+        put_attr_inode(&mut raw, false, 137, 2, &[fixtures::extent(0, AG1 | 16, 3, false)]);
+        let attrs = [(0x04, "selinux", Value::Local(b"system_u")), (0, "big", Value::Remote(1, 5000))];
+        fixtures::put_block(&mut raw, AG1 | 16, &leaf(false, &attrs, 0));
+        let value: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        fixtures::put_block(&mut raw, AG1 | 17, &value[..4096]);
+        fixtures::put_block(&mut raw, AG1 | 18, &value[4096..]);
+
+        let attrs = read(raw, 137);
+        assert_eq!(attrs.format, Some(super::Format::Leaf));
+        assert_eq!(attrs.attrs[0].namespace, super::Namespace::Secure);
+        assert_eq!(attrs.attrs[0].storage, super::Storage::Local);
+        assert_eq!(attrs.attrs[1].storage, super::Storage::Remote { blocks: 2 });
+        assert_eq!(attrs.attrs[1].value, value);
+        assert_eq!(attrs.stats.local, super::Usage { attrs: 1, value_bytes: 8 });
+        assert_eq!(attrs.stats.remote, super::Usage { attrs: 1, value_bytes: 5000 });
+        assert_eq!(attrs.stats.remote_blocks, 2);
+        assert_eq!((attrs.blocks, attrs.levels), (3, 1));
+    }
+
+    #[test]
+    fn it_rejects_truncated_and_oversized_remote_values() {
+        for &(len, mapped) in &[(5000, 2), (70000, 20)] {
+            let mut raw = fixtures::image(false);
+            put_attr_inode(&mut raw, false, 137, 2, &[fixtures::extent(0, AG1 | 16, mapped, false)]);
+            fixtures::put_block(&mut raw, AG1 | 16, &leaf(false, &[(0, "big", Value::Remote(1, len))], 0));
+            let mut image = Cursor::new(raw);
+            let sb = superblock::read(&mut image).unwrap();
+            assert!(super::read(&mut image, &sb, 137).is_err());
+        }
+    }
+
+    #[test]
+    fn it_walks_a_node_attribute_fork() {
+        let mut raw = fixtures::image(true);
+        put_attr_inode(&mut raw, true, 138, 2, &[fixtures::extent(0, AG1 | 20, 4, false)]);
+        let mut root = fixtures::da_block(true, 0x3ebe, 0, 0);
+        fixtures::put_u16(&mut root, 56, 2);
+        fixtures::put_u16(&mut root, 58, 1);
+        fixtures::put_u32(&mut root, 68, 1);
+        fixtures::put_u32(&mut root, 76, 2);
+        fixtures::put_block(&mut raw, AG1 | 20, &root);
+        fixtures::put_block(&mut raw, AG1 | 21, &leaf(true, &[(0, "a", Value::Local(b"1"))], 2));
+        fixtures::put_block(&mut raw, AG1 | 22, &leaf(true, &[(0x02, "b", Value::Remote(3, 10))], 0));
+        let mut remote = vec![0u8; 4096];
+        fixtures::put_u32(&mut remote, 0, 0x5841524d);
+        fixtures::put_u32(&mut remote, 8, 10);
+        remote[56..66].copy_from_slice(b"0123456789");
+        fixtures::put_block(&mut raw, AG1 | 23, &remote);
+
+        let attrs = read(raw, 138);
+        assert_eq!(attrs.format, Some(super::Format::Node));
+        assert_eq!(attrs.levels, 2);
+        let names: Vec<String> = attrs.attrs.iter().map(|a| a.name().into_owned()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(attrs.attrs[1].value, b"0123456789");
+        assert_eq!(attrs.attrs[1].namespace, super::Namespace::Trusted);
+    }
+
+    #[test]
+    fn it_handles_a_missing_attribute_fork() {
+        let attrs = read(fixtures::image(true), 130);
+        assert_eq!(attrs.format, None);
+        assert!(attrs.attrs.is_empty());
+    }
+}
+
+/// Attribute leaf block (v4, v5).
+const LEAF_MAGIC: (u16, u16) = (0xfbee, 0x3bee);
+/// Remote value block header (v5 only), "XARM".
+const REMOTE_MAGIC: u32 = 0x5841524d;
+/// Size of the header on every v5 remote value block.
+const REMOTE_HEADER_LEN: usize = 56;
+/// The largest value the kernel stores (`XATTR_SIZE_MAX`).
+const SIZE_MAX: usize = 65536;
+
+/// Value is stored next to the name in the leaf.
+pub const LOCAL: u8 = 0x01;
+pub const ROOT: u8 = 0x02;
+pub const SECURE: u8 = 0x04;
+pub const PARENT: u8 = 0x08;
+/// Set while an attribute is being created or removed.
+pub const INCOMPLETE: u8 = 0x80;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Namespace {
+    /// "user." attributes.
+    User,
+    /// "trusted." attributes (`ATTR_ROOT`).
+    Trusted,
+    /// "security." attributes.
+    Secure,
+    /// Directory parent pointers.
+    Parent,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Attributes stored in the inode itself.
+    ShortForm,
+    /// A single leaf block.
+    Leaf,
+    /// A btree of leaf blocks.
+    Node,
+}
+
+/// Where an attribute's value is kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Storage {
+    /// In the inode, with the name.
+    ShortForm,
+    /// In the leaf block, with the name.
+    Local,
+    /// In blocks of their own.
+    Remote { blocks: u32 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attr {
+    pub namespace: Namespace,
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+    pub storage: Storage,
+    /// Left behind by an interrupted set or remove.
+    pub incomplete: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub attrs: u64,
+    pub value_bytes: u64,
+}
+
+/// How attributes are stored: next to their names or externally.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub short_form: Usage,
+    pub local: Usage,
+    pub remote: Usage,
+    /// Filesystem blocks holding remote values.
+    pub remote_blocks: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Attributes {
+    pub ino: u64,
+    /// None if the inode has no attribute fork.
+    pub format: Option<Format>,
+    pub attrs: Vec<Attr>,
+    pub stats: Stats,
+    /// Filesystem blocks mapped by the attribute fork.
+    pub blocks: u64,
+    /// Height of the leaf btree: zero for short-form, one for a single
+    /// leaf.
+    pub levels: u32,
+}
+
+impl Attr {
+    pub fn name(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.name)
+    }
+}
+
+impl Stats {
+    fn add(&mut self, attr: &Attr) {
+        let usage = match attr.storage {
+            Storage::ShortForm => &mut self.short_form,
+            Storage::Local => &mut self.local,
+            Storage::Remote { blocks } => {
+                self.remote_blocks += u64::from(blocks);
+                &mut self.remote
+            }
+        };
+        usage.attrs += 1;
+        usage.value_bytes += attr.value.len() as u64;
+    }
+
+    /// Merges the counts of another inode.
+    pub fn merge(&mut self, other: &Stats) {
+        for (ours, theirs) in [(&mut self.short_form, &other.short_form),
+                               (&mut self.local, &other.local),
+                               (&mut self.remote, &other.remote)] {
+            ours.attrs += theirs.attrs;
+            ours.value_bytes += theirs.value_bytes;
+        }
+        self.remote_blocks += other.remote_blocks;
+    }
+}
+
+fn namespace(flags: u8) -> Namespace {
+    if flags & PARENT != 0 {
+        Namespace::Parent
+    } else if flags & SECURE != 0 {
+        Namespace::Secure
+    } else if flags & ROOT != 0 {
+        Namespace::Trusted
+    } else {
+        Namespace::User
+    }
+}
+
+fn be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+/// Decodes short-form attributes from the start of the attribute fork.
+pub fn parse_short_form(fork: &[u8]) -> Result<Vec<Attr>, XfsError> {
+    if fork.len() < 4 {
+        return Err(XfsError::Incomplete);
+    }
+    let count = fork[2] as usize;
+    let mut offset = 4;
+    let mut attrs = Vec::with_capacity(count);
+    for _ in 0..count {
+        if offset + 3 > fork.len() {
+            return Err(XfsError::Incomplete);
+        }
+        let (namelen, valuelen, flags) = (fork[offset] as usize, fork[offset + 1] as usize, fork[offset + 2]);
+        let name_start = offset + 3;
+        let end = name_start + namelen + valuelen;
+        if end > fork.len() {
+            return Err(XfsError::Incomplete);
+        }
+        attrs.push(Attr {
+            namespace: namespace(flags),
+            name: fork[name_start..name_start + namelen].to_vec(),
+            value: fork[name_start + namelen..end].to_vec(),
+            storage: Storage::ShortForm,
+            incomplete: flags & INCOMPLETE != 0,
+        });
+        offset = end;
+    }
+    Ok(attrs)
+}
+
+/// Reads a remote value of `len` bytes starting at attribute fork block
+/// `blk`. Returns the value and the number of blocks it occupies.
+fn read_remote<R>(dev: &mut R, sb: &Superblock, extents: &[Extent], blk: u32, len: usize)
+                  -> Result<(Vec<u8>, u32), XfsError>
+    where R: Read + Seek
+{
+    if len > SIZE_MAX {
+        return Err(XfsError::Parse);
+    }
+    let block_size = sb.block_size as usize;
+    let header = if sb.has_crc() { REMOTE_HEADER_LEN } else { 0 };
+    let per_block = match block_size.checked_sub(header) {
+        Some(per_block) if per_block > 0 => per_block,
+        _ => return Err(XfsError::Parse),
+    };
+    let blocks = len.div_ceil(per_block).max(1);
+    let raw = inode::read_blocks(dev, sb, extents, u64::from(blk), blocks as u64)?;
+    let mut value = Vec::with_capacity(len);
+    for block in raw.chunks(block_size) {
+        if sb.has_crc() && be32(block, 0) != REMOTE_MAGIC {
+            return Err(XfsError::BadMagic);
+        }
+        let take = per_block.min(len - value.len());
+        value.extend_from_slice(&block[header..header + take]);
+    }
+    // The blocks run into a hole.
+    if value.len() != len {
+        return Err(XfsError::Parse);
+    }
+    Ok((value, blocks as u32))
+}
+
+/// Decodes the attributes of a leaf block, reading remote values.
+fn parse_leaf<R>(dev: &mut R, sb: &Superblock, extents: &[Extent], block: &[u8]) -> Result<Vec<Attr>, XfsError>
+    where R: Read + Seek
+{
+    let (info, hdr) = if sb.has_crc() { (56, 80) } else { (12, 32) };
+    let count = be16(block, info) as usize;
+    if hdr + count * 8 > block.len() {
+        return Err(XfsError::Parse);
+    }
+    let mut attrs = Vec::with_capacity(count);
+    for i in 0..count {
+        let entry = hdr + i * 8;
+        let nameidx = be16(block, entry + 4) as usize;
+        let flags = block[entry + 6];
+        let attr = if flags & LOCAL != 0 {
+            if nameidx + 3 > block.len() {
+                return Err(XfsError::Parse);
+            }
+            let valuelen = be16(block, nameidx) as usize;
+            let namelen = block[nameidx + 2] as usize;
+            let name_start = nameidx + 3;
+            let end = name_start + namelen + valuelen;
+            if end > block.len() {
+                return Err(XfsError::Parse);
+            }
+            Attr {
+                namespace: namespace(flags),
+                name: block[name_start..name_start + namelen].to_vec(),
+                value: block[name_start + namelen..end].to_vec(),
+                storage: Storage::Local,
+                incomplete: flags & INCOMPLETE != 0,
+            }
+        } else {
+            if nameidx + 9 > block.len() {
+                return Err(XfsError::Parse);
+            }
+            let valueblk = be32(block, nameidx);
+            let valuelen = be32(block, nameidx + 4) as usize;
+            let namelen = block[nameidx + 8] as usize;
+            let name_start = nameidx + 9;
+            if name_start + namelen > block.len() {
+                return Err(XfsError::Parse);
+            }
+            let (value, blocks) = read_remote(dev, sb, extents, valueblk, valuelen)?;
+            Attr {
+                namespace: namespace(flags),
+                name: block[name_start..name_start + namelen].to_vec(),
+                value,
+                storage: Storage::Remote { blocks },
+                incomplete: flags & INCOMPLETE != 0,
+            }
+        };
+        attrs.push(attr);
+    }
+    Ok(attrs)
+}
+
+/// Reads and decodes the attributes of inode `ino`.
+pub fn read<R: Read + Seek>(dev: &mut R, sb: &Superblock, ino: u64) -> Result<Attributes, XfsError> {
+    let inode = inode::read(dev, sb, ino)?;
+    read_inode(dev, sb, ino, &inode)
+}
+
+/// Decodes the attributes of an inode that has already been read.
+pub fn read_inode<R>(dev: &mut R, sb: &Superblock, ino: u64, inode: &Dinode) -> Result<Attributes, XfsError>
+    where R: Read + Seek
+{
+    let mut result = Attributes {
+        ino,
+        format: None,
+        attrs: vec![],
+        stats: Stats::default(),
+        blocks: 0,
+        levels: 0,
+    };
+    let fork = match inode.attr_fork() {
+        Some(fork) => fork,
+        None => return Ok(result),
+    };
+
+    if inode.aformat == inode::Format::Local {
+        result.format = Some(Format::ShortForm);
+        result.attrs = parse_short_form(fork)?;
+    } else {
+        let extents = inode::attr_extents(dev, sb, inode)?;
+        result.blocks = extents.iter().map(|e| e.length).sum();
+        let leaf_magic = if sb.has_crc() { LEAF_MAGIC.1 } else { LEAF_MAGIC.0 };
+        let node_magic = if sb.has_crc() { DA_NODE_MAGIC.1 } else { DA_NODE_MAGIC.0 };
+        let (node_hdr, level_offset) = if sb.has_crc() { (64, 58) } else { (16, 14) };
+
+        // Descend the leftmost path to the first leaf, then follow the
+        // forward sibling pointers.
+        let mut blk = 0;
+        let mut block = inode::read_blocks(dev, sb, &extents, 0, 1)?;
+        result.levels = 1;
+        if be16(&block, 8) == node_magic {
+            result.format = Some(Format::Node);
+            result.levels += u32::from(be16(&block, level_offset));
+            for _ in 1..result.levels {
+                if be16(&block, 8) != node_magic {
+                    return Err(XfsError::BadMagic);
+                }
+                blk = be32(&block, node_hdr + 4);
+                block = inode::read_blocks(dev, sb, &extents, u64::from(blk), 1)?;
+            }
+        } else {
+            result.format = Some(Format::Leaf);
+        }
+        let mut visited = 0;
+        loop {
+            if be16(&block, 8) != leaf_magic {
+                return Err(XfsError::BadMagic);
+            }
+            result.attrs.extend(parse_leaf(dev, sb, &extents, &block)?);
+            visited += 1;
+            let forw = be32(&block, 0);
+            if forw == 0 || forw == blk || visited > result.blocks {
+                break;
+            }
+            blk = forw;
+            block = inode::read_blocks(dev, sb, &extents, u64::from(blk), 1)?;
+        }
+    }
+    for attr in &result.attrs {
+        result.stats.add(attr);
+    }
+    Ok(result)
+}